@@ -0,0 +1,71 @@
+//! Angles in rad that wrap around at pi, such as the heading.
+
+use crate::Frac;
+use fixed::consts::{PI, TAU};
+use fixed::traits::FixedSigned;
+
+/// `angle` moved into -pi up to pi, in any fixed point type that holds 2 pi.
+pub fn wrap<F: FixedSigned>(angle: F) -> F {
+    let tau = F::from_num(TAU);
+    // shifting by pi first could overflow at the ends of the range
+    let angle = angle.rem_euclid(tau);
+    if angle >= F::from_num(PI) {
+        angle - tau
+    } else {
+        angle
+    }
+}
+
+/// The shortest turn from `from` to `to`, from -pi up to pi.
+pub fn difference(to: Frac, from: Frac) -> Frac {
+    wrap(to.wrapping_sub(from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f64::consts::PI;
+
+    fn close(a: Frac, b: f64) -> bool {
+        (a.to_num::<f64>() - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn wrap_keeps_angles_in_range() {
+        assert!(close(wrap(Frac::from_num(1)), 1.0));
+        assert!(close(wrap(Frac::from_num(-1)), -1.0));
+        assert!(close(wrap(Frac::from_num(4)), 4.0 - 2.0 * PI));
+        assert!(close(wrap(Frac::from_num(-4)), 2.0 * PI - 4.0));
+        assert!(close(wrap(Frac::from_num(20)), 20.0 - 6.0 * PI));
+    }
+
+    #[test]
+    fn wrap_handles_the_ends_of_the_range() {
+        // whole turns of the 2 pi a `Frac` holds, which is a bit off over thousands of turns
+        let tau = Frac::from_num(TAU).to_num::<f64>();
+        for angle in [Frac::MAX, Frac::MIN] {
+            let turned = angle.to_num::<f64>().rem_euclid(tau);
+            let expected = if turned >= PI { turned - tau } else { turned };
+            let wrapped = wrap(angle);
+            assert!(close(wrapped, expected), "{angle}: {wrapped}");
+            assert!(wrapped.to_num::<f64>().abs() <= PI, "{angle}: {wrapped}");
+        }
+    }
+
+    #[test]
+    fn difference_takes_the_short_way_around() {
+        // from just left of pi to just right of -pi is a small turn to the left
+        assert!(close(
+            difference(Frac::from_num(-3.1), Frac::from_num(3.1)),
+            2.0 * PI - 6.2
+        ));
+        assert!(close(
+            difference(Frac::from_num(3.1), Frac::from_num(-3.1)),
+            6.2 - 2.0 * PI
+        ));
+        assert!(close(
+            difference(Frac::from_num(0.5), Frac::from_num(-0.5)),
+            1.0
+        ));
+    }
+}
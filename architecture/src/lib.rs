@@ -2,29 +2,42 @@
 extern crate fixed;
 
 use core::ops;
-use fixed::traits::FromFixed;
-use fixed::types::I16F16;
+use fixed::types::{I16F16, I24F8};
 use serde::{Deserialize, Serialize};
 
+pub mod angle;
 mod control_request_impl;
+mod crc;
+mod quaternion;
+mod units;
+mod vector_ops;
+
+pub use crc::crc16;
+pub use quaternion::{Quat, Vec3};
+pub use units::*;
+use vector_ops::wide_mul;
 
 pub type Frac = I16F16;
+/// Pressures in Pa. Absolute readings do not fit in a `Frac`, the fractional bits keep the
+/// filtered values smooth.
+pub type Pressure = I24F8;
 
 pub const BASE_STATION: bool = false;
 
+/// The rates the drone turns at about each axis.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Copy)]
 pub struct Velocity {
-    pub yaw: Frac,
-    pub pitch: Frac,
-    pub roll: Frac,
+    pub yaw: RadiansPerSecond,
+    pub pitch: RadiansPerSecond,
+    pub roll: RadiansPerSecond,
 }
 
 impl Velocity {
     pub fn new() -> Self {
         Velocity {
-            yaw: Frac::from_num(0),
-            pitch: Frac::from_num(0),
-            roll: Frac::from_num(0),
+            yaw: RadiansPerSecond::ZERO,
+            pitch: RadiansPerSecond::ZERO,
+            roll: RadiansPerSecond::ZERO,
         }
     }
 }
@@ -32,40 +45,90 @@ impl ops::Add<Velocity> for Velocity {
     type Output = Velocity;
 
     fn add(self, rhs: Self) -> Self::Output {
-        Velocity {
-            yaw: self.yaw + rhs.yaw,
-            pitch: self.pitch + rhs.pitch,
-            roll: self.roll + rhs.roll,
+        self.saturating_add(rhs)
+    }
+}
+impl ops::Sub<Velocity> for Velocity {
+    type Output = Velocity;
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.saturating_sub(rhs)
+    }
+}
+impl ops::Mul<Seconds> for Velocity {
+    type Output = Attitude;
+    fn mul(self, rhs: Seconds) -> Self::Output {
+        Attitude {
+            yaw: self.yaw * rhs,
+            pitch: self.pitch * rhs,
+            roll: self.roll * rhs,
         }
     }
 }
-impl ops::Add<YawPitchRoll> for Velocity {
+impl ops::Mul<Frac> for Velocity {
     type Output = Velocity;
-    fn add(self, rhs: YawPitchRoll) -> Self::Output {
-        Velocity {
-            yaw: self.yaw + rhs.yaw,
-            pitch: self.pitch + rhs.pitch,
-            roll: self.roll + rhs.roll,
+    fn mul(self, rhs: Frac) -> Self::Output {
+        self.saturating_mul(rhs)
+    }
+}
+impl ops::Div<Frac> for Velocity {
+    type Output = Velocity;
+    fn div(self, rhs: Frac) -> Self::Output {
+        self.saturating_div(rhs)
+    }
+}
+
+/// The yaw, pitch and roll the drone is at.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Copy)]
+pub struct Attitude {
+    pub yaw: Radians,
+    pub pitch: Radians,
+    pub roll: Radians,
+}
+
+impl Attitude {
+    pub fn new() -> Self {
+        Attitude {
+            yaw: Radians::ZERO,
+            pitch: Radians::ZERO,
+            roll: Radians::ZERO,
         }
     }
 }
-impl ops::Sub<Velocity> for Velocity {
+impl ops::Add<Attitude> for Attitude {
+    type Output = Attitude;
+    fn add(self, rhs: Attitude) -> Self::Output {
+        self.saturating_add(rhs)
+    }
+}
+impl ops::Sub<Attitude> for Attitude {
+    type Output = Attitude;
+    fn sub(self, rhs: Attitude) -> Self::Output {
+        self.saturating_sub(rhs)
+    }
+}
+impl ops::Div<Seconds> for Attitude {
     type Output = Velocity;
-    fn sub(self, rhs: Self) -> Self::Output {
+    fn div(self, rhs: Seconds) -> Self::Output {
         Velocity {
-            yaw: self.yaw - rhs.yaw,
-            pitch: self.pitch - rhs.pitch,
-            roll: self.roll - rhs.roll,
+            yaw: self.yaw / rhs,
+            pitch: self.pitch / rhs,
+            roll: self.roll / rhs,
         }
     }
 }
-impl ops::Mul<Frac> for Velocity {
-    type Output = YawPitchRoll;
-    fn mul(self, rhs: Frac) -> Self::Output {
+impl ops::Div<Frac> for Attitude {
+    type Output = Attitude;
+    fn div(self, rhs: Frac) -> Self::Output {
+        self.saturating_div(rhs)
+    }
+}
+/// The angles in rad as plain numbers, for the controller.
+impl From<Attitude> for YawPitchRoll {
+    fn from(attitude: Attitude) -> Self {
         YawPitchRoll {
-            yaw: self.yaw * rhs,
-            pitch: self.pitch * rhs,
-            roll: self.roll * rhs,
+            yaw: attitude.yaw.0,
+            pitch: attitude.pitch.0,
+            roll: attitude.roll.0,
         }
     }
 }
@@ -84,32 +147,20 @@ impl ops::Add for Accel {
     type Output = Accel;
 
     fn add(self, rhs: Self) -> Self::Output {
-        Accel {
-            x: self.x + rhs.x,
-            y: self.y + rhs.y,
-            z: self.z + rhs.z,
-        }
+        self.saturating_add(rhs)
     }
 }
 impl ops::Sub for Accel {
     type Output = Accel;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        Accel {
-            x: self.x - rhs.x,
-            y: self.y - rhs.y,
-            z: self.z - rhs.z,
-        }
+        self.saturating_sub(rhs)
     }
 }
 impl ops::Div<i32> for Accel {
     type Output = Accel;
     fn div(self, rhs: i32) -> Self::Output {
-        Accel {
-            x: self.x / rhs,
-            y: self.y / rhs,
-            z: self.z / rhs,
-        }
+        self.saturating_div(rhs)
     }
 }
 #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
@@ -132,7 +183,7 @@ pub enum Message {
     },
     ControlInput {
         request: ControlRequest,
-        base_pressure: f32,
+        base_pressure: Pascal,
     },
     SensorData {
         data: SensorData,
@@ -154,16 +205,550 @@ pub enum Message {
     LoggerMode {
         mode: LoggerMode,
     },
-    LogDownload {
-        entry: Option<SensorData>, // None signals end of download
+    /// Logs `channel` every `decimation` ticks, 0 turns it off.
+    LogChannel {
+        channel: LogChannel,
+        decimation: u16,
+    },
+    /// Asks the drone to send the chunks of the records of `session` that cover `length` bytes
+    /// from byte `offset`, one per tick while it is safe. Replaces the read asked for before.
+    LogRead {
+        session: u16,
+        offset: u32,
+        length: u32,
+    },
+    LogChunk {
+        chunk: LogChunk,
+    },
+    LogSession {
+        info: Option<SessionInfo>, // None signals end of the list
+    },
+    EstimatorState {
+        bias: Velocity,
+        innovation: YawPitchRoll,
+        nis: YawPitchRoll, // normalized innovation squared, averaged
+    },
+    /// Telemetry of the gyro bias left after the calibration, in rad/s, and the integrated
+    /// heading in rad.
+    GyroBias {
+        bias: Velocity,
+        heading: Radians,
+    },
+    SelectEstimator {
+        estimator: AttitudeEstimator,
+    },
+    /// Sent by the drone every second and whenever the level changes.
+    BatteryStatus {
+        /// The filtered voltage at rest.
+        voltage: Millivolts,
+        /// Charge left, percent.
+        charge: u8,
+        level: BatteryLevel,
+    },
+    /// Sent by the drone whenever the health of a sensor changes.
+    SensorHealth {
+        sensor: SensorKind,
+        state: HealthState,
+        /// The last fault seen, `None` for a sensor that recovered without one.
+        fault: Option<SensorFault>,
+    },
+    SelectYawMode {
+        mode: YawMode,
     },
+    CalibrationResult {
+        result: CalibrationResult,
+    },
+    /// Asks the drone to measure gravity while it lies on `face`.
+    MeasureAccelFace {
+        face: AccelFace,
+    },
+    AccelFaceMeasured {
+        face: AccelFace,
+        failure: Option<CalibrationFailure>,
+    },
+    /// Sent once all six faces are measured, the correction is in use from then on.
+    AccelCalibrationResult {
+        correction: AccelCorrection,
+    },
+    /// Asks the drone to save, load, reset or send its configuration.
+    Config {
+        command: ConfigCommand,
+    },
+    /// The configuration, sent by the drone on [`ConfigCommand::Export`] or by the host to
+    /// import one. An import is only used until the next reboot unless it is saved.
+    ConfigData {
+        config: DroneConfig,
+    },
+    /// The outcome of a configuration command or an import.
+    ConfigStatus {
+        error: Option<ConfigError>,
+    },
+}
+
+/// Sent by the drone at the end of every calibration.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CalibrationResult {
+    /// `None` if the offsets were taken over, otherwise the old ones are kept.
+    pub failure: Option<CalibrationFailure>,
+    pub samples: u16,
+    pub attitude_offset: Attitude,
+    pub gyro_offset: Velocity,
+    pub accel_offset: Accel,
+    /// Standard deviation of the rates, rad/s.
+    pub gyro_noise: Velocity,
+    /// Standard deviation of the accelerations, raw counts.
+    pub accel_noise: Accel,
+}
+
+/// Why a calibration was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CalibrationFailure {
+    /// The gyroscope was too noisy, the drone was turned.
+    Rotating,
+    /// The accelerometer was too noisy, the drone was moved or shaken.
+    Shaking,
+    /// Gravity did not point along the axis of the face the drone should lie on.
+    WrongFace,
+}
+
+/// The side of the drone facing up during a six position accelerometer calibration, named
+/// after the axis of the MPU that points up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccelFace {
+    XUp,
+    XDown,
+    YUp,
+    YDown,
+    ZUp,
+    ZDown,
+}
+
+impl AccelFace {
+    pub const ALL: [AccelFace; 6] = [
+        AccelFace::XUp,
+        AccelFace::XDown,
+        AccelFace::YUp,
+        AccelFace::YDown,
+        AccelFace::ZUp,
+        AccelFace::ZDown,
+    ];
+
+    /// Index of the axis pointing up, x is 0.
+    pub fn axis(self) -> usize {
+        match self {
+            AccelFace::XUp | AccelFace::XDown => 0,
+            AccelFace::YUp | AccelFace::YDown => 1,
+            AccelFace::ZUp | AccelFace::ZDown => 2,
+        }
+    }
+
+    pub fn is_up(self) -> bool {
+        matches!(self, AccelFace::XUp | AccelFace::YUp | AccelFace::ZUp)
+    }
+}
+
+/// Per axis correction of the accelerometer: `(raw - offset) * gain`, in raw counts.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AccelCorrection {
+    pub offset: Accel,
+    /// x, y, z
+    pub gain: [Frac; 3],
+}
+
+impl AccelCorrection {
+    /// Leaves the readings as they are.
+    pub fn new() -> Self {
+        AccelCorrection {
+            offset: Accel::new(),
+            gain: [Frac::from_num(1); 3],
+        }
+    }
+
+    pub fn apply(&self, raw: Accel) -> Accel {
+        let correct = |raw: i32, offset: i32, gain: Frac| {
+            wide_mul(raw.saturating_sub(offset), gain)
+                .round()
+                .saturating_to_num::<i32>()
+        };
+        Accel {
+            x: correct(raw.x, self.offset.x, self.gain[0]),
+            y: correct(raw.y, self.offset.y, self.gain[1]),
+            z: correct(raw.z, self.offset.z, self.gain[2]),
+        }
+    }
+}
+
+/// Which filter computes the attitude from the raw sensor values in raw mode.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AttitudeEstimator {
+    Kalman,
+    Mahony,
+}
+
+/// How empty the battery is, and what the drone does about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatteryLevel {
+    Normal,
+    /// Time to land, the pilot is warned.
+    Low,
+    /// The drone lands by itself, the pilot can still steer.
+    Land,
+    /// The drone panics.
+    Critical,
+}
+
+/// The sensors watched by the health monitor of the drone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SensorKind {
+    /// Raw gyroscope and accelerometer.
+    Imu,
+    /// Attitude quaternions of the motion processor.
+    Dmp,
+    Barometer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HealthState {
+    Healthy,
+    /// Faults were seen, the readings are still used.
+    Degraded,
+    /// Faults kept coming, the readings cannot be trusted.
+    Failed,
+}
+
+/// Why a reading was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SensorFault {
+    /// The sensor did not answer on the bus.
+    ReadError,
+    /// The same reading came back for too long.
+    Stuck,
+    /// The reading is at the end of the range of the sensor or physically impossible.
+    OutOfRange,
+    /// The reading changed more in one tick than the drone can.
+    Jump,
+    /// The motion processor produced no quaternion in time.
+    DmpStall,
+}
+
+/// What the yaw stick commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum YawMode {
+    /// A yaw rate, the heading drifts with the gyro.
+    Rate,
+    /// A turn of the heading, which is held while the stick is centered.
+    HeadingHold,
+}
+
+/// Settings the drone keeps in flash across reboots.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DroneConfig {
+    /// Tells drones apart, chosen by the host.
+    pub id: u16,
+    /// Whether the offsets come from a calibration that passed.
+    pub calibrated: bool,
+    pub attitude_offset: Attitude,
+    pub gyro_offset: Velocity,
+    pub accel_offset: Accel,
+    pub gravity_scale: Frac,
+    pub accel_correction: AccelCorrection,
+    pub p: Frac,
+    pub i: Frac,
+    pub d: Frac,
+    pub filters: FilterCutoffs,
+    pub limits: Limits,
+}
+
+/// Cutoffs of the sensor and controller filters in Hz, 0 if the filter is off.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FilterCutoffs {
+    pub gyro: Frac,
+    /// Center of the notch of the rates.
+    pub gyro_notch: Frac,
+    pub accel: Frac,
+    pub pressure: Frac,
+    pub d_term: Frac,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    /// Highest speed of the motors.
+    pub motor_max: u16,
+    /// Battery level below which the drone panics.
+    pub min_battery: Millivolts,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ConfigCommand {
+    /// Writes the configuration in use to flash.
+    Save,
+    /// Uses the configuration last saved.
+    Load,
+    /// Uses and saves the defaults.
+    Reset,
+    /// Sends the configuration in use to the host.
+    Export,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ConfigError {
+    /// The configuration can only change in safe mode.
+    NotSafe,
+    /// No intact configuration of this version is saved.
+    NotFound,
+    /// A value of the configuration is out of range.
+    Invalid,
+    /// Reading or writing the flash failed.
+    Disk,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum LoggerMode {
+    /// Starts a new session, and another one every time the drone arms after a flight.
     Enabled,
     Disabled,
-    Download,
+    /// Sends the [`SessionInfo`] of every session.
+    List,
+    /// Deletes the sessions before `before`.
+    Delete {
+        before: u16,
+    },
+}
+
+/// A session of the flight log as the drone lists it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub session: u16,
+    /// Tick the session started at, counted from the boot of the drone.
+    pub started: u32,
+    /// Ticks from the start to the last record kept.
+    pub duration: u32,
+    /// Rate of the ticks, Hz.
+    pub frequency: u16,
+    /// Bytes of its records, what a [`Message::LogRead`] of all of it sends.
+    pub size: u32,
+}
+
+/// Changes whenever the layout of the flight log records does.
+pub const LOG_SCHEMA: u16 = 3;
+
+/// A kind of record of the flight log that can be turned on and off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogChannel {
+    Attitude,
+    Rates,
+    RawImu,
+    Barometer,
+    Battery,
+    Motors,
+    ControlRequest,
+    ControllerTerms,
+    /// Mode changes, battery levels and sensor health, logged when they happen.
+    Events,
+}
+
+impl LogChannel {
+    pub const ALL: [LogChannel; 9] = [
+        LogChannel::Attitude,
+        LogChannel::Rates,
+        LogChannel::RawImu,
+        LogChannel::Barometer,
+        LogChannel::Battery,
+        LogChannel::Motors,
+        LogChannel::ControlRequest,
+        LogChannel::ControllerTerms,
+        LogChannel::Events,
+    ];
+}
+
+/// Every how many ticks each [`LogChannel`] is logged, 0 is off. Events only tell on or off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogChannels {
+    pub decimation: [u16; LogChannel::ALL.len()],
+}
+
+impl LogChannels {
+    /// The attitude, rates and motors every third tick, about 50 Hz at the default 150 Hz
+    /// loop. Every tick fills the flash three times as fast, in well under a minute. The slow
+    /// sensors are logged less often.
+    pub fn new() -> Self {
+        let mut channels = LogChannels {
+            decimation: [0; LogChannel::ALL.len()],
+        };
+        for (channel, decimation) in [
+            (LogChannel::Attitude, 3),
+            (LogChannel::Rates, 3),
+            (LogChannel::Barometer, 10),
+            (LogChannel::Battery, 100),
+            (LogChannel::Motors, 3),
+            (LogChannel::ControlRequest, 5),
+            (LogChannel::Events, 1),
+        ] {
+            channels.set(channel, decimation);
+        }
+        channels
+    }
+
+    pub fn get(&self, channel: LogChannel) -> u16 {
+        self.decimation[channel as usize]
+    }
+
+    pub fn set(&mut self, channel: LogChannel, decimation: u16) {
+        self.decimation[channel as usize] = decimation;
+    }
+
+    /// Whether `channel` is logged at `tick`.
+    pub fn is_due(&self, channel: LogChannel, tick: u32) -> bool {
+        match self.get(channel) {
+            0 => false,
+            _ if channel == LogChannel::Events => true,
+            decimation => tick % decimation as u32 == 0,
+        }
+    }
+}
+
+/// The first record of a flight log, describing the session.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LogHeader {
+    /// Version of the firmware that wrote the log, zero padded.
+    pub firmware: [u8; 16],
+    /// [`LOG_SCHEMA`] of the firmware.
+    pub schema: u16,
+    /// Number of the session, one higher than the one before.
+    pub session: u16,
+    /// Tick the session started at, counted from the boot of the drone.
+    pub started: u32,
+    /// Rate of the control loop the ticks of the records count, Hz.
+    pub frequency: u16,
+    /// The configuration in use when the log started.
+    pub config: DroneConfig,
+    /// The channels logged until a [`LogRecord::Channels`] changes them.
+    pub channels: LogChannels,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LogRecord {
+    Header(LogHeader),
+    /// The channels logged from here on.
+    Channels(LogChannels),
+    Attitude(Attitude),
+    Rates(Velocity),
+    /// Accelerometer and gyroscope counts, x y z.
+    RawImu {
+        accel: [i16; 3],
+        gyro: [i16; 3],
+    },
+    Barometer {
+        pressure: Pascal,
+        height: Metres,
+        v_z: MetresPerSecond,
+    },
+    /// Filtered voltage at rest.
+    Battery(Millivolts),
+    Motors([u16; 4]),
+    ControlRequest {
+        radius: YawPitchRoll,
+        throttle: i16,
+    },
+    ControllerTerms(ControllerTerms),
+    Event(LogEvent),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LogEvent {
+    Mode(Mode),
+    Battery(BatteryLevel),
+    SensorHealth {
+        sensor: SensorKind,
+        state: HealthState,
+    },
+}
+
+/// What each term of the attitude controller adds to the motor commands.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ControllerTerms {
+    pub p: YawPitchRoll,
+    pub i: YawPitchRoll,
+    pub d: YawPitchRoll,
+}
+
+impl ControllerTerms {
+    pub fn new() -> Self {
+        ControllerTerms {
+            p: YawPitchRoll::new(),
+            i: YawPitchRoll::new(),
+            d: YawPitchRoll::new(),
+        }
+    }
+}
+
+/// What the reader of a flight log finds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LogEntry {
+    Record {
+        tick: u32,
+        record: LogRecord,
+    },
+    /// `length` bytes from `address` on are no intact record and were skipped.
+    Corrupt {
+        address: u32,
+        length: u32,
+    },
+}
+
+/// Bytes of records a [`LogChunk`] holds at most.
+pub const LOG_CHUNK: usize = 32;
+
+/// Records of a session as the flight log stores them, sent by the drone for a
+/// [`Message::LogRead`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogChunk {
+    pub session: u16,
+    /// Tick the session started at, which tells it from an earlier session of the same number.
+    pub started: u32,
+    /// Where `data` starts in the records of the session.
+    pub offset: u32,
+    /// Bytes of `data` in use, fewer than [`LOG_CHUNK`] only where the session ends.
+    pub length: u8,
+    pub data: [u8; LOG_CHUNK],
+    /// CRC of the fields above.
+    pub crc: u16,
+}
+
+impl LogChunk {
+    /// The chunk of at most [`LOG_CHUNK`] of `bytes`, found at `offset` of `session`, which
+    /// started at tick `started`.
+    pub fn new(session: u16, started: u32, offset: u32, bytes: &[u8]) -> Self {
+        let length = bytes.len().min(LOG_CHUNK);
+        let mut data = [0; LOG_CHUNK];
+        data[..length].copy_from_slice(&bytes[..length]);
+        let mut chunk = LogChunk {
+            session,
+            started,
+            offset,
+            length: length as u8,
+            data,
+            crc: 0,
+        };
+        chunk.crc = chunk.checksum();
+        chunk
+    }
+
+    /// The bytes of records, `None` if the chunk was damaged on the way.
+    pub fn bytes(&self) -> Option<&[u8]> {
+        let length = self.length as usize;
+        (length <= LOG_CHUNK && self.crc == self.checksum()).then(|| &self.data[..length])
+    }
+
+    fn checksum(&self) -> u16 {
+        let mut bytes = [0; 11 + LOG_CHUNK];
+        bytes[..2].copy_from_slice(&self.session.to_le_bytes());
+        bytes[2..6].copy_from_slice(&self.started.to_le_bytes());
+        bytes[6..10].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[10] = self.length;
+        bytes[11..].copy_from_slice(&self.data);
+        crc16(&bytes)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, enum_map::Enum)]
@@ -188,13 +773,16 @@ pub struct SensorData {
     sp: u16,
     sq: u16,
     sr: u16,*/
-    pub height: Frac,
-    pub v_z: Frac,
-    pub pressure: f32,
+    pub height: Metres,
+    pub v_z: MetresPerSecond,
+    /// On the drone the pressure minus the one at the base station, on the base station the
+    /// absolute pressure.
+    pub pressure: Pascal,
     pub velocity: Velocity,
-    pub radius: YawPitchRoll,
+    pub radius: Attitude,
+    /// Raw counts of the accelerometer, [`ACCEL_SCALE`] per g.
     pub acceleration: Accel,
-    pub bat: u16,
+    pub bat: Millivolts,
     pub motor_speeds: [u16; 4], // might not be needed
                                 // data for all sensors
 }
@@ -202,69 +790,34 @@ impl ops::Mul<Frac> for SensorData {
     type Output = SensorData;
 
     fn mul(self, rhs: Frac) -> Self::Output {
-        SensorData {
-            height: self.height * rhs,
-            v_z: self.v_z * rhs,
-            pressure: self.pressure * rhs.to_num::<f32>(),
-            velocity: Velocity {
-                yaw: self.velocity.yaw * rhs,
-                pitch: self.velocity.pitch * rhs,
-                roll: self.velocity.roll * rhs,
-            },
-            radius: self.radius * rhs,
-            acceleration: Accel {
-                x: i32::from_fixed(self.acceleration.x * rhs),
-                y: i32::from_fixed(self.acceleration.y * rhs),
-                z: i32::from_fixed(self.acceleration.z * rhs),
-            },
-            bat: self.bat,
-            motor_speeds: self.motor_speeds,
-        }
+        self.saturating_mul(rhs)
     }
 }
 impl ops::Add<SensorData> for SensorData {
     type Output = SensorData;
 
     fn add(self, rhs: SensorData) -> Self::Output {
-        SensorData {
-            height: self.height + rhs.height,
-            v_z: self.v_z + rhs.v_z,
-            pressure: self.pressure + rhs.pressure,
-            velocity: self.velocity + rhs.velocity,
-            radius: self.radius + rhs.radius,
-            acceleration: self.acceleration + rhs.acceleration,
-            bat: self.bat,
-            motor_speeds: self.motor_speeds,
-        }
+        self.saturating_add(rhs)
     }
 }
 impl SensorData {
     pub fn new() -> Self {
         SensorData {
-            height: Frac::from_num(0.),
-            v_z: Frac::from_num(0.),
-            pressure: 0.0,
-            velocity: Velocity {
-                yaw: Frac::from_num(0.),
-                pitch: Frac::from_num(0.),
-                roll: Frac::from_num(0.),
-            },
-            radius: YawPitchRoll {
-                yaw: Frac::from_num(0.),
-                pitch: Frac::from_num(0.),
-                roll: Frac::from_num(0.),
-            },
+            height: Metres::ZERO,
+            v_z: MetresPerSecond::ZERO,
+            pressure: Pascal::ZERO,
+            velocity: Velocity::new(),
+            radius: Attitude::new(),
             acceleration: Accel { x: 0, y: 0, z: 0 },
-            bat: 0,
+            bat: Millivolts(0),
             motor_speeds: [0, 0, 0, 0],
         }
     }
 }
-pub trait SensorDriver {
-    fn get_values(&mut self, calibration: bool, raw: bool);
-    fn calibrate(&mut self);
-    fn filter_ewma(&mut self, alpha: Frac, pressure_alpha: f32);
-    fn filter_fir(&mut self, alpha: Frac, beta: Frac, gama: Frac);
+/// `H` is the hardware the sensors are read from.
+pub trait SensorDriver<H> {
+    fn get_values(&mut self, hal: &mut H, calibration: bool, raw: bool);
+    fn calibrate(&mut self, hal: &mut H);
 }
 
 // Drone Controller Component
@@ -296,8 +849,8 @@ pub trait TelemetryDisplay {
     fn display_telemetry(&mut self, data: &SensorData) -> ();
 }
 
-/// This struct holds the yaw, pitch, and roll that the drone things it is in.
-/// The struct is currently implemented using `f32`, you may want to change this to use fixed point arithmetic.
+/// Yaw, pitch and roll as plain numbers: the sticks, where pitch and roll are angles in rad and
+/// yaw is a rate in rad/s, and the errors and terms of the controller.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct YawPitchRoll {
     pub yaw: Frac,
@@ -317,53 +870,37 @@ impl YawPitchRoll {
 impl ops::Add<YawPitchRoll> for YawPitchRoll {
     type Output = YawPitchRoll;
     fn add(self, rhs: YawPitchRoll) -> Self::Output {
-        YawPitchRoll {
-            yaw: self.yaw + rhs.yaw,
-            pitch: self.pitch + rhs.pitch,
-            roll: self.roll + rhs.roll,
-        }
+        self.saturating_add(rhs)
     }
 }
 impl ops::Sub<YawPitchRoll> for YawPitchRoll {
     type Output = YawPitchRoll;
     fn sub(self, rhs: YawPitchRoll) -> Self::Output {
-        YawPitchRoll {
-            yaw: self.yaw - rhs.yaw,
-            pitch: self.pitch - rhs.pitch,
-            roll: self.roll - rhs.roll,
-        }
+        self.saturating_sub(rhs)
     }
 }
 impl ops::Div<Frac> for YawPitchRoll {
     type Output = YawPitchRoll;
     fn div(self, rhs: Frac) -> Self::Output {
-        YawPitchRoll {
-            yaw: self.yaw / rhs,
-            pitch: self.pitch / rhs,
-            roll: self.roll / rhs,
-        }
+        self.saturating_div(rhs)
     }
 }
 impl ops::Mul<Frac> for YawPitchRoll {
     type Output = YawPitchRoll;
     fn mul(self, rhs: Frac) -> Self::Output {
-        YawPitchRoll {
-            yaw: self.yaw * rhs,
-            pitch: self.pitch * rhs,
-            roll: self.roll * rhs,
-        }
+        self.saturating_mul(rhs)
     }
 }
 #[derive(Clone)]
 pub struct ControllerInput {
     pub ypr: YawPitchRoll,
-    pub height: Frac,
+    pub height: Metres,
 }
 impl ControllerInput {
     pub fn new() -> Self {
         ControllerInput {
             ypr: YawPitchRoll::new(),
-            height: Frac::from_num(0),
+            height: Metres::ZERO,
         }
     }
 }
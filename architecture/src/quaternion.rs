@@ -0,0 +1,565 @@
+//! Quaternions and 3-vectors for the attitude.
+//!
+//! Vectors are in the frame of the MPU: x to the front, y to the left and z up. A [`Quat`]
+//! rotates the body frame into the world frame. The angles of an [`Attitude`] turn about z,
+//! then y and then x, with the pitch positive nose up, which is a turn about -y. Like the other
+//! types the vector operators saturate.
+//!
+//! Both types default to `Frac` components. The arithmetic works in any signed fixed point type,
+//! so state that changes by less than a bit of a `Frac` per step can keep more fractional bits;
+//! the square roots and the angles are only there for `Frac`.
+
+use crate::{angle, Attitude, Frac, Radians, Seconds};
+use core::ops;
+use fixed::traits::FixedSigned;
+use fixed_trigonometry::*;
+use serde::{Deserialize, Serialize};
+
+/// Above this sine of the pitch, about 0.8 deg from straight up or down, yaw and roll turn
+/// about the same axis and only their sum or difference is known.
+const GIMBAL_LOCK: Frac = Frac::lit("0.9999");
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Vec3<F = Frac> {
+    pub x: F,
+    pub y: F,
+    pub z: F,
+}
+
+impl<F: FixedSigned> Vec3<F> {
+    pub const ZERO: Self = Vec3::new(F::ZERO, F::ZERO, F::ZERO);
+
+    pub const fn new(x: F, y: F, z: F) -> Self {
+        Vec3 { x, y, z }
+    }
+
+    /// The same vector in the fixed point type `G`, saturating.
+    pub fn to_num<G: FixedSigned>(self) -> Vec3<G> {
+        let num = |c: F| G::saturating_from_num(c);
+        Vec3::new(num(self.x), num(self.y), num(self.z))
+    }
+
+    pub fn dot(self, rhs: Self) -> F {
+        self.x
+            .saturating_mul(rhs.x)
+            .saturating_add(self.y.saturating_mul(rhs.y))
+            .saturating_add(self.z.saturating_mul(rhs.z))
+    }
+
+    pub fn cross(self, rhs: Self) -> Self {
+        let ab = |a: F, b: F, c: F, d: F| a.saturating_mul(b).saturating_sub(c.saturating_mul(d));
+        Vec3 {
+            x: ab(self.y, rhs.z, self.z, rhs.y),
+            y: ab(self.z, rhs.x, self.x, rhs.z),
+            z: ab(self.x, rhs.y, self.y, rhs.x),
+        }
+    }
+}
+
+impl Vec3 {
+    /// The length, the square saturates for vectors longer than about 181.
+    pub fn norm(self) -> Frac {
+        sqrt::niirf(self.dot(self), 2)
+    }
+
+    /// The vector scaled to length 1, `None` for the zero vector.
+    pub fn normalize(self) -> Option<Self> {
+        let norm = self.norm();
+        if norm == 0 {
+            return None;
+        }
+        Some(self / norm)
+    }
+}
+
+impl<F: FixedSigned> ops::Add for Vec3<F> {
+    type Output = Vec3<F>;
+    fn add(self, rhs: Self) -> Self::Output {
+        Vec3 {
+            x: self.x.saturating_add(rhs.x),
+            y: self.y.saturating_add(rhs.y),
+            z: self.z.saturating_add(rhs.z),
+        }
+    }
+}
+
+impl<F: FixedSigned> ops::Sub for Vec3<F> {
+    type Output = Vec3<F>;
+    fn sub(self, rhs: Self) -> Self::Output {
+        Vec3 {
+            x: self.x.saturating_sub(rhs.x),
+            y: self.y.saturating_sub(rhs.y),
+            z: self.z.saturating_sub(rhs.z),
+        }
+    }
+}
+
+impl<F: FixedSigned> ops::Neg for Vec3<F> {
+    type Output = Vec3<F>;
+    fn neg(self) -> Self::Output {
+        Vec3 {
+            x: self.x.saturating_neg(),
+            y: self.y.saturating_neg(),
+            z: self.z.saturating_neg(),
+        }
+    }
+}
+
+impl<F: FixedSigned> ops::Mul<F> for Vec3<F> {
+    type Output = Vec3<F>;
+    fn mul(self, rhs: F) -> Self::Output {
+        Vec3 {
+            x: self.x.saturating_mul(rhs),
+            y: self.y.saturating_mul(rhs),
+            z: self.z.saturating_mul(rhs),
+        }
+    }
+}
+
+impl<F: FixedSigned> ops::Div<F> for Vec3<F> {
+    type Output = Vec3<F>;
+    fn div(self, rhs: F) -> Self::Output {
+        Vec3 {
+            x: self.x.saturating_div(rhs),
+            y: self.y.saturating_div(rhs),
+            z: self.z.saturating_div(rhs),
+        }
+    }
+}
+
+/// A rotation as the quaternion `w + x i + y j + z k`, of norm 1 unless noted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quat<F = Frac> {
+    pub w: F,
+    pub x: F,
+    pub y: F,
+    pub z: F,
+}
+
+impl<F: FixedSigned> Quat<F> {
+    pub const fn new(w: F, x: F, y: F, z: F) -> Self {
+        Quat { w, x, y, z }
+    }
+
+    fn from_parts(w: F, v: Vec3<F>) -> Self {
+        Quat::new(w, v.x, v.y, v.z)
+    }
+
+    /// The same quaternion in the fixed point type `G`, saturating.
+    pub fn to_num<G: FixedSigned>(self) -> Quat<G> {
+        Quat::from_parts(G::saturating_from_num(self.w), self.vector().to_num())
+    }
+
+    /// The vector part `x, y, z`.
+    pub fn vector(self) -> Vec3<F> {
+        Vec3::new(self.x, self.y, self.z)
+    }
+
+    /// The inverse rotation.
+    pub fn conjugate(self) -> Self {
+        Quat::from_parts(self.w, -self.vector())
+    }
+
+    pub fn norm_squared(self) -> F {
+        self.w
+            .saturating_mul(self.w)
+            .saturating_add(self.vector().dot(self.vector()))
+    }
+
+    /// `v` turned from the body frame into the world frame.
+    pub fn rotate(self, v: Vec3<F>) -> Vec3<F> {
+        // v + 2 w (u x v) + 2 u x (u x v) with the vector part u
+        let u = self.vector();
+        let t = u.cross(v) * F::from_num(2);
+        v + t * self.w + u.cross(t)
+    }
+
+    /// The attitude after turning with the body rates `rates` in rad/s for `dt`, about the axes
+    /// of the body frame.
+    ///
+    /// Integrates `q' = q (0, rates) / 2` in a single step and normalizes again, which is
+    /// accurate as long as the turn per step is small. At low rates the change per step gets
+    /// close to the resolution of a `Frac`, integrate with more fractional bits when that
+    /// matters.
+    pub fn integrate(self, rates: Vec3<F>, dt: Seconds) -> Self {
+        let two = F::from_num(2);
+        let turn = Quat::from_parts(F::ZERO, rates * (F::from_num(dt.0) / two));
+        let delta = self * turn;
+        let q = Quat::new(
+            self.w.saturating_add(delta.w),
+            self.x.saturating_add(delta.x),
+            self.y.saturating_add(delta.y),
+            self.z.saturating_add(delta.z),
+        );
+        // the norm stays close to one, a single newton step of 1/sqrt is enough
+        let scale = (F::from_num(3) - q.norm_squared()) / two;
+        Quat::from_parts(q.w.saturating_mul(scale), q.vector() * scale)
+    }
+}
+
+impl Quat {
+    pub const IDENTITY: Quat = Quat::new(Frac::ONE, Frac::ZERO, Frac::ZERO, Frac::ZERO);
+
+    /// The quaternion scaled to norm 1, `None` for zero.
+    pub fn normalize(self) -> Option<Self> {
+        let norm = sqrt::niirf(self.norm_squared(), 2);
+        if norm == 0 {
+            return None;
+        }
+        Some(Quat::from_parts(self.w / norm, self.vector() / norm))
+    }
+
+    pub fn from_yaw_pitch_roll(angles: Attitude) -> Self {
+        let half = |angle: Radians| {
+            let angle = angle.0 / 2;
+            (sin(angle), cos(angle))
+        };
+        let (sy, cy) = half(angles.yaw);
+        // nose up turns about -y
+        let (sp, cp) = half(-angles.pitch);
+        let (sr, cr) = half(angles.roll);
+        Quat::new(
+            cr * cp * cy + sr * sp * sy,
+            sr * cp * cy - cr * sp * sy,
+            cr * sp * cy + sr * cp * sy,
+            cr * cp * sy - sr * sp * cy,
+        )
+    }
+
+    /// The angles of the rotation, yaw and roll from -pi up to pi and pitch from -pi/2 up to
+    /// pi/2. In the gimbal lock the roll is 0 and the yaw takes the whole turn.
+    pub fn to_yaw_pitch_roll(self) -> Attitude {
+        let Quat { w, x, y, z } = self;
+        let one = Frac::ONE;
+        let two = Frac::from_num(2);
+        // sine of the turn about y
+        let sin_turn = (two * (w * y - x * z)).clamp(-one, one);
+        if sin_turn.abs() > GIMBAL_LOCK {
+            let pitch = if sin_turn > 0 {
+                -Frac::FRAC_PI_2
+            } else {
+                Frac::FRAC_PI_2
+            };
+            return Attitude {
+                yaw: Radians(angle::wrap(atan::atan2(z, w) * 2)),
+                pitch: Radians(pitch),
+                roll: Radians::ZERO,
+            };
+        }
+        let cos_turn = sqrt::niirf(one - sin_turn * sin_turn, 2);
+        Attitude {
+            yaw: Radians(atan::atan2(
+                two * (w * z + x * y),
+                one - two * (y * y + z * z),
+            )),
+            pitch: Radians(-atan::atan2(sin_turn, cos_turn)),
+            roll: Radians(atan::atan2(
+                two * (w * x + y * z),
+                one - two * (x * x + y * y),
+            )),
+        }
+    }
+}
+
+impl<F: FixedSigned> Default for Quat<F> {
+    /// No rotation.
+    fn default() -> Self {
+        Quat::new(F::from_num(1), F::ZERO, F::ZERO, F::ZERO)
+    }
+}
+
+impl<F: FixedSigned> ops::Mul for Quat<F> {
+    type Output = Quat<F>;
+    /// The rotation by `rhs` followed by `self`.
+    fn mul(self, rhs: Self) -> Self::Output {
+        let (a, b) = (self.vector(), rhs.vector());
+        Quat::from_parts(
+            self.w.saturating_mul(rhs.w).saturating_sub(a.dot(b)),
+            b * self.w + a * rhs.w + a.cross(b),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use fixed::types::I8F24;
+    use std::f64::consts::{FRAC_PI_2, TAU};
+    use std::println;
+    use std::vec::Vec;
+
+    /// Largest error in rad of an angle against the same math in f64.
+    const MAX_ERROR: f64 = 0.01;
+    /// Largest error of a component of a unit quaternion or vector.
+    const MAX_COMPONENT_ERROR: f64 = 0.005;
+
+    fn f(x: Frac) -> f64 {
+        x.to_num()
+    }
+
+    fn quat(q: Quat) -> [f64; 4] {
+        [q.w, q.x, q.y, q.z].map(f)
+    }
+
+    fn vec3(v: Vec3) -> [f64; 3] {
+        [v.x, v.y, v.z].map(f)
+    }
+
+    fn frac_vec3(v: [f64; 3]) -> Vec3 {
+        Vec3::new(
+            Frac::from_num(v[0]),
+            Frac::from_num(v[1]),
+            Frac::from_num(v[2]),
+        )
+    }
+
+    fn angles(yaw: f64, pitch: f64, roll: f64) -> Attitude {
+        Attitude {
+            yaw: Radians(Frac::from_num(yaw)),
+            pitch: Radians(Frac::from_num(pitch)),
+            roll: Radians(Frac::from_num(roll)),
+        }
+    }
+
+    /// Difference of two angles in rad, around the circle.
+    fn angle_error(actual: Radians, expected: f64) -> f64 {
+        let error = (f(actual.0) - expected).rem_euclid(TAU);
+        error.min(TAU - error)
+    }
+
+    fn hamilton(a: [f64; 4], b: [f64; 4]) -> [f64; 4] {
+        let [w1, x1, y1, z1] = a;
+        let [w2, x2, y2, z2] = b;
+        [
+            w1 * w2 - x1 * x2 - y1 * y2 - z1 * z2,
+            w1 * x2 + x1 * w2 + y1 * z2 - z1 * y2,
+            w1 * y2 - x1 * z2 + y1 * w2 + z1 * x2,
+            w1 * z2 + x1 * y2 - y1 * x2 + z1 * w2,
+        ]
+    }
+
+    /// `v` rotated by `q`, as `q (0, v) q*`.
+    fn rotate_f64(q: [f64; 4], v: [f64; 3]) -> [f64; 3] {
+        let conjugate = [q[0], -q[1], -q[2], -q[3]];
+        let [_, x, y, z] = hamilton(hamilton(q, [0.0, v[0], v[1], v[2]]), conjugate);
+        [x, y, z]
+    }
+
+    /// The quaternion of the angles, yaw about z, then nose up about -y, then roll about x.
+    fn from_angles_f64(yaw: f64, pitch: f64, roll: f64) -> [f64; 4] {
+        let about = |axis: usize, angle: f64| {
+            let mut q = [(angle / 2.0).cos(), 0.0, 0.0, 0.0];
+            q[axis + 1] = (angle / 2.0).sin();
+            q
+        };
+        hamilton(hamilton(about(2, yaw), about(1, -pitch)), about(0, roll))
+    }
+
+    fn max_difference<const N: usize>(a: [f64; N], b: [f64; N]) -> f64 {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f64::max)
+    }
+
+    fn degrees(range: i32, step: usize) -> impl Iterator<Item = f64> + Clone {
+        (-range..=range)
+            .step_by(step)
+            .map(|d| (d as f64).to_radians())
+    }
+
+    const AXES: [[f64; 3]; 4] = [
+        [1.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0],
+        [0.0, 0.0, 1.0],
+        [0.3, -0.5, 0.8],
+    ];
+
+    #[test]
+    fn angles_round_trip_through_quaternions() {
+        let mut max: [f64; 4] = [0.0; 4];
+        for yaw in degrees(170, 20) {
+            for pitch in degrees(85, 5) {
+                for roll in degrees(170, 20) {
+                    let q = Quat::from_yaw_pitch_roll(angles(yaw, pitch, roll));
+                    let expected = from_angles_f64(yaw, pitch, roll);
+                    max[0] = max[0].max(max_difference(quat(q), expected));
+
+                    let back = q.to_yaw_pitch_roll();
+                    max[1] = max[1].max(angle_error(back.yaw, yaw));
+                    max[2] = max[2].max(angle_error(back.pitch, pitch));
+                    max[3] = max[3].max(angle_error(back.roll, roll));
+                }
+            }
+        }
+        println!(
+            "max error quaternion {}, yaw {} rad, pitch {} rad, roll {} rad",
+            max[0], max[1], max[2], max[3]
+        );
+        assert!(max[0] < MAX_COMPONENT_ERROR, "{max:?}");
+        assert!(max[1..].iter().all(|max| *max < MAX_ERROR), "{max:?}");
+    }
+
+    #[test]
+    fn gimbal_lock_keeps_the_rotation() {
+        for pitch in [
+            FRAC_PI_2,
+            -FRAC_PI_2,
+            89.9f64.to_radians(),
+            -89.9f64.to_radians(),
+        ] {
+            for (yaw, roll) in [(0.0, 0.0), (0.3, 0.1), (1.0, -0.5), (-2.0, 1.0), (3.0, 3.0)] {
+                let q = Quat::from_yaw_pitch_roll(angles(yaw, pitch, roll));
+                let back = q.to_yaw_pitch_roll();
+                assert!(
+                    angle_error(back.pitch, pitch) < MAX_ERROR,
+                    "{pitch} {yaw} {roll}: {back:?}"
+                );
+                // yaw and roll are ambiguous there, the rotation they make is not
+                let again = Quat::from_yaw_pitch_roll(back);
+                for v in AXES {
+                    let error = max_difference(
+                        vec3(again.rotate(frac_vec3(v))),
+                        vec3(q.rotate(frac_vec3(v))),
+                    );
+                    assert!(error < 2.0 * MAX_ERROR, "{pitch} {yaw} {roll}: {error}");
+                }
+            }
+        }
+        // straight up the yaw takes the whole turn
+        let up = Quat::from_yaw_pitch_roll(angles(1.0, FRAC_PI_2, 0.0)).to_yaw_pitch_roll();
+        assert_eq!(up.roll, Radians::ZERO);
+        assert!(angle_error(up.yaw, 1.0) < MAX_ERROR, "{up:?}");
+    }
+
+    #[test]
+    fn products_and_rotations_match_f64() {
+        let mut max: [f64; 2] = [0.0; 2];
+        let samples: Vec<Quat> = degrees(150, 50)
+            .flat_map(|yaw| degrees(60, 30).map(move |pitch| (yaw, pitch)))
+            .flat_map(|(yaw, pitch)| degrees(150, 75).map(move |roll| angles(yaw, pitch, roll)))
+            .map(Quat::from_yaw_pitch_roll)
+            .collect();
+        for a in &samples {
+            for b in &samples {
+                max[0] = max[0].max(max_difference(quat(*a * *b), hamilton(quat(*a), quat(*b))));
+            }
+            for v in AXES {
+                let expected = rotate_f64(quat(*a), v);
+                max[1] = max[1].max(max_difference(vec3(a.rotate(frac_vec3(v))), expected));
+            }
+        }
+        println!("max error product {}, rotation {}", max[0], max[1]);
+        assert!(max.iter().all(|max| *max < MAX_COMPONENT_ERROR), "{max:?}");
+
+        // a quarter turn of yaw turns the front to the left, nose up turns it up
+        let left = Quat::from_yaw_pitch_roll(angles(FRAC_PI_2, 0.0, 0.0));
+        let front = frac_vec3(AXES[0]);
+        assert!(max_difference(vec3(left.rotate(front)), [0.0, 1.0, 0.0]) < MAX_COMPONENT_ERROR);
+        let up = Quat::from_yaw_pitch_roll(angles(0.0, FRAC_PI_2, 0.0));
+        assert!(max_difference(vec3(up.rotate(front)), [0.0, 0.0, 1.0]) < MAX_COMPONENT_ERROR);
+        // and the conjugate turns it back
+        let back = left.conjugate().rotate(left.rotate(front));
+        assert!(max_difference(vec3(back), AXES[0]) < MAX_COMPONENT_ERROR);
+    }
+
+    #[test]
+    fn vectors_match_f64() {
+        let a = frac_vec3([0.3, -0.5, 0.8]);
+        let b = frac_vec3([-1.5, 2.0, 0.25]);
+        assert!((f(a.dot(b)) - (-0.45 - 1.0 + 0.2)).abs() < 1e-3);
+        let cross = [
+            -0.5 * 0.25 - 0.8 * 2.0,
+            0.8 * -1.5 - 0.3 * 0.25,
+            0.3 * 2.0 - -0.5 * -1.5,
+        ];
+        assert!(max_difference(vec3(a.cross(b)), cross) < 1e-3);
+
+        let unit = frac_vec3([3.0, 4.0, 0.0]).normalize().unwrap();
+        assert!(max_difference(vec3(unit), [0.6, 0.8, 0.0]) < MAX_COMPONENT_ERROR);
+        assert_eq!(frac_vec3([0.0; 3]).normalize(), None);
+
+        let q = Quat::new(Frac::from_num(2), Frac::ZERO, Frac::ZERO, Frac::ZERO);
+        assert!(max_difference(quat(q.normalize().unwrap()), quat(Quat::IDENTITY)) < 1e-3);
+        assert_eq!(
+            Quat::new(Frac::ZERO, Frac::ZERO, Frac::ZERO, Frac::ZERO).normalize(),
+            None
+        );
+
+        let big = Vec3::new(Frac::MAX, Frac::MIN, Frac::ONE);
+        assert_eq!(
+            big + big,
+            Vec3::new(Frac::MAX, Frac::MIN, Frac::from_num(2))
+        );
+        assert_eq!(-big, Vec3::new(-Frac::MAX, Frac::MAX, -Frac::ONE));
+    }
+
+    #[test]
+    fn integrating_body_rates_matches_f64() {
+        const FREQUENCY: u64 = 150;
+        let dt = Seconds::per_tick(FREQUENCY);
+        for rates in [
+            [1.0, 0.0, 0.0],
+            [0.0, -1.0, 0.0],
+            [0.0, 0.0, 2.0],
+            [0.5, 0.3, -0.8],
+        ] {
+            let mut q = Quat::from_yaw_pitch_roll(angles(0.4, 0.2, -0.3));
+            let mut expected = quat(q);
+            // about a fixed axis the exact solution is a single turn
+            let rate = (rates[0] * rates[0] + rates[1] * rates[1] + rates[2] * rates[2]).sqrt();
+            let angle = rate / FREQUENCY as f64;
+            let [x, y, z] = rates.map(|r| r / rate * (angle / 2.0).sin());
+            let step = [(angle / 2.0).cos(), x, y, z];
+            for _ in 0..FREQUENCY {
+                q = q.integrate(frac_vec3(rates), dt);
+                expected = hamilton(expected, step);
+            }
+            let mut error: f64 = 0.0;
+            for v in AXES {
+                let actual = vec3(q.rotate(frac_vec3(v)));
+                error = error.max(max_difference(actual, rotate_f64(expected, v)));
+            }
+            println!("max error after a second at {rates:?}: {error}");
+            assert!(error < 2.0 * MAX_ERROR, "{rates:?}: {error}");
+            assert!((f(q.norm_squared()) - 1.0).abs() < 1e-3);
+        }
+
+        // a turn about z is yaw, about y is nose down
+        let mut q = Quat::IDENTITY;
+        for _ in 0..FREQUENCY {
+            q = q.integrate(frac_vec3([0.0, 0.5, 1.0]), dt);
+        }
+        let mut yaw = Quat::IDENTITY;
+        let mut pitch = Quat::IDENTITY;
+        for _ in 0..FREQUENCY {
+            yaw = yaw.integrate(frac_vec3([0.0, 0.0, 1.0]), dt);
+            pitch = pitch.integrate(frac_vec3([0.0, 0.5, 0.0]), dt);
+        }
+        let yaw = yaw.to_yaw_pitch_roll();
+        let pitch = pitch.to_yaw_pitch_roll();
+        assert!(angle_error(yaw.yaw, 1.0) < 2.0 * MAX_ERROR, "{yaw:?}");
+        assert!(
+            angle_error(pitch.pitch, -0.5) < 2.0 * MAX_ERROR,
+            "{pitch:?}"
+        );
+        assert!(f(q.norm_squared()) > 0.99);
+    }
+
+    #[test]
+    fn more_fractional_bits_integrate_slow_turns() {
+        // at 0.01 rad/s a tick turns the quaternion by about two bits of a Frac
+        const FREQUENCY: u64 = 150;
+        let dt = Seconds::per_tick(FREQUENCY);
+        let rates = frac_vec3([0.0, 0.0, 0.01]);
+        let mut q = Quat::<I8F24>::default();
+        for _ in 0..FREQUENCY * 10 {
+            q = q.integrate(rates.to_num(), dt);
+        }
+        let z: f64 = q.z.to_num();
+        assert!((z - 0.05f64.sin()).abs() < 1e-4, "{q:?}");
+        assert!((f(q.to_num::<Frac>().norm_squared()) - 1.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,330 @@
+//! Physical units of the sensor data.
+//!
+//! Each unit wraps the fixed-point number it is stored in, so it costs nothing at runtime and
+//! is sent the same way, but a height cannot be added to a vertical speed by accident. Raw
+//! sensor counts only become a unit through the conversions here. Like the vector types the
+//! operators saturate, see [`crate::vector_ops`].
+
+use crate::{Frac, Pressure, Velocity};
+use core::ops;
+use fixed::types::I48F16;
+use serde::{Deserialize, Serialize};
+
+/// Counts of the gyroscope per deg/s in its 2000 deg/s range.
+pub const GYRO_SCALE: Frac = Frac::lit("16.4");
+/// Counts of the accelerometer per g in its 2 g range.
+pub const ACCEL_SCALE: i32 = 16384;
+/// m/s^2
+pub const STANDARD_GRAVITY: Frac = Frac::lit("9.80665");
+
+/// A quantity stored in a `Frac`, with saturating arithmetic within the unit and scaling by
+/// plain numbers.
+macro_rules! unit {
+    ($(#[$doc:meta])* $unit:ident) => {
+        $(#[$doc])*
+        #[derive(
+            Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+        )]
+        #[serde(transparent)]
+        #[repr(transparent)]
+        pub struct $unit(pub Frac);
+
+        impl $unit {
+            pub const ZERO: $unit = $unit(Frac::ZERO);
+
+            pub fn abs(self) -> Self {
+                $unit(self.0.saturating_abs())
+            }
+
+            pub fn saturating_add(self, rhs: Self) -> Self {
+                $unit(self.0.saturating_add(rhs.0))
+            }
+
+            pub fn checked_add(self, rhs: Self) -> Option<Self> {
+                self.0.checked_add(rhs.0).map($unit)
+            }
+
+            pub fn saturating_sub(self, rhs: Self) -> Self {
+                $unit(self.0.saturating_sub(rhs.0))
+            }
+
+            pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+                self.0.checked_sub(rhs.0).map($unit)
+            }
+
+            pub fn saturating_mul(self, rhs: Frac) -> Self {
+                $unit(self.0.saturating_mul(rhs))
+            }
+
+            pub fn checked_mul(self, rhs: Frac) -> Option<Self> {
+                self.0.checked_mul(rhs).map($unit)
+            }
+        }
+
+        impl ops::Add for $unit {
+            type Output = $unit;
+            fn add(self, rhs: Self) -> Self::Output {
+                self.saturating_add(rhs)
+            }
+        }
+
+        impl ops::AddAssign for $unit {
+            fn add_assign(&mut self, rhs: Self) {
+                *self = self.saturating_add(rhs);
+            }
+        }
+
+        impl ops::Sub for $unit {
+            type Output = $unit;
+            fn sub(self, rhs: Self) -> Self::Output {
+                self.saturating_sub(rhs)
+            }
+        }
+
+        impl ops::SubAssign for $unit {
+            fn sub_assign(&mut self, rhs: Self) {
+                *self = self.saturating_sub(rhs);
+            }
+        }
+
+        impl ops::Neg for $unit {
+            type Output = $unit;
+            fn neg(self) -> Self::Output {
+                $unit(self.0.saturating_neg())
+            }
+        }
+
+        impl ops::Mul<Frac> for $unit {
+            type Output = $unit;
+            fn mul(self, rhs: Frac) -> Self::Output {
+                self.saturating_mul(rhs)
+            }
+        }
+    };
+}
+
+/// `$lhs * $rhs = $product`, and back by dividing.
+macro_rules! product {
+    ($lhs:ident * $rhs:ident = $product:ident) => {
+        impl ops::Mul<$rhs> for $lhs {
+            type Output = $product;
+            fn mul(self, rhs: $rhs) -> Self::Output {
+                $product(self.0.saturating_mul(rhs.0))
+            }
+        }
+
+        impl ops::Div<$rhs> for $product {
+            type Output = $lhs;
+            /// Saturates, also when dividing by zero.
+            fn div(self, rhs: $rhs) -> Self::Output {
+                $lhs(if rhs.0 == 0 {
+                    if self.0 >= 0 {
+                        Frac::MAX
+                    } else {
+                        Frac::MIN
+                    }
+                } else {
+                    self.0.saturating_div(rhs.0)
+                })
+            }
+        }
+    };
+}
+
+unit!(
+    /// An angle in rad.
+    Radians
+);
+unit!(
+    /// An angular rate in rad/s.
+    RadiansPerSecond
+);
+unit!(
+    /// A height or distance in m.
+    Metres
+);
+unit!(
+    /// A speed in m/s.
+    MetresPerSecond
+);
+unit!(
+    /// An acceleration in m/s^2.
+    MetresPerSecondSquared
+);
+unit!(
+    /// A duration in s, up to about 9 hours.
+    Seconds
+);
+
+product!(RadiansPerSecond * Seconds = Radians);
+product!(MetresPerSecond * Seconds = Metres);
+product!(MetresPerSecondSquared * Seconds = MetresPerSecond);
+
+impl Radians {
+    pub fn from_degrees(degrees: Frac) -> Self {
+        Radians(degrees.saturating_mul(Frac::PI / 180))
+    }
+}
+
+impl RadiansPerSecond {
+    /// The rate of one axis of the gyroscope from its counts.
+    pub fn from_gyro_counts(counts: i16) -> Self {
+        RadiansPerSecond(Frac::from_num(counts) / GYRO_SCALE * (Frac::PI / 180))
+    }
+}
+
+impl MetresPerSecondSquared {
+    /// The acceleration along one axis of the accelerometer from its counts.
+    pub fn from_accel_counts(counts: i32) -> Self {
+        let g = I48F16::from_num(counts) / i64::from(ACCEL_SCALE);
+        let accel = g.saturating_mul(I48F16::from_num(STANDARD_GRAVITY));
+        MetresPerSecondSquared(accel.saturating_to_num())
+    }
+}
+
+impl Seconds {
+    /// The time between two ticks of a loop running at `frequency` Hz.
+    pub fn per_tick(frequency: u64) -> Self {
+        Seconds(Frac::ONE / Frac::saturating_from_num(frequency.max(1)))
+    }
+}
+
+impl Velocity {
+    /// The rates of the gyroscope counts of each axis.
+    pub fn from_gyro_counts(yaw: i16, pitch: i16, roll: i16) -> Self {
+        Velocity {
+            yaw: RadiansPerSecond::from_gyro_counts(yaw),
+            pitch: RadiansPerSecond::from_gyro_counts(pitch),
+            roll: RadiansPerSecond::from_gyro_counts(roll),
+        }
+    }
+}
+
+/// A pressure in Pa, absolute or a difference of two.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+#[repr(transparent)]
+pub struct Pascal(pub Pressure);
+
+impl Pascal {
+    pub const ZERO: Pascal = Pascal(Pressure::ZERO);
+
+    /// A reading of the barometer, which measures whole Pa.
+    pub fn from_barometer(pressure: u32) -> Self {
+        Pascal(Pressure::saturating_from_num(pressure))
+    }
+
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Pascal(self.0.saturating_add(rhs.0))
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Pascal)
+    }
+
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Pascal(self.0.saturating_sub(rhs.0))
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.0.checked_sub(rhs.0).map(Pascal)
+    }
+
+    pub fn saturating_mul(self, rhs: Frac) -> Self {
+        Pascal(self.0.saturating_mul_add(rhs, Pressure::ZERO))
+    }
+
+    pub fn checked_mul(self, rhs: Frac) -> Option<Self> {
+        self.0.checked_mul_add(rhs, Pressure::ZERO).map(Pascal)
+    }
+}
+
+impl ops::Add for Pascal {
+    type Output = Pascal;
+    fn add(self, rhs: Self) -> Self::Output {
+        self.saturating_add(rhs)
+    }
+}
+
+impl ops::Sub for Pascal {
+    type Output = Pascal;
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.saturating_sub(rhs)
+    }
+}
+
+impl ops::Mul<Frac> for Pascal {
+    type Output = Pascal;
+    fn mul(self, rhs: Frac) -> Self::Output {
+        self.saturating_mul(rhs)
+    }
+}
+
+/// A voltage in mV.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+#[repr(transparent)]
+pub struct Millivolts(pub u16);
+
+impl Millivolts {
+    /// A reading of the battery, which measures in steps of 10 mV.
+    pub fn from_battery(reading: u16) -> Self {
+        Millivolts(reading.saturating_mul(10))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    #[test]
+    fn raw_counts_convert_to_units() {
+        // 164 counts are 10 deg/s
+        let rate = RadiansPerSecond::from_gyro_counts(164).0.to_num::<f64>();
+        assert!((rate - 10f64.to_radians()).abs() < 1e-4, "{rate}");
+        let velocity = Velocity::from_gyro_counts(164, 0, -164);
+        assert_eq!(velocity.yaw, RadiansPerSecond::from_gyro_counts(164));
+        assert_eq!(velocity.roll, -velocity.yaw);
+
+        let g = MetresPerSecondSquared::from_accel_counts(-ACCEL_SCALE);
+        assert_eq!(g, -MetresPerSecondSquared(STANDARD_GRAVITY));
+        assert_eq!(
+            MetresPerSecondSquared::from_accel_counts(i32::MAX).0,
+            Frac::MAX
+        );
+
+        assert_eq!(Pascal::from_barometer(101_325).0, 101_325);
+        assert_eq!(Pascal::from_barometer(u32::MAX).0, Pressure::MAX);
+        assert_eq!(Millivolts::from_battery(1_110), Millivolts(11_100));
+        assert_eq!(Millivolts::from_battery(u16::MAX), Millivolts(u16::MAX));
+        let right = Radians::from_degrees(Frac::from_num(90)).0.to_num::<f64>();
+        assert!((right - core::f64::consts::FRAC_PI_2).abs() < 1e-4);
+    }
+
+    #[test]
+    fn units_combine_over_time() {
+        let dt = Seconds::per_tick(100);
+        assert!((dt.0 - Frac::from_num(0.01)).abs() <= Frac::DELTA);
+        let climb = MetresPerSecond(Frac::from_num(2)) * Seconds(Frac::from_num(3));
+        assert_eq!(climb, Metres(Frac::from_num(6)));
+        assert_eq!(
+            climb / Seconds(Frac::from_num(3)),
+            MetresPerSecond(Frac::from_num(2))
+        );
+        assert_eq!(climb / Seconds::ZERO, MetresPerSecond(Frac::MAX));
+        let turn = RadiansPerSecond(Frac::from_num(-1)) * Seconds(Frac::from_num(0.5));
+        assert_eq!(turn, Radians(Frac::from_num(-0.5)));
+
+        let mut height = Metres(Frac::MAX);
+        height += Metres(Frac::ONE);
+        assert_eq!(height, Metres(Frac::MAX));
+        assert_eq!(-Metres(Frac::MIN), Metres(Frac::MAX));
+        assert_eq!(height.checked_add(Metres(Frac::ONE)), None);
+        assert_eq!(
+            Pascal(Pressure::from_num(300)) - Pascal(Pressure::from_num(400)),
+            Pascal(Pressure::from_num(-100))
+        );
+    }
+}
@@ -0,0 +1,489 @@
+//! Overflow handling of the vector types.
+//!
+//! The operators saturate: a component that does not fit is clamped to the largest or smallest
+//! value of its type, and dividing by zero gives the limit with the sign of the dividend. A
+//! reading that overflows must not panic the flight code in a debug build, nor wrap around to
+//! the opposite sign in a release build. Code that has to know uses the `checked_*` variants,
+//! which return `None` instead.
+
+use crate::{Accel, Attitude, Frac, Radians, RadiansPerSecond, SensorData, Velocity, YawPitchRoll};
+use fixed::types::I48F16;
+
+/// `x / y` saturated, the limit with the sign of `x` if `y` is zero.
+fn saturating_div_frac(x: Frac, y: Frac) -> Frac {
+    if y != 0 {
+        x.saturating_div(y)
+    } else if x > 0 {
+        Frac::MAX
+    } else if x < 0 {
+        Frac::MIN
+    } else {
+        Frac::ZERO
+    }
+}
+
+/// `x / y` saturated, the limit with the sign of `x` if `y` is zero.
+fn saturating_div_int(x: i32, y: i32) -> i32 {
+    if y != 0 {
+        x.saturating_div(y)
+    } else if x > 0 {
+        i32::MAX
+    } else if x < 0 {
+        i32::MIN
+    } else {
+        0
+    }
+}
+
+/// `x * y` rounded down. The counts do not fit in a `Frac`, so multiply with the same fraction
+/// bits but more integer bits; every product fits.
+pub(crate) fn wide_mul(x: i32, y: Frac) -> I48F16 {
+    I48F16::from_num(x) * I48F16::from_num(y)
+}
+
+/// The division of a component of a yaw, pitch, roll vector by a plain number.
+trait Component: Sized {
+    /// Saturated, the limit with the sign of `self` if `rhs` is zero.
+    fn div_saturating(self, rhs: Frac) -> Self;
+    fn div_checked(self, rhs: Frac) -> Option<Self>;
+}
+
+impl Component for Frac {
+    fn div_saturating(self, rhs: Frac) -> Self {
+        saturating_div_frac(self, rhs)
+    }
+
+    fn div_checked(self, rhs: Frac) -> Option<Self> {
+        self.checked_div(rhs)
+    }
+}
+
+macro_rules! unit_component {
+    ($unit:ident) => {
+        impl Component for $unit {
+            fn div_saturating(self, rhs: Frac) -> Self {
+                $unit(saturating_div_frac(self.0, rhs))
+            }
+
+            fn div_checked(self, rhs: Frac) -> Option<Self> {
+                self.0.checked_div(rhs).map($unit)
+            }
+        }
+    };
+}
+
+unit_component!(Radians);
+unit_component!(RadiansPerSecond);
+
+/// Saturating and checked operations on each component of a yaw, pitch, roll vector.
+macro_rules! yaw_pitch_roll_ops {
+    ($vector:ident) => {
+        impl $vector {
+            pub fn saturating_add(self, rhs: Self) -> Self {
+                $vector {
+                    yaw: self.yaw.saturating_add(rhs.yaw),
+                    pitch: self.pitch.saturating_add(rhs.pitch),
+                    roll: self.roll.saturating_add(rhs.roll),
+                }
+            }
+
+            pub fn checked_add(self, rhs: Self) -> Option<Self> {
+                Some($vector {
+                    yaw: self.yaw.checked_add(rhs.yaw)?,
+                    pitch: self.pitch.checked_add(rhs.pitch)?,
+                    roll: self.roll.checked_add(rhs.roll)?,
+                })
+            }
+
+            pub fn saturating_sub(self, rhs: Self) -> Self {
+                $vector {
+                    yaw: self.yaw.saturating_sub(rhs.yaw),
+                    pitch: self.pitch.saturating_sub(rhs.pitch),
+                    roll: self.roll.saturating_sub(rhs.roll),
+                }
+            }
+
+            pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+                Some($vector {
+                    yaw: self.yaw.checked_sub(rhs.yaw)?,
+                    pitch: self.pitch.checked_sub(rhs.pitch)?,
+                    roll: self.roll.checked_sub(rhs.roll)?,
+                })
+            }
+
+            pub fn saturating_mul(self, rhs: Frac) -> Self {
+                $vector {
+                    yaw: self.yaw.saturating_mul(rhs),
+                    pitch: self.pitch.saturating_mul(rhs),
+                    roll: self.roll.saturating_mul(rhs),
+                }
+            }
+
+            pub fn checked_mul(self, rhs: Frac) -> Option<Self> {
+                Some($vector {
+                    yaw: self.yaw.checked_mul(rhs)?,
+                    pitch: self.pitch.checked_mul(rhs)?,
+                    roll: self.roll.checked_mul(rhs)?,
+                })
+            }
+
+            pub fn saturating_div(self, rhs: Frac) -> Self {
+                $vector {
+                    yaw: self.yaw.div_saturating(rhs),
+                    pitch: self.pitch.div_saturating(rhs),
+                    roll: self.roll.div_saturating(rhs),
+                }
+            }
+
+            pub fn checked_div(self, rhs: Frac) -> Option<Self> {
+                Some($vector {
+                    yaw: self.yaw.div_checked(rhs)?,
+                    pitch: self.pitch.div_checked(rhs)?,
+                    roll: self.roll.div_checked(rhs)?,
+                })
+            }
+        }
+    };
+}
+
+yaw_pitch_roll_ops!(Velocity);
+yaw_pitch_roll_ops!(Attitude);
+yaw_pitch_roll_ops!(YawPitchRoll);
+
+impl Accel {
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Accel {
+            x: self.x.saturating_add(rhs.x),
+            y: self.y.saturating_add(rhs.y),
+            z: self.z.saturating_add(rhs.z),
+        }
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        Some(Accel {
+            x: self.x.checked_add(rhs.x)?,
+            y: self.y.checked_add(rhs.y)?,
+            z: self.z.checked_add(rhs.z)?,
+        })
+    }
+
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Accel {
+            x: self.x.saturating_sub(rhs.x),
+            y: self.y.saturating_sub(rhs.y),
+            z: self.z.saturating_sub(rhs.z),
+        }
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        Some(Accel {
+            x: self.x.checked_sub(rhs.x)?,
+            y: self.y.checked_sub(rhs.y)?,
+            z: self.z.checked_sub(rhs.z)?,
+        })
+    }
+
+    /// Scales every component, rounded down.
+    pub fn saturating_mul(self, rhs: Frac) -> Self {
+        Accel {
+            x: wide_mul(self.x, rhs).saturating_to_num(),
+            y: wide_mul(self.y, rhs).saturating_to_num(),
+            z: wide_mul(self.z, rhs).saturating_to_num(),
+        }
+    }
+
+    /// Scales every component, rounded down.
+    pub fn checked_mul(self, rhs: Frac) -> Option<Self> {
+        Some(Accel {
+            x: wide_mul(self.x, rhs).checked_to_num()?,
+            y: wide_mul(self.y, rhs).checked_to_num()?,
+            z: wide_mul(self.z, rhs).checked_to_num()?,
+        })
+    }
+
+    pub fn saturating_div(self, rhs: i32) -> Self {
+        Accel {
+            x: saturating_div_int(self.x, rhs),
+            y: saturating_div_int(self.y, rhs),
+            z: saturating_div_int(self.z, rhs),
+        }
+    }
+
+    pub fn checked_div(self, rhs: i32) -> Option<Self> {
+        Some(Accel {
+            x: self.x.checked_div(rhs)?,
+            y: self.y.checked_div(rhs)?,
+            z: self.z.checked_div(rhs)?,
+        })
+    }
+}
+
+impl SensorData {
+    /// Adds the measurements, the battery and the motor speeds are the ones of `self`.
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        SensorData {
+            height: self.height.saturating_add(rhs.height),
+            v_z: self.v_z.saturating_add(rhs.v_z),
+            pressure: self.pressure.saturating_add(rhs.pressure),
+            velocity: self.velocity.saturating_add(rhs.velocity),
+            radius: self.radius.saturating_add(rhs.radius),
+            acceleration: self.acceleration.saturating_add(rhs.acceleration),
+            ..self
+        }
+    }
+
+    /// Adds the measurements, the battery and the motor speeds are the ones of `self`.
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        Some(SensorData {
+            height: self.height.checked_add(rhs.height)?,
+            v_z: self.v_z.checked_add(rhs.v_z)?,
+            pressure: self.pressure.checked_add(rhs.pressure)?,
+            velocity: self.velocity.checked_add(rhs.velocity)?,
+            radius: self.radius.checked_add(rhs.radius)?,
+            acceleration: self.acceleration.checked_add(rhs.acceleration)?,
+            ..self
+        })
+    }
+
+    /// Scales the measurements, the battery and the motor speeds stay as they are.
+    pub fn saturating_mul(self, rhs: Frac) -> Self {
+        SensorData {
+            height: self.height.saturating_mul(rhs),
+            v_z: self.v_z.saturating_mul(rhs),
+            pressure: self.pressure.saturating_mul(rhs),
+            velocity: self.velocity.saturating_mul(rhs),
+            radius: self.radius.saturating_mul(rhs),
+            acceleration: self.acceleration.saturating_mul(rhs),
+            ..self
+        }
+    }
+
+    /// Scales the measurements, the battery and the motor speeds stay as they are.
+    pub fn checked_mul(self, rhs: Frac) -> Option<Self> {
+        Some(SensorData {
+            height: self.height.checked_mul(rhs)?,
+            v_z: self.v_z.checked_mul(rhs)?,
+            pressure: self.pressure.checked_mul(rhs)?,
+            velocity: self.velocity.checked_mul(rhs)?,
+            radius: self.radius.checked_mul(rhs)?,
+            acceleration: self.acceleration.checked_mul(rhs)?,
+            ..self
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::{Metres, MetresPerSecond, Millivolts, Pascal, Pressure};
+    use std::vec::Vec;
+
+    /// The limits, the values next to them and a few ordinary ones.
+    fn fracs() -> Vec<Frac> {
+        [
+            Frac::MIN,
+            Frac::MIN + Frac::DELTA,
+            Frac::from_num(-1.5),
+            -Frac::DELTA,
+            Frac::ZERO,
+            Frac::DELTA,
+            Frac::from_num(0.75),
+            Frac::from_num(181),
+            Frac::MAX - Frac::DELTA,
+            Frac::MAX,
+        ]
+        .to_vec()
+    }
+
+    fn ints() -> Vec<i32> {
+        [
+            i32::MIN,
+            i32::MIN + 1,
+            -32768,
+            -1,
+            0,
+            1,
+            16384,
+            i32::MAX - 1,
+            i32::MAX,
+        ]
+        .to_vec()
+    }
+
+    fn fits(exact: i128) -> bool {
+        i32::try_from(exact).is_ok()
+    }
+
+    fn clamp(exact: i128) -> i32 {
+        exact.clamp(i32::MIN as i128, i32::MAX as i128) as i32
+    }
+
+    /// The limit an overflow with the sign of `sign` saturates to.
+    fn limit(sign: Frac) -> Frac {
+        if sign > 0 {
+            Frac::MAX
+        } else if sign < 0 {
+            Frac::MIN
+        } else {
+            Frac::ZERO
+        }
+    }
+
+    fn vector(a: Frac) -> YawPitchRoll {
+        YawPitchRoll {
+            yaw: a,
+            pitch: a,
+            roll: a,
+        }
+    }
+
+    #[test]
+    fn frac_sums_are_clamped_exactly() {
+        for a in fracs() {
+            for b in fracs() {
+                let sum = a.to_bits() as i128 + b.to_bits() as i128;
+                let difference = a.to_bits() as i128 - b.to_bits() as i128;
+                let (x, y) = (vector(a), vector(b));
+                assert_eq!(x.saturating_add(y).yaw.to_bits(), clamp(sum), "{a} + {b}");
+                assert_eq!(
+                    x.saturating_sub(y).yaw.to_bits(),
+                    clamp(difference),
+                    "{a} - {b}"
+                );
+                assert_eq!(x.checked_add(y).is_some(), fits(sum), "{a} + {b}");
+                assert_eq!(x.checked_sub(y).is_some(), fits(difference), "{a} - {b}");
+                assert_eq!(x + y, x.saturating_add(y));
+                assert_eq!(x - y, x.saturating_sub(y));
+            }
+        }
+    }
+
+    #[test]
+    fn frac_products_saturate_with_the_sign() {
+        for a in fracs() {
+            for b in fracs() {
+                let x = vector(a);
+                let sign = a.signum() * b.signum();
+                let quotient_sign = if b == 0 { a.signum() } else { sign };
+                for (saturated, checked, sign) in [
+                    (x.saturating_mul(b), x.checked_mul(b), sign),
+                    (x.saturating_div(b), x.checked_div(b), quotient_sign),
+                ] {
+                    match checked {
+                        Some(checked) => assert_eq!(saturated, checked, "{a}, {b}"),
+                        None => assert_eq!(saturated, vector(limit(sign)), "{a}, {b}"),
+                    }
+                }
+                assert_eq!(x * b, x.saturating_mul(b));
+                assert_eq!(x / b, x.saturating_div(b));
+            }
+        }
+        let v = Velocity {
+            yaw: RadiansPerSecond(Frac::MAX),
+            pitch: RadiansPerSecond(Frac::MIN),
+            roll: RadiansPerSecond::ZERO,
+        };
+        assert_eq!(v.saturating_div(Frac::ZERO), v);
+        assert_eq!(v.checked_div(Frac::ZERO), None);
+    }
+
+    #[test]
+    fn accel_is_clamped_exactly() {
+        let accel = |a: i32| Accel { x: a, y: a, z: a };
+        for a in ints() {
+            for b in ints() {
+                let (x, y) = (accel(a), accel(b));
+                let sum = a as i128 + b as i128;
+                let difference = a as i128 - b as i128;
+                assert_eq!(x.saturating_add(y), accel(clamp(sum)), "{a} + {b}");
+                assert_eq!(x.saturating_sub(y), accel(clamp(difference)), "{a} - {b}");
+                assert_eq!(x.checked_add(y).is_some(), fits(sum));
+                assert_eq!(x.checked_sub(y).is_some(), fits(difference));
+                assert_eq!(x + y, x.saturating_add(y));
+                assert_eq!(x - y, x.saturating_sub(y));
+
+                let quotient = match b {
+                    0 => a.signum() as i128 * i128::MAX,
+                    b => a as i128 / b as i128,
+                };
+                assert_eq!(x / b, accel(clamp(quotient)), "{a} / {b}");
+                assert_eq!(x.checked_div(b).is_some(), b != 0 && fits(quotient));
+            }
+            for scale in fracs() {
+                // rounded down, like the shift
+                let product = (a as i128 * scale.to_bits() as i128) >> 16;
+                let x = accel(a);
+                assert_eq!(
+                    x.saturating_mul(scale),
+                    accel(clamp(product)),
+                    "{a} * {scale}"
+                );
+                assert_eq!(
+                    x.checked_mul(scale),
+                    Some(accel(clamp(product))).filter(|_| fits(product))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn extreme_sensor_data_saturates() {
+        let mut extreme = SensorData::new();
+        extreme.height = Metres(Frac::MAX);
+        extreme.v_z = MetresPerSecond(Frac::MIN);
+        extreme.pressure = Pascal(Pressure::MAX);
+        extreme.velocity = Velocity {
+            yaw: RadiansPerSecond(Frac::MAX),
+            pitch: RadiansPerSecond(Frac::MIN),
+            roll: RadiansPerSecond(Frac::DELTA),
+        };
+        let angle = Radians(Frac::MIN);
+        extreme.radius = Attitude {
+            yaw: angle,
+            pitch: angle,
+            roll: angle,
+        };
+        extreme.acceleration = Accel {
+            x: i32::MAX,
+            y: i32::MIN,
+            z: 16384,
+        };
+        extreme.bat = Millivolts(11_000);
+        extreme.motor_speeds = [400; 4];
+
+        let twice = extreme + extreme;
+        assert_eq!(twice.height.0, Frac::MAX);
+        assert_eq!(twice.v_z.0, Frac::MIN);
+        assert_eq!(twice.pressure.0, Pressure::MAX);
+        assert_eq!(twice.radius, extreme.radius);
+        assert_eq!(twice.acceleration.x, i32::MAX);
+        assert_eq!(twice.acceleration.y, i32::MIN);
+        assert_eq!(twice.acceleration.z, 32768);
+        assert_eq!(twice.bat, Millivolts(11_000));
+        assert_eq!(extreme.checked_add(extreme), None);
+
+        for scale in fracs() {
+            let scaled = extreme * scale;
+            assert_eq!(scaled, extreme.saturating_mul(scale));
+            if let Some(checked) = extreme.checked_mul(scale) {
+                assert_eq!(scaled, checked, "{scale}");
+            }
+            assert_eq!(scaled.motor_speeds, extreme.motor_speeds);
+        }
+        let doubled = extreme * Frac::from_num(2);
+        assert_eq!(doubled.height.0, Frac::MAX);
+        assert_eq!(doubled.pressure.0, Pressure::MAX);
+        assert_eq!(doubled.acceleration.y, i32::MIN);
+        assert_eq!(doubled.acceleration.z, 32768);
+        assert_eq!(extreme.checked_mul(Frac::from_num(2)), None);
+
+        let zero = SensorData {
+            bat: Millivolts(11_000),
+            motor_speeds: [400; 4],
+            ..SensorData::new()
+        };
+        assert_eq!(extreme * Frac::ZERO, zero);
+    }
+}
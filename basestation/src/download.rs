@@ -0,0 +1,314 @@
+//! Downloading a session of the flight log to a file.
+//!
+//! The drone sends the records of a session in [`LogChunk`]s, one per tick, for the bytes a
+//! [`Message::LogRead`] asks for. The download asks for a window of them at a time, from the
+//! first byte it is missing up to the first chunk it already has, so only the chunks that got
+//! lost or damaged are sent again. When nothing arrives for a while it asks again. The bytes
+//! complete from the start of the session are appended to the file, so a download that was
+//! cut off goes on after what the file holds. The tick the session started at is kept next to
+//! the file, in `<file>.started`, so resuming with chunks of another session of the same number
+//! fails instead of appending them.
+//!
+//! The file holds the records as the flight log stores them: a sync byte, the length of the
+//! record, the tick, the serialized [`architecture::LogRecord`] and a CRC.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use architecture::{LogChunk, Message, LOG_CHUNK};
+use protocol::{DataLink, Link, MessageLink};
+
+/// Bytes asked for at a time.
+pub const WINDOW: u32 = 16 * LOG_CHUNK as u32;
+/// How long to wait for a chunk before asking again.
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+pub struct Download {
+    session: u16,
+    /// Tick the session in the file started at, `None` until the first chunk of an empty file.
+    started: Option<u32>,
+    /// Where `started` is kept.
+    started_path: PathBuf,
+    file: File,
+    /// Bytes of the session in the file.
+    written: u32,
+    /// The intact chunks after `written`, by offset.
+    received: BTreeMap<u32, Vec<u8>>,
+    /// Where the session ends, once a chunk told.
+    end: Option<u32>,
+    /// End of the bytes asked for last.
+    requested: u32,
+    /// When the drone was last asked or answered.
+    heard: Option<Instant>,
+}
+
+impl Download {
+    /// Downloads `session` into the file at `path`, after what it already holds.
+    pub fn open(session: u16, path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written =
+            u32::try_from(file.metadata()?.len()).map_err(|_| invalid_data("file too large"))?;
+        let started_path = started_path(path);
+        // without the start of the session the bytes in the file cannot be resumed
+        let started = match written {
+            0 => None,
+            _ => Some(
+                fs::read_to_string(&started_path)
+                    .ok()
+                    .and_then(|started| started.trim().parse().ok())
+                    .ok_or_else(|| invalid_data("not known which session the file holds"))?,
+            ),
+        };
+        Ok(Download {
+            session,
+            started,
+            started_path,
+            file,
+            written,
+            received: BTreeMap::new(),
+            end: None,
+            requested: written,
+            heard: None,
+        })
+    }
+
+    /// Bytes of the session in the file.
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.end.map_or(false, |end| self.written >= end)
+    }
+
+    /// The read to send to the drone at `now`, if it is time to ask for more.
+    pub fn poll(&mut self, now: Instant) -> Option<Message> {
+        if self.is_done() {
+            return None;
+        }
+        let stalled = self
+            .heard
+            .map_or(true, |heard| now.duration_since(heard) >= TIMEOUT);
+        if !stalled && self.written < self.requested {
+            return None;
+        }
+        let gap = match self.received.keys().next() {
+            Some(offset) => offset - self.written,
+            None => WINDOW,
+        };
+        let length = gap.min(WINDOW);
+        self.requested = self.written + length;
+        self.heard = Some(now);
+        Some(Message::LogRead {
+            session: self.session,
+            offset: self.written,
+            length,
+        })
+    }
+
+    /// Takes in `chunk`, which arrived at `now`, and appends what is complete to the file.
+    pub fn receive(&mut self, chunk: &LogChunk, now: Instant) -> io::Result<()> {
+        // a damaged chunk is asked for again
+        let Some(bytes) = chunk.bytes() else {
+            return Ok(());
+        };
+        let end = chunk.offset + bytes.len() as u32;
+        if chunk.session != self.session || end < self.written {
+            return Ok(());
+        }
+        match self.started {
+            Some(started) if started != chunk.started => {
+                return Err(invalid_data(
+                    "the file holds another session of the same number",
+                ));
+            }
+            Some(_) => (),
+            None => {
+                fs::write(&self.started_path, chunk.started.to_string())?;
+                self.started = Some(chunk.started);
+            }
+        }
+        self.heard = Some(now);
+        if bytes.len() < LOG_CHUNK {
+            self.end = Some(end);
+        }
+        self.received.insert(chunk.offset, bytes.to_vec());
+        while let Some(entry) = self.received.first_entry() {
+            let offset = *entry.key();
+            if offset > self.written {
+                break;
+            }
+            let bytes = entry.remove();
+            let skip = (self.written - offset) as usize;
+            if skip < bytes.len() {
+                self.file.write_all(&bytes[skip..])?;
+                self.written += (bytes.len() - skip) as u32;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where the tick the session in the file at `path` started at is kept.
+fn started_path(path: &Path) -> PathBuf {
+    let mut started = path.as_os_str().to_owned();
+    started.push(".started");
+    started.into()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Downloads `session` into the file at `path` over `message_link`, resuming after what the
+/// file holds. Waits for the drone for as long as it takes.
+pub fn download<T: Link>(
+    message_link: &mut MessageLink<T>,
+    session: u16,
+    path: impl AsRef<Path>,
+) -> io::Result<()> {
+    let mut download = Download::open(session, path)?;
+    while !download.is_done() {
+        if let Some(request) = download.poll(Instant::now()) {
+            let _ = message_link.send(&request);
+        }
+        while let Ok(Some(message)) = message_link.check_for_message() {
+            if let Message::LogChunk { chunk } = message {
+                download.receive(&chunk, Instant::now())?;
+            }
+        }
+        sleep(Duration::from_millis(1));
+    }
+    println!("session {session}: {} bytes", download.written());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, path::PathBuf};
+
+    fn session() -> Vec<u8> {
+        (0..1000).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("basestation-{}-{name}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(started_path(&path));
+        path
+    }
+
+    /// What the drone sends for `request` of the session that started at tick `started`.
+    fn answer(request: Message, started: u32, data: &[u8]) -> Vec<LogChunk> {
+        let Message::LogRead {
+            session,
+            offset,
+            length,
+        } = request
+        else {
+            panic!("{request:?}");
+        };
+        let mut chunks = Vec::new();
+        let mut at = offset;
+        while at < offset + length {
+            let start = (at as usize).min(data.len());
+            let end = (start + LOG_CHUNK).min(data.len());
+            chunks.push(LogChunk::new(session, started, at, &data[start..end]));
+            if end - start < LOG_CHUNK {
+                break;
+            }
+            at += LOG_CHUNK as u32;
+        }
+        chunks
+    }
+
+    #[test]
+    fn lost_and_damaged_chunks_are_asked_for_again() {
+        let data = session();
+        let path = path("lossy");
+        let mut download = Download::open(3, &path).unwrap();
+        let mut now = Instant::now();
+        let mut sent = 0;
+        while !download.is_done() {
+            now += Duration::from_millis(10);
+            let Some(request) = download.poll(now) else {
+                continue;
+            };
+            for mut chunk in answer(request, 40, &data) {
+                sent += 1;
+                match sent % 7 {
+                    0 => continue,
+                    3 => chunk.data[0] ^= 1,
+                    _ => (),
+                }
+                download.receive(&chunk, now).unwrap();
+            }
+            assert!(sent < 200);
+        }
+        assert_eq!(fs::read(&path).unwrap(), data);
+        fs::remove_file(started_path(&path)).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_download_resumes_after_the_file() {
+        let data = session();
+        let path = path("resume");
+        let mut download = Download::open(3, &path).unwrap();
+        let now = Instant::now();
+        let request = download.poll(now).unwrap();
+        for chunk in answer(request, 40, &data).iter().take(5) {
+            download.receive(chunk, now).unwrap();
+        }
+        drop(download);
+
+        let mut download = Download::open(3, &path).unwrap();
+        assert_eq!(download.written(), 5 * LOG_CHUNK as u32);
+        while let Some(request) = download.poll(now) {
+            let Message::LogRead { offset, .. } = request else {
+                panic!("{request:?}");
+            };
+            assert!(offset >= 5 * LOG_CHUNK as u32);
+            for chunk in answer(request, 40, &data) {
+                download.receive(&chunk, now).unwrap();
+            }
+        }
+        assert!(download.is_done());
+        assert_eq!(fs::read(&path).unwrap(), data);
+        fs::remove_file(started_path(&path)).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_download_does_not_resume_another_session() {
+        let data = session();
+        let path = path("renumbered");
+        let mut download = Download::open(3, &path).unwrap();
+        let now = Instant::now();
+        let request = download.poll(now).unwrap();
+        for chunk in answer(request, 40, &data).iter().take(5) {
+            download.receive(chunk, now).unwrap();
+        }
+        drop(download);
+
+        // the log was deleted and a later session got the same number
+        let mut download = Download::open(3, &path).unwrap();
+        let request = download.poll(now).unwrap();
+        let chunk = answer(request, 900, &data)[0];
+        assert!(download.receive(&chunk, now).is_err());
+        assert_eq!(fs::read(&path).unwrap(), data[..5 * LOG_CHUNK]);
+
+        // nor a file it does not know the session of
+        fs::remove_file(started_path(&path)).unwrap();
+        assert!(Download::open(3, &path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
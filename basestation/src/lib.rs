@@ -1,3 +1,5 @@
+pub mod download;
+
 use std::{
     io::{Read, Write},
     net::{TcpListener, TcpStream},
@@ -19,7 +21,7 @@ pub fn start<T: Link>(mut message_link: MessageLink<T>) -> ! {
             Ok(msg) => match msg {
                 Some(msg) => match msg {
                     architecture::Message::SensorData { data } => {
-                        last = data.pressure * alpha + last * (1.0 - alpha);
+                        last = data.pressure.0.to_num::<f32>() * alpha + last * (1.0 - alpha);
                         stream.write_all(&last.to_le_bytes()).unwrap();
                     }
                     _ => (),
@@ -0,0 +1,181 @@
+use crate::altitude::Wide;
+use crate::calibration::Statistics;
+use crate::mahony_filter::ACCEL_SCALE;
+use architecture::{Accel, AccelCorrection, AccelFace, CalibrationFailure, Frac};
+
+/// Readings averaged per face, one per tick.
+const SAMPLES: u16 = 100;
+/// Largest standard deviation of a face that still counts as lying still, g.
+const MAX_NOISE: Wide = Wide::lit("0.05");
+/// Smallest share of gravity along the axis of the face, g.
+const MIN_GRAVITY: Wide = Wide::lit("0.8");
+
+/// Six position calibration of the offset and scale of each accelerometer axis.
+///
+/// The host asks for the faces one after the other and tells the user to put the drone on each
+/// of them. With `up` and `down` the mean reading of an axis pointing up and down, in g,
+/// the offset is `(up + down) / 2` and the scale `(up - down) / 2`.
+#[derive(Debug, Clone)]
+pub struct AccelCalibration {
+    /// The face being measured, the statistics of x, y, z in g and the number of readings.
+    measuring: Option<(AccelFace, [Statistics; 3], u16)>,
+    /// Mean reading of the axis pointing up or down per face, in the order of
+    /// [`AccelFace::ALL`], g.
+    faces: [Option<Wide>; 6],
+}
+
+impl AccelCalibration {
+    pub fn new() -> Self {
+        AccelCalibration {
+            measuring: None,
+            faces: [None; 6],
+        }
+    }
+
+    /// Starts measuring `face`, replacing an earlier measurement of it.
+    pub fn measure(&mut self, face: AccelFace) {
+        self.measuring = Some((face, [Statistics::new(); 3], 0));
+    }
+
+    pub fn is_measuring(&self) -> bool {
+        self.measuring.is_some()
+    }
+
+    /// Adds a reading without correction. Returns the face and whether it was accepted once it
+    /// is measured.
+    pub fn add(&mut self, raw: Accel) -> Option<(AccelFace, Option<CalibrationFailure>)> {
+        let (face, statistics, samples) = self.measuring.as_mut()?;
+        for (statistics, value) in statistics.iter_mut().zip([raw.x, raw.y, raw.z]) {
+            statistics.add(Wide::from_num(value) / ACCEL_SCALE as i64);
+        }
+        *samples += 1;
+        if *samples < SAMPLES {
+            return None;
+        }
+
+        let face = *face;
+        let noisy = statistics
+            .iter()
+            .any(|statistics| statistics.deviation(SAMPLES) > MAX_NOISE);
+        let gravity = statistics[face.axis()].mean(SAMPLES);
+        let failure = if noisy {
+            Some(CalibrationFailure::Shaking)
+        } else if (face.is_up() && gravity < MIN_GRAVITY)
+            || (!face.is_up() && gravity > -MIN_GRAVITY)
+        {
+            Some(CalibrationFailure::WrongFace)
+        } else {
+            None
+        };
+        if failure.is_none() {
+            let index = AccelFace::ALL.iter().position(|f| *f == face).unwrap();
+            self.faces[index] = Some(gravity);
+        }
+        self.measuring = None;
+        Some((face, failure))
+    }
+
+    /// The correction, once all six faces are measured.
+    pub fn correction(&self) -> Option<AccelCorrection> {
+        let mut offset = [0; 3];
+        let mut gain = [Frac::from_num(1); 3];
+        for axis in 0..3 {
+            let up = self.faces[2 * axis]?;
+            let down = self.faces[2 * axis + 1]?;
+            offset[axis] = ((up + down) / 2 * ACCEL_SCALE as i64)
+                .round()
+                .saturating_to_num();
+            gain[axis] = Frac::saturating_from_num(Wide::from_num(2) / (up - down));
+        }
+        Some(AccelCorrection {
+            offset: Accel {
+                x: offset[0],
+                y: offset[1],
+                z: offset[2],
+            },
+            gain,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sensor with offset `(200, -300, 500)` and scales `(1.02, 0.97, 1.05)`, lying on `face`.
+    fn reading(face: AccelFace) -> Accel {
+        let mut g = [0.0; 3];
+        g[face.axis()] = if face.is_up() { 1.0 } else { -1.0 };
+        let counts = |g: f64, scale: f64, offset: f64| (g * scale * 16384.0 + offset) as i32;
+        Accel {
+            x: counts(g[0], 1.02, 200.0),
+            y: counts(g[1], 0.97, -300.0),
+            z: counts(g[2], 1.05, 500.0),
+        }
+    }
+
+    fn measure(calibration: &mut AccelCalibration, face: AccelFace, raw: Accel) {
+        calibration.measure(face);
+        for _ in 1..SAMPLES {
+            assert_eq!(calibration.add(raw), None);
+        }
+        assert_eq!(calibration.add(raw), Some((face, None)));
+    }
+
+    #[test]
+    fn six_faces_give_offset_and_scale() {
+        let mut calibration = AccelCalibration::new();
+        for face in AccelFace::ALL {
+            assert_eq!(calibration.correction(), None);
+            measure(&mut calibration, face, reading(face));
+        }
+        let correction = calibration.correction().unwrap();
+        assert_eq!(
+            correction.offset,
+            Accel {
+                x: 200,
+                y: -300,
+                z: 500
+            }
+        );
+        for face in AccelFace::ALL {
+            let corrected = correction.apply(reading(face));
+            let expected = if face.is_up() { 16384 } else { -16384 };
+            let value = [corrected.x, corrected.y, corrected.z][face.axis()];
+            assert!((value - expected).abs() <= 2, "{face:?}: {value}");
+        }
+    }
+
+    #[test]
+    fn lying_on_the_wrong_face_is_rejected() {
+        let mut calibration = AccelCalibration::new();
+        calibration.measure(AccelFace::XDown);
+        let mut result = None;
+        while result.is_none() {
+            result = calibration.add(reading(AccelFace::ZUp));
+        }
+        assert_eq!(
+            result,
+            Some((AccelFace::XDown, Some(CalibrationFailure::WrongFace)))
+        );
+        assert!(!calibration.is_measuring());
+    }
+
+    #[test]
+    fn moving_is_rejected() {
+        let mut calibration = AccelCalibration::new();
+        calibration.measure(AccelFace::ZUp);
+        let mut result = None;
+        let mut i = 0;
+        while result.is_none() {
+            let mut raw = reading(AccelFace::ZUp);
+            raw.x += if i % 2 == 0 { 3000 } else { -3000 };
+            result = calibration.add(raw);
+            i += 1;
+        }
+        assert_eq!(
+            result,
+            Some((AccelFace::ZUp, Some(CalibrationFailure::Shaking)))
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use architecture::{Frac, Metres, Pascal};
+use fixed::types::I32F32;
+
+/// Absolute altitudes and pressures in Pa do not fit in a `Frac`.
+pub type Wide = I32F32;
+
+/// Pressure at sea level in the standard atmosphere, Pa.
+const SEA_LEVEL_PRESSURE: Wide = Wide::lit("101325");
+/// Temperature at sea level over the temperature lapse rate, 288.15 K / 0.0065 K/m.
+const ALTITUDE_SCALE: Wide = Wide::lit("44330.77");
+/// R L / (g M) of the troposphere.
+const EXPONENT: Wide = Wide::lit("0.190263");
+/// Lowest pressure in Pa the altitude is computed for, far below what the barometer measures.
+const MIN_PRESSURE: Wide = Wide::ONE;
+
+/// Altitude in m of `pressure` in Pa in the standard atmosphere (ISA).
+///
+/// `h = 44330.77 (1 - (p / 101325) ^ 0.190263)`, with the power evaluated as `exp(b ln x)`.
+/// Pressures below [`MIN_PRESSURE`], which only a broken reading gives, count as it.
+pub fn pressure_altitude(pressure: Wide) -> Wide {
+    let ratio = pressure.max(MIN_PRESSURE) / SEA_LEVEL_PRESSURE;
+    ALTITUDE_SCALE * (Wide::ONE - exp(EXPONENT * ln(ratio)))
+}
+
+/// Natural logarithm of a positive `x`.
+fn ln(x: Wide) -> Wide {
+    assert!(x > 0);
+    // x = m 2^e with m in [1, 2)
+    let mut m = x;
+    let mut e = 0;
+    while m >= 2 {
+        m /= 2;
+        e += 1;
+    }
+    while m < 1 {
+        m *= 2;
+        e -= 1;
+    }
+    // ln m = 2 atanh(s) = 2 (s + s^3 / 3 + s^5 / 5 + ...) with s <= 1/3
+    let s = (m - Wide::ONE) / (m + Wide::ONE);
+    let s2 = s * s;
+    let mut power = s;
+    let mut sum = Wide::ZERO;
+    for k in 0..8 {
+        sum += power / (2 * k + 1);
+        power *= s2;
+    }
+    Wide::from_num(e) * Wide::LN_2 + sum * 2
+}
+
+/// Exponential of `y`, accurate for `|y| < 1` which covers the range of the barometer.
+fn exp(y: Wide) -> Wide {
+    let mut term = Wide::ONE;
+    let mut sum = Wide::ONE;
+    for n in 1..14 {
+        term = term * y / n;
+        sum += term;
+    }
+    sum
+}
+
+/// Height above a ground reference from the absolute pressure of the barometer.
+///
+/// The reference is taken at calibration. The base station sends the pressure it measures on
+/// the ground; changes of it since the reference are the weather and are subtracted, which makes
+/// the height differential. Without a base station its pressure stays constant and the
+/// correction is zero, so connect it before calibrating.
+#[derive(Debug, Clone, Copy)]
+pub struct Altimeter {
+    /// Altitude of the drone at the reference, m.
+    ground: Wide,
+    /// Altitude of the base station at the reference, m.
+    base: Wide,
+    /// The last pressure of the base station and its altitude, which change rarely.
+    last_base: (Pascal, Wide),
+}
+
+impl Altimeter {
+    pub fn new() -> Self {
+        Altimeter {
+            ground: Wide::ZERO,
+            base: Wide::ZERO,
+            last_base: (Pascal::ZERO, pressure_altitude(Wide::ZERO)),
+        }
+    }
+
+    /// Takes the current pressures of the drone and the base station as height 0.
+    pub fn set_ground(&mut self, pressure: Pascal, base_pressure: Pascal) {
+        self.ground = pressure_altitude(Wide::from_num(pressure.0));
+        self.base = self.base_altitude(base_pressure);
+    }
+
+    /// Height above the ground reference.
+    pub fn height(&mut self, pressure: Pascal, base_pressure: Pascal) -> Metres {
+        let weather = self.base_altitude(base_pressure) - self.base;
+        let altitude = pressure_altitude(Wide::from_num(pressure.0));
+        Metres(Frac::saturating_from_num(altitude - self.ground - weather))
+    }
+
+    fn base_altitude(&mut self, base_pressure: Pascal) -> Wide {
+        if self.last_base.0 != base_pressure {
+            self.last_base = (
+                base_pressure,
+                pressure_altitude(Wide::from_num(base_pressure.0)),
+            );
+        }
+        self.last_base.1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    fn pa(pressure: u32) -> Pascal {
+        Pascal::from_barometer(pressure)
+    }
+
+    fn isa(pressure: f64) -> f64 {
+        44330.77 * (1.0 - (pressure / 101325.0).powf(0.190263))
+    }
+
+    #[test]
+    fn matches_the_standard_atmosphere_over_the_sensor_range() {
+        // the MS5611 measures 10 to 1200 mbar
+        for pressure in (1_000..=120_000).step_by(500) {
+            let altitude: f64 = pressure_altitude(Wide::from_num(pressure)).to_num();
+            let expected = isa(pressure as f64);
+            assert!(
+                (altitude - expected).abs() < 0.01,
+                "{pressure} Pa: {altitude} m instead of {expected} m"
+            );
+        }
+    }
+
+    #[test]
+    fn broken_readings_have_an_altitude() {
+        let highest: f64 = pressure_altitude(MIN_PRESSURE).to_num();
+        assert!((highest - isa(1.0)).abs() < 0.1, "{highest}");
+        for pressure in [Wide::ZERO, Wide::from_num(-5), Wide::MIN, Wide::MAX] {
+            let altitude = pressure_altitude(pressure);
+            assert!(altitude <= pressure_altitude(MIN_PRESSURE));
+        }
+    }
+
+    #[test]
+    fn sea_level_is_zero() {
+        let altitude: f64 = pressure_altitude(SEA_LEVEL_PRESSURE).to_num();
+        assert!(altitude.abs() < 0.001);
+    }
+
+    #[test]
+    fn height_is_relative_to_the_ground_reference() {
+        let mut altimeter = Altimeter::new();
+        altimeter.set_ground(pa(95_000), pa(95_000));
+        assert_eq!(altimeter.height(pa(95_000), pa(95_000)), Metres::ZERO);
+        // about 11 Pa per m up here, so 120 Pa less is roughly 10.5 m higher
+        let height: f64 = altimeter.height(pa(94_880), pa(95_000)).0.to_num();
+        let expected = isa(94_880.0) - isa(95_000.0);
+        assert!((height - expected).abs() < 0.01);
+        assert!((height - 10.5).abs() < 0.5);
+    }
+
+    #[test]
+    fn weather_measured_by_the_base_station_cancels() {
+        let mut altimeter = Altimeter::new();
+        altimeter.set_ground(pa(101_000), pa(101_050));
+        // a high pressure area moves in, raising both by 300 Pa while the drone stays put
+        let height: f64 = altimeter.height(pa(101_300), pa(101_350)).0.to_num();
+        assert!(height.abs() < 0.05, "{height}");
+    }
+}
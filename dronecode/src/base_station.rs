@@ -1,24 +1,19 @@
-use architecture::{Message, SensorData};
-use protocol::{DataLink, FuncLink, MessageLink};
-use tudelft_quadrupel::{
-    barometer::read_pressure,
-    time::{set_tick_frequency, wait_for_next_tick},
-    uart::{receive_bytes, send_bytes},
-};
+use crate::hal::{Barometer, Clock, Uart};
+use architecture::{Message, Pascal, SensorData};
+use protocol::DataLink;
 
-pub fn base_station_loop() -> ! {
+pub fn base_station_loop<H: Barometer + Clock + Uart>(mut hal: H) -> ! {
     // initialization
-    let link = FuncLink::from_func(send_bytes, receive_bytes);
-    let mut link = MessageLink::new(link);
+    let mut link = H::link();
 
-    set_tick_frequency(100);
+    hal.set_tick_frequency(100);
 
     let mut data = SensorData::new();
     loop {
-        data.pressure = read_pressure() as f32;
+        data.pressure = Pascal::from_barometer(hal.read_pressure());
 
         link.send(&Message::SensorData { data: data.clone() })
             .unwrap();
-        wait_for_next_tick();
+        hal.wait_for_next_tick();
     }
 }
@@ -0,0 +1,228 @@
+use crate::altitude::Wide;
+use architecture::{BatteryLevel, Millivolts};
+
+/// Time constant of the voltage filter, s.
+const TIME_CONSTANT: Wide = Wide::lit("1");
+/// Sag of the pack per squared motor command in mV, the internal resistance times the current
+/// the motors draw.
+const SAG: Wide = Wide::lit("0.001");
+/// Margins above the critical level, `Limits::min_battery`, of the other levels, mV.
+const LAND_MARGIN: u16 = 900;
+const LOW_MARGIN: u16 = 1700;
+/// How far the voltage has to recover above a level to leave it again, mV.
+const HYSTERESIS: u16 = 200;
+/// Rested voltage of a 3S LiPo pack in mV and the charge left in percent.
+const CHARGE_CURVE: [(u16, u8); 11] = [
+    (9900, 0),
+    (10500, 5),
+    (10800, 10),
+    (10950, 20),
+    (11100, 30),
+    (11250, 40),
+    (11400, 50),
+    (11700, 65),
+    (12000, 80),
+    (12300, 90),
+    (12600, 100),
+];
+
+/// The levels from full to empty.
+const LEVELS: [BatteryLevel; 4] = [
+    BatteryLevel::Normal,
+    BatteryLevel::Low,
+    BatteryLevel::Land,
+    BatteryLevel::Critical,
+];
+
+/// Filters the battery voltage, compensates the sag under load and tells when the pack runs
+/// low.
+///
+/// The voltage drops with the current the motors draw, so the reading plus the estimated sag
+/// is the voltage the pack would have at rest. It is low-pass filtered so a single noisy
+/// reading changes nothing. Every level has a threshold; the level goes down as soon as the
+/// voltage drops below one, and only goes back up when it recovered [`HYSTERESIS`] above it.
+#[derive(Debug, Clone, Copy)]
+pub struct BatteryMonitor {
+    /// Rested voltage in mV, `None` before the first reading.
+    voltage: Option<Wide>,
+    pub level: BatteryLevel,
+}
+
+impl BatteryMonitor {
+    pub fn new() -> Self {
+        BatteryMonitor {
+            voltage: None,
+            level: BatteryLevel::Normal,
+        }
+    }
+
+    /// Adds a reading taken while the motors ran at `motors`, in a loop running at `frequency`
+    /// Hz, with `min_battery` as the critical level. Returns the level once when it changes.
+    pub fn update(
+        &mut self,
+        reading: Millivolts,
+        motors: [u16; 4],
+        min_battery: Millivolts,
+        frequency: u64,
+    ) -> Option<BatteryLevel> {
+        // nothing connected, the board runs from usb
+        if reading.0 == 0 {
+            return None;
+        }
+        let load: Wide = motors
+            .iter()
+            .map(|speed| Wide::from_num(*speed) * Wide::from_num(*speed))
+            .sum();
+        let rested = Wide::from_num(reading.0) + load * SAG;
+        let voltage = match self.voltage {
+            Some(voltage) => {
+                let alpha = Wide::ONE / Wide::from_num(frequency.max(1)) / TIME_CONSTANT;
+                voltage + (rested - voltage) * alpha.min(Wide::ONE)
+            }
+            None => rested,
+        };
+        self.voltage = Some(voltage);
+
+        let level = self.classify(voltage, min_battery);
+        if level == self.level {
+            return None;
+        }
+        self.level = level;
+        Some(level)
+    }
+
+    fn classify(&self, voltage: Wide, Millivolts(min_battery): Millivolts) -> BatteryLevel {
+        let threshold = |index: usize| {
+            Wide::from_num(match LEVELS[index] {
+                BatteryLevel::Normal => u16::MAX,
+                BatteryLevel::Low => min_battery.saturating_add(LOW_MARGIN),
+                BatteryLevel::Land => min_battery.saturating_add(LAND_MARGIN),
+                BatteryLevel::Critical => min_battery,
+            })
+        };
+        let mut index = LEVELS.iter().position(|l| *l == self.level).unwrap();
+        while index + 1 < LEVELS.len() && voltage < threshold(index + 1) {
+            index += 1;
+        }
+        while index > 0 && voltage > threshold(index) + Wide::from_num(HYSTERESIS) {
+            index -= 1;
+        }
+        LEVELS[index]
+    }
+
+    /// The filtered voltage at rest, 0 before the first reading.
+    pub fn voltage(&self) -> Millivolts {
+        Millivolts(
+            self.voltage
+                .map_or(0, |voltage| voltage.round().saturating_to_num()),
+        )
+    }
+
+    /// The charge left in percent, estimated from the voltage at rest.
+    pub fn state_of_charge(&self) -> u8 {
+        let Millivolts(voltage) = self.voltage();
+        let (first, _) = CHARGE_CURVE[0];
+        if voltage <= first {
+            return 0;
+        }
+        for pair in CHARGE_CURVE.windows(2) {
+            let [(v0, c0), (v1, c1)] = [pair[0], pair[1]];
+            if voltage <= v1 {
+                let step = (c1 - c0) as u32 * (voltage - v0) as u32 / (v1 - v0) as u32;
+                return c0 + step as u8;
+            }
+        }
+        100
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const FREQUENCY: u64 = 150;
+    const MIN: Millivolts = Millivolts(9_100);
+
+    fn run(
+        monitor: &mut BatteryMonitor,
+        millivolts: u16,
+        motors: [u16; 4],
+        seconds: u64,
+    ) -> Vec<BatteryLevel> {
+        let mut changes = Vec::new();
+        for _ in 0..seconds * FREQUENCY {
+            if let Some(level) = monitor.update(Millivolts(millivolts), motors, MIN, FREQUENCY) {
+                changes.push(level);
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn a_single_low_reading_is_ignored() {
+        let mut monitor = BatteryMonitor::new();
+        run(&mut monitor, 12_000, [0; 4], 2);
+        assert_eq!(
+            monitor.update(Millivolts(8_000), [0; 4], MIN, FREQUENCY),
+            None
+        );
+        assert_eq!(run(&mut monitor, 12_000, [0; 4], 2), Vec::new());
+        assert_eq!(monitor.level, BatteryLevel::Normal);
+    }
+
+    #[test]
+    fn sag_under_load_is_compensated() {
+        let mut monitor = BatteryMonitor::new();
+        // 0.36 V of sag at 300 on every motor
+        assert_eq!(run(&mut monitor, 12_000 - 360, [300; 4], 5), Vec::new());
+        assert_eq!(monitor.voltage(), Millivolts(12_000));
+        assert_eq!(monitor.state_of_charge(), 80);
+    }
+
+    #[test]
+    fn levels_fire_once_in_order() {
+        let mut monitor = BatteryMonitor::new();
+        let mut changes = Vec::new();
+        for millivolts in (9_000..=11_500).rev().step_by(10) {
+            changes.extend(run(&mut monitor, millivolts, [0; 4], 1));
+        }
+        assert_eq!(
+            changes,
+            [
+                BatteryLevel::Low,
+                BatteryLevel::Land,
+                BatteryLevel::Critical
+            ]
+        );
+    }
+
+    #[test]
+    fn recovery_needs_the_hysteresis() {
+        let mut monitor = BatteryMonitor::new();
+        let low = MIN.0 + LOW_MARGIN;
+        run(&mut monitor, low - 50, [0; 4], 10);
+        assert_eq!(monitor.level, BatteryLevel::Low);
+        assert_eq!(run(&mut monitor, low + 100, [0; 4], 10), Vec::new());
+        assert_eq!(
+            run(&mut monitor, low + 300, [0; 4], 10),
+            [BatteryLevel::Normal]
+        );
+    }
+
+    #[test]
+    fn state_of_charge_follows_the_curve() {
+        let mut monitor = BatteryMonitor::new();
+        for (millivolts, charge) in [
+            (12_600, 100),
+            (11_550, 57),
+            (10_000, 0),
+            (12_900, 100),
+            (10_650, 7),
+        ] {
+            monitor.voltage = None;
+            monitor.update(Millivolts(millivolts), [0; 4], MIN, FREQUENCY);
+            assert_eq!(monitor.state_of_charge(), charge, "{millivolts}");
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use crate::altitude::Wide;
+use crate::mahony_filter::ACCEL_SCALE;
+use architecture::{
+    Accel, Attitude, CalibrationFailure, CalibrationResult, Frac, Radians, RadiansPerSecond,
+    SensorData, Velocity,
+};
+
+/// How long a calibration takes and how still the drone has to stand.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationSettings {
+    /// Number of readings, one per tick.
+    pub samples: u16,
+    /// Largest standard deviation of the rates that still counts as standing still, rad/s.
+    pub max_gyro_noise: Frac,
+    /// Largest standard deviation of the accelerations that still counts as standing still, g.
+    pub max_accel_noise: Frac,
+}
+
+impl CalibrationSettings {
+    pub fn new() -> Self {
+        CalibrationSettings {
+            samples: 100,
+            max_gyro_noise: Frac::from_num(0.05),
+            max_accel_noise: Frac::from_num(0.05),
+        }
+    }
+}
+
+/// Sum and sum of squares of one signal.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Statistics {
+    sum: Wide,
+    sum_of_squares: Wide,
+}
+
+impl Statistics {
+    pub(crate) fn new() -> Self {
+        Statistics {
+            sum: Wide::ZERO,
+            sum_of_squares: Wide::ZERO,
+        }
+    }
+
+    pub(crate) fn add(&mut self, x: Wide) {
+        self.sum = self.sum.saturating_add(x);
+        self.sum_of_squares = self.sum_of_squares.saturating_add(x.saturating_mul(x));
+    }
+
+    pub(crate) fn mean(&self, n: u16) -> Wide {
+        self.sum / Wide::from_num(n)
+    }
+
+    pub(crate) fn deviation(&self, n: u16) -> Wide {
+        let mean = self.mean(n);
+        let variance = self.sum_of_squares / Wide::from_num(n) - mean * mean;
+        sqrt(variance.max(Wide::ZERO))
+    }
+}
+
+/// Square root by Newton's method.
+fn sqrt(x: Wide) -> Wide {
+    if x == 0 {
+        return Wide::ZERO;
+    }
+    let mut root = x.max(Wide::ONE);
+    for _ in 0..32 {
+        root = (root + x / root) / 2;
+    }
+    root
+}
+
+/// Collects the readings of a calibration, one per tick, and checks the drone stood still.
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    settings: CalibrationSettings,
+    samples: u16,
+    /// yaw, pitch, roll
+    radius: [Statistics; 3],
+    /// yaw, pitch, roll
+    gyro: [Statistics; 3],
+    /// x, y, z in g
+    accel: [Statistics; 3],
+    /// Pa
+    pressure: u64,
+}
+
+impl Calibration {
+    pub fn new(settings: CalibrationSettings) -> Self {
+        Calibration {
+            settings: CalibrationSettings {
+                samples: settings.samples.max(1),
+                ..settings
+            },
+            samples: 0,
+            radius: [Statistics::new(); 3],
+            gyro: [Statistics::new(); 3],
+            accel: [Statistics::new(); 3],
+            pressure: 0,
+        }
+    }
+
+    /// Adds a reading taken without the old offsets, `pressure` in Pa.
+    pub fn add(&mut self, data: &SensorData, pressure: u32) {
+        let radius = [data.radius.yaw, data.radius.pitch, data.radius.roll].map(|angle| angle.0);
+        let gyro = [data.velocity.yaw, data.velocity.pitch, data.velocity.roll].map(|rate| rate.0);
+        let accel = [
+            data.acceleration.x,
+            data.acceleration.y,
+            data.acceleration.z,
+        ];
+        for i in 0..3 {
+            self.radius[i].add(Wide::from_num(radius[i]));
+            self.gyro[i].add(Wide::from_num(gyro[i]));
+            self.accel[i].add(Wide::from_num(accel[i]) / ACCEL_SCALE as i64);
+        }
+        self.pressure += pressure as u64;
+        self.samples += 1;
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.samples >= self.settings.samples
+    }
+
+    /// The offsets and noise of the readings so far, and the mean pressure in Pa.
+    pub fn result(&self) -> (CalibrationResult, u32) {
+        let n = self.samples.max(1);
+        let frac = |statistics: &Statistics| Frac::saturating_from_num(statistics.mean(n));
+        let counts = |g: Wide| (g * ACCEL_SCALE as i64).round().saturating_to_num::<i32>();
+        let gyro_noise = self.gyro.map(|gyro| gyro.deviation(n));
+        let accel_noise = self.accel.map(|accel| accel.deviation(n));
+
+        let max_gyro_noise = Wide::from_num(self.settings.max_gyro_noise);
+        let max_accel_noise = Wide::from_num(self.settings.max_accel_noise);
+        let failure = if gyro_noise.iter().any(|noise| *noise > max_gyro_noise) {
+            Some(CalibrationFailure::Rotating)
+        } else if accel_noise.iter().any(|noise| *noise > max_accel_noise) {
+            Some(CalibrationFailure::Shaking)
+        } else {
+            None
+        };
+
+        let result = CalibrationResult {
+            failure,
+            samples: self.samples,
+            attitude_offset: Attitude {
+                yaw: Radians(frac(&self.radius[0])),
+                pitch: Radians(frac(&self.radius[1])),
+                roll: Radians(frac(&self.radius[2])),
+            },
+            gyro_offset: Velocity {
+                yaw: RadiansPerSecond(frac(&self.gyro[0])),
+                pitch: RadiansPerSecond(frac(&self.gyro[1])),
+                roll: RadiansPerSecond(frac(&self.gyro[2])),
+            },
+            accel_offset: Accel {
+                x: counts(self.accel[0].mean(n)),
+                y: counts(self.accel[1].mean(n)),
+                z: counts(self.accel[2].mean(n)),
+            },
+            gyro_noise: Velocity {
+                yaw: RadiansPerSecond(Frac::saturating_from_num(gyro_noise[0])),
+                pitch: RadiansPerSecond(Frac::saturating_from_num(gyro_noise[1])),
+                roll: RadiansPerSecond(Frac::saturating_from_num(gyro_noise[2])),
+            },
+            accel_noise: Accel {
+                x: counts(accel_noise[0]),
+                y: counts(accel_noise[1]),
+                z: counts(accel_noise[2]),
+            },
+        };
+        (result, (self.pressure / n as u64) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    fn reading(pitch_rate: f32, z: i32) -> SensorData {
+        let mut data = SensorData::new();
+        data.velocity.pitch = RadiansPerSecond(Frac::from_num(pitch_rate));
+        data.velocity.yaw = RadiansPerSecond(Frac::from_num(0.02));
+        data.acceleration.z = z;
+        data
+    }
+
+    fn calibrate(readings: impl Iterator<Item = SensorData>) -> CalibrationResult {
+        let mut calibration = Calibration::new(CalibrationSettings::new());
+        for data in readings {
+            calibration.add(&data, 101_325);
+        }
+        assert!(calibration.is_done());
+        calibration.result().0
+    }
+
+    #[test]
+    fn still_drone_passes_with_the_mean_as_offset() {
+        // alternating noise of 0.01 rad/s and 20 counts
+        let result = calibrate((0..100).map(|i| {
+            let sign = if i % 2 == 0 { 1 } else { -1 };
+            reading(0.01 * sign as f32, 16384 + 20 * sign)
+        }));
+        assert_eq!(result.failure, None);
+        assert_eq!(result.samples, 100);
+        assert!((result.gyro_offset.yaw.0.to_num::<f32>() - 0.02).abs() < 1e-4);
+        assert!(result.gyro_offset.pitch.0.to_num::<f32>().abs() < 1e-4);
+        assert_eq!(result.accel_offset.z, 16384);
+        assert!((result.gyro_noise.pitch.0.to_num::<f32>() - 0.01).abs() < 1e-3);
+        assert!(result.gyro_noise.yaw.0.to_num::<f32>() < 1e-3);
+        assert_eq!(result.accel_noise.z, 20);
+    }
+
+    #[test]
+    fn turning_the_drone_fails() {
+        let result = calibrate((0..100).map(|i| reading(if i < 50 { 0.0 } else { 0.5 }, 16384)));
+        assert_eq!(result.failure, Some(CalibrationFailure::Rotating));
+    }
+
+    #[test]
+    fn lifting_the_drone_fails() {
+        let result = calibrate((0..100).map(|i| reading(0.0, if i < 50 { 16384 } else { 20000 })));
+        assert_eq!(result.failure, Some(CalibrationFailure::Shaking));
+    }
+
+    #[test]
+    fn square_root() {
+        for x in [0.0, 1e-4, 0.25, 2.0, 1e4] {
+            let root: f64 = sqrt(Wide::from_num(x)).to_num();
+            assert!((root * root - x).abs() < 1e-6 * x.max(1.0), "{x}");
+        }
+    }
+}
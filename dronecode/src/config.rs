@@ -0,0 +1,564 @@
+use crate::control::Controller;
+use crate::gyro_bias::GyroBias;
+use crate::hal::Hal;
+use crate::height_filter::HeightFilter;
+use crate::lowpassfilter::{FilterConfig, FilterSettings};
+use crate::sensor::Sensor;
+use alloc::rc::Rc;
+use architecture::{
+    crc16, Accel, AccelCorrection, Attitude, ConfigError, DroneConfig, FilterCutoffs, Frac, Limits,
+    Millivolts, SensorData, Velocity,
+};
+use core::cell::RefCell;
+use log::disk::DiskError;
+use log::Disk;
+
+/// Size of the flash chip of the quadrupel.
+pub const FLASH_SIZE: u32 = 128 * 1024;
+/// Smallest part of the flash that can be erased on its own.
+pub const SECTOR_SIZE: u32 = 4 * 1024;
+/// Size of the configuration area at the top of the flash, two sectors used in turn.
+const CONFIG_SIZE: u32 = 2 * SECTOR_SIZE;
+/// The flight log ends where the configuration area starts.
+pub const CONFIG_START: u32 = FLASH_SIZE - CONFIG_SIZE;
+/// Every save takes one slot.
+const SLOT_SIZE: usize = 256;
+const SLOTS_PER_SECTOR: u32 = SECTOR_SIZE / SLOT_SIZE as u32;
+const SLOTS: u32 = CONFIG_SIZE / SLOT_SIZE as u32;
+
+/// Highest speed the motors can be limited to.
+const MOTOR_LIMIT: u16 = 1000;
+
+const MAGIC: [u8; 2] = [0xc0, 0x4f];
+/// Changes whenever the layout of a record or of [`DroneConfig`] does, or the unit of one of
+/// its fields, older records are ignored.
+pub const CONFIG_VERSION: u8 = 4;
+/// Magic, version, the sequence number of the save and the length of the payload.
+const HEADER: usize = 9;
+/// CRC of the header and the payload, after the payload.
+const CRC: usize = 2;
+
+/// A [`Disk`] that can also erase a single sector.
+pub trait EraseSector: Disk {
+    /// Erases the [`SECTOR_SIZE`] bytes from `address` on, which must start a sector.
+    fn erase_sector(&mut self, address: u32) -> Result<(), DiskError>;
+
+    /// Like [`Self::erase_sector`], but returns while the flash is still erasing. Until
+    /// [`Self::is_busy`] is `false` the other operations wait for the erase to finish.
+    fn start_erase(&mut self, address: u32) -> Result<(), DiskError> {
+        self.erase_sector(address)
+    }
+
+    /// Whether an erase from [`Self::start_erase`] is still running.
+    fn is_busy(&mut self) -> Result<bool, DiskError> {
+        Ok(false)
+    }
+}
+
+/// Splits the flash into the flight log and the configuration area.
+///
+/// Each save takes the next free slot of one of the two sectors of the configuration area and
+/// loading takes the intact record saved last. Once a sector is full the other one is erased
+/// and used, so the records of the full one stay intact until the new sector holds one.
+pub fn partition<D: EraseSector>(disk: D) -> (LogArea<D>, ConfigStore<D>) {
+    let disk = Rc::new(RefCell::new(disk));
+    (
+        LogArea { disk: disk.clone() },
+        ConfigStore {
+            disk,
+            id: defaults().id,
+        },
+    )
+}
+
+/// The part of the flash below [`CONFIG_START`], for the flight log.
+pub struct LogArea<D: Disk> {
+    disk: Rc<RefCell<D>>,
+}
+
+impl<D: Disk> LogArea<D> {
+    fn check(address: u32, len: usize) -> Result<(), DiskError> {
+        if address as u64 + len as u64 > CONFIG_START as u64 {
+            Err(DiskError::OutOfSpace)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<D: EraseSector> Disk for LogArea<D> {
+    fn write_bytes(&mut self, address: u32, bytes: &[u8]) -> Result<(), DiskError> {
+        Self::check(address, bytes.len())?;
+        self.disk.borrow_mut().write_bytes(address, bytes)
+    }
+
+    fn read_bytes(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), DiskError> {
+        Self::check(address, buffer.len())?;
+        self.disk.borrow_mut().read_bytes(address, buffer)
+    }
+
+    /// Erases the sectors of the log, the configuration area is left alone.
+    fn erase_data(&mut self) -> Result<(), DiskError> {
+        let disk = &mut *self.disk.borrow_mut();
+        for address in (0..CONFIG_START).step_by(SECTOR_SIZE as usize) {
+            disk.erase_sector(address)?;
+        }
+        Ok(())
+    }
+
+    fn write_byte(&mut self, address: u32, byte: u8) -> Result<(), DiskError> {
+        Self::check(address, 1)?;
+        self.disk.borrow_mut().write_byte(address, byte)
+    }
+
+    fn read_byte(&mut self, address: u32) -> Result<u8, DiskError> {
+        Self::check(address, 1)?;
+        self.disk.borrow_mut().read_byte(address)
+    }
+}
+
+impl<D: EraseSector> EraseSector for LogArea<D> {
+    fn erase_sector(&mut self, address: u32) -> Result<(), DiskError> {
+        Self::check(address, SECTOR_SIZE as usize)?;
+        self.disk.borrow_mut().erase_sector(address)
+    }
+
+    fn start_erase(&mut self, address: u32) -> Result<(), DiskError> {
+        Self::check(address, SECTOR_SIZE as usize)?;
+        self.disk.borrow_mut().start_erase(address)
+    }
+
+    fn is_busy(&mut self) -> Result<bool, DiskError> {
+        self.disk.borrow_mut().is_busy()
+    }
+}
+
+/// Saves and loads the configuration in the area above [`CONFIG_START`].
+pub struct ConfigStore<D: Disk> {
+    disk: Rc<RefCell<D>>,
+    /// The identity of the drone, kept with its configuration.
+    pub id: u16,
+}
+
+impl<D: EraseSector> ConfigStore<D> {
+    /// The last configuration saved.
+    pub fn load(&mut self) -> Result<DroneConfig, ConfigError> {
+        let latest = scan(&mut *self.disk.borrow_mut()).map_err(|_| ConfigError::Disk)?;
+        let record = latest.ok_or(ConfigError::NotFound)?;
+        let payload = payload(&record.slot).ok_or(ConfigError::NotFound)?;
+        postcard::from_bytes(payload).map_err(|_| ConfigError::NotFound)
+    }
+
+    /// Writes `config` to the next free slot and reads it back.
+    pub fn save(&mut self, config: &DroneConfig) -> Result<(), ConfigError> {
+        let disk = &mut *self.disk.borrow_mut();
+        let latest = scan(disk).map_err(|_| ConfigError::Disk)?;
+        let mut slot = [0xff; SLOT_SIZE];
+        let length = postcard::to_slice(config, &mut slot[HEADER..SLOT_SIZE - CRC])
+            .map_err(|_| ConfigError::Disk)?
+            .len();
+        let sequence = latest.map_or(0, |record| record.sequence.wrapping_add(1));
+        slot[..2].copy_from_slice(&MAGIC);
+        slot[2] = CONFIG_VERSION;
+        slot[3..7].copy_from_slice(&sequence.to_le_bytes());
+        slot[7..HEADER].copy_from_slice(&(length as u16).to_le_bytes());
+        let end = HEADER + length;
+        let crc = crc16(&slot[..end]);
+        slot[end..end + CRC].copy_from_slice(&crc.to_le_bytes());
+
+        let index = next_slot(disk, latest.map_or(0, |record| record.index))
+            .map_err(|_| ConfigError::Disk)?;
+        disk.write_bytes(slot_address(index), &slot[..end + CRC])
+            .map_err(|_| ConfigError::Disk)?;
+        match read_slot(disk, index) {
+            Ok(Some(written)) if written[..end + CRC] == slot[..end + CRC] => Ok(()),
+            _ => Err(ConfigError::Disk),
+        }
+    }
+
+    /// The configuration in use.
+    pub fn current(&self, controller: &Controller, sensor: &Sensor) -> DroneConfig {
+        let cutoff = |config: FilterConfig| config.low_pass.unwrap_or(Frac::ZERO);
+        let gyro = sensor.filters.gyro[0].config();
+        DroneConfig {
+            id: self.id,
+            calibrated: sensor.calibrated,
+            attitude_offset: sensor.calibrate_offset.radius,
+            gyro_offset: sensor.calibrate_offset.velocity,
+            accel_offset: sensor.calibrate_offset.acceleration,
+            gravity_scale: sensor.gravity_scale,
+            accel_correction: sensor.accel_correction,
+            p: controller.p,
+            i: controller.i,
+            d: controller.d,
+            filters: FilterCutoffs {
+                gyro: cutoff(gyro),
+                gyro_notch: gyro.notch.map_or(Frac::ZERO, |notch| notch.center),
+                accel: cutoff(sensor.filters.accel[0].config()),
+                pressure: cutoff(sensor.filters.pressure.config()),
+                d_term: cutoff(controller.d_term_filter[0].config()),
+            },
+            limits: controller.limits,
+        }
+    }
+
+    /// Puts `config` in use.
+    ///
+    /// Restored offsets need a new ground reference for the height, which is taken when the
+    /// drone is armed.
+    pub fn apply<H: Hal>(
+        &mut self,
+        config: &DroneConfig,
+        controller: &mut Controller,
+        sensor: &mut Sensor,
+        hal: &mut H,
+    ) {
+        self.id = config.id;
+        sensor.calibrated = config.calibrated;
+        sensor.calibrate_offset = SensorData {
+            radius: config.attitude_offset,
+            velocity: config.gyro_offset,
+            acceleration: config.accel_offset,
+            ..SensorData::new()
+        };
+        sensor.gravity_scale = config.gravity_scale;
+        sensor.accel_correction = config.accel_correction;
+        sensor.height_filter = HeightFilter::new(Frac::from_num(1));
+        sensor.gyro_bias = GyroBias::new();
+        controller.set_parameters(config.p, config.i, config.d);
+
+        let filters = &config.filters;
+        for filter in &mut sensor.filters.gyro {
+            filter.set_low_pass(filters.gyro);
+            filter.set_notch(filters.gyro_notch);
+        }
+        for filter in &mut sensor.filters.accel {
+            filter.set_low_pass(filters.accel);
+        }
+        sensor.filters.pressure.set_low_pass(filters.pressure);
+        for filter in &mut controller.d_term_filter {
+            filter.set_low_pass(filters.d_term);
+        }
+
+        controller.limits = config.limits;
+        hal.set_motor_max(config.limits.motor_max);
+    }
+}
+
+/// Checks that every value of `config` is in range for a loop running at `frequency` Hz.
+pub fn validate(config: &DroneConfig, frequency: u64) -> Result<(), ConfigError> {
+    let nyquist = Frac::saturating_from_num(frequency / 2);
+    let filters = &config.filters;
+    let cutoffs = [
+        filters.gyro,
+        filters.gyro_notch,
+        filters.accel,
+        filters.pressure,
+        filters.d_term,
+    ];
+    // a cutoff of 0 turns the filter off
+    let filters_valid = cutoffs
+        .iter()
+        .all(|cutoff| *cutoff >= 0 && *cutoff < nyquist);
+    let scale_valid = !config.calibrated || config.gravity_scale > 0;
+    let motor_max_valid = (1..=MOTOR_LIMIT).contains(&config.limits.motor_max);
+    if filters_valid && scale_valid && motor_max_valid {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid)
+    }
+}
+
+/// The configuration of a drone that was never set up.
+pub fn defaults() -> DroneConfig {
+    let filters = FilterSettings::new();
+    let cutoff = |config: FilterConfig| config.low_pass.unwrap_or(Frac::ZERO);
+    DroneConfig {
+        id: 0,
+        calibrated: false,
+        attitude_offset: Attitude::new(),
+        gyro_offset: Velocity::new(),
+        accel_offset: Accel::new(),
+        gravity_scale: Frac::ZERO,
+        accel_correction: AccelCorrection::new(),
+        p: Frac::from_num(100),
+        i: Frac::from_num(10),
+        d: Frac::from_num(3000),
+        filters: FilterCutoffs {
+            gyro: cutoff(filters.gyro),
+            gyro_notch: Frac::ZERO,
+            accel: cutoff(filters.accel),
+            pressure: cutoff(filters.pressure),
+            d_term: cutoff(filters.d_term),
+        },
+        limits: Limits {
+            motor_max: 800,
+            min_battery: Millivolts(9_100),
+        },
+    }
+}
+
+fn slot_address(index: u32) -> u32 {
+    CONFIG_START + index * SLOT_SIZE as u32
+}
+
+/// An intact record and where it is.
+#[derive(Clone, Copy)]
+struct Record {
+    slot: [u8; SLOT_SIZE],
+    index: u32,
+    sequence: u32,
+}
+
+/// Reads slot `index`, `None` if nothing was written to it since the last erase.
+fn read_slot<D: Disk>(disk: &mut D, index: u32) -> Result<Option<[u8; SLOT_SIZE]>, DiskError> {
+    let mut slot = [0; SLOT_SIZE];
+    disk.read_bytes(slot_address(index), &mut slot)?;
+    if slot[..HEADER].iter().all(|byte| *byte == 0xff) {
+        Ok(None)
+    } else {
+        Ok(Some(slot))
+    }
+}
+
+/// The intact record saved last.
+///
+/// A record cut short by a reset fails its CRC and is skipped, the slot stays used.
+fn scan<D: Disk>(disk: &mut D) -> Result<Option<Record>, DiskError> {
+    let mut latest: Option<Record> = None;
+    for index in 0..SLOTS {
+        let Some(slot) = read_slot(disk, index)? else {
+            continue;
+        };
+        if payload(&slot).is_none() {
+            continue;
+        }
+        let sequence = u32::from_le_bytes([slot[3], slot[4], slot[5], slot[6]]);
+        if latest.map_or(true, |record| sequence > record.sequence) {
+            latest = Some(Record {
+                slot,
+                index,
+                sequence,
+            });
+        }
+    }
+    Ok(latest)
+}
+
+/// The slot to save to after the one at `index`: the next free slot of its sector, or the
+/// first of the other sector, which is erased for it.
+fn next_slot<D: EraseSector>(disk: &mut D, index: u32) -> Result<u32, DiskError> {
+    let sector = index / SLOTS_PER_SECTOR;
+    let first = sector * SLOTS_PER_SECTOR;
+    for index in first..first + SLOTS_PER_SECTOR {
+        if read_slot(disk, index)?.is_none() {
+            return Ok(index);
+        }
+    }
+    let other = (sector + 1) % (SLOTS / SLOTS_PER_SECTOR);
+    disk.erase_sector(CONFIG_START + other * SECTOR_SIZE)?;
+    Ok(other * SLOTS_PER_SECTOR)
+}
+
+/// The serialized configuration of an intact record of [`CONFIG_VERSION`].
+fn payload(slot: &[u8; SLOT_SIZE]) -> Option<&[u8]> {
+    if slot[..2] != MAGIC || slot[2] != CONFIG_VERSION {
+        return None;
+    }
+    let end = HEADER + u16::from_le_bytes([slot[7], slot[8]]) as usize;
+    if end + CRC > SLOT_SIZE {
+        return None;
+    }
+    let crc = u16::from_le_bytes([slot[end], slot[end + 1]]);
+    if crc16(&slot[..end]) == crc {
+        Some(&slot[HEADER..end])
+    } else {
+        None
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::hal::mock::RamDisk;
+    use architecture::RadiansPerSecond;
+
+    fn config(id: u16) -> DroneConfig {
+        DroneConfig {
+            id,
+            calibrated: true,
+            gyro_offset: Velocity {
+                yaw: RadiansPerSecond(Frac::from_num(0.02)),
+                pitch: RadiansPerSecond(Frac::from_num(-0.01)),
+                roll: RadiansPerSecond(Frac::from_num(0.005)),
+            },
+            accel_offset: Accel {
+                x: 120,
+                y: -80,
+                z: 16400,
+            },
+            gravity_scale: Frac::from_num(1672.3),
+            ..defaults()
+        }
+    }
+
+    fn flash() -> (LogArea<RamDisk>, ConfigStore<RamDisk>) {
+        partition(RamDisk::new(FLASH_SIZE as usize))
+    }
+
+    #[test]
+    fn the_last_save_is_loaded() {
+        let (_, mut store) = flash();
+        assert_eq!(store.load(), Err(ConfigError::NotFound));
+        store.save(&config(1)).unwrap();
+        store.save(&config(2)).unwrap();
+        assert_eq!(store.load(), Ok(config(2)));
+    }
+
+    #[test]
+    fn a_damaged_record_is_skipped() {
+        let (_, mut store) = flash();
+        store.save(&config(1)).unwrap();
+        store.save(&config(2)).unwrap();
+        // flip a bit in the payload of the second record
+        let byte = slot_address(1) as usize + HEADER + 3;
+        store.disk.borrow_mut().data[byte] ^= 0x01;
+        assert_eq!(store.load(), Ok(config(1)));
+        store.save(&config(3)).unwrap();
+        assert_eq!(store.load(), Ok(config(3)));
+    }
+
+    #[test]
+    fn other_versions_are_ignored() {
+        let (_, mut store) = flash();
+        store.save(&config(1)).unwrap();
+        let mut disk = store.disk.borrow_mut();
+        let mut slot = read_slot(&mut *disk, 0).ok().flatten().unwrap();
+        let end = HEADER + payload(&slot).unwrap().len();
+        slot[2] = CONFIG_VERSION + 1;
+        let crc = crc16(&slot[..end]);
+        slot[end..end + CRC].copy_from_slice(&crc.to_le_bytes());
+        assert!(disk
+            .write_bytes(slot_address(1), &slot[..end + CRC])
+            .is_ok());
+        drop(disk);
+        assert_eq!(store.load(), Ok(config(1)));
+    }
+
+    #[test]
+    fn saving_goes_on_in_the_other_sector() {
+        let (_, mut store) = flash();
+        for id in 0..5 * SLOTS as u16 {
+            store.save(&config(id)).unwrap();
+            assert_eq!(store.load(), Ok(config(id)));
+        }
+    }
+
+    #[test]
+    fn a_cut_off_switch_of_the_sector_keeps_the_last_save() {
+        let (_, mut store) = flash();
+        for id in 0..SLOTS_PER_SECTOR as u16 {
+            store.save(&config(id)).unwrap();
+        }
+        // the reset came after erasing the second sector, before writing to it
+        let mut disk = store.disk.borrow_mut();
+        assert!(disk.erase_sector(CONFIG_START + SECTOR_SIZE).is_ok());
+        drop(disk);
+        let last = config(SLOTS_PER_SECTOR as u16 - 1);
+        assert_eq!(store.load(), Ok(last));
+        store.save(&config(100)).unwrap();
+        assert_eq!(store.load(), Ok(config(100)));
+    }
+
+    #[test]
+    fn clearing_the_log_keeps_the_configuration() {
+        let (mut log, mut store) = flash();
+        assert!(log.write_bytes(0, &[1, 2, 3]).is_ok());
+        assert!(log.write_bytes(CONFIG_START - 1, &[4]).is_ok());
+        store.save(&config(1)).unwrap();
+        assert!(log.erase_data().is_ok());
+        assert_eq!(log.read_byte(0).ok(), Some(0xff));
+        assert_eq!(log.read_byte(CONFIG_START - 1).ok(), Some(0xff));
+        assert_eq!(store.load(), Ok(config(1)));
+    }
+
+    #[test]
+    fn out_of_range_values_are_invalid() {
+        assert_eq!(validate(&defaults(), 350), Ok(()));
+        assert_eq!(validate(&config(1), 350), Ok(()));
+        let mut filters = defaults().filters;
+        filters.d_term = Frac::from_num(175);
+        let invalid = [
+            DroneConfig {
+                gravity_scale: Frac::ZERO,
+                ..config(1)
+            },
+            DroneConfig {
+                gravity_scale: Frac::from_num(-1),
+                ..config(1)
+            },
+            DroneConfig {
+                limits: Limits {
+                    motor_max: 0,
+                    ..defaults().limits
+                },
+                ..defaults()
+            },
+            DroneConfig {
+                limits: Limits {
+                    motor_max: MOTOR_LIMIT + 1,
+                    ..defaults().limits
+                },
+                ..defaults()
+            },
+            DroneConfig {
+                filters,
+                ..defaults()
+            },
+        ];
+        for config in invalid {
+            assert_eq!(
+                validate(&config, 350),
+                Err(ConfigError::Invalid),
+                "{config:?}"
+            );
+        }
+        let mut filters = defaults().filters;
+        filters.gyro = Frac::from_num(-1);
+        let negative = DroneConfig {
+            filters,
+            ..defaults()
+        };
+        assert_eq!(validate(&negative, 350), Err(ConfigError::Invalid));
+    }
+
+    #[test]
+    fn the_log_stays_below_the_configuration() {
+        let (mut log, mut store) = flash();
+        store.save(&config(1)).unwrap();
+        assert!(matches!(
+            log.write_bytes(CONFIG_START - 2, &[0, 0, 0]),
+            Err(DiskError::OutOfSpace)
+        ));
+        assert!(matches!(
+            log.read_byte(CONFIG_START),
+            Err(DiskError::OutOfSpace)
+        ));
+        assert!(log.write_bytes(CONFIG_START - 2, &[0, 0]).is_ok());
+        assert_eq!(store.load(), Ok(config(1)));
+    }
+
+    #[test]
+    fn a_record_fits_in_a_slot() {
+        let mut buffer = [0; SLOT_SIZE - HEADER - CRC];
+        let extreme = DroneConfig {
+            id: u16::MAX,
+            gravity_scale: Frac::MIN,
+            p: Frac::MIN,
+            i: Frac::MIN,
+            d: Frac::MIN,
+            ..config(1)
+        };
+        assert!(postcard::to_slice(&extreme, &mut buffer).is_ok());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::hal::{Hal, Led::Red};
+use crate::lowpassfilter::{FilterSettings, SignalFilter};
 use crate::sensor::Sensor;
 use alloc::vec;
 use alloc::vec::Vec;
@@ -5,10 +7,17 @@ use architecture::Mode::{Calibrate, Panic, Safe};
 use architecture::YawPitchRoll;
 use architecture::*;
 use protocol::{DataLink, MessageLink};
-use tudelft_quadrupel::led::Led::Red;
-use tudelft_quadrupel::motor::{get_motors, set_motors};
 
-use tudelft_quadrupel::time::assembly_delay;
+/// Yaw rate in rad/s commanded per rad of heading error in [`YawMode::HeadingHold`].
+const HEADING_GAIN: Frac = Frac::lit("2");
+/// How far the heading setpoint may run ahead of the heading, rad.
+const MAX_HEADING_LEAD: Frac = Frac::lit("0.5");
+/// The yaw of a [`ControlRequest`] counts 16.4 per rad/s, the unit the yaw rate was in before
+/// the sensor data was kept in rad/s.
+const YAW_STICK_SCALE: Frac = Frac::lit("16.4");
+/// Scales the yaw rate error in rad/s for the gain `p`, which was tuned on rates 16.4 times as
+/// large.
+const YAW_RATE_GAIN: Frac = Frac::lit("16.4");
 
 pub struct Controller {
     pub p: Frac,
@@ -19,12 +28,22 @@ pub struct Controller {
     pub cache: Vec<ControllerInput>,
     pub mode: Mode,
     pub raw_option: bool,
+    pub estimator: AttitudeEstimator,
     pub frequency: u64,
+    /// Low-pass of the pitch and roll derivative
+    pub d_term_filter: [SignalFilter; 2],
+    pub limits: Limits,
+    pub yaw_mode: YawMode,
+    /// Heading held in [`YawMode::HeadingHold`], taken from the heading when `None`.
+    pub heading_setpoint: Option<Radians>,
+    /// The terms of the last attitude output, for the flight log.
+    pub terms: ControllerTerms,
 }
 
 impl Controller {
-    pub fn control_algo<T: protocol::Link>(
+    pub fn control_algo<T: protocol::Link, H: Hal>(
         &mut self,
+        hal: &mut H,
         data: &mut ControlRequest,
         sensor: &mut Sensor,
         link: &mut MessageLink<T>,
@@ -47,25 +66,36 @@ impl Controller {
                     let mut roll_derivative: Frac = Frac::from_num(0.);
                     if self.cache.len() > 1 {
                         // yaw_derivative = yaw_diff - self.cache[self.cache.len() - 2].ypr.yaw;
-                        pitch_derivative = pitch_diff - self.cache[self.cache.len() - 2].ypr.pitch;
-                        roll_derivative = roll_diff - self.cache[self.cache.len() - 2].ypr.roll;
+                        pitch_derivative =
+                            pitch_diff.saturating_sub(self.cache[self.cache.len() - 2].ypr.pitch);
+                        roll_derivative =
+                            roll_diff.saturating_sub(self.cache[self.cache.len() - 2].ypr.roll);
                     }
+                    pitch_derivative =
+                        self.d_term_filter[0].apply_frac(pitch_derivative, self.frequency);
+                    roll_derivative =
+                        self.d_term_filter[1].apply_frac(roll_derivative, self.frequency);
                     let mut yaw_acc: Frac = Frac::from_num(0.);
                     let mut pitch_acc: Frac = Frac::from_num(0.);
                     let mut roll_acc: Frac = Frac::from_num(0.);
                     for ypr in &self.cache {
-                        yaw_acc += ypr.ypr.yaw;
-                        pitch_acc += ypr.ypr.pitch;
-                        roll_acc += ypr.ypr.roll;
+                        yaw_acc = yaw_acc.saturating_add(ypr.ypr.yaw);
+                        pitch_acc = pitch_acc.saturating_add(ypr.ypr.pitch);
+                        roll_acc = roll_acc.saturating_add(ypr.ypr.roll);
                     }
 
-                    let yaw_output: i16 = (yaw_diff * self.p / Frac::from_num(4)).to_num();
-                    let pitch_output: i16 =
-                        (pitch_diff * self.p + pitch_derivative * self.d + pitch_acc * self.i)
-                            .to_num();
-                    let roll_output: i16 =
-                        (roll_diff * self.p + roll_derivative * self.d + roll_acc * self.i)
-                            .to_num();
+                    let yaw_p = yaw_diff
+                        .saturating_mul(self.p)
+                        .saturating_mul(YAW_RATE_GAIN)
+                        / Frac::from_num(4);
+                    let yaw_output: i16 = yaw_p.saturating_to_num();
+                    self.record_terms(
+                        yaw_p,
+                        [pitch_diff, pitch_derivative, pitch_acc],
+                        [roll_diff, roll_derivative, roll_acc],
+                    );
+                    let pitch_output = self.pid(pitch_diff, pitch_derivative, pitch_acc);
+                    let roll_output = self.pid(roll_diff, roll_derivative, roll_acc);
                     //motor 0: front motor 1 right motor 2 back motor 3 left
                     let max_c = 300;
                     let max = 800;
@@ -76,23 +106,23 @@ impl Controller {
                         }
                     } else {
                         output[3] = ((((0 as i16)
-                            .saturating_add(roll_output - yaw_output)
+                            .saturating_add(roll_output.saturating_sub(yaw_output))
                             .clamp(-max_c, max_c))
                             + lift)
                             .clamp(min, max)) as u16;
                         output[1] = ((((0 as i16)
-                            .saturating_sub(roll_output + yaw_output)
+                            .saturating_sub(roll_output.saturating_add(yaw_output))
                             .clamp(-max_c, max_c))
                             + lift)
                             .clamp(min, max)) as u16;
 
                         output[0] = ((((0 as i16)
-                            .saturating_add(pitch_output + yaw_output)
+                            .saturating_add(pitch_output.saturating_add(yaw_output))
                             .clamp(-max_c, max_c))
                             + lift)
                             .clamp(min, max)) as u16;
                         output[2] = ((((0 as i16)
-                            .saturating_sub(pitch_output - yaw_output)
+                            .saturating_sub(pitch_output.saturating_sub(yaw_output))
                             .clamp(-max_c, max_c))
                             + lift)
                             .clamp(min, max)) as u16;
@@ -102,34 +132,37 @@ impl Controller {
                     let min = 200;
                     let max = 400;
                     let scale: Frac = Frac::from_num(100);
+                    let radius = &data.radius;
+                    let command =
+                        |tilt: Frac| -> i16 { tilt.saturating_mul(scale).saturating_to_num() };
                     output[1] = (((lift) as i16)
-                        .saturating_sub(((data.radius.roll + data.radius.yaw) * scale).to_num())
+                        .saturating_sub(command(radius.roll.saturating_add(radius.yaw)))
                         .clamp(min, max)) as u16;
                     output[3] = (((lift) as i16)
-                        .saturating_add(((data.radius.roll - data.radius.yaw) * scale).to_num())
+                        .saturating_add(command(radius.roll.saturating_sub(radius.yaw)))
                         .clamp(min, max)) as u16;
 
                     output[2] = (((lift) as i16)
-                        .saturating_sub(((data.radius.pitch - data.radius.yaw) * scale).to_num())
+                        .saturating_sub(command(radius.pitch.saturating_sub(radius.yaw)))
                         .clamp(min, max)) as u16;
                     output[0] = (((lift) as i16)
-                        .saturating_add(((data.radius.pitch + data.radius.yaw) * scale).to_num())
+                        .saturating_add(command(radius.pitch.saturating_add(radius.yaw)))
                         .clamp(min, max)) as u16;
                 }
                 Mode::Panic => {
                     let temp = 2;
                     for _i in (0..200).rev() {
-                        let motors_val = get_motors();
-                        set_motors([
+                        let motors_val = hal.get_motors();
+                        hal.set_motors([
                             (motors_val[0].saturating_sub(temp)).clamp(0, 400),
                             (motors_val[1].saturating_sub(temp)).clamp(0, 400),
                             (motors_val[2].saturating_sub(temp)).clamp(0, 400),
                             (motors_val[3].saturating_sub(temp)).clamp(0, 400),
                         ]);
-                        assembly_delay(100000);
+                        hal.assembly_delay(100000);
                     }
                     self.mode = Mode::Safe;
-                    Red.off();
+                    hal.led_off(Red);
                     link.send(&Message::ChangeMode { mode: Mode::Safe })
                         .unwrap();
                     for i in 0..4 {
@@ -145,7 +178,9 @@ impl Controller {
                     for i in 0..4 {
                         output[i] = 0;
                     }
-                    sensor.calibrate();
+                    if let Some(result) = sensor.calibration_step(hal) {
+                        let _ = link.send(&Message::CalibrationResult { result });
+                    }
                 }
                 Mode::YawControl => {
                     let max_c = 200;
@@ -153,66 +188,82 @@ impl Controller {
                     let min = 200;
 
                     let yaw_diff = self.input.ypr.yaw;
-                    let yaw_output: i16 = (yaw_diff * self.p / Frac::from_num(8)).to_num();
+                    let yaw_output: i16 = (yaw_diff
+                        .saturating_mul(self.p)
+                        .saturating_mul(YAW_RATE_GAIN)
+                        / Frac::from_num(8))
+                    .saturating_to_num();
                     let scale: Frac = Frac::from_num(80);
+                    let command =
+                        |tilt: Frac| -> i16 { tilt.saturating_mul(scale).saturating_to_num() };
                     output[3] = ((((0 as i16)
-                        .saturating_add((data.radius.roll * scale).to_num::<i16>() - yaw_output)
+                        .saturating_add(command(data.radius.roll).saturating_sub(yaw_output))
                         .clamp(-max_c, max_c))
                         + lift)
                         .clamp(min, max)) as u16;
                     output[1] = ((((0 as i16)
-                        .saturating_sub((data.radius.roll * scale).to_num::<i16>() + yaw_output)
+                        .saturating_sub(command(data.radius.roll).saturating_add(yaw_output))
                         .clamp(-max_c, max_c))
                         + lift)
                         .clamp(min, max)) as u16;
 
                     output[0] = ((((0 as i16)
-                        .saturating_add((data.radius.pitch * scale).to_num::<i16>() + yaw_output)
+                        .saturating_add(command(data.radius.pitch).saturating_add(yaw_output))
                         .clamp(-max_c, max_c))
                         + lift)
                         .clamp(min, max)) as u16;
                     output[2] = ((((0 as i16)
-                        .saturating_sub((data.radius.pitch * scale).to_num::<i16>() - yaw_output)
+                        .saturating_sub(command(data.radius.pitch).saturating_sub(yaw_output))
                         .clamp(-max_c, max_c))
                         + lift)
                         .clamp(min, max)) as u16;
                 }
                 Mode::Height => {
-                    let height_diff = self.input.height;
+                    let height_diff = self.input.height.0;
                     let yaw_diff = self.input.ypr.yaw;
                     let pitch_diff = self.input.ypr.pitch;
                     let roll_diff = self.input.ypr.roll;
-                    let mut height_derivative = Frac::from_num(0);
+                    // the height error changes with the vertical speed, per tick like the others
+                    let height_derivative =
+                        (-sensor.data.v_z * Seconds::per_tick(self.frequency)).0;
                     // let mut yaw_derivative = Frac::from_num(0);
                     let mut pitch_derivative = Frac::from_num(0);
                     let mut roll_derivative = Frac::from_num(0);
                     if self.cache.len() > 1 {
-                        height_derivative = height_diff - self.cache[self.cache.len() - 2].height;
                         // yaw_derivative = yaw_diff - self.cache[self.cache.len() - 2].ypr.yaw;
-                        pitch_derivative = pitch_diff - self.cache[self.cache.len() - 2].ypr.pitch;
-                        roll_derivative = roll_diff - self.cache[self.cache.len() - 2].ypr.roll;
+                        pitch_derivative =
+                            pitch_diff.saturating_sub(self.cache[self.cache.len() - 2].ypr.pitch);
+                        roll_derivative =
+                            roll_diff.saturating_sub(self.cache[self.cache.len() - 2].ypr.roll);
                     }
+                    pitch_derivative =
+                        self.d_term_filter[0].apply_frac(pitch_derivative, self.frequency);
+                    roll_derivative =
+                        self.d_term_filter[1].apply_frac(roll_derivative, self.frequency);
                     let mut height_acc = Frac::from_num(0);
                     let mut yaw_acc = Frac::from_num(0);
                     let mut pitch_acc = Frac::from_num(0);
                     let mut roll_acc = Frac::from_num(0);
                     for ypr in &self.cache {
-                        height_acc += ypr.height;
-                        yaw_acc += ypr.ypr.yaw;
-                        pitch_acc += ypr.ypr.pitch;
-                        roll_acc += ypr.ypr.roll;
+                        height_acc = height_acc.saturating_add(ypr.height.0);
+                        yaw_acc = yaw_acc.saturating_add(ypr.ypr.yaw);
+                        pitch_acc = pitch_acc.saturating_add(ypr.ypr.pitch);
+                        roll_acc = roll_acc.saturating_add(ypr.ypr.roll);
                     }
 
-                    let yaw_output: i16 = (yaw_diff * self.p / Frac::from_num(8)).to_num();
-                    let height_output: i16 =
-                        (height_diff * self.p + height_derivative * self.d + height_acc * self.i)
-                            .to_num::<i16>();
-                    let pitch_output: i16 =
-                        (pitch_diff * self.p + pitch_derivative * self.d + pitch_acc * self.i)
-                            .to_num();
-                    let roll_output: i16 =
-                        (roll_diff * self.p + roll_derivative * self.d + roll_acc * self.i)
-                            .to_num();
+                    let yaw_p = yaw_diff
+                        .saturating_mul(self.p)
+                        .saturating_mul(YAW_RATE_GAIN)
+                        / Frac::from_num(8);
+                    let yaw_output: i16 = yaw_p.saturating_to_num();
+                    self.record_terms(
+                        yaw_p,
+                        [pitch_diff, pitch_derivative, pitch_acc],
+                        [roll_diff, roll_derivative, roll_acc],
+                    );
+                    let height_output = self.pid(height_diff, height_derivative, height_acc);
+                    let pitch_output = self.pid(pitch_diff, pitch_derivative, pitch_acc);
+                    let roll_output = self.pid(roll_diff, roll_derivative, roll_acc);
                     //motor 0: front motor 1 right motor 2 back motor 3 left
                     let max_c = 100;
                     let max = 600;
@@ -224,23 +275,39 @@ impl Controller {
                         }
                     } else {
                         output[3] = ((((0 as i16)
-                            .saturating_add(height_output + roll_output - yaw_output)
+                            .saturating_add(
+                                height_output
+                                    .saturating_add(roll_output)
+                                    .saturating_sub(yaw_output),
+                            )
                             .clamp(-max_c, max_c))
                             + hover_lift)
                             .clamp(min, max)) as u16;
                         output[1] = ((((0 as i16)
-                            .saturating_sub(roll_output + yaw_output - height_output)
+                            .saturating_sub(
+                                roll_output
+                                    .saturating_add(yaw_output)
+                                    .saturating_sub(height_output),
+                            )
                             .clamp(-max_c, max_c))
                             + hover_lift)
                             .clamp(min, max)) as u16;
 
                         output[0] = ((((0 as i16)
-                            .saturating_add(pitch_output + yaw_output + height_output)
+                            .saturating_add(
+                                pitch_output
+                                    .saturating_add(yaw_output)
+                                    .saturating_add(height_output),
+                            )
                             .clamp(-max_c, max_c))
                             + hover_lift)
                             .clamp(min, max)) as u16;
                         output[2] = ((((0 as i16)
-                            .saturating_sub(pitch_output - yaw_output - height_output)
+                            .saturating_sub(
+                                pitch_output
+                                    .saturating_sub(yaw_output)
+                                    .saturating_sub(height_output),
+                            )
                             .clamp(-max_c, max_c))
                             + hover_lift)
                             .clamp(min, max)) as u16;
@@ -258,30 +325,107 @@ impl Controller {
             d: Frac::from_num(0),
             input: ControllerInput {
                 ypr: YawPitchRoll::new(),
-                height: Frac::from_num(0),
+                height: Metres::ZERO,
             },
             output: [0, 0, 0, 0],
             cache: vec![],
             mode: Mode::Safe,
             raw_option: false,
+            estimator: AttitudeEstimator::Kalman,
             frequency: 150,
+            d_term_filter: [SignalFilter::new(FilterSettings::new().d_term); 2],
+            limits: Limits {
+                motor_max: 800,
+                min_battery: Millivolts(9_100),
+            },
+            yaw_mode: YawMode::Rate,
+            heading_setpoint: None,
+            terms: ControllerTerms::new(),
         }
     }
+    /// `p * error + d * derivative + i * integral` as a motor command, saturating like the
+    /// sensor data so an extreme reading gives the largest correction instead of a panic.
+    fn pid(&self, error: Frac, derivative: Frac, integral: Frac) -> i16 {
+        error
+            .saturating_mul(self.p)
+            .saturating_add(derivative.saturating_mul(self.d))
+            .saturating_add(integral.saturating_mul(self.i))
+            .saturating_to_num()
+    }
+    /// Keeps what the terms add to the outputs for the flight log. `yaw` is the yaw output, the
+    /// pitch and roll are the error, derivative and integral given to [`Self::pid`].
+    fn record_terms(&mut self, yaw: Frac, pitch: [Frac; 3], roll: [Frac; 3]) {
+        let term = |gain: Frac, index: usize, yaw: Frac| YawPitchRoll {
+            yaw,
+            pitch: pitch[index].saturating_mul(gain),
+            roll: roll[index].saturating_mul(gain),
+        };
+        let zero = Frac::from_num(0);
+        self.terms = ControllerTerms {
+            p: term(self.p, 0, yaw),
+            d: term(self.d, 1, zero),
+            i: term(self.i, 2, zero),
+        };
+    }
     pub fn set_parameters(&mut self, p: Frac, i: Frac, d: Frac) {
         self.p = p;
         self.i = i;
         self.d = d;
     }
+    /// Estimates the attitude from the raw readings, at the loop frequency and with the gains
+    /// of the raw mode. The tick frequency of the hal still has to follow.
+    pub fn use_raw_sensors(&mut self) {
+        self.raw_option = true;
+        self.frequency = 350;
+        self.set_parameters(
+            Frac::from_num(100),
+            Frac::from_num(10),
+            Frac::from_num(2300),
+        );
+    }
+    /// Moves the heading setpoint by the yaw rate of the `stick` over one tick and returns the
+    /// yaw rate that turns the drone from `heading` towards it.
+    pub fn hold_heading(&mut self, stick: RadiansPerSecond, heading: Radians) -> RadiansPerSecond {
+        let turn = stick * Seconds::per_tick(self.frequency);
+        let setpoint = self.heading_setpoint.unwrap_or(heading);
+        let setpoint = angle::wrap((setpoint + turn).0);
+        // a setpoint the drone cannot follow would wind up
+        let error =
+            angle::difference(setpoint, heading.0).clamp(-MAX_HEADING_LEAD, MAX_HEADING_LEAD);
+        self.heading_setpoint = Some(Radians(angle::wrap(heading.0 + error)));
+        stick.saturating_add(RadiansPerSecond(error * HEADING_GAIN))
+    }
     pub fn calculate_difference(&mut self, sensor: &Sensor, request: &ControlRequest) {
-        self.input.ypr = request.radius - sensor.data.radius;
-        self.input.ypr.yaw = request.radius.yaw - sensor.data.velocity.yaw;
-        self.input.height = Frac::from_num(request.throttle / 400) - sensor.data.height;
+        self.input.ypr = request.radius - YawPitchRoll::from(sensor.data.radius);
+        self.input.height = Metres(Frac::from_num(request.throttle / 400)) - sensor.data.height;
         //dead zone
         let pitch_roll_deadzone = 0.; //rad
-        let yaw_deadzone = 0.6; //rad/s
-        let height_deadzone = 0.1; // m
-        if self.input.ypr.yaw < yaw_deadzone && self.input.ypr.yaw > -yaw_deadzone {
-            self.input.ypr.yaw = Frac::from_num(0);
+        let yaw_deadzone = yaw_stick(Frac::lit("0.6"));
+        let height_deadzone = Metres(Frac::lit("0.1"));
+        if matches!(self.mode, Safe | Panic | Calibrate) {
+            // lock the heading again when taking off
+            self.heading_setpoint = None;
+        }
+        match self.yaw_mode {
+            YawMode::Rate => {
+                let error = yaw_stick(request.radius.yaw) - sensor.data.velocity.yaw;
+                self.input.ypr.yaw = if error.abs() < yaw_deadzone {
+                    Frac::from_num(0)
+                } else {
+                    error.0
+                };
+            }
+            YawMode::HeadingHold => {
+                // no deadzone on the error, the drift is what gets corrected
+                let stick = yaw_stick(request.radius.yaw);
+                let stick = if stick.abs() < yaw_deadzone {
+                    RadiansPerSecond::ZERO
+                } else {
+                    stick
+                };
+                let rate = self.hold_heading(stick, sensor.gyro_bias.heading());
+                self.input.ypr.yaw = (rate - sensor.data.velocity.yaw).0;
+            }
         }
         if self.input.ypr.pitch < pitch_roll_deadzone && self.input.ypr.pitch > -pitch_roll_deadzone
         {
@@ -294,10 +438,14 @@ impl Controller {
             self.input.ypr.roll = Frac::from_num(0);
         }
         if self.input.height < height_deadzone && self.input.height > -height_deadzone {
-            self.input.height = Frac::from_num(0);
+            self.input.height = Metres::ZERO;
         }
     }
 }
+/// The yaw rate commanded by the yaw of a [`ControlRequest`].
+fn yaw_stick(yaw: Frac) -> RadiansPerSecond {
+    RadiansPerSecond(yaw / YAW_STICK_SCALE)
+}
 pub fn enqueue<T>(v: &mut Vec<T>, value: T) {
     if v.len() < 10 {
         v.push(value)
@@ -352,3 +500,49 @@ pub fn enqueue<T>(v: &mut Vec<T>, value: T) {
 //
 //
 // }
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::hal::mock::MockHal;
+    use crate::hal::Uart;
+    use crate::test_data::extreme;
+
+    #[test]
+    fn extreme_readings_give_bounded_motor_commands() {
+        let mut hal = MockHal::new();
+        let mut link = MockHal::link();
+        let mut sensor = Sensor::new();
+        for mode in [
+            Mode::Manual,
+            Mode::FullControl,
+            Mode::Height,
+            Mode::YawControl,
+        ] {
+            for yaw_mode in [YawMode::Rate, YawMode::HeadingHold] {
+                let mut controller = Controller::new();
+                controller.set_parameters(Frac::MAX, Frac::MAX, Frac::MAX);
+                controller.mode = mode;
+                controller.yaw_mode = yaw_mode;
+                let mut request = ControlRequest {
+                    throttle: 4000,
+                    ..ControlRequest::new()
+                };
+                for n in 0..60 {
+                    sensor.data = extreme(n / 5 % 2 == 0);
+                    // the sticks at their ends as well
+                    request.radius = extreme(n / 3 % 2 == 0).radius.into();
+                    sensor.apply_filters(controller.frequency);
+                    controller.calculate_difference(&sensor, &request);
+                    enqueue(&mut controller.cache, controller.input.clone());
+                    let output =
+                        controller.control_algo(&mut hal, &mut request, &mut sensor, &mut link);
+                    assert!(
+                        output.iter().all(|speed| (150..=800).contains(speed)),
+                        "{mode:?} {yaw_mode:?}: {output:?}"
+                    );
+                }
+            }
+        }
+    }
+}
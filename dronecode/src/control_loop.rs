@@ -1,4 +1,10 @@
-use crate::funcdisk::FuncDisk;
+use crate::battery::BatteryMonitor;
+use crate::config::{self, ConfigStore, EraseSector, LogArea};
+use crate::flight_log::FlightLog;
+use crate::hal::{
+    Hal,
+    Led::{Blue, Green, Red},
+};
 use crate::liveness::Liveliness;
 use crate::message::handle_message;
 use crate::profiling::macros::{profiler_event, profiler_event_if};
@@ -11,21 +17,14 @@ use crate::{
 use alloc::borrow::ToOwned;
 use alloc::string::String;
 use architecture::Mode::Panic;
-use architecture::{ControlRequest, Frac, Message, Mode, ProfilerEvent, SensorDriver};
-use log::Logger;
-use protocol::{DataLink, FuncLink, MessageLink};
-use tudelft_quadrupel::motor::set_motor_max;
-use tudelft_quadrupel::{
-    led::Led::{Blue, Green, Red},
-    motor::set_motors,
-    time::{set_tick_frequency, wait_for_next_tick},
-    uart::{receive_bytes, send_bytes},
+use architecture::{
+    AttitudeEstimator, BatteryLevel, ControlRequest, Frac, LogChannel, LogEvent, LogRecord,
+    Message, Mode, ProfilerEvent, Seconds, SensorDriver, SensorKind,
 };
+use protocol::{DataLink, FuncLink, MessageLink};
 
-use crate::kalman_filter::KalmanFilter;
-use tudelft_quadrupel::flash::flash_write_byte;
-use tudelft_quadrupel::flash::flash_write_bytes;
-use tudelft_quadrupel::flash::{flash_chip_erase, flash_read_byte, flash_read_bytes};
+use crate::kalman_filter::{KalmanFilter, NoiseParameters};
+use crate::mahony_filter::MahonyFilter;
 
 pub fn print(link: &mut MessageLink<FuncLink>, m: String) {
     let mut msg = m.as_bytes();
@@ -43,111 +42,412 @@ pub fn print(link: &mut MessageLink<FuncLink>, m: String) {
     }
 }
 
-pub fn control_loop() -> ! {
-    // initialization
-    let link = FuncLink::from_func(send_bytes, receive_bytes);
-    let mut link = MessageLink::new(link);
-    let mut liveness = Liveliness::new(120);
-
-    let disk = FuncDisk::func(
-        flash_write_bytes,
-        flash_read_bytes,
-        flash_chip_erase,
-        flash_write_byte,
-        flash_read_byte,
-    );
-    let mut logger = Logger::new(disk);
-
-    let mut karman_filter = KalmanFilter::new(Frac::from_num(4), Frac::from_num(5000.));
-    let mut controller = Controller::new();
-    let mut sensor = Sensor::new();
-    let mut control_request = ControlRequest::new();
-    set_tick_frequency(controller.frequency);
-    controller.set_parameters(
-        Frac::from_num(100),
-        Frac::from_num(10),
-        Frac::from_num(3000),
-    );
-    set_motor_max(800);
-    // Check sensors
-    // Send sensor data
-    // Check and handle messages
-    // Control algorithm
-    for i in 0.. {
-        profiler_event!(link, ProfilerEvent::MainLoopStart);
-        profiler_event_if!(
-            controller.mode == Mode::FullControl,
+/// How fast a forced landing takes the throttle down, per second.
+const LANDING_RATE: Frac = Frac::lit("500");
+
+/// Everything the flight code keeps between ticks.
+///
+/// [`control_loop`] runs this forever on the drone, the simulator steps it one tick at a time.
+pub struct ControlLoop<H: Hal, D: EraseSector> {
+    pub hal: H,
+    pub link: MessageLink<FuncLink>,
+    pub liveness: Liveliness,
+    pub logger: FlightLog<LogArea<D>>,
+    pub config: ConfigStore<D>,
+    pub karman_filter: KalmanFilter,
+    pub mahony_filter: MahonyFilter,
+    pub controller: Controller,
+    pub sensor: Sensor,
+    pub control_request: ControlRequest,
+    pub battery: BatteryMonitor,
+    /// Highest throttle while the battery forces a landing.
+    pub landing_throttle: Option<Frac>,
+    pub tick_count: u64,
+}
+
+impl<H: Hal, D: EraseSector> ControlLoop<H, D> {
+    pub fn new(mut hal: H, disk: D) -> Self {
+        let link = H::link();
+        let liveness = Liveliness::new(120);
+        let (log_area, mut config) = config::partition(disk);
+        let logger = FlightLog::new(log_area, config::CONFIG_START);
+
+        let karman_filter = KalmanFilter::new(NoiseParameters::quadrupel());
+        let mahony_filter = MahonyFilter::new(Frac::from_num(1), Frac::from_num(0.05));
+        let mut controller = Controller::new();
+        let mut sensor = Sensor::new();
+        let control_request = ControlRequest::new();
+        hal.set_tick_frequency(controller.frequency);
+        let stored = config
+            .load()
+            .ok()
+            .filter(|stored| config::validate(stored, controller.frequency).is_ok())
+            .unwrap_or_else(config::defaults);
+        config.apply(&stored, &mut controller, &mut sensor, &mut hal);
+        ControlLoop {
+            hal,
             link,
+            liveness,
+            logger,
+            config,
+            karman_filter,
+            mahony_filter,
+            controller,
+            sensor,
+            control_request,
+            battery: BatteryMonitor::new(),
+            landing_throttle: None,
+            tick_count: 0,
+        }
+    }
+
+    /// Runs one iteration of the loop, without waiting for the next tick.
+    pub fn tick(&mut self) {
+        // Check sensors
+        // Send sensor data
+        // Check and handle messages
+        // Control algorithm
+        let i = self.tick_count;
+        self.tick_count += 1;
+        let mode = self.controller.mode;
+        profiler_event!(self.link, self.hal, ProfilerEvent::MainLoopStart);
+        profiler_event_if!(
+            self.controller.mode == Mode::FullControl,
+            self.link,
+            self.hal,
             ProfilerEvent::MainLoopFullControlStart
         );
         // let dt = now.duration_since(last);
-        karman_filter.integration_constant = Frac::from_num(1.0 / controller.frequency as f32);
-        match liveness.tick() {
+        let dt = Seconds::per_tick(self.controller.frequency);
+        self.karman_filter.integration_constant = dt.0;
+        self.mahony_filter.integration_constant = self.karman_filter.integration_constant;
+        match self.liveness.tick() {
             Some(_) => {
-                if controller.mode != Mode::Safe {
-                    controller.mode = Mode::Panic;
+                if self.controller.mode != Mode::Safe {
+                    self.controller.mode = Mode::Panic;
 
-                    Red.on();
+                    self.hal.led_on(Red);
                 }
             }
             None => (),
         }
 
-        sensor.get_values(true, controller.raw_option);
-        if logger.get_enabled() {
-            logger.append(&sensor.data).unwrap();
+        self.sensor
+            .get_values(&mut self.hal, true, self.controller.raw_option);
+        self.check_sensor_health();
+        if self.controller.mode == Mode::Safe || self.controller.mode == Mode::Calibrate {
+            // the six position calibration needs the motors off
+            self.sensor.accel_calibration_step(&mut self.link);
         }
-        if sensor.calibrated {
-            enqueue(&mut sensor.fir_cache, sensor.data);
-            if sensor.fir_cache.len() > 3 {
-                //  sensor.filter_FIR(Frac::from_num(0.2),Frac::from_num(0.2),Frac::from_num(0.6))
-            }
-            sensor.filter_ewma(Frac::from_num(0.1), 0.015);
-            sensor.calculate_height(Frac::from_num(1.0 / controller.frequency as f32));
-            if controller.raw_option {
-                karman_filter.fusion_algorithm(&mut sensor);
+        if self.sensor.calibrated {
+            self.sensor.compensate_gyro_bias(
+                self.controller.mode == Mode::Safe,
+                self.controller.frequency,
+            );
+            self.sensor.apply_fir();
+            self.sensor.apply_filters(self.controller.frequency);
+            self.sensor.calculate_height(dt);
+            if self.controller.raw_option {
+                match self.controller.estimator {
+                    AttitudeEstimator::Kalman => {
+                        self.karman_filter.fusion_algorithm(&mut self.sensor)
+                    }
+                    AttitudeEstimator::Mahony => {
+                        self.mahony_filter.fusion_algorithm(&mut self.sensor)
+                    }
+                }
             }
         }
-        if sensor.data.bat != 0 && sensor.data.bat <= 910 {
-            // 9.1 V minimum safe level
-            controller.mode = Panic;
-            // change_mode(&mut controller, Panic, &mut link); // New function
-            let message = Message::ChangeMode { mode: Panic };
-            link.send(&message).unwrap();
-            print(&mut link, "Battery P".to_owned());
+        if let Some(level) = self.battery.update(
+            self.sensor.data.bat,
+            self.sensor.data.motor_speeds,
+            self.controller.limits.min_battery,
+            self.controller.frequency,
+        ) {
+            self.send_battery_status();
+            self.log_event(LogEvent::Battery(level));
+            match level {
+                BatteryLevel::Normal => (),
+                BatteryLevel::Low => print(&mut self.link, "Battery low".to_owned()),
+                BatteryLevel::Land => self.land(),
+                BatteryLevel::Critical => {
+                    print(&mut self.link, "Battery P".to_owned());
+                    self.panic();
+                }
+            }
         }
 
         if i % 20 == 0 {
-            let _ = Blue.toggle();
+            self.hal.led_toggle(Blue);
+        }
+        if i % 40 == 0 || (!self.controller.raw_option && i % 20 == 0) {
+            self.sensor.send_data(&mut self.link);
         }
-        if i % 40 == 0 || (!controller.raw_option && i % 20 == 0) {
-            sensor.send_data(&mut link);
+        if i % 150 == 75 {
+            self.send_battery_status();
+        }
+        if i % 40 == 10 {
+            let _ = self.link.send(&Message::GyroBias {
+                bias: self.sensor.gyro_bias.bias(),
+                heading: self.sensor.gyro_bias.heading(),
+            });
+        }
+        if self.controller.raw_option
+            && self.controller.estimator == AttitudeEstimator::Kalman
+            && i % 40 == 20
+        {
+            let bias = self.sensor.gyro_bias.bias();
+            let _ = self.link.send(&self.karman_filter.state_message(bias));
         }
         handle_message(
-            &mut liveness,
-            &mut link,
-            &mut logger,
-            &mut controller,
-            &mut control_request,
-            &mut sensor,
+            &mut self.hal,
+            &mut self.liveness,
+            &mut self.link,
+            &mut self.logger,
+            &mut self.config,
+            &mut self.controller,
+            &mut self.control_request,
+            &mut self.sensor,
+            self.battery.level,
+            i as u32,
         );
 
-        controller.calculate_difference(&mut sensor, &mut control_request);
-        enqueue(&mut controller.cache, controller.input.clone());
-        if controller.raw_option == true {
-            Green.on();
+        if self.controller.mode == Mode::Safe {
+            self.landing_throttle = None;
+            if let Ok(Some(chunk)) = self.logger.next_chunk() {
+                let _ = self.link.send(&Message::LogChunk { chunk });
+            }
+            if let Ok(Some(info)) = self.logger.next_session() {
+                let _ = self.link.send(&Message::LogSession { info });
+            }
+        } else if self.battery.level == BatteryLevel::Land && self.landing_throttle.is_none() {
+            // the level is only reported once, armed again the drone still has to land
+            self.land();
+        }
+        self.descend();
+        self.controller
+            .calculate_difference(&mut self.sensor, &mut self.control_request);
+        enqueue(&mut self.controller.cache, self.controller.input.clone());
+        if self.controller.raw_option == true {
+            self.hal.led_on(Green);
         } else {
-            Green.off();
+            self.hal.led_off(Green);
+        }
+        let motors = self.controller.control_algo(
+            &mut self.hal,
+            &mut self.control_request,
+            &mut self.sensor,
+            &mut self.link,
+        );
+        self.hal.set_motors(motors);
+        if self.controller.mode != mode {
+            let armed = !matches!(
+                self.controller.mode,
+                Mode::Safe | Mode::Panic | Mode::Calibrate
+            );
+            if mode == Mode::Safe && armed {
+                let config = self.config.current(&self.controller, &self.sensor);
+                let arm = self.logger.arm(config, self.controller.frequency, i as u32);
+                if arm.is_err() {
+                    self.stop_logging();
+                }
+            }
+            self.log_event(LogEvent::Mode(self.controller.mode));
         }
-        set_motors(controller.control_algo(&mut control_request, &mut sensor, &mut link));
-        profiler_event!(link, ProfilerEvent::MainLoopStop);
+        self.log_channels(i as u32, motors);
+        profiler_event!(self.link, self.hal, ProfilerEvent::MainLoopStop);
         profiler_event_if!(
-            controller.mode == Mode::FullControl,
-            link,
+            self.controller.mode == Mode::FullControl,
+            self.link,
+            self.hal,
             ProfilerEvent::MainLoopFullControlStop
         );
+    }
 
-        wait_for_next_tick();
+    /// Reports the sensors whose health changed and stops relying on the ones that failed.
+    fn check_sensor_health(&mut self) {
+        while let Some((sensor, health)) = self.sensor.health.take_change() {
+            let _ = self.link.send(&Message::SensorHealth {
+                sensor,
+                state: health.state,
+                fault: health.fault,
+            });
+            self.log_event(LogEvent::SensorHealth {
+                sensor,
+                state: health.state,
+            });
+            if !health.is_failed() {
+                continue;
+            }
+            match sensor {
+                SensorKind::Dmp => self.fall_back_to_raw(),
+                SensorKind::Imu => self.panic(),
+                SensorKind::Barometer => {
+                    if self.controller.mode == Mode::Height {
+                        self.panic();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Estimates the attitude from the raw readings instead of the DMP. The estimator has not
+    /// run so far, it takes over the last attitude of the DMP.
+    fn fall_back_to_raw(&mut self) {
+        if self.controller.raw_option {
+            return;
+        }
+        let attitude = self.sensor.dmp_radius;
+        match self.controller.estimator {
+            AttitudeEstimator::Kalman => self.karman_filter.seed(attitude),
+            AttitudeEstimator::Mahony => self.mahony_filter.seed(attitude),
+        }
+        self.controller.use_raw_sensors();
+        self.hal.set_tick_frequency(self.controller.frequency);
+    }
+
+    /// Appends the channels of the flight log due at `tick`.
+    fn log_channels(&mut self, tick: u32, motors: [u16; 4]) {
+        let data = &self.sensor.data;
+        let (accel, gyro) = self.sensor.raw_imu;
+        let request = &self.control_request;
+        let records = [
+            (LogChannel::Attitude, LogRecord::Attitude(data.radius)),
+            (LogChannel::Rates, LogRecord::Rates(data.velocity)),
+            (
+                LogChannel::RawImu,
+                LogRecord::RawImu {
+                    accel: [accel.x, accel.y, accel.z],
+                    gyro: [gyro.x, gyro.y, gyro.z],
+                },
+            ),
+            (
+                LogChannel::Barometer,
+                LogRecord::Barometer {
+                    pressure: data.pressure,
+                    height: data.height,
+                    v_z: data.v_z,
+                },
+            ),
+            (
+                LogChannel::Battery,
+                LogRecord::Battery(self.battery.voltage()),
+            ),
+            (LogChannel::Motors, LogRecord::Motors(motors)),
+            (
+                LogChannel::ControlRequest,
+                LogRecord::ControlRequest {
+                    radius: request.radius,
+                    throttle: request.throttle,
+                },
+            ),
+            (
+                LogChannel::ControllerTerms,
+                LogRecord::ControllerTerms(self.controller.terms),
+            ),
+        ];
+        for (channel, record) in records {
+            if self.logger.is_due(channel, tick) {
+                self.log(tick, &record);
+            }
+        }
+    }
+
+    /// Appends `event` to the flight log at the current tick.
+    fn log_event(&mut self, event: LogEvent) {
+        let tick = self.tick_count.wrapping_sub(1) as u32;
+        if self.logger.is_due(LogChannel::Events, tick) {
+            self.log(tick, &LogRecord::Event(event));
+        }
+    }
+
+    /// Appends `record` to the flight log, and stops logging if the flash fails.
+    fn log(&mut self, tick: u32, record: &LogRecord) {
+        if self.logger.append(tick, record).is_err() {
+            self.stop_logging();
+        }
+    }
+
+    fn stop_logging(&mut self) {
+        self.logger.set_enabled(false);
+        print(&mut self.link, "Log stopped".to_owned());
+    }
+
+    /// Takes the throttle down slowly until the drone is on the ground.
+    fn land(&mut self) {
+        if !matches!(
+            self.controller.mode,
+            Mode::Safe | Mode::Panic | Mode::Calibrate
+        ) {
+            self.landing_throttle = Some(Frac::from_num(self.control_request.throttle));
+        }
+    }
+
+    /// Takes the throttle of a landing down by one tick.
+    fn descend(&mut self) {
+        if let Some(throttle) = &mut self.landing_throttle {
+            // the pilot can still steer, but not climb
+            let step = LANDING_RATE * Seconds::per_tick(self.controller.frequency).0;
+            *throttle = throttle.saturating_sub(step);
+            self.control_request.throttle = self.control_request.throttle.min(throttle.to_num());
+        }
+    }
+
+    fn send_battery_status(&mut self) {
+        let _ = self.link.send(&Message::BatteryStatus {
+            voltage: self.battery.voltage(),
+            charge: self.battery.state_of_charge(),
+            level: self.battery.level,
+        });
+    }
+
+    /// Starts the controlled descent of panic mode, unless the drone is on the ground already.
+    fn panic(&mut self) {
+        if self.controller.mode != Mode::Safe && self.controller.mode != Panic {
+            self.controller.mode = Panic;
+            self.hal.led_on(Red);
+            let _ = self.link.send(&Message::ChangeMode { mode: Panic });
+        }
+    }
+}
+
+pub fn control_loop<H: Hal, D: EraseSector>(hal: H, disk: D) -> ! {
+    // initialization
+    let mut control_loop = ControlLoop::new(hal, disk);
+    loop {
+        control_loop.tick();
+        control_loop.hal.wait_for_next_tick();
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::config::FLASH_SIZE;
+    use crate::hal::mock::{MockHal, RamDisk};
+
+    #[test]
+    fn a_forced_landing_takes_as_long_at_any_loop_rate() {
+        for frequency in [150, 350] {
+            let mut control_loop =
+                ControlLoop::new(MockHal::new(), RamDisk::new(FLASH_SIZE as usize));
+            control_loop.controller.mode = Mode::FullControl;
+            control_loop.controller.frequency = frequency;
+            control_loop.control_request.throttle = 3000;
+            control_loop.land();
+            let mut ticks = 0;
+            loop {
+                // the pilot keeps the stick where it was
+                control_loop.control_request.throttle = 3000;
+                control_loop.descend();
+                ticks += 1;
+                if control_loop.control_request.throttle <= 0 {
+                    break;
+                }
+            }
+            // 3000 at 500 per second
+            let seconds = ticks as f64 / frequency as f64;
+            assert!((seconds - 6.0).abs() < 0.05, "{frequency} Hz: {seconds} s");
+        }
     }
-    unreachable!();
 }
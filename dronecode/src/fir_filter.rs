@@ -0,0 +1,338 @@
+use crate::lowpassfilter::{omega, sin_cos, Coefficient};
+use architecture::{Frac, Pascal, SensorData};
+
+/// Hamming window `0.54 - 0.46 cos(2 pi n / (N - 1))`.
+const HAMMING_A: Coefficient = Coefficient::lit("0.54");
+const HAMMING_B: Coefficient = Coefficient::lit("0.46");
+
+/// Finite impulse response filter `y = c[0] x + c[1] x[-1] + ... + c[N - 1] x[-(N - 1)]`.
+///
+/// The coefficients can be changed at any time, the length is fixed so nothing is allocated.
+#[derive(Debug, Clone, Copy)]
+pub struct Fir<const N: usize> {
+    coefficients: [Coefficient; N],
+    /// The last `N` inputs, `newest` is the index of the last one.
+    history: [Coefficient; N],
+    newest: usize,
+}
+
+impl<const N: usize> Fir<N> {
+    pub fn new(coefficients: [Coefficient; N]) -> Self {
+        Fir {
+            coefficients,
+            history: [Coefficient::ZERO; N],
+            newest: 0,
+        }
+    }
+
+    pub fn coefficients(&self) -> &[Coefficient; N] {
+        &self.coefficients
+    }
+
+    pub fn set_coefficients(&mut self, coefficients: [Coefficient; N]) {
+        self.coefficients = coefficients;
+    }
+
+    /// Fills the history with `x`, as if it had been the input for `N` samples.
+    pub fn prime(&mut self, x: Coefficient) {
+        self.history = [x; N];
+    }
+
+    pub fn apply(&mut self, x: Coefficient) -> Coefficient {
+        self.newest = (self.newest + 1) % N;
+        self.history[self.newest] = x;
+        let mut y = Coefficient::ZERO;
+        for (k, c) in self.coefficients.iter().enumerate() {
+            y = y.saturating_add(c.saturating_mul(self.history[(self.newest + N - k) % N]));
+        }
+        y
+    }
+
+    pub fn apply_frac(&mut self, x: Frac) -> Frac {
+        Frac::saturating_from_num(self.apply(Coefficient::from_num(x)))
+    }
+}
+
+/// `N` equal taps adding up to one.
+pub fn moving_average<const N: usize>() -> [Coefficient; N] {
+    [Coefficient::ONE / N as i64; N]
+}
+
+/// Low-pass with `cutoff` Hz at `sample_rate` Hz: a sinc cut to `N` taps by a Hamming window,
+/// scaled to a gain of one at DC.
+///
+/// More taps give a steeper transition, the delay is `(N - 1) / 2` samples.
+pub fn windowed_sinc<const N: usize>(cutoff: Frac, sample_rate: u64) -> [Coefficient; N] {
+    let omega = omega(cutoff, sample_rate);
+    let middle = Coefficient::from_num(N - 1) / 2;
+    let mut taps = [Coefficient::ZERO; N];
+    let mut sum = Coefficient::ZERO;
+    for (n, tap) in taps.iter_mut().enumerate() {
+        let k = Coefficient::from_num(n) - middle;
+        let sinc = if k == 0 {
+            omega / Coefficient::PI
+        } else {
+            sin_cos(omega * k).0 / (Coefficient::PI * k)
+        };
+        let window = if N > 1 {
+            let (_, cos) = sin_cos(Coefficient::TAU * Coefficient::from_num(n) / (N as i64 - 1));
+            HAMMING_A - HAMMING_B * cos
+        } else {
+            Coefficient::ONE
+        };
+        *tap = sinc * window;
+        sum += *tap;
+    }
+    for tap in &mut taps {
+        *tap /= sum;
+    }
+    taps
+}
+
+/// The same FIR filter on every component of the rates, attitude, accelerations and pressure.
+#[derive(Debug, Clone, Copy)]
+pub struct SensorFir<const N: usize> {
+    /// yaw, pitch, roll
+    velocity: [Fir<N>; 3],
+    /// yaw, pitch, roll
+    radius: [Fir<N>; 3],
+    /// x, y, z
+    acceleration: [Fir<N>; 3],
+    pressure: Fir<N>,
+}
+
+impl<const N: usize> SensorFir<N> {
+    pub fn new(coefficients: [Coefficient; N]) -> Self {
+        let fir = Fir::new(coefficients);
+        SensorFir {
+            velocity: [fir; 3],
+            radius: [fir; 3],
+            acceleration: [fir; 3],
+            pressure: fir,
+        }
+    }
+
+    pub fn set_coefficients(&mut self, coefficients: [Coefficient; N]) {
+        for fir in self
+            .velocity
+            .iter_mut()
+            .chain(&mut self.radius)
+            .chain(&mut self.acceleration)
+        {
+            fir.set_coefficients(coefficients);
+        }
+        self.pressure.set_coefficients(coefficients);
+    }
+
+    /// Fills the history with `data`, so a filter started in flight does not pull the readings
+    /// towards zero.
+    pub fn prime(&mut self, data: &SensorData) {
+        let velocity = [data.velocity.yaw, data.velocity.pitch, data.velocity.roll];
+        let radius = [data.radius.yaw, data.radius.pitch, data.radius.roll];
+        for (fir, value) in self.velocity.iter_mut().zip(velocity) {
+            fir.prime(Coefficient::from_num(value.0));
+        }
+        for (fir, value) in self.radius.iter_mut().zip(radius) {
+            fir.prime(Coefficient::from_num(value.0));
+        }
+        let acceleration = [
+            data.acceleration.x,
+            data.acceleration.y,
+            data.acceleration.z,
+        ];
+        for (fir, value) in self.acceleration.iter_mut().zip(acceleration) {
+            fir.prime(Coefficient::from_num(value));
+        }
+        self.pressure.prime(Coefficient::from_num(data.pressure.0));
+    }
+
+    pub fn apply(&mut self, data: &mut SensorData) {
+        let velocity = &mut data.velocity;
+        velocity.yaw.0 = self.velocity[0].apply_frac(velocity.yaw.0);
+        velocity.pitch.0 = self.velocity[1].apply_frac(velocity.pitch.0);
+        velocity.roll.0 = self.velocity[2].apply_frac(velocity.roll.0);
+
+        let radius = &mut data.radius;
+        radius.yaw.0 = self.radius[0].apply_frac(radius.yaw.0);
+        radius.pitch.0 = self.radius[1].apply_frac(radius.pitch.0);
+        radius.roll.0 = self.radius[2].apply_frac(radius.roll.0);
+
+        let acceleration = &mut data.acceleration;
+        for (fir, value) in self.acceleration.iter_mut().zip([
+            &mut acceleration.x,
+            &mut acceleration.y,
+            &mut acceleration.z,
+        ]) {
+            *value = fir
+                .apply(Coefficient::from_num(*value))
+                .saturating_round()
+                .saturating_to_num();
+        }
+
+        data.pressure = Pascal(
+            self.pressure
+                .apply(Coefficient::from_num(data.pressure.0))
+                .saturating_to_num(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::test_data::extreme;
+    use architecture::{Pressure, Radians, RadiansPerSecond};
+    use std::f64::consts::PI;
+    use std::vec::Vec;
+
+    fn to_f64<const N: usize>(taps: &[Coefficient; N]) -> Vec<f64> {
+        taps.iter().map(|tap| tap.to_num()).collect()
+    }
+
+    /// The filter computed directly in floating point.
+    fn reference(taps: &[f64], input: &[f64]) -> Vec<f64> {
+        (0..input.len())
+            .map(|i| {
+                taps.iter()
+                    .enumerate()
+                    .filter(|(k, _)| *k <= i)
+                    .map(|(k, tap)| tap * input[i - k])
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Windowed sinc designed in floating point.
+    fn reference_sinc(n: usize, cutoff: f64, sample_rate: f64) -> Vec<f64> {
+        let omega = 2.0 * PI * cutoff / sample_rate;
+        let middle = (n - 1) as f64 / 2.0;
+        let taps: Vec<f64> = (0..n)
+            .map(|i| {
+                let k = i as f64 - middle;
+                let sinc = if k == 0.0 {
+                    omega / PI
+                } else {
+                    (omega * k).sin() / (PI * k)
+                };
+                sinc * (0.54 - 0.46 * (2.0 * PI * i as f64 / (n - 1) as f64).cos())
+            })
+            .collect();
+        let sum: f64 = taps.iter().sum();
+        taps.iter().map(|tap| tap / sum).collect()
+    }
+
+    /// Steps, ramps and a sine, in the range of the gyro in rad/s.
+    fn input() -> Vec<f64> {
+        (0..300)
+            .map(|i| match i {
+                0..=49 => 0.0,
+                50..=99 => 2.5,
+                100..=199 => -3.0 + 0.05 * (i - 100) as f64,
+                _ => 4.0 * (i as f64 * 0.3).sin(),
+            })
+            .collect()
+    }
+
+    fn assert_matches_reference<const N: usize>(taps: [Coefficient; N]) {
+        let input = input();
+        let expected = reference(&to_f64(&taps), &input);
+        let mut fir = Fir::new(taps);
+        for (x, expected) in input.iter().zip(expected) {
+            let y: f64 = fir.apply(Coefficient::from_num(*x)).to_num();
+            assert!((y - expected).abs() < 1e-6, "{y} instead of {expected}");
+        }
+    }
+
+    #[test]
+    fn moving_average_matches_reference() {
+        assert_matches_reference(moving_average::<1>());
+        assert_matches_reference(moving_average::<4>());
+        assert_matches_reference(moving_average::<15>());
+    }
+
+    #[test]
+    fn windowed_sinc_matches_reference() {
+        assert_matches_reference(windowed_sinc::<9>(Frac::from_num(10), 150));
+        assert_matches_reference(windowed_sinc::<31>(Frac::from_num(40), 350));
+    }
+
+    #[test]
+    fn windowed_sinc_design_matches_reference() {
+        for (n, cutoff, sample_rate) in [(5, 20, 150), (16, 5, 150), (31, 40, 350)] {
+            let expected = reference_sinc(n, cutoff as f64, sample_rate as f64);
+            let taps: Vec<f64> = match n {
+                5 => to_f64(&windowed_sinc::<5>(Frac::from_num(cutoff), sample_rate)),
+                16 => to_f64(&windowed_sinc::<16>(Frac::from_num(cutoff), sample_rate)),
+                _ => to_f64(&windowed_sinc::<31>(Frac::from_num(cutoff), sample_rate)),
+            };
+            for (tap, expected) in taps.iter().zip(expected) {
+                assert!((tap - expected).abs() < 1e-7, "{tap} instead of {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn coefficients_can_change_at_runtime() {
+        let mut fir = Fir::new(moving_average::<4>());
+        for _ in 0..4 {
+            fir.apply(Coefficient::from_num(2));
+        }
+        fir.set_coefficients([
+            Coefficient::ONE,
+            Coefficient::ZERO,
+            Coefficient::ZERO,
+            Coefficient::ZERO,
+        ]);
+        assert_eq!(fir.apply(Coefficient::from_num(5)), 5);
+    }
+
+    #[test]
+    fn filters_every_component() {
+        let mut fir = SensorFir::new(moving_average::<2>());
+        let mut data = SensorData::new();
+        data.velocity.pitch = RadiansPerSecond(Frac::from_num(1));
+        data.radius.roll = Radians(Frac::from_num(-2));
+        data.acceleration.z = 16000;
+        data.pressure = Pascal(Pressure::from_num(300));
+        fir.apply(&mut data);
+        assert_eq!(data.velocity.pitch, RadiansPerSecond(Frac::from_num(0.5)));
+        assert_eq!(data.radius.roll, Radians(Frac::from_num(-1)));
+        assert_eq!(data.acceleration.z, 8000);
+        assert_eq!(data.pressure.0, 150);
+    }
+
+    #[test]
+    fn a_primed_filter_passes_the_reading() {
+        let mut data = SensorData::new();
+        data.velocity.yaw = RadiansPerSecond(Frac::from_num(0.75));
+        data.radius.pitch = Radians(Frac::from_num(-0.25));
+        data.acceleration.z = 16000;
+        data.pressure = Pascal(Pressure::from_num(-40));
+        let mut fir = SensorFir::new(moving_average::<4>());
+        fir.prime(&data);
+        let mut filtered = data;
+        fir.apply(&mut filtered);
+        assert_eq!(filtered, data);
+    }
+
+    #[test]
+    fn extreme_readings_saturate() {
+        let mut fir = SensorFir::new(windowed_sinc::<9>(Frac::from_num(20), 150));
+        for n in 0..100 {
+            fir.apply(&mut extreme(n / 3 % 2 == 0));
+        }
+
+        // every partial sum of a moving average fits
+        let mut fir = SensorFir::new(moving_average::<4>());
+        for high in [true, false, true] {
+            for _ in 0..3 {
+                fir.apply(&mut extreme(high));
+            }
+            let mut data = extreme(high);
+            fir.apply(&mut data);
+            assert_eq!(data, extreme(high));
+        }
+    }
+}
@@ -0,0 +1,1152 @@
+use crate::config::{EraseSector, SECTOR_SIZE};
+use alloc::vec::Vec;
+use architecture::{
+    crc16, DroneConfig, LogChannel, LogChannels, LogChunk, LogEntry, LogHeader, LogRecord,
+    SessionInfo, LOG_CHUNK, LOG_SCHEMA,
+};
+use log::disk::DiskError;
+
+/// First byte of every record.
+const SYNC: u8 = 0xa5;
+/// Sync, length of the record and tick.
+const FRAME_HEADER: usize = 7;
+/// CRC of the frame header and the record, after the record.
+const CRC: usize = 2;
+/// Longest serialized record.
+const MAX_RECORD: usize = 240;
+const FRAME_SIZE: usize = FRAME_HEADER + MAX_RECORD + CRC;
+
+const SECTOR_MAGIC: [u8; 2] = [0x10, 0x9f];
+/// Magic, sequence number and CRC at the start of every sector in use.
+const SECTOR_HEADER: u32 = 8;
+/// Records a download reads per tick at most to find where the data of a sector ends.
+const SCAN_STEPS: usize = 4;
+
+/// The flight log: sessions of a [`LogHeader`] followed by records.
+///
+/// Every record starts with a sync byte, its length and the tick it was taken at, and ends
+/// with a CRC. The serialized [`LogRecord`] starts with its variant, which is the type tag.
+/// The reader skips everything that is no intact record, a record cut short by a reset or a
+/// damaged byte, and reports it as [`LogEntry::Corrupt`].
+///
+/// The log is a ring of flash sectors. Each sector in use starts with a sequence number one
+/// higher than the one written before, so the oldest and the newest sector, and with it where
+/// to append, follow from the flash alone after a reset. Records do not cross sectors, and the
+/// data of a sector ends where it is still erased. When the log is full the oldest sector is
+/// erased and reused, so it always holds the last minutes. The header of the session starts
+/// every sector, so the records after a reclaimed sector can still be decoded. The sector after
+/// the head is erased ahead, in the background, so the control loop does not wait for the
+/// flash when the head fills up; records that come while the flash is erasing are dropped. A new session
+/// starts a new sector, so the sessions are an index of the sectors, which is read back from
+/// the first record of each.
+///
+/// Each [`LogChannel`] is logged every so many ticks. Changing that during a session logs the
+/// new [`LogChannels`] before the next record, so the reader always knows what to expect.
+///
+/// A host downloads a session as the bytes of its records, the data of its sectors one after
+/// another, in [`LogChunk`]s it asks for by offset. Where the data of a sector ends is only
+/// known by reading its records, so every chunk reads the few records it covers, and the
+/// sectors counted are kept for the next read of the session.
+pub struct FlightLog<D: EraseSector> {
+    disk: D,
+    /// Sectors of the disk the log may use.
+    sectors: u32,
+    enabled: bool,
+    /// The sector written to and its sequence number, `None` while the log is empty.
+    head: Option<(u32, u32)>,
+    /// Where the next record goes.
+    end: u32,
+    /// The sector after the head, once its erase was started.
+    ahead: Option<u32>,
+    /// The header of the session being logged.
+    session: Option<LogHeader>,
+    /// Number of the newest session, also one that is not logged any more.
+    last_session: Option<u16>,
+    /// Whether the drone armed during the session.
+    flown: bool,
+    channels: LogChannels,
+    /// Whether the channels changed since they were last logged.
+    announce: bool,
+    /// The download asked for last.
+    transfer: Option<Transfer>,
+    /// The listing of the sessions asked for last.
+    listing: Option<Listing>,
+}
+
+/// A read of the records of a session, see [`FlightLog::request`].
+struct Transfer {
+    session: u16,
+    /// Tick the session started at, known once its first sector is found.
+    started: u32,
+    /// The next byte to send, and the end of the read.
+    offset: u32,
+    end: u32,
+    /// The sectors of the session found so far, in order.
+    sectors: Vec<Span>,
+}
+
+/// A sector of a session being downloaded.
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    index: u32,
+    sequence: u32,
+    /// Bytes of records counted so far, all of them unless it is the last sector found.
+    length: u32,
+}
+
+/// A count of the sessions, see [`FlightLog::list`].
+struct Listing {
+    /// The sector being counted and its sequence number, `None` before the oldest.
+    sector: Option<(u32, u32)>,
+    /// The next record in the sector.
+    cursor: u32,
+    /// The session being counted, sent once the next one starts.
+    info: Option<SessionInfo>,
+}
+
+impl Transfer {
+    /// Bytes of records counted in all sectors.
+    fn counted(&self) -> u32 {
+        self.sectors.iter().map(|span| span.length).sum()
+    }
+}
+
+impl<D: EraseSector> FlightLog<D> {
+    /// The log on the first `capacity` bytes of `disk`, appending after what is already there.
+    pub fn new(disk: D, capacity: u32) -> Self {
+        let mut log = FlightLog {
+            disk,
+            sectors: capacity / SECTOR_SIZE,
+            enabled: false,
+            head: None,
+            end: 0,
+            ahead: None,
+            session: None,
+            last_session: None,
+            flown: false,
+            channels: LogChannels::new(),
+            announce: false,
+            transfer: None,
+            listing: None,
+        };
+        if let Ok(Some(head)) = log.newest() {
+            let mut cursor = head.0 * SECTOR_SIZE + SECTOR_HEADER;
+            while let Ok(Some(_)) = log.read_in_sector(&mut cursor) {}
+            log.head = Some(head);
+            log.end = cursor;
+            if let Ok(Some(header)) = log.sector_header(head.0) {
+                log.last_session = Some(header.session);
+            }
+        }
+        log
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn channels(&self) -> LogChannels {
+        self.channels
+    }
+
+    /// Logs `channel` every `decimation` ticks from now on, 0 turns it off.
+    pub fn set_decimation(&mut self, channel: LogChannel, decimation: u16) {
+        if self.channels.get(channel) != decimation {
+            self.channels.set(channel, decimation);
+            self.announce = true;
+        }
+    }
+
+    /// Whether logging is enabled and `channel` is logged at `tick`.
+    pub fn is_due(&self, channel: LogChannel, tick: u32) -> bool {
+        self.enabled && self.channels.is_due(channel, tick)
+    }
+
+    /// Starts a new session at `tick` with `config` at `frequency` Hz and enables logging.
+    pub fn start(
+        &mut self,
+        config: DroneConfig,
+        frequency: u64,
+        tick: u32,
+    ) -> Result<(), DiskError> {
+        let session = self.last_session.map_or(0, |last| last.wrapping_add(1));
+        let header = header(config, frequency, self.channels, session, tick);
+        self.session = Some(header);
+        self.last_session = Some(session);
+        self.announce = false;
+        self.flown = false;
+        self.open_sector()?;
+        self.write(tick, &LogRecord::Header(header))?;
+        self.enabled = true;
+        Ok(())
+    }
+
+    /// Starts a new session when the drone arms at `tick`, unless it did not fly since the
+    /// session started.
+    pub fn arm(&mut self, config: DroneConfig, frequency: u64, tick: u32) -> Result<(), DiskError> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.flown {
+            self.start(config, frequency, tick)?;
+        }
+        self.flown = true;
+        Ok(())
+    }
+
+    /// Lists the sessions from the oldest to the newest through [`Self::next_session`].
+    /// Replaces the listing asked for before.
+    pub fn list(&mut self) {
+        self.listing = Some(Listing {
+            sector: None,
+            cursor: 0,
+            info: None,
+        });
+    }
+
+    /// The next session of the listing asked for, and `Some(None)` once after the newest.
+    /// `None` while the records of a session are still being counted, or when nothing is
+    /// listed. Reading the flash fails the listing, so the host has to ask again.
+    pub fn next_session(&mut self) -> Result<Option<Option<SessionInfo>>, DiskError> {
+        if self.disk.is_busy()? {
+            return Ok(None);
+        }
+        let Some(mut listing) = self.listing.take() else {
+            return Ok(None);
+        };
+        for _ in 0..SCAN_STEPS {
+            if listing.sector.is_some() {
+                let start = listing.cursor;
+                if let Some(entry) = self.read_in_sector(&mut listing.cursor)? {
+                    // sectors before the first header belong to no session
+                    if let Some(info) = &mut listing.info {
+                        info.size += listing.cursor - start;
+                        if let LogEntry::Record { tick, .. } = entry {
+                            info.duration = tick.wrapping_sub(info.started);
+                        }
+                    }
+                    continue;
+                }
+            }
+            let after = listing.sector.map(|(_, sequence)| sequence);
+            let Some((index, sequence)) = self.following(after)? else {
+                // the newest session, then the end of the list
+                let info = listing.info.take();
+                if info.is_some() {
+                    self.listing = Some(listing);
+                }
+                return Ok(Some(info));
+            };
+            listing.sector = Some((index, sequence));
+            listing.cursor = index * SECTOR_SIZE + SECTOR_HEADER;
+            // like the index, a sector without a header belongs to the session before
+            let Some(header) = self.sector_header(index)? else {
+                continue;
+            };
+            let same = listing
+                .info
+                .map_or(false, |info| info.session == header.session);
+            if same {
+                continue;
+            }
+            let done = listing.info.replace(SessionInfo {
+                session: header.session,
+                started: header.started,
+                duration: 0,
+                frequency: header.frequency,
+                size: 0,
+            });
+            if done.is_some() {
+                self.listing = Some(listing);
+                return Ok(Some(done));
+            }
+        }
+        self.listing = Some(listing);
+        Ok(None)
+    }
+
+    /// A cursor at the oldest record of `session`, `None` if it is not in the log.
+    pub fn first_of(&mut self, session: u16) -> Result<Option<u32>, DiskError> {
+        let first = self.first_sector_of(session)?;
+        Ok(first.map(|(index, _)| index * SECTOR_SIZE + SECTOR_HEADER))
+    }
+
+    /// The oldest sector of `session` and its sequence number.
+    fn first_sector_of(&mut self, session: u16) -> Result<Option<(u32, u32)>, DiskError> {
+        let mut next = self.following(None)?;
+        while let Some((index, sequence)) = next {
+            if let Some(header) = self.sector_header(index)? {
+                if header.session == session {
+                    return Ok(Some((index, sequence)));
+                }
+            }
+            next = self.following(Some(sequence))?;
+        }
+        Ok(None)
+    }
+
+    /// Like [`Self::read`], but `None` where the next session starts.
+    pub fn read_session(
+        &mut self,
+        cursor: &mut u32,
+        session: u16,
+    ) -> Result<Option<LogEntry>, DiskError> {
+        match self.read(cursor)? {
+            Some(LogEntry::Record {
+                record: LogRecord::Header(header),
+                ..
+            }) if header.session != session => Ok(None),
+            entry => Ok(entry),
+        }
+    }
+
+    /// Sends the chunks of the records of `session` that cover `length` bytes from byte
+    /// `offset`, through [`Self::next_chunk`]. Replaces the read asked for before.
+    pub fn request(&mut self, session: u16, offset: u32, length: u32) {
+        let (started, sectors) = match self.transfer.take() {
+            Some(transfer) if transfer.session == session => (transfer.started, transfer.sectors),
+            _ => (0, Vec::new()),
+        };
+        self.transfer = Some(Transfer {
+            session,
+            started,
+            offset,
+            end: offset.saturating_add(length),
+            sectors,
+        });
+    }
+
+    /// The next chunk of the read asked for. `None` when all of it was sent, or while the
+    /// records it covers are still being counted. A short chunk ends the session, and with it
+    /// the read. Reading the flash fails the read, so the host has to ask again.
+    pub fn next_chunk(&mut self) -> Result<Option<LogChunk>, DiskError> {
+        if self.disk.is_busy()? {
+            return Ok(None);
+        }
+        let Some(mut transfer) = self.transfer.take() else {
+            return Ok(None);
+        };
+        let offset = transfer.offset;
+        let wanted = offset.saturating_add(LOG_CHUNK as u32);
+        let mut ended = false;
+        if offset < transfer.end {
+            for _ in 0..SCAN_STEPS {
+                if transfer.counted() >= wanted {
+                    break;
+                }
+                if !self.advance(&mut transfer)? {
+                    ended = true;
+                    break;
+                }
+            }
+        }
+        let available = transfer.counted().min(wanted);
+        if offset >= transfer.end || (available < wanted && !ended) {
+            self.transfer = Some(transfer);
+            return Ok(None);
+        }
+        let mut data = [0; LOG_CHUNK];
+        let length = available.saturating_sub(offset) as usize;
+        if !self.read_spans(&transfer.sectors, offset, &mut data[..length])? {
+            // a sector was reclaimed, count again
+            transfer.sectors.clear();
+            self.transfer = Some(transfer);
+            return Ok(None);
+        }
+        transfer.offset = if length < LOG_CHUNK {
+            transfer.end
+        } else {
+            wanted
+        };
+        let chunk = LogChunk::new(transfer.session, transfer.started, offset, &data[..length]);
+        self.transfer = Some(transfer);
+        Ok(Some(chunk))
+    }
+
+    /// Counts the next record of the session of `transfer`, or goes on to its next sector.
+    /// `false` at the end of the session.
+    fn advance(&mut self, transfer: &mut Transfer) -> Result<bool, DiskError> {
+        let Some(last) = transfer.sectors.last_mut() else {
+            let first = self.first_sector_of(transfer.session)?;
+            if let Some((index, sequence)) = first {
+                if let Some(header) = self.sector_header(index)? {
+                    transfer.started = header.started;
+                }
+                transfer.sectors.push(Span {
+                    index,
+                    sequence,
+                    length: 0,
+                });
+            }
+            return Ok(first.is_some());
+        };
+        let start = last.index * SECTOR_SIZE + SECTOR_HEADER;
+        let mut cursor = start + last.length;
+        if self.read_in_sector(&mut cursor)?.is_some() {
+            last.length = cursor - start;
+            return Ok(true);
+        }
+        let Some((index, sequence)) = self.following(Some(last.sequence))? else {
+            return Ok(false);
+        };
+        // like the index, a sector without a header belongs to the session before
+        let header = self.sector_header(index)?;
+        if header.map_or(false, |header| header.session != transfer.session) {
+            return Ok(false);
+        }
+        transfer.sectors.push(Span {
+            index,
+            sequence,
+            length: 0,
+        });
+        Ok(true)
+    }
+
+    /// Reads the records of `sectors` from byte `offset` on into `buf`. `false` if one of the
+    /// sectors was reclaimed since it was counted.
+    fn read_spans(
+        &mut self,
+        sectors: &[Span],
+        offset: u32,
+        buf: &mut [u8],
+    ) -> Result<bool, DiskError> {
+        let mut start = 0;
+        let mut filled = 0;
+        for span in sectors {
+            let end = start + span.length;
+            let at = offset + filled as u32;
+            if filled < buf.len() && at < end {
+                if self.sequence(span.index)? != Some(span.sequence) {
+                    return Ok(false);
+                }
+                let count = ((end - at) as usize).min(buf.len() - filled);
+                let address = span.index * SECTOR_SIZE + SECTOR_HEADER + (at - start);
+                self.disk
+                    .read_bytes(address, &mut buf[filled..filled + count])?;
+                filled += count;
+            }
+            start = end;
+        }
+        Ok(true)
+    }
+
+    /// Deletes the sessions before `before` by erasing their sectors.
+    pub fn delete_before(&mut self, before: u16) -> Result<(), DiskError> {
+        let mut old = Vec::new();
+        let mut keep = false;
+        for index in 0..self.sectors {
+            if self.sequence(index)?.is_none() {
+                continue;
+            }
+            match self.sector_header(index)? {
+                Some(header) if header.session < before => old.push(index),
+                _ => keep = true,
+            }
+        }
+        if !keep {
+            // erasing everything also works on a flash that only erases the whole chip
+            self.disk.erase_data()?;
+            self.head = None;
+            self.end = 0;
+            self.ahead = None;
+            return Ok(());
+        }
+        // the head belongs to the newest session, which is kept
+        for index in old {
+            self.disk.erase_sector(index * SECTOR_SIZE)?;
+        }
+        Ok(())
+    }
+
+    /// Appends `record` taken at `tick`, if logging is enabled and the flash is not erasing.
+    pub fn append(&mut self, tick: u32, record: &LogRecord) -> Result<(), DiskError> {
+        if !self.enabled || self.disk.is_busy()? {
+            return Ok(());
+        }
+        if self.announce {
+            self.write(tick, &LogRecord::Channels(self.channels))?;
+            self.announce = false;
+        }
+        self.write(tick, record)
+    }
+
+    fn write(&mut self, tick: u32, record: &LogRecord) -> Result<(), DiskError> {
+        let (frame, size) = frame(tick, record)?;
+        let fits = self.head.map_or(false, |(index, _)| {
+            self.end as usize + size <= ((index + 1) * SECTOR_SIZE) as usize
+        });
+        if !fits {
+            self.open_sector()?;
+            if let (Some(session), false) = (self.session, matches!(record, LogRecord::Header(_))) {
+                let header = LogHeader {
+                    channels: self.channels,
+                    ..session
+                };
+                let (frame, size) = self::frame(tick, &LogRecord::Header(header))?;
+                self.put(&frame[..size])?;
+                self.announce = false;
+            }
+        }
+        self.put(&frame[..size])?;
+        self.erase_ahead()
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<(), DiskError> {
+        self.disk.write_bytes(self.end, bytes)?;
+        self.end += bytes.len() as u32;
+        Ok(())
+    }
+
+    /// Starts the sector after the head. Unless it was erased ahead, it is erased first if it
+    /// holds the oldest records.
+    fn open_sector(&mut self) -> Result<(), DiskError> {
+        if self.sectors == 0 {
+            return Err(DiskError::OutOfSpace);
+        }
+        let (index, sequence) = match self.head {
+            Some((index, sequence)) => ((index + 1) % self.sectors, sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+        let address = index * SECTOR_SIZE;
+        let mut marker = [0; SECTOR_HEADER as usize];
+        if self.ahead.take() != Some(index) {
+            self.disk.read_bytes(address, &mut marker)?;
+            if marker.iter().any(|byte| *byte != 0xff) {
+                self.disk.erase_sector(address)?;
+            }
+        }
+        marker[..2].copy_from_slice(&SECTOR_MAGIC);
+        marker[2..6].copy_from_slice(&sequence.to_le_bytes());
+        let crc = crc16(&marker[..6]);
+        marker[6..].copy_from_slice(&crc.to_le_bytes());
+        self.disk.write_bytes(address, &marker)?;
+        self.head = Some((index, sequence));
+        self.end = address + SECTOR_HEADER;
+        Ok(())
+    }
+
+    /// Starts erasing the sector after the head, unless that already happened since the boot;
+    /// a reset may have cut the erase short.
+    fn erase_ahead(&mut self) -> Result<(), DiskError> {
+        let Some((index, _)) = self.head else {
+            return Ok(());
+        };
+        let next = (index + 1) % self.sectors;
+        if next == index || self.ahead == Some(next) {
+            return Ok(());
+        }
+        self.disk.start_erase(next * SECTOR_SIZE)?;
+        self.ahead = Some(next);
+        Ok(())
+    }
+
+    /// The header the data of sector `index` starts with.
+    fn sector_header(&mut self, index: u32) -> Result<Option<LogHeader>, DiskError> {
+        let mut cursor = index * SECTOR_SIZE + SECTOR_HEADER;
+        match self.read_in_sector(&mut cursor)? {
+            Some(LogEntry::Record {
+                record: LogRecord::Header(header),
+                ..
+            }) => Ok(Some(header)),
+            _ => Ok(None),
+        }
+    }
+
+    /// The sequence number of sector `index`, `None` unless it starts with an intact marker.
+    fn sequence(&mut self, index: u32) -> Result<Option<u32>, DiskError> {
+        let mut marker = [0; SECTOR_HEADER as usize];
+        self.disk.read_bytes(index * SECTOR_SIZE, &mut marker)?;
+        let crc = u16::from_le_bytes([marker[6], marker[7]]);
+        if marker[..2] != SECTOR_MAGIC || crc16(&marker[..6]) != crc {
+            return Ok(None);
+        }
+        Ok(Some(u32::from_le_bytes([
+            marker[2], marker[3], marker[4], marker[5],
+        ])))
+    }
+
+    /// The sector written first after the one with sequence number `after`, or the oldest one.
+    fn following(&mut self, after: Option<u32>) -> Result<Option<(u32, u32)>, DiskError> {
+        let mut next: Option<(u32, u32)> = None;
+        for index in 0..self.sectors {
+            if let Some(sequence) = self.sequence(index)? {
+                if after.map_or(true, |after| sequence > after)
+                    && next.map_or(true, |(_, best)| sequence < best)
+                {
+                    next = Some((index, sequence));
+                }
+            }
+        }
+        Ok(next)
+    }
+
+    /// The sector written last.
+    fn newest(&mut self) -> Result<Option<(u32, u32)>, DiskError> {
+        let mut newest: Option<(u32, u32)> = None;
+        for index in 0..self.sectors {
+            if let Some(sequence) = self.sequence(index)? {
+                if newest.map_or(true, |(_, best)| sequence > best) {
+                    newest = Some((index, sequence));
+                }
+            }
+        }
+        Ok(newest)
+    }
+
+    /// A cursor at the oldest record.
+    pub fn first(&mut self) -> u32 {
+        let oldest = self.following(None).ok().flatten();
+        oldest.map_or(0, |(index, _)| index) * SECTOR_SIZE + SECTOR_HEADER
+    }
+
+    /// The entry at `cursor`, which moves on to the next one. `None` at the end of the log.
+    pub fn read(&mut self, cursor: &mut u32) -> Result<Option<LogEntry>, DiskError> {
+        loop {
+            // a cursor is never at the start of a sector, but can be right after its end
+            let sector = cursor.saturating_sub(1) / SECTOR_SIZE;
+            let Some(sequence) = self.sequence(sector)? else {
+                return Ok(None);
+            };
+            if let Some(entry) = self.read_in_sector(cursor)? {
+                return Ok(Some(entry));
+            }
+            match self.following(Some(sequence))? {
+                Some((index, _)) => *cursor = index * SECTOR_SIZE + SECTOR_HEADER,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Like [`Self::read`], but `None` at the end of the data of the sector.
+    fn read_in_sector(&mut self, cursor: &mut u32) -> Result<Option<LogEntry>, DiskError> {
+        let start = *cursor;
+        let limit = (start.saturating_sub(1) / SECTOR_SIZE + 1) * SECTOR_SIZE;
+        if self.is_erased(start, limit)? {
+            return Ok(None);
+        }
+        if let Some((entry, size)) = self.record_at(start, limit)? {
+            *cursor += size;
+            return Ok(Some(entry));
+        }
+        // resynchronize on the next intact record
+        let mut next = start + 1;
+        while !self.is_erased(next, limit)? && self.record_at(next, limit)?.is_none() {
+            next += 1;
+        }
+        *cursor = next;
+        Ok(Some(LogEntry::Corrupt {
+            address: start,
+            length: next - start,
+        }))
+    }
+
+    /// Whether nothing was written at `address` since the last erase, or no record fits
+    /// before `limit`.
+    fn is_erased(&mut self, address: u32, limit: u32) -> Result<bool, DiskError> {
+        if address as u64 + FRAME_HEADER as u64 > limit as u64 {
+            return Ok(true);
+        }
+        let mut header = [0; FRAME_HEADER];
+        self.disk.read_bytes(address, &mut header)?;
+        Ok(header.iter().all(|byte| *byte == 0xff))
+    }
+
+    /// The intact record at `address` that ends before `limit`, and its size.
+    fn record_at(
+        &mut self,
+        address: u32,
+        limit: u32,
+    ) -> Result<Option<(LogEntry, u32)>, DiskError> {
+        let mut frame = [0; FRAME_SIZE];
+        if address as u64 + (FRAME_HEADER + CRC) as u64 > limit as u64 {
+            return Ok(None);
+        }
+        self.disk.read_bytes(address, &mut frame[..FRAME_HEADER])?;
+        let length = u16::from_le_bytes([frame[1], frame[2]]) as usize;
+        let end = FRAME_HEADER + length;
+        if frame[0] != SYNC
+            || length > MAX_RECORD
+            || address as u64 + (end + CRC) as u64 > limit as u64
+        {
+            return Ok(None);
+        }
+        self.disk.read_bytes(
+            address + FRAME_HEADER as u32,
+            &mut frame[FRAME_HEADER..end + CRC],
+        )?;
+        let crc = u16::from_le_bytes([frame[end], frame[end + 1]]);
+        if crc16(&frame[..end]) != crc {
+            return Ok(None);
+        }
+        let tick = u32::from_le_bytes([frame[3], frame[4], frame[5], frame[6]]);
+        Ok(postcard::from_bytes(&frame[FRAME_HEADER..end])
+            .ok()
+            .map(|record| (LogEntry::Record { tick, record }, (end + CRC) as u32)))
+    }
+}
+
+/// `record` taken at `tick` as it goes to the flash, and its size.
+fn frame(tick: u32, record: &LogRecord) -> Result<([u8; FRAME_SIZE], usize), DiskError> {
+    let mut frame = [0xff; FRAME_SIZE];
+    let length = postcard::to_slice(record, &mut frame[FRAME_HEADER..FRAME_HEADER + MAX_RECORD])
+        .map_err(|_| DiskError::OutOfSpace)?
+        .len();
+    frame[0] = SYNC;
+    frame[1..3].copy_from_slice(&(length as u16).to_le_bytes());
+    frame[3..FRAME_HEADER].copy_from_slice(&tick.to_le_bytes());
+    let end = FRAME_HEADER + length;
+    let crc = crc16(&frame[..end]);
+    frame[end..end + CRC].copy_from_slice(&crc.to_le_bytes());
+    Ok((frame, end + CRC))
+}
+
+/// The header of session `session` of `channels`, started at `started` with `config` by a loop
+/// running at `frequency` Hz.
+fn header(
+    config: DroneConfig,
+    frequency: u64,
+    channels: LogChannels,
+    session: u16,
+    started: u32,
+) -> LogHeader {
+    let mut firmware = [0; 16];
+    let version = env!("CARGO_PKG_VERSION").as_bytes();
+    let length = version.len().min(firmware.len());
+    firmware[..length].copy_from_slice(&version[..length]);
+    LogHeader {
+        firmware,
+        schema: LOG_SCHEMA,
+        session,
+        started,
+        frequency: frequency.min(u16::MAX as u64) as u16,
+        config,
+        channels,
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::config::defaults;
+    use crate::hal::mock::RamDisk;
+    use alloc::vec::Vec;
+    use log::Disk;
+
+    const CAPACITY: u32 = 4 * SECTOR_SIZE;
+
+    fn data(tick: u32) -> LogRecord {
+        LogRecord::Motors([tick as u16; 4])
+    }
+
+    fn entries(log: &mut FlightLog<RamDisk>) -> Vec<LogEntry> {
+        let mut cursor = log.first();
+        let mut entries = Vec::new();
+        while let Some(entry) = log.read(&mut cursor).unwrap() {
+            entries.push(entry);
+        }
+        entries
+    }
+
+    fn record(tick: u32, record: LogRecord) -> LogEntry {
+        LogEntry::Record { tick, record }
+    }
+
+    fn written() -> FlightLog<RamDisk> {
+        let mut log = FlightLog::new(RamDisk::new(CAPACITY as usize), CAPACITY);
+        log.start(defaults(), 350, 0).unwrap();
+        for tick in 1..=3 {
+            log.append(tick, &data(tick)).unwrap();
+        }
+        log
+    }
+
+    /// The ticks of the motor records, which are all intact.
+    fn ticks(log: &mut FlightLog<RamDisk>) -> Vec<u32> {
+        entries(log)
+            .into_iter()
+            .filter_map(|entry| match entry {
+                LogEntry::Record {
+                    tick,
+                    record: LogRecord::Motors(_),
+                } => Some(tick),
+                LogEntry::Record { .. } => None,
+                entry => panic!("{entry:?}"),
+            })
+            .collect()
+    }
+
+    /// The sessions the log lists, a few records per call.
+    fn listed(log: &mut FlightLog<RamDisk>) -> Vec<SessionInfo> {
+        log.list();
+        let mut sessions = Vec::new();
+        for _ in 0..10_000 {
+            match log.next_session().unwrap() {
+                Some(Some(info)) => sessions.push(info),
+                Some(None) => return sessions,
+                None => (),
+            }
+        }
+        panic!("the listing did not end");
+    }
+
+    /// The chunks the log sends for a read, until it has none for a while.
+    fn download(
+        log: &mut FlightLog<RamDisk>,
+        session: u16,
+        offset: u32,
+        length: u32,
+    ) -> Vec<LogChunk> {
+        log.request(session, offset, length);
+        let mut chunks = Vec::new();
+        for _ in 0..10_000 {
+            if let Some(chunk) = log.next_chunk().unwrap() {
+                chunks.push(chunk);
+            }
+        }
+        chunks
+    }
+
+    #[test]
+    fn records_follow_the_header() {
+        let mut log = written();
+        log.set_enabled(false);
+        log.append(4, &data(4)).unwrap();
+
+        let entries = entries(&mut log);
+        let header = match entries[0] {
+            LogEntry::Record {
+                tick: 0,
+                record: LogRecord::Header(header),
+            } => header,
+            entry => panic!("{entry:?}"),
+        };
+        assert_eq!(header.schema, LOG_SCHEMA);
+        assert_eq!((header.session, header.started), (0, 0));
+        assert_eq!(header.frequency, 350);
+        assert_eq!(header.config, defaults());
+        assert_eq!(header.channels, LogChannels::new());
+        assert!(header
+            .firmware
+            .starts_with(env!("CARGO_PKG_VERSION").as_bytes()));
+        assert_eq!(
+            entries[1..],
+            [record(1, data(1)), record(2, data(2)), record(3, data(3))]
+        );
+    }
+
+    #[test]
+    fn a_damaged_record_is_reported_and_skipped() {
+        let mut log = written();
+        let intact = entries(&mut log);
+        let mut cursor = log.first();
+        log.read(&mut cursor).unwrap();
+        let damaged = cursor;
+        log.read(&mut cursor).unwrap();
+        log.disk.data[damaged as usize + FRAME_HEADER + 1] ^= 0x10;
+
+        assert_eq!(
+            entries(&mut log),
+            [
+                intact[0],
+                LogEntry::Corrupt {
+                    address: damaged,
+                    length: cursor - damaged
+                },
+                intact[2],
+                intact[3]
+            ]
+        );
+    }
+
+    #[test]
+    fn appending_resumes_after_a_record_cut_short() {
+        let log = written();
+        let end = log.end;
+        let mut disk = log.disk;
+        // a reset while writing the next record
+        let partial = [SYNC, 60, 0, 4, 0, 0, 0, 0x12, 0x34];
+        assert!(disk.write_bytes(end, &partial).is_ok());
+
+        let mut log = FlightLog::new(disk, CAPACITY);
+        assert_eq!(log.end, end + partial.len() as u32);
+        log.set_enabled(true);
+        log.append(5, &data(5)).unwrap();
+        let entries = entries(&mut log);
+        assert_eq!(
+            entries[4..],
+            [
+                LogEntry::Corrupt {
+                    address: end,
+                    length: partial.len() as u32
+                },
+                record(5, data(5))
+            ]
+        );
+    }
+
+    #[test]
+    fn the_oldest_sectors_are_reclaimed() {
+        let mut log = written();
+        let last = 4000;
+        for tick in 4..=last {
+            log.append(tick, &data(tick)).unwrap();
+        }
+        let kept = ticks(&mut log);
+        assert!(kept[0] > 3);
+        assert!(kept.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        assert_eq!(*kept.last().unwrap(), last);
+        // every sector starts with the header
+        let headers = entries(&mut log)
+            .iter()
+            .filter(|entry| {
+                matches!(
+                    entry,
+                    LogEntry::Record {
+                        record: LogRecord::Header(_),
+                        ..
+                    }
+                )
+            })
+            .count();
+        // the sector after the head is erased ahead
+        assert_eq!(headers as u32, CAPACITY / SECTOR_SIZE - 1);
+
+        // after a reset the log goes on where it was
+        let (head, end) = (log.head, log.end);
+        let mut log = FlightLog::new(log.disk, CAPACITY);
+        assert_eq!((log.head, log.end), (head, end));
+        assert_eq!(ticks(&mut log), kept);
+    }
+
+    #[test]
+    fn a_reset_while_erasing_ahead_loses_nothing_else() {
+        let mut log = written();
+        let mut tick = 4;
+        while log.head.unwrap().1 < 5 {
+            log.append(tick, &data(tick)).unwrap();
+            tick += 1;
+        }
+        let before = ticks(&mut log);
+        // the reset came while the sector after the head was erased ahead
+        let next = (log.head.unwrap().0 + 1) % 4;
+        let start = (next * SECTOR_SIZE) as usize;
+        let sector = start..start + SECTOR_SIZE as usize;
+        assert!(log.disk.data[sector].iter().all(|byte| *byte == 0xff));
+        log.disk.data[start + 100..start + 200].fill(0);
+
+        let mut log = FlightLog::new(log.disk, CAPACITY);
+        assert_eq!(ticks(&mut log), before);
+
+        // it is erased again before the log gets there
+        log.session = Some(header(defaults(), 350, LogChannels::new(), 0, 0));
+        log.set_enabled(true);
+        while log.head.unwrap().0 != (next + 1) % 4 {
+            log.append(tick, &data(tick)).unwrap();
+            tick += 1;
+        }
+        let ticks = ticks(&mut log);
+        assert!(ticks.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        assert_eq!(*ticks.last().unwrap(), tick - 1);
+    }
+
+    #[test]
+    fn appending_does_not_wait_for_an_erase() {
+        let mut log = written();
+        log.disk.erase_polls = 3;
+        let (erases, waits) = (log.disk.erases, log.disk.waits);
+        let last = 4000;
+        for tick in 4..=last {
+            log.append(tick, &data(tick)).unwrap();
+        }
+        assert!(log.disk.erases > erases + 4, "{}", log.disk.erases);
+        assert_eq!(log.disk.waits, waits);
+
+        // only the records that came while the flash was erasing are missing
+        let kept = ticks(&mut log);
+        assert_eq!(*kept.last().unwrap(), last);
+        assert!(kept.windows(2).any(|pair| pair[1] > pair[0] + 1));
+        assert!(kept.windows(2).all(|pair| pair[1] <= pair[0] + 4));
+    }
+
+    #[test]
+    fn channel_changes_are_logged_before_the_next_record() {
+        let mut log = FlightLog::new(RamDisk::new(CAPACITY as usize), CAPACITY);
+        assert!(!log.is_due(LogChannel::Attitude, 0));
+        log.set_decimation(LogChannel::Barometer, 0);
+        log.start(defaults(), 350, 0).unwrap();
+        assert!(log.is_due(LogChannel::Attitude, 6));
+        assert!(!log.is_due(LogChannel::Attitude, 7));
+        assert!(!log.is_due(LogChannel::Barometer, 0));
+        assert!(log.is_due(LogChannel::Events, 7));
+
+        log.set_decimation(LogChannel::Attitude, 4);
+        log.set_decimation(LogChannel::Attitude, 4);
+        assert!(log.is_due(LogChannel::Attitude, 8));
+        assert!(!log.is_due(LogChannel::Attitude, 9));
+        log.append(8, &data(8)).unwrap();
+        log.append(9, &data(9)).unwrap();
+
+        let entries = entries(&mut log);
+        match entries[0] {
+            LogEntry::Record {
+                record: LogRecord::Header(header),
+                ..
+            } => assert_eq!(header.channels.get(LogChannel::Barometer), 0),
+            entry => panic!("{entry:?}"),
+        }
+        assert_eq!(
+            entries[1..],
+            [
+                record(8, LogRecord::Channels(log.channels())),
+                record(8, data(8)),
+                record(9, data(9))
+            ]
+        );
+    }
+
+    #[test]
+    fn every_flight_is_a_session() {
+        let mut log = written();
+        // armed on the ground after enabling, still the first session
+        log.arm(defaults(), 350, 10).unwrap();
+        log.append(11, &data(11)).unwrap();
+        log.arm(defaults(), 350, 100).unwrap();
+        for tick in 101..=110 {
+            log.append(tick, &data(tick)).unwrap();
+        }
+
+        log.list();
+        // the first session takes more calls than a tick may read records
+        assert_eq!(log.next_session().unwrap(), None);
+        let sessions = listed(&mut log);
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(log.next_session().unwrap(), None);
+        assert_eq!(
+            (
+                sessions[0].session,
+                sessions[0].started,
+                sessions[0].duration
+            ),
+            (0, 0, 11)
+        );
+        assert_eq!(
+            (
+                sessions[1].session,
+                sessions[1].started,
+                sessions[1].duration
+            ),
+            (1, 100, 10)
+        );
+        assert!(sessions[0].size > SECTOR_HEADER && sessions[0].size < SECTOR_SIZE);
+
+        let mut read = |session| {
+            let mut cursor = log.first_of(session).unwrap().unwrap();
+            let mut ticks = Vec::new();
+            while let Some(entry) = log.read_session(&mut cursor, session).unwrap() {
+                match entry {
+                    LogEntry::Record {
+                        tick,
+                        record: LogRecord::Motors(_),
+                    } => ticks.push(tick),
+                    LogEntry::Record { .. } => (),
+                    entry => panic!("{entry:?}"),
+                }
+            }
+            ticks
+        };
+        assert_eq!(read(0), [1, 2, 3, 11]);
+        assert_eq!(read(1), (101..=110).collect::<Vec<_>>());
+        assert_eq!(log.first_of(2).unwrap(), None);
+    }
+
+    #[test]
+    fn old_sessions_are_deleted() {
+        let mut log = written();
+        for started in [10, 20] {
+            log.start(defaults(), 350, started).unwrap();
+            log.append(started + 1, &data(started + 1)).unwrap();
+        }
+        log.delete_before(2).unwrap();
+        let sessions = listed(&mut log);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!((sessions[0].session, sessions[0].started), (2, 20));
+        log.append(22, &data(22)).unwrap();
+        assert_eq!(ticks(&mut log), [21, 22]);
+
+        log.delete_before(3).unwrap();
+        assert!(listed(&mut log).is_empty());
+        let mut log = FlightLog::new(log.disk, CAPACITY);
+        assert_eq!(log.last_session, None);
+        log.start(defaults(), 350, 0).unwrap();
+        log.start(defaults(), 350, 5).unwrap();
+        // after a reset the numbers go on from the newest session
+        let mut log = FlightLog::new(log.disk, CAPACITY);
+        log.start(defaults(), 350, 0).unwrap();
+        let sessions = listed(&mut log);
+        assert_eq!(
+            sessions.iter().map(|info| info.session).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn sessions_are_downloaded_in_chunks() {
+        let mut log = written();
+        for tick in 4..=400 {
+            log.append(tick, &data(tick)).unwrap();
+        }
+        log.start(defaults(), 350, 500).unwrap();
+        log.append(501, &data(501)).unwrap();
+        let size = listed(&mut log)[0].size;
+        assert!(size > SECTOR_SIZE);
+
+        // the records as they were written, the header of every sector included
+        let mut frames = Vec::new();
+        let mut cursor = log.first_of(0).unwrap().unwrap();
+        while let Some(entry) = log.read_session(&mut cursor, 0).unwrap() {
+            let LogEntry::Record { tick, record } = entry else {
+                panic!("{entry:?}");
+            };
+            let (frame, length) = frame(tick, &record).unwrap();
+            frames.extend_from_slice(&frame[..length]);
+        }
+        assert_eq!(frames.len() as u32, size);
+
+        let chunks = download(&mut log, 0, 0, u32::MAX);
+        let mut bytes = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk.offset, (i * LOG_CHUNK) as u32);
+            bytes.extend_from_slice(chunk.bytes().unwrap());
+        }
+        assert!((chunks.last().unwrap().length as usize) < LOG_CHUNK);
+        assert_eq!(bytes, frames);
+
+        // a part again, in whole chunks
+        let part = download(&mut log, 0, 100, 40);
+        assert_eq!(
+            part.iter().map(|chunk| chunk.offset).collect::<Vec<_>>(),
+            [100, 132]
+        );
+        assert_eq!(part[1].bytes().unwrap(), &frames[132..164]);
+        let ends = |chunks: Vec<LogChunk>| {
+            chunks
+                .iter()
+                .map(|chunk| (chunk.offset, chunk.length))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ends(download(&mut log, 0, size + 10, 40)), [(size + 10, 0)]);
+        assert_eq!(ends(download(&mut log, 7, 0, 40)), [(0, 0)]);
+
+        // the chunks tell the session from an earlier one of the same number
+        let newest = download(&mut log, 1, 0, 40);
+        assert!(!newest.is_empty());
+        assert!(
+            newest.iter().all(|chunk| chunk.started == 500),
+            "{newest:?}"
+        );
+    }
+}
@@ -1,6 +1,25 @@
+use crate::config::{EraseSector, FLASH_SIZE, SECTOR_SIZE};
+use core::ptr::{read_volatile, write_volatile};
 use log::disk::DiskError;
 use log::Disk;
 use tudelft_quadrupel::flash::FlashError;
+
+// `tudelft_quadrupel` only erases the whole chip, the sector erase talks to the flash on the SPI1
+// peripheral it set up, see the reference manual of the nrf51.
+const SPI1_EVENTS_READY: *mut u32 = 0x4000_4108 as *mut u32;
+const SPI1_RXD: *const u32 = 0x4000_4518 as *const u32;
+const SPI1_TXD: *mut u32 = 0x4000_451c as *mut u32;
+const GPIO_OUTSET: *mut u32 = 0x5000_0508 as *mut u32;
+const GPIO_OUTCLR: *mut u32 = 0x5000_050c as *mut u32;
+/// Pin of the chip select of the flash, low during a command.
+const FLASH_CS: u32 = 17;
+
+const WRITE_ENABLE: u8 = 0x06;
+const READ_STATUS: u8 = 0x05;
+/// Erases the 4 KiB sector holding the 24 bit address that follows.
+const SECTOR_ERASE: u8 = 0x20;
+/// Status bit set while an erase is running.
+const BUSY: u8 = 0x01;
 // use log::
 pub struct FuncDisk {
     write: fn(u32, &[u8]) -> Result<(), FlashError>,
@@ -8,6 +27,8 @@ pub struct FuncDisk {
     erase: fn() -> Result<(), FlashError>,
     write_one_byte: fn(u32, u8) -> Result<(), FlashError>,
     read_one_byte: fn(u32) -> Result<u8, FlashError>,
+    /// Whether a sector erase may still be running.
+    erasing: bool,
 }
 
 impl FuncDisk {
@@ -24,12 +45,22 @@ impl FuncDisk {
             erase: erase,
             write_one_byte: write_one_byte,
             read_one_byte: read_one_byte,
+            erasing: false,
+        }
+    }
+
+    /// Waits for a sector erase to finish, the flash ignores everything else until then.
+    fn wait(&mut self) {
+        if self.erasing {
+            while flash_command(&[READ_STATUS, 0]) & BUSY != 0 {}
+            self.erasing = false;
         }
     }
 }
 
 impl Disk for FuncDisk {
     fn write_bytes(&mut self, address: u32, bytes: &[u8]) -> Result<(), DiskError> {
+        self.wait();
         match (self.write)(address, bytes) {
             Ok(()) => Ok(()),
             Err(FlashError::OutOfSpace) => Err(DiskError::OutOfSpace),
@@ -38,6 +69,7 @@ impl Disk for FuncDisk {
     }
 
     fn read_bytes(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), DiskError> {
+        self.wait();
         match (self.read)(address, buffer) {
             Ok(()) => Ok(()),
             Err(FlashError::OutOfSpace) => Err(DiskError::OutOfSpace),
@@ -46,6 +78,7 @@ impl Disk for FuncDisk {
     }
 
     fn erase_data(&mut self) -> Result<(), DiskError> {
+        self.wait();
         match (self.erase)() {
             Ok(()) => Ok(()),
             Err(_) => Err(DiskError::SpiError),
@@ -53,6 +86,7 @@ impl Disk for FuncDisk {
     }
 
     fn write_byte(&mut self, address: u32, byte: u8) -> Result<(), DiskError> {
+        self.wait();
         match (self.write_one_byte)(address, byte) {
             Ok(()) => Ok(()),
             Err(FlashError::OutOfSpace) => Err(DiskError::OutOfSpace),
@@ -61,6 +95,7 @@ impl Disk for FuncDisk {
     }
 
     fn read_byte(&mut self, address: u32) -> Result<u8, DiskError> {
+        self.wait();
         match (self.read_one_byte)(address) {
             Ok(read_byte) => Ok(read_byte),
             Err(FlashError::OutOfSpace) => Err(DiskError::OutOfSpace),
@@ -68,3 +103,49 @@ impl Disk for FuncDisk {
         }
     }
 }
+
+/// Sends `command` to the flash with the chip selected and returns the last byte it sent back.
+fn flash_command(command: &[u8]) -> u8 {
+    let mut received = 0;
+    // SAFETY: the registers exist on the nrf51 and the flash functions of `tudelft_quadrupel`,
+    // the only other user of SPI1, do not run at the same time.
+    unsafe {
+        write_volatile(GPIO_OUTCLR, 1 << FLASH_CS);
+        for &byte in command {
+            write_volatile(SPI1_EVENTS_READY, 0);
+            write_volatile(SPI1_TXD, byte as u32);
+            while read_volatile(SPI1_EVENTS_READY) == 0 {}
+            received = read_volatile(SPI1_RXD) as u8;
+        }
+        write_volatile(GPIO_OUTSET, 1 << FLASH_CS);
+    }
+    received
+}
+
+impl EraseSector for FuncDisk {
+    /// Blocks until the flash is done, which takes about 25 ms.
+    fn erase_sector(&mut self, address: u32) -> Result<(), DiskError> {
+        self.start_erase(address)?;
+        self.wait();
+        Ok(())
+    }
+
+    fn start_erase(&mut self, address: u32) -> Result<(), DiskError> {
+        if address % SECTOR_SIZE != 0 || address >= FLASH_SIZE {
+            return Err(DiskError::OutOfSpace);
+        }
+        self.wait();
+        let [_, high, middle, low] = address.to_be_bytes();
+        flash_command(&[WRITE_ENABLE]);
+        flash_command(&[SECTOR_ERASE, high, middle, low]);
+        self.erasing = true;
+        Ok(())
+    }
+
+    fn is_busy(&mut self) -> Result<bool, DiskError> {
+        if self.erasing && flash_command(&[READ_STATUS, 0]) & BUSY == 0 {
+            self.erasing = false;
+        }
+        Ok(self.erasing)
+    }
+}
@@ -0,0 +1,220 @@
+use crate::altitude::Wide;
+use crate::mahony_filter::ACCEL_SCALE;
+use architecture::angle::wrap;
+use architecture::{Accel, Frac, Radians, RadiansPerSecond, Velocity};
+
+/// Largest rate that still counts as standing still, rad/s.
+const MAX_REST_RATE: Wide = Wide::lit("0.05");
+/// Largest difference of the acceleration from 1 g that still counts as standing still, g.
+const MAX_REST_ACCEL: Wide = Wide::lit("0.05");
+/// How long the drone has to stand still before the bias follows the rates, s.
+const SETTLE_TIME: Wide = Wide::lit("0.5");
+/// Time constant of the bias estimate at rest, s.
+const TIME_CONSTANT: Wide = Wide::lit("2");
+
+/// Tracks the gyro bias left after the calibration and integrates the yaw rate to a heading.
+///
+/// The offsets of the calibration drift with the temperature. Whenever the drone is disarmed
+/// and stands still the mean rate is the bias, so the estimate follows it then. In flight
+/// nothing measures the yaw bias and the last estimate is kept; the Kalman filter hands the
+/// pitch and roll bias it measures over with [`GyroBias::absorb`].
+#[derive(Debug, Clone, Copy)]
+pub struct GyroBias {
+    /// yaw, pitch, roll in rad/s
+    bias: [Wide; 3],
+    /// rad
+    heading: Wide,
+    /// s
+    still_for: Wide,
+}
+
+impl GyroBias {
+    pub fn new() -> Self {
+        GyroBias {
+            bias: [Wide::ZERO; 3],
+            heading: Wide::ZERO,
+            still_for: Wide::ZERO,
+        }
+    }
+
+    /// Estimated bias.
+    pub fn bias(&self) -> Velocity {
+        let [yaw, pitch, roll] = self.bias.map(rate);
+        Velocity { yaw, pitch, roll }
+    }
+
+    /// Integrated yaw, from -pi up to pi.
+    pub fn heading(&self) -> Radians {
+        Radians(Frac::saturating_from_num(self.heading))
+    }
+
+    /// Adds `residual`, the bias an attitude estimator found in the corrected rates, so this
+    /// estimate stays the only one removed from the rates.
+    pub fn absorb(&mut self, residual: Velocity) {
+        let residual = [residual.yaw, residual.pitch, residual.roll];
+        for (bias, residual) in self.bias.iter_mut().zip(residual) {
+            *bias += Wide::from_num(residual.0);
+        }
+    }
+
+    /// Whether the drone stood still long enough for the bias to follow the rates.
+    pub fn is_at_rest(&self) -> bool {
+        self.still_for >= SETTLE_TIME
+    }
+
+    /// Removes the bias from the rates `velocity` sampled at `frequency` Hz and integrates the
+    /// heading. The bias is only adapted if `disarmed` and the drone stands still, judged by
+    /// the rates and the acceleration `accel` including gravity.
+    pub fn update(
+        &mut self,
+        velocity: &mut Velocity,
+        accel: Accel,
+        disarmed: bool,
+        frequency: u64,
+    ) {
+        let dt = Wide::ONE / Wide::from_num(frequency.max(1));
+        let rates =
+            [velocity.yaw, velocity.pitch, velocity.roll].map(|rate| Wide::from_num(rate.0));
+
+        let still = disarmed
+            && is_still(accel)
+            && rates
+                .iter()
+                .zip(&self.bias)
+                .all(|(rate, bias)| (*rate - *bias).abs() < MAX_REST_RATE);
+        self.still_for = if still {
+            (self.still_for + dt).min(SETTLE_TIME)
+        } else {
+            Wide::ZERO
+        };
+        if self.is_at_rest() {
+            let alpha = dt / TIME_CONSTANT;
+            for (bias, rate) in self.bias.iter_mut().zip(rates) {
+                *bias += (rate - *bias) * alpha;
+            }
+        }
+
+        let [yaw, pitch, roll] = [0, 1, 2].map(|i| rates[i] - self.bias[i]);
+        *velocity = Velocity {
+            yaw: rate(yaw),
+            pitch: rate(pitch),
+            roll: rate(roll),
+        };
+        self.heading = wrap(self.heading + yaw * dt);
+    }
+}
+
+fn rate(rad_per_s: Wide) -> RadiansPerSecond {
+    RadiansPerSecond(Frac::saturating_from_num(rad_per_s))
+}
+
+/// Whether the acceleration is gravity alone, in any orientation.
+fn is_still(accel: Accel) -> bool {
+    let squared: Wide = [accel.x, accel.y, accel.z]
+        .iter()
+        .map(|a| {
+            let g = Wide::from_num(*a) / ACCEL_SCALE as i64;
+            g * g
+        })
+        .sum();
+    let low = Wide::ONE - MAX_REST_ACCEL;
+    let high = Wide::ONE + MAX_REST_ACCEL;
+    squared > low * low && squared < high * high
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FREQUENCY: u64 = 150;
+    const LEVEL: Accel = Accel {
+        x: 0,
+        y: 0,
+        z: ACCEL_SCALE,
+    };
+
+    fn rates(yaw: f64, pitch: f64, roll: f64) -> Velocity {
+        let unit = |rad_per_s: f64| RadiansPerSecond(Frac::from_num(rad_per_s));
+        Velocity {
+            yaw: unit(yaw),
+            pitch: unit(pitch),
+            roll: unit(roll),
+        }
+    }
+
+    fn run(bias: &mut GyroBias, rate: Velocity, accel: Accel, disarmed: bool, seconds: u64) {
+        for _ in 0..seconds * FREQUENCY {
+            let mut velocity = rate;
+            bias.update(&mut velocity, accel, disarmed, FREQUENCY);
+        }
+    }
+
+    #[test]
+    fn follows_the_bias_at_rest() {
+        let mut bias = GyroBias::new();
+        run(&mut bias, rates(0.02, -0.01, 0.005), LEVEL, true, 20);
+        assert!(bias.is_at_rest());
+        let estimate = bias.bias();
+        assert!((estimate.yaw.0.to_num::<f64>() - 0.02).abs() < 1e-4);
+        assert!((estimate.pitch.0.to_num::<f64>() + 0.01).abs() < 1e-4);
+        assert!((estimate.roll.0.to_num::<f64>() - 0.005).abs() < 1e-4);
+
+        let mut velocity = rates(0.02, -0.01, 0.005);
+        bias.update(&mut velocity, LEVEL, true, FREQUENCY);
+        assert!(velocity.yaw.abs() < RadiansPerSecond(Frac::from_num(1e-3)));
+    }
+
+    #[test]
+    fn keeps_the_bias_when_armed_or_moving() {
+        let mut bias = GyroBias::new();
+        run(&mut bias, rates(0.02, 0.0, 0.0), LEVEL, false, 10);
+        assert_eq!(bias.bias().yaw, RadiansPerSecond::ZERO);
+
+        run(&mut bias, rates(0.5, 0.0, 0.0), LEVEL, true, 10);
+        assert_eq!(bias.bias().yaw, RadiansPerSecond::ZERO);
+
+        let lifted = Accel {
+            z: ACCEL_SCALE * 13 / 10,
+            ..LEVEL
+        };
+        run(&mut bias, rates(0.02, 0.0, 0.0), lifted, true, 10);
+        assert_eq!(bias.bias().yaw, RadiansPerSecond::ZERO);
+    }
+
+    #[test]
+    fn heading_integrates_the_yaw_rate_and_wraps() {
+        let mut bias = GyroBias::new();
+        run(&mut bias, rates(1.0, 0.0, 0.0), LEVEL, false, 1);
+        assert!((bias.heading().0.to_num::<f64>() - 1.0).abs() < 1e-3);
+        run(&mut bias, rates(1.0, 0.0, 0.0), LEVEL, false, 3);
+        // 4 rad is 4 - 2 pi
+        assert!((bias.heading().0.to_num::<f64>() + 2.2832).abs() < 1e-3);
+    }
+
+    #[test]
+    fn absorbed_residuals_add_to_the_bias() {
+        let mut bias = GyroBias::new();
+        run(&mut bias, rates(0.02, 0.0, 0.0), LEVEL, true, 20);
+        bias.absorb(rates(0.0, 0.01, -0.004));
+        let estimate = bias.bias();
+        assert!((estimate.yaw.0.to_num::<f64>() - 0.02).abs() < 1e-4);
+        assert!((estimate.pitch.0.to_num::<f64>() - 0.01).abs() < 1e-4);
+        assert!((estimate.roll.0.to_num::<f64>() + 0.004).abs() < 1e-4);
+        let mut velocity = rates(0.02, 0.01, -0.004);
+        bias.update(&mut velocity, LEVEL, false, FREQUENCY);
+        let small = RadiansPerSecond(Frac::from_num(1e-3));
+        assert!(velocity.pitch.abs() < small && velocity.roll.abs() < small);
+    }
+
+    #[test]
+    fn heading_stays_put_for_minutes_once_the_bias_is_known() {
+        let mut bias = GyroBias::new();
+        let drift = rates(0.03, 0.0, 0.0);
+        run(&mut bias, drift, LEVEL, true, 20);
+        let start = bias.heading();
+        run(&mut bias, drift, LEVEL, false, 300);
+        let moved = (bias.heading() - start).abs().0.to_num::<f64>();
+        // without the estimate it would have turned 9 rad
+        assert!(moved < 0.05, "{moved}");
+    }
+}
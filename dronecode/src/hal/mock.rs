@@ -0,0 +1,292 @@
+//! A host implementation of the hardware traits.
+//!
+//! Sensor values are plain fields that a test or simulator sets before each tick, and
+//! everything the flight code writes (motors, leds, uart) can be inspected afterwards.
+//! The uart is a pair of buffers per thread, [`host_link`] is the pc side of it.
+
+use crate::config::{EraseSector, SECTOR_SIZE};
+use crate::hal::{
+    Barometer, Battery, Clock, DmpQuaternion, Imu, ImuError, Led, Leds, Motors, RawVec, Uart,
+};
+use architecture::Frac;
+use core::cell::RefCell;
+use log::disk::DiskError;
+use log::Disk;
+use protocol::{FuncLink, MessageLink};
+use std::collections::VecDeque;
+use std::thread::LocalKey;
+use std::vec;
+use std::vec::Vec;
+
+std::thread_local! {
+    static UART_RX: RefCell<VecDeque<u8>> = RefCell::new(VecDeque::new());
+    static UART_TX: RefCell<VecDeque<u8>> = RefCell::new(VecDeque::new());
+}
+
+fn pop_into(buffer: &'static LocalKey<RefCell<VecDeque<u8>>>, bytes: &mut [u8]) -> usize {
+    buffer.with_borrow_mut(|buffer| {
+        let n = bytes.len().min(buffer.len());
+        for (b, r) in bytes.iter_mut().zip(buffer.drain(..n)) {
+            *b = r;
+        }
+        n
+    })
+}
+
+fn host_send(bytes: &[u8]) -> bool {
+    MockHal::push_rx(bytes);
+    true
+}
+
+fn host_receive(bytes: &mut [u8]) -> usize {
+    pop_into(&UART_TX, bytes)
+}
+
+/// The pc end of the mock uart: what is sent here is received by the drone and vice versa.
+pub fn host_link() -> MessageLink<FuncLink> {
+    MessageLink::new(FuncLink::from_func(host_send, host_receive))
+}
+
+pub struct MockHal {
+    pub motors: [u16; 4],
+    pub motor_max: u16,
+    pub accel: RawVec,
+    pub gyro: RawVec,
+    /// The next quaternion returned by the DMP, `None` while it is still "computing".
+    pub dmp: Option<DmpQuaternion>,
+    pub imu_error: Option<ImuError>,
+    pub pressure: u32,
+    pub battery: u16,
+    /// On/off state of the red, yellow, green and blue led.
+    pub leds: [bool; 4],
+    pub tick_frequency: u64,
+    pub ticks: u64,
+    pub time_ns: u64,
+}
+
+impl MockHal {
+    pub fn new() -> Self {
+        MockHal {
+            motors: [0; 4],
+            motor_max: 1000,
+            accel: RawVec { x: 0, y: 0, z: 0 },
+            gyro: RawVec { x: 0, y: 0, z: 0 },
+            dmp: Some(DmpQuaternion {
+                w: Frac::from_num(1),
+                x: Frac::from_num(0),
+                y: Frac::from_num(0),
+                z: Frac::from_num(0),
+            }),
+            imu_error: None,
+            pressure: 100000,
+            battery: 1150,
+            leds: [false; 4],
+            tick_frequency: 100,
+            ticks: 0,
+            time_ns: 0,
+        }
+    }
+
+    /// Queues bytes to be received by the drone.
+    pub fn push_rx(bytes: &[u8]) {
+        UART_RX.with_borrow_mut(|rx| rx.extend(bytes));
+    }
+
+    /// Takes all bytes the drone has sent so far.
+    pub fn take_tx() -> Vec<u8> {
+        UART_TX.with_borrow_mut(|tx| tx.drain(..).collect())
+    }
+
+    pub fn led(&self, led: Led) -> bool {
+        self.leds[led as usize]
+    }
+}
+
+impl Motors for MockHal {
+    fn set_motors(&mut self, speeds: [u16; 4]) {
+        self.motors = speeds.map(|s| s.min(self.motor_max));
+    }
+
+    fn get_motors(&self) -> [u16; 4] {
+        self.motors
+    }
+
+    fn set_motor_max(&mut self, max: u16) {
+        self.motor_max = max;
+    }
+}
+
+impl Imu for MockHal {
+    fn read_raw(&mut self) -> Result<(RawVec, RawVec), ImuError> {
+        match self.imu_error {
+            Some(e) => Err(e),
+            None => Ok((self.accel, self.gyro)),
+        }
+    }
+
+    fn read_dmp(&mut self) -> Result<Option<DmpQuaternion>, ImuError> {
+        match self.imu_error {
+            Some(e) => Err(e),
+            None => Ok(self.dmp),
+        }
+    }
+}
+
+impl Barometer for MockHal {
+    fn read_pressure(&mut self) -> u32 {
+        self.pressure
+    }
+}
+
+impl Battery for MockHal {
+    fn read_battery(&mut self) -> u16 {
+        self.battery
+    }
+}
+
+impl Leds for MockHal {
+    fn led_on(&mut self, led: Led) {
+        self.leds[led as usize] = true;
+    }
+
+    fn led_off(&mut self, led: Led) {
+        self.leds[led as usize] = false;
+    }
+
+    fn led_toggle(&mut self, led: Led) {
+        self.leds[led as usize] = !self.leds[led as usize];
+    }
+}
+
+impl Clock for MockHal {
+    fn set_tick_frequency(&mut self, frequency: u64) {
+        self.tick_frequency = frequency;
+    }
+
+    fn wait_for_next_tick(&mut self) {
+        self.ticks += 1;
+        self.time_ns += 1_000_000_000 / self.tick_frequency;
+    }
+
+    fn assembly_delay(&mut self, cycles: usize) {
+        // the nrf51 runs at 16 MHz, one loop iteration is roughly one cycle
+        self.time_ns += cycles as u64 * 1_000 / 16;
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.time_ns += ms as u64 * 1_000_000;
+    }
+
+    fn now_ns(&self) -> u64 {
+        self.time_ns
+    }
+}
+
+impl Uart for MockHal {
+    fn send_bytes(bytes: &[u8]) -> bool {
+        UART_TX.with_borrow_mut(|tx| tx.extend(bytes));
+        true
+    }
+
+    fn receive_bytes(bytes: &mut [u8]) -> usize {
+        pop_into(&UART_RX, bytes)
+    }
+}
+
+/// A flash chip in memory. Like on the real chip, erased bytes read as `0xff` and writing
+/// can only clear bits.
+pub struct RamDisk {
+    pub data: Vec<u8>,
+    /// Sectors erased so far.
+    pub erases: u32,
+    /// Times an operation had to wait for an erase to finish.
+    pub waits: u32,
+    /// Polls of [`EraseSector::is_busy`] an erase from [`EraseSector::start_erase`] takes.
+    pub erase_polls: u32,
+    /// Polls left of the running erase.
+    busy: u32,
+}
+
+impl RamDisk {
+    pub fn new(size: usize) -> Self {
+        RamDisk {
+            data: vec![0xff; size],
+            erases: 0,
+            waits: 0,
+            erase_polls: 0,
+            busy: 0,
+        }
+    }
+
+    fn wait(&mut self) {
+        if self.busy > 0 {
+            self.busy = 0;
+            self.waits += 1;
+        }
+    }
+
+    fn range(&self, address: u32, len: usize) -> Result<core::ops::Range<usize>, DiskError> {
+        let start = address as usize;
+        if start + len > self.data.len() {
+            return Err(DiskError::OutOfSpace);
+        }
+        Ok(start..start + len)
+    }
+}
+
+impl Disk for RamDisk {
+    fn write_bytes(&mut self, address: u32, bytes: &[u8]) -> Result<(), DiskError> {
+        self.wait();
+        let range = self.range(address, bytes.len())?;
+        for (old, new) in self.data[range].iter_mut().zip(bytes) {
+            *old &= new;
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), DiskError> {
+        self.wait();
+        let range = self.range(address, buffer.len())?;
+        buffer.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn erase_data(&mut self) -> Result<(), DiskError> {
+        self.wait();
+        self.data.fill(0xff);
+        Ok(())
+    }
+
+    fn write_byte(&mut self, address: u32, byte: u8) -> Result<(), DiskError> {
+        self.write_bytes(address, &[byte])
+    }
+
+    fn read_byte(&mut self, address: u32) -> Result<u8, DiskError> {
+        let mut byte = [0];
+        self.read_bytes(address, &mut byte)?;
+        Ok(byte[0])
+    }
+}
+
+impl EraseSector for RamDisk {
+    fn erase_sector(&mut self, address: u32) -> Result<(), DiskError> {
+        self.wait();
+        let range = self.range(address, SECTOR_SIZE as usize)?;
+        self.data[range].fill(0xff);
+        self.erases += 1;
+        Ok(())
+    }
+
+    /// Erases at once, but reports busy for [`Self::erase_polls`] polls.
+    fn start_erase(&mut self, address: u32) -> Result<(), DiskError> {
+        self.erase_sector(address)?;
+        self.busy = self.erase_polls;
+        Ok(())
+    }
+
+    fn is_busy(&mut self) -> Result<bool, DiskError> {
+        let busy = self.busy > 0;
+        self.busy = self.busy.saturating_sub(1);
+        Ok(busy)
+    }
+}
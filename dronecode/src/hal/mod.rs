@@ -0,0 +1,102 @@
+//! Hardware abstraction for the flight code.
+//!
+//! Everything the drone code needs from the board goes through the traits in this module,
+//! so the control loop, controller, sensor pipeline and message handling can run on the
+//! quadrupel ([`quadrupel::Quadrupel`]) as well as on a host machine ([`mock::MockHal`]).
+
+use architecture::Frac;
+use protocol::{FuncLink, MessageLink};
+
+#[cfg(feature = "std")]
+pub mod mock;
+#[cfg(feature = "quadrupel")]
+pub mod quadrupel;
+
+/// The leds available on the board.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Led {
+    Red,
+    Yellow,
+    Green,
+    Blue,
+}
+
+/// One raw reading of a three axis sensor, in sensor LSB counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RawVec {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+}
+
+/// The attitude quaternion computed by the DMP of the MPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmpQuaternion {
+    pub w: Frac,
+    pub x: Frac,
+    pub y: Frac,
+    pub z: Frac,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImuError {
+    /// The sensor could not be reached over the bus.
+    Bus,
+}
+
+pub trait Motors {
+    fn set_motors(&mut self, speeds: [u16; 4]);
+    fn get_motors(&self) -> [u16; 4];
+    fn set_motor_max(&mut self, max: u16);
+}
+
+pub trait Imu {
+    /// Returns the raw accelerometer and gyroscope readings.
+    fn read_raw(&mut self) -> Result<(RawVec, RawVec), ImuError>;
+    /// Returns the next quaternion from the DMP, or `None` if it has not produced one yet.
+    fn read_dmp(&mut self) -> Result<Option<DmpQuaternion>, ImuError>;
+}
+
+pub trait Barometer {
+    /// Returns the pressure in Pa.
+    fn read_pressure(&mut self) -> u32;
+}
+
+pub trait Battery {
+    /// Returns the battery voltage in units of 10 mV.
+    fn read_battery(&mut self) -> u16;
+}
+
+pub trait Leds {
+    fn led_on(&mut self, led: Led);
+    fn led_off(&mut self, led: Led);
+    fn led_toggle(&mut self, led: Led);
+}
+
+pub trait Clock {
+    fn set_tick_frequency(&mut self, frequency: u64);
+    fn wait_for_next_tick(&mut self);
+    /// Busy waits for roughly `cycles` iterations of a spin loop.
+    fn assembly_delay(&mut self, cycles: usize);
+    fn delay_ms(&mut self, ms: u32);
+    /// Nanoseconds since the board started.
+    fn now_ns(&self) -> u64;
+}
+
+/// The serial connection to the pc.
+///
+/// These are associated functions instead of methods because [`FuncLink`] stores plain
+/// function pointers.
+pub trait Uart {
+    fn send_bytes(bytes: &[u8]) -> bool;
+    fn receive_bytes(bytes: &mut [u8]) -> usize;
+
+    fn link() -> MessageLink<FuncLink> {
+        MessageLink::new(FuncLink::from_func(Self::send_bytes, Self::receive_bytes))
+    }
+}
+
+/// Everything the control loop needs from the board.
+pub trait Hal: Motors + Imu + Barometer + Battery + Leds + Clock + Uart {}
+
+impl<T: Motors + Imu + Barometer + Battery + Leds + Clock + Uart> Hal for T {}
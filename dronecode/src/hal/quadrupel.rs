@@ -0,0 +1,124 @@
+use crate::hal::{
+    Barometer, Battery, Clock, DmpQuaternion, Imu, ImuError, Led, Leds, Motors, RawVec, Uart,
+};
+use architecture::Frac;
+use tudelft_quadrupel::led::Led as BoardLed;
+use tudelft_quadrupel::{barometer, battery, motor, mpu, time, uart};
+
+/// The real hardware. All peripherals are global in `tudelft_quadrupel`, so this is just a marker.
+#[derive(Debug, Clone, Copy)]
+pub struct Quadrupel;
+
+fn board_led(led: Led) -> BoardLed {
+    match led {
+        Led::Red => BoardLed::Red,
+        Led::Yellow => BoardLed::Yellow,
+        Led::Green => BoardLed::Green,
+        Led::Blue => BoardLed::Blue,
+    }
+}
+
+impl Motors for Quadrupel {
+    fn set_motors(&mut self, speeds: [u16; 4]) {
+        motor::set_motors(speeds);
+    }
+
+    fn get_motors(&self) -> [u16; 4] {
+        motor::get_motors()
+    }
+
+    fn set_motor_max(&mut self, max: u16) {
+        motor::set_motor_max(max);
+    }
+}
+
+impl Imu for Quadrupel {
+    fn read_raw(&mut self) -> Result<(RawVec, RawVec), ImuError> {
+        match mpu::read_raw() {
+            Ok((accel, gyro)) => Ok((
+                RawVec {
+                    x: accel.x,
+                    y: accel.y,
+                    z: accel.z,
+                },
+                RawVec {
+                    x: gyro.x,
+                    y: gyro.y,
+                    z: gyro.z,
+                },
+            )),
+            Err(_) => Err(ImuError::Bus),
+        }
+    }
+
+    fn read_dmp(&mut self) -> Result<Option<DmpQuaternion>, ImuError> {
+        match mpu::read_dmp_bytes() {
+            Ok(q) => Ok(Some(DmpQuaternion {
+                w: Frac::from_num(q.w),
+                x: Frac::from_num(q.x),
+                y: Frac::from_num(q.y),
+                z: Frac::from_num(q.z),
+            })),
+            Err(nb::Error::WouldBlock) => Ok(None),
+            Err(nb::Error::Other(_)) => Err(ImuError::Bus),
+        }
+    }
+}
+
+impl Barometer for Quadrupel {
+    fn read_pressure(&mut self) -> u32 {
+        barometer::read_pressure()
+    }
+}
+
+impl Battery for Quadrupel {
+    fn read_battery(&mut self) -> u16 {
+        battery::read_battery()
+    }
+}
+
+impl Leds for Quadrupel {
+    fn led_on(&mut self, led: Led) {
+        board_led(led).on();
+    }
+
+    fn led_off(&mut self, led: Led) {
+        board_led(led).off();
+    }
+
+    fn led_toggle(&mut self, led: Led) {
+        let _ = board_led(led).toggle();
+    }
+}
+
+impl Clock for Quadrupel {
+    fn set_tick_frequency(&mut self, frequency: u64) {
+        time::set_tick_frequency(frequency);
+    }
+
+    fn wait_for_next_tick(&mut self) {
+        time::wait_for_next_tick();
+    }
+
+    fn assembly_delay(&mut self, cycles: usize) {
+        time::assembly_delay(cycles);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        time::delay_ms_assembly(ms);
+    }
+
+    fn now_ns(&self) -> u64 {
+        time::Instant::now().ns_since_start()
+    }
+}
+
+impl Uart for Quadrupel {
+    fn send_bytes(bytes: &[u8]) -> bool {
+        uart::send_bytes(bytes)
+    }
+
+    fn receive_bytes(bytes: &mut [u8]) -> usize {
+        uart::receive_bytes(bytes)
+    }
+}
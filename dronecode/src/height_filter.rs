@@ -0,0 +1,140 @@
+use crate::altitude::Wide;
+use architecture::{
+    Accel, Attitude, Frac, Metres, MetresPerSecond, MetresPerSecondSquared, Seconds,
+    STANDARD_GRAVITY,
+};
+use fixed_trigonometry::*;
+
+pub const GRAVITY: MetresPerSecondSquared = MetresPerSecondSquared(STANDARD_GRAVITY);
+
+/// Third order complementary filter fusing the barometric height with the vertical
+/// acceleration.
+///
+/// The barometer is slow and noisy but does not drift, the accelerometer is fast but has to be
+/// integrated twice. The filter follows the accelerometer at high frequencies and the barometer
+/// at low ones, and estimates the offset of the accelerometer on the way. With all three poles
+/// at `omega` the gains are `k1 = 3 omega`, `k2 = 3 omega^2` and `k3 = omega^3`.
+#[derive(Debug, Clone, Copy)]
+pub struct HeightFilter {
+    pub height: Metres,
+    /// Positive up.
+    pub v_z: MetresPerSecond,
+    /// Correction of the vertical acceleration.
+    pub accel_offset: MetresPerSecondSquared,
+    k1: Frac,
+    k2: Frac,
+    k3: Frac,
+}
+
+impl HeightFilter {
+    /// A filter with all poles at `omega` rad/s, starting at height 0.
+    pub fn new(omega: Frac) -> Self {
+        HeightFilter {
+            height: Metres::ZERO,
+            v_z: MetresPerSecond::ZERO,
+            accel_offset: MetresPerSecondSquared::ZERO,
+            k1: omega * 3,
+            k2: omega * omega * 3,
+            k3: omega * omega * omega,
+        }
+    }
+
+    /// Acceleration along the world z axis without gravity.
+    ///
+    /// `acceleration` is in counts in the frame of the MPU, `gravity_scale` the counts per
+    /// m/s^2 and `attitude` the pitch and roll of the drone. Zero without a scale of at least
+    /// one count per m/s^2, before the calibration. The counts of a corrected reading can
+    /// exceed the range of the raw ones, so they are scaled in a [`Wide`] and saturated.
+    pub fn vertical_acceleration(
+        acceleration: Accel,
+        gravity_scale: Frac,
+        attitude: Attitude,
+    ) -> MetresPerSecondSquared {
+        if gravity_scale < Frac::ONE {
+            return MetresPerSecondSquared::ZERO;
+        }
+        let wide = |f: Frac| Wide::from_num(f);
+        let (pitch, roll) = (attitude.pitch.0, attitude.roll.0);
+        let (sin_pitch, cos_pitch) = (wide(sin(pitch)), wide(cos(pitch)));
+        let (sin_roll, cos_roll) = (wide(sin(roll)), wide(cos(roll)));
+        let [x, y, z] = [acceleration.x, acceleration.y, acceleration.z]
+            .map(|counts| Wide::from_num(counts) / wide(gravity_scale));
+        // up in the body frame is (sin pitch, cos pitch sin roll, cos pitch cos roll)
+        let level = sin_roll
+            .saturating_mul(y)
+            .saturating_add(cos_roll.saturating_mul(z));
+        let up = sin_pitch
+            .saturating_mul(x)
+            .saturating_add(cos_pitch.saturating_mul(level));
+        MetresPerSecondSquared(Frac::saturating_from_num(up)) - GRAVITY
+    }
+
+    /// Advances the filter by `dt` with the vertical acceleration `accel_z` and the height from
+    /// the barometer.
+    pub fn update(&mut self, accel_z: MetresPerSecondSquared, baro_height: Metres, dt: Seconds) {
+        // the gains turn the height error into the unit of each state
+        let error = (baro_height - self.height).0;
+        self.accel_offset += MetresPerSecondSquared(self.k3 * error * dt.0);
+        self.v_z += (accel_z + self.accel_offset + MetresPerSecondSquared(self.k2 * error)) * dt;
+        self.height += (self.v_z + MetresPerSecond(self.k1 * error)) * dt;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use architecture::Radians;
+
+    const FREQUENCY: u32 = 150;
+    /// Counts per m/s^2 of the 2 g range.
+    const SCALE: Frac = Frac::lit("1670.7");
+
+    fn level() -> Attitude {
+        Attitude::new()
+    }
+
+    #[test]
+    fn height_and_speed_follow_a_barometer_step() {
+        let mut filter = HeightFilter::new(Frac::from_num(1));
+        let dt = Seconds::per_tick(FREQUENCY as u64);
+        let step = Metres(Frac::from_num(1));
+        let mut highest = Metres::ZERO;
+        for _ in 0..10 * FREQUENCY {
+            filter.update(MetresPerSecondSquared::ZERO, step, dt);
+            highest = highest.max(filter.height);
+            assert!(filter.v_z >= MetresPerSecond(Frac::from_num(-0.1)));
+        }
+        // the zeros of the filter overshoot about a fifth
+        assert!(highest < Metres(Frac::from_num(1.25)), "{highest:?}");
+        assert!((filter.height - step).abs() < Metres(Frac::from_num(0.05)));
+        assert!(filter.v_z.abs() < MetresPerSecond(Frac::from_num(0.05)));
+    }
+
+    #[test]
+    fn standing_still_is_no_vertical_acceleration() {
+        let z = (SCALE * STANDARD_GRAVITY).to_num::<i32>();
+        let accel = HeightFilter::vertical_acceleration(Accel { x: 0, y: 0, z }, SCALE, level());
+        assert!(accel.abs() < MetresPerSecondSquared(Frac::from_num(0.01)));
+    }
+
+    #[test]
+    fn extreme_counts_saturate() {
+        let extreme = Accel {
+            x: i32::MAX,
+            y: i32::MIN,
+            z: i32::MAX,
+        };
+        let tilted = Attitude {
+            yaw: Radians::ZERO,
+            pitch: Radians(Frac::from_num(0.3)),
+            roll: Radians(Frac::from_num(-0.3)),
+        };
+        let accel = HeightFilter::vertical_acceleration(extreme, SCALE, tilted);
+        assert!(accel > MetresPerSecondSquared::ZERO);
+        let accel = HeightFilter::vertical_acceleration(extreme, Frac::ONE, level());
+        assert!(accel > MetresPerSecondSquared(Frac::from_num(30000)));
+        // before the calibration
+        let accel = HeightFilter::vertical_acceleration(extreme, Frac::ZERO, level());
+        assert_eq!(accel, MetresPerSecondSquared::ZERO);
+    }
+}
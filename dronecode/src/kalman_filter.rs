@@ -1,51 +1,342 @@
 use crate::sensor::Sensor;
 use crate::yaw_pitch_roll_quaternion::yaw_pitch_roll_from_acc;
-use architecture::{Frac, Velocity, YawPitchRoll};
+use architecture::angle::wrap;
+use architecture::{Attitude, Frac, Message, Radians, RadiansPerSecond, Velocity, YawPitchRoll};
+use fixed::types::I4F28;
 
+/// Covariances are tiny compared to the angles, so they get most of the bits.
+pub type Cov = I4F28;
+
+/// Bound of the variances and covariances. An axis without measurements only predicts, and
+/// the prediction grows them every tick.
+const MAX_VARIANCE: Cov = Cov::lit("4");
+
+/// Spectral densities of the process noise and the variance of the accelerometer angle.
+#[derive(Debug, Clone, Copy)]
+pub struct NoiseParameters {
+    /// rad^2/s
+    pub angle: Cov,
+    /// (rad/s)^2/s
+    pub bias: Cov,
+    /// rad^2
+    pub accel: Cov,
+}
+
+impl NoiseParameters {
+    pub fn new(angle: Cov, bias: Cov, accel: Cov) -> Self {
+        NoiseParameters { angle, bias, accel }
+    }
+
+    /// The noise of the MPU on the quadrupel.
+    pub fn quadrupel() -> Self {
+        NoiseParameters::new(Cov::lit("0.001"), Cov::lit("0.00003"), Cov::lit("0.01"))
+    }
+}
+
+/// Kalman filter for one axis with the angle and the gyro bias as state.
+#[derive(Debug, Clone, Copy)]
+pub struct AxisFilter {
+    pub angle: Frac,
+    pub bias: Frac,
+    p: [[Cov; 2]; 2],
+    /// Measured minus predicted angle of the last update.
+    pub innovation: Frac,
+    /// Average of the normalized innovation squared, close to 1 when the noise is tuned well.
+    pub nis: Frac,
+}
+
+impl AxisFilter {
+    pub fn new() -> Self {
+        AxisFilter {
+            angle: Frac::from_num(0),
+            bias: Frac::from_num(0),
+            p: [
+                [Cov::from_num(1), Cov::from_num(0)],
+                [Cov::from_num(0), Cov::from_num(0.01)],
+            ],
+            innovation: Frac::from_num(0),
+            nis: Frac::from_num(1),
+        }
+    }
+
+    pub fn angle_variance(&self) -> Cov {
+        self.p[0][0]
+    }
+
+    pub fn bias_variance(&self) -> Cov {
+        self.p[1][1]
+    }
+
+    /// Integrates the gyro `rate` in rad/s over `dt` seconds.
+    pub fn predict(&mut self, rate: Frac, dt: Frac, noise: &NoiseParameters) {
+        self.angle += (rate - self.bias) * dt;
+
+        // P = F P F^T + Q with F = [[1, -dt], [0, 1]]
+        let dt = Cov::from_num(dt);
+        let [[p00, p01], [p10, p11]] = self.p;
+        let p00 = p00 - dt * p01.saturating_add(p10) + dt * dt * p11 + noise.angle * dt;
+        let p01 = p01 - dt * p11;
+        let p10 = p10 - dt * p11;
+        let p11 = p11 + noise.bias * dt;
+        let bound = |p: Cov| p.clamp(-MAX_VARIANCE, MAX_VARIANCE);
+        self.p = [
+            [p00.min(MAX_VARIANCE), bound(p01)],
+            [bound(p10), p11.min(MAX_VARIANCE)],
+        ];
+    }
+
+    /// Corrects the state with a direct measurement of the angle with variance `r`.
+    pub fn update(&mut self, measured: Frac, r: Cov) {
+        let [[p00, p01], [p10, p11]] = self.p;
+        let s = p00 + r;
+        let k0 = p00 / s;
+        let k1 = p10 / s;
+
+        let y = measured - self.angle;
+        let y_precise = Cov::saturating_from_num(y);
+        self.angle += Frac::from_num(k0 * y_precise);
+        self.bias += Frac::from_num(k1 * y_precise);
+
+        self.p = [
+            [p00 - k0 * p00, p01 - k0 * p01],
+            [p10 - k1 * p00, p11 - k1 * p01],
+        ];
+
+        self.innovation = y;
+        let nis = y * y / Frac::from_num(s).max(Frac::DELTA);
+        self.nis += (nis - self.nis) / 32;
+    }
+}
+
+/// Estimates attitude and gyro bias from the raw gyro and accelerometer.
+///
+/// [`KalmanFilter::fusion_algorithm`] hands the bias over to [`Sensor::gyro_bias`] every tick,
+/// so the rates are corrected once and the state only holds what was found since.
+///
+/// Pitch and roll are corrected by the angle from the accelerometer. Yaw has no absolute
+/// reference, it is only the integrated, bias corrected gyro rate, wrapped into -pi up to pi.
 pub struct KalmanFilter {
-    acc_sphi: YawPitchRoll,
-    out_rate: Velocity,
-    out_phi: YawPitchRoll,
+    pub yaw: AxisFilter,
+    pub pitch: AxisFilter,
+    pub roll: AxisFilter,
+    pub noise: NoiseParameters,
     pub integration_constant: Frac,
-    c1: Frac,
-    c2: Frac,
 }
 
 impl KalmanFilter {
-    pub fn new(c1: Frac, c2: Frac) -> Self {
+    pub fn new(noise: NoiseParameters) -> Self {
         KalmanFilter {
-            acc_sphi: YawPitchRoll {
-                yaw: Frac::from_num(0.),
-                pitch: Frac::from_num(0.),
-                roll: Frac::from_num(0.),
-            },
-            out_rate: Velocity {
-                yaw: Frac::from_num(0.),
-                pitch: Frac::from_num(0.),
-                roll: Frac::from_num(0.),
-            },
-            out_phi: YawPitchRoll {
-                yaw: Frac::from_num(0.),
-                pitch: Frac::from_num(0.),
-                roll: Frac::from_num(0.),
-            },
+            yaw: AxisFilter::new(),
+            pitch: AxisFilter::new(),
+            roll: AxisFilter::new(),
+            noise,
             integration_constant: Frac::from_num(0.),
-            c1,
-            c2,
         }
     }
+
+    /// Continues from `attitude`, uncalibrated like the state, instead of from where the filter
+    /// was when it last ran.
+    pub fn seed(&mut self, attitude: Attitude) {
+        self.yaw.angle = attitude.yaw.0;
+        self.pitch.angle = attitude.pitch.0;
+        self.roll.angle = attitude.roll.0;
+    }
+
+    /// Estimated gyro bias.
+    pub fn bias(&self) -> Velocity {
+        Velocity {
+            yaw: RadiansPerSecond(self.yaw.bias),
+            pitch: RadiansPerSecond(self.pitch.bias),
+            roll: RadiansPerSecond(self.roll.bias),
+        }
+    }
+
     pub fn fusion_algorithm(&mut self, sensor: &mut Sensor) {
-        self.acc_sphi = yaw_pitch_roll_from_acc(
+        let acc_sphi = yaw_pitch_roll_from_acc(
             sensor.data.acceleration + sensor.calibrate_offset.acceleration,
         );
-        self.out_rate = sensor.data.velocity;
-        self.out_phi = self.out_phi + self.out_rate * self.integration_constant;
-        let e = self.out_phi - self.acc_sphi;
-        // sensor.data.radius = self.out_phi;
-        self.out_phi = self.out_phi - e / self.c1;
-        sensor.calibrate_offset.velocity =
-            sensor.calibrate_offset.velocity + (e / self.integration_constant) / self.c2;
-        sensor.data.radius = self.out_phi - sensor.calibrate_offset.radius;
-        sensor.data.radius.yaw = Frac::from_num(0.);
+        let rate = sensor.data.velocity;
+        let dt = self.integration_constant;
+
+        self.yaw.predict(rate.yaw.0, dt, &self.noise);
+        self.yaw.angle = wrap(self.yaw.angle);
+        self.pitch.predict(rate.pitch.0, dt, &self.noise);
+        self.roll.predict(rate.roll.0, dt, &self.noise);
+        self.pitch.update(acc_sphi.pitch.0, self.noise.accel);
+        self.roll.update(acc_sphi.roll.0, self.noise.accel);
+
+        let bias = self.bias();
+        sensor.data.velocity = rate - bias;
+        // the rates are corrected by the gyro bias estimate, which takes over what is left
+        sensor.gyro_bias.absorb(bias);
+        for axis in [&mut self.yaw, &mut self.pitch, &mut self.roll] {
+            axis.bias = Frac::ZERO;
+        }
+        sensor.data.radius = Attitude {
+            yaw: Radians(self.yaw.angle),
+            pitch: Radians(self.pitch.angle),
+            roll: Radians(self.roll.angle),
+        } - sensor.calibrate_offset.radius;
+    }
+
+    /// Telemetry about how well the filter is doing, with `bias` the estimate it feeds.
+    pub fn state_message(&self, bias: Velocity) -> Message {
+        Message::EstimatorState {
+            bias,
+            innovation: YawPitchRoll {
+                yaw: self.yaw.innovation,
+                pitch: self.pitch.innovation,
+                roll: self.roll.innovation,
+            },
+            nis: YawPitchRoll {
+                yaw: self.yaw.nis,
+                pitch: self.pitch.nis,
+                roll: self.roll.nis,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use architecture::Accel;
+    use std::f64::consts::TAU;
+
+    const FREQUENCY: f64 = 150.0;
+
+    fn noise() -> NoiseParameters {
+        NoiseParameters::quadrupel()
+    }
+
+    /// Uniform noise from -1 up to 1, the same every run.
+    struct Noise(u32);
+
+    impl Noise {
+        fn next(&mut self) -> f64 {
+            self.0 = self.0.wrapping_mul(1103515245).wrapping_add(12345) & 0x7fff_ffff;
+            self.0 as f64 / (1u32 << 30) as f64 - 1.0
+        }
+    }
+
+    /// The complementary filter the Kalman filter replaced, for one axis in rad.
+    struct Complementary {
+        angle: f64,
+        bias: f64,
+    }
+
+    impl Complementary {
+        fn fuse(&mut self, rate: f64, measured: f64, dt: f64) {
+            self.angle += (rate - self.bias) * dt;
+            let error = self.angle - measured;
+            self.angle -= error / 4.0;
+            self.bias += error / dt / 5000.0;
+        }
+    }
+
+    /// Swings an axis for 30 s with a biased gyro and a noisy accelerometer angle. Returns the
+    /// largest angle error of the Kalman and the complementary filter after 10 s, and the
+    /// filter.
+    fn swing(seed: u32, bias: f64) -> (f64, f64, AxisFilter) {
+        let mut filter = AxisFilter::new();
+        let mut reference = Complementary {
+            angle: 0.0,
+            bias: 0.0,
+        };
+        let mut noise = Noise(seed);
+        let dt = 1.0 / FREQUENCY;
+        let (mut kalman, mut complementary) = (0.0f64, 0.0f64);
+        for tick in 0..(30.0 * FREQUENCY) as usize {
+            let time = tick as f64 * dt;
+            let angle = 0.3 * (TAU * 0.5 * time).sin();
+            let rate = 0.3 * TAU * 0.5 * (TAU * 0.5 * time).cos() + bias;
+            let measured = angle + 0.1 * noise.next();
+
+            filter.predict(Frac::from_num(rate), Frac::from_num(dt), &noise());
+            filter.update(Frac::from_num(measured), noise().accel);
+            reference.fuse(rate, measured, dt);
+            if time > 10.0 {
+                kalman = kalman.max((filter.angle.to_num::<f64>() - angle).abs());
+                complementary = complementary.max((reference.angle - angle).abs());
+            }
+        }
+        (kalman, complementary, filter)
+    }
+
+    #[test]
+    fn angle_and_bias_converge() {
+        for seed in 1..4 {
+            let (error, _, filter) = swing(seed, 0.02);
+            assert!(error < 0.05, "{error}");
+            let bias = filter.bias.to_num::<f64>();
+            assert!((bias - 0.02).abs() < 0.005, "{bias}");
+            assert!(filter.angle_variance() < Cov::from_num(0.001));
+        }
+    }
+
+    #[test]
+    fn errors_are_below_the_complementary_filter() {
+        for seed in 1..4 {
+            let (kalman, complementary, _) = swing(seed, 0.02);
+            assert!(kalman < 0.6 * complementary, "{kalman} {complementary}");
+        }
+    }
+
+    #[test]
+    fn the_bias_is_handed_to_the_gyro_bias_estimate() {
+        let mut kalman = KalmanFilter::new(noise());
+        kalman.integration_constant = Frac::from_num(1.0 / FREQUENCY);
+        let mut sensor = Sensor::new();
+        sensor.data.acceleration = Accel {
+            x: 0,
+            y: 0,
+            z: 16384,
+        };
+        for _ in 0..(30.0 * FREQUENCY) as usize {
+            sensor.data.velocity = Velocity {
+                pitch: RadiansPerSecond(Frac::from_num(0.02)),
+                ..Velocity::new()
+            };
+            // armed, so only the filter measures the bias
+            sensor.compensate_gyro_bias(false, FREQUENCY as u64);
+            kalman.fusion_algorithm(&mut sensor);
+            assert_eq!(kalman.bias(), Velocity::new());
+        }
+        let bias = sensor.gyro_bias.bias().pitch.0.to_num::<f64>();
+        assert!((bias - 0.02).abs() < 0.005, "{bias}");
+        assert!(kalman.pitch.angle.abs() < 0.02);
+    }
+
+    #[test]
+    fn an_axis_without_measurements_stays_bounded() {
+        let mut filter = AxisFilter::new();
+        let dt = Frac::from_num(1.0 / 350.0);
+        // an hour of yaw in raw mode
+        for _ in 0..350 * 3600 {
+            filter.predict(Frac::ZERO, dt, &noise());
+        }
+        for p in filter.p.iter().flatten() {
+            assert!(p.abs() <= MAX_VARIANCE);
+        }
+
+        // turning on the spot for 20 s
+        let mut kalman = KalmanFilter::new(noise());
+        kalman.integration_constant = Frac::from_num(1.0 / FREQUENCY);
+        let mut sensor = Sensor::new();
+        sensor.data.acceleration = Accel {
+            x: 0,
+            y: 0,
+            z: 16384,
+        };
+        for _ in 0..(20.0 * FREQUENCY) as usize {
+            sensor.data.velocity = Velocity {
+                yaw: RadiansPerSecond(Frac::from_num(2)),
+                ..Velocity::new()
+            };
+            kalman.fusion_algorithm(&mut sensor);
+            assert!(kalman.yaw.angle.abs() <= Frac::PI);
+        }
     }
 }
@@ -0,0 +1,42 @@
+//! The flight code of the drone.
+//!
+//! All hardware access goes through [`hal`], so everything in here also builds for the host
+//! when the `quadrupel` feature is disabled. The firmware binary itself lives in `main.rs`.
+#![no_std]
+
+#[macro_use]
+extern crate enum_map;
+
+extern crate alloc;
+extern crate architecture;
+extern crate log;
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod accel_calibration;
+pub mod altitude;
+pub mod base_station;
+pub mod battery;
+pub mod calibration;
+pub mod config;
+pub mod control;
+pub mod control_loop;
+pub mod fir_filter;
+pub mod flight_log;
+#[cfg(feature = "quadrupel")]
+pub mod funcdisk;
+pub mod gyro_bias;
+pub mod hal;
+pub mod height_filter;
+pub mod kalman_filter;
+pub mod liveness;
+pub mod lowpassfilter;
+pub mod mahony_filter;
+pub mod message;
+pub mod profiling;
+pub mod sensor;
+pub mod sensor_health;
+pub mod state_machine;
+#[cfg(test)]
+mod test_data;
+pub mod yaw_pitch_roll_quaternion;
@@ -1,170 +1,402 @@
-// pub fn init_ewma() -> SensorData{
-//     let ewma0 = SensorData{
-//         height: Frac::from_num(0.0),
-//         v_z: Frac::from_num(0.0),
-//         pressure: 0,
-//         velocity: Velocity{ yaw: Frac::from_num(0.0), pitch: Frac::from_num(0.0), roll: Frac::from_num(0.0) },
-//         radius: YawPitchRoll{ yaw: Frac::from_num(0.0), pitch: Frac::from_num(0.0), roll: Frac::from_num(0.0) },
-//         acceleration: Accel{ x: 0, y: 0, z: 0},
-//         bat: 0,
-//         motor_speeds: [0;4],
-//     };
-//     ewma0
-// }
-
-// pub fn find_ewma0(data: [SensorData; 10]) -> SensorData{
-//     let num_avg = Frac::from_num(10);
-//     let mut ewma_height = Frac::from_num(0.0);
-//     let mut ewma_v_z = Frac::from_num(0.0);
-//     let mut ewma_pressure = 0;
-
-//     let mut ewma_velocity = Velocity{
-//         yaw: Frac::from_num(0.0),
-//         pitch: Frac::from_num(0.0),
-//         roll: Frac::from_num(0.0)
-//     };
-//     let mut ewma_vel_yaw = ewma_velocity.yaw;
-//     let mut ewma_vel_pitch = ewma_velocity.pitch;
-//     let mut ewma_vel_roll = ewma_velocity.roll;
-
-//     let mut ewma_radius = YawPitchRoll{
-//         yaw: Frac::from_num(0.0),
-//         pitch: Frac::from_num(0.0),
-//         roll: Frac::from_num(0.0)
-//     };
-//     let mut ewma_rad_yaw = ewma_radius.yaw;
-//     let mut ewma_rad_pitch = ewma_radius.pitch;
-//     let mut ewma_rad_roll = ewma_radius.roll;
-
-//     let mut ewma_acceleration = Accel{ x: 0, y: 0, z: 0 };
-//     let ewma_acc_x = ewma_acceleration.x;
-//     let ewma_acc_y = ewma_acceleration.y;
-//     let ewma_acc_z = ewma_acceleration.z;
-
-//     let mut ewma_bat = 0;
-//     let mut ewma_motor_speeds = [0;4];
-
-//     for i in 0..10{
-//         ewma_height = ewma_height + data[i].height;
-//         ewma_v_z = ewma_v_z + data[i].v_z;
-//         ewma_pressure = ewma_pressure + data[i].pressure;
-
-//         ewma_vel_yaw = ewma_vel_yaw + data[i].velocity.yaw;
-//         ewma_vel_pitch = ewma_vel_pitch + data[i].velocity.pitch;
-//         ewma_vel_roll = ewma_vel_roll + data[i].velocity.roll;
-
-//         ewma_rad_yaw = ewma_rad_yaw + data[i].radius.yaw;
-//         ewma_rad_pitch = ewma_rad_pitch + data[i].radius.pitch;
-//         ewma_rad_roll = ewma_rad_roll + data[i].radius.roll;
-
-//         ewma_bat = ewma_bat + data[i].bat;
-//         for j in 0..4{
-//             ewma_motor_speeds[j] = ewma_motor_speeds[j] + data[i].motor_speeds[j];
-//         }
-//     }
-
-//     ewma_velocity = Velocity{
-//         yaw: Frac::from_num(ewma_vel_yaw/num_avg),
-//         pitch: Frac::from_num(ewma_vel_pitch/num_avg),
-//         roll: Frac::from_num(ewma_vel_roll/num_avg) };
-//     ewma_radius = YawPitchRoll{
-//         yaw: Frac::from_num(ewma_rad_yaw/num_avg),
-//         pitch: Frac::from_num(ewma_rad_pitch/num_avg),
-//         roll: Frac::from_num(ewma_rad_roll/num_avg) };
-//     ewma_acceleration = Accel{ x: ewma_acc_x/10, y: ewma_acc_y/10, z: ewma_acc_z/10 };
-//     for i in 0..4{
-//         ewma_motor_speeds[i] = ewma_motor_speeds[i] / 10;
-//     }
-
-//     let ewma0 = SensorData {
-//         height: ewma_height / num_avg,
-//         v_z: ewma_v_z / num_avg,
-//         pressure: ewma_pressure / 10,
-//         velocity: ewma_velocity,
-//         radius: ewma_radius,
-//         acceleration: ewma_acceleration,
-//         bat: ewma_bat/10,
-//         motor_speeds: ewma_motor_speeds
-//     };
-
-//     ewma0
-// }
-
-// pub fn lpf(data: SensorData, last_ewma: SensorData, alpha_mul: f32) -> SensorData{
-//     let alpha = Frac::from_num(alpha_mul);
-//     let one_minus_alpha = Frac::from_num(1.0) - alpha;
-
-//     let last_ewma_height = alpha * data.height + one_minus_alpha * last_ewma.height;
-//     let last_ewma_v_z = alpha * data.v_z + one_minus_alpha * last_ewma.v_z;
-//     let last_ewma_pressure = (alpha_mul * (data.pressure as f32) + (1.0 - alpha_mul) * (last_ewma.pressure as f32)) as i32;
-
-//     let last_ewma_vel_yaw = alpha * data.velocity.yaw + one_minus_alpha * last_ewma.velocity.yaw;
-//     let last_ewma_vel_pitch = alpha * data.velocity.pitch + one_minus_alpha * last_ewma.velocity.pitch;
-//     let last_ewma_vel_roll = alpha * data.velocity.roll + one_minus_alpha * last_ewma.velocity.roll;
-
-//     let last_ewma_rad_yaw = alpha * data.radius.yaw + one_minus_alpha * last_ewma.radius.yaw;
-//     let last_ewma_rad_pitch = alpha * data.radius.pitch + one_minus_alpha * last_ewma.radius.pitch;
-//     let last_ewma_rad_roll = alpha * data.radius.roll + one_minus_alpha * last_ewma.radius.roll;
-
-//     let last_ewma_acc_x = (alpha_mul * (data.acceleration.x as f32) + (1.0 - alpha_mul) * (last_ewma.acceleration.x as f32)) as i32;
-//     let last_ewma_acc_y = (alpha_mul * (data.acceleration.y as f32) + (1.0 - alpha_mul) * (last_ewma.acceleration.y as f32)) as i32;
-//     let last_ewma_acc_z = (alpha_mul * (data.acceleration.z as f32) + (1.0 - alpha_mul) * (last_ewma.acceleration.z as f32)) as i32;
-
-//     let last_ewma_bat = (alpha_mul * (data.bat as f32) + (1.0 - alpha_mul) * (last_ewma.bat as f32)) as u16;
-//     let mut last_ewma_motor_sp: [u16; 4] = [0; 4];
-//     for i in 0..4{
-//         last_ewma_motor_sp[i] = (alpha_mul * (data.motor_speeds[i] as f32) + (1.0 - alpha_mul) * (last_ewma.motor_speeds[i] as f32)) as u16;
-//     }
-
-//     let last_ewma_vel = Velocity{
-//         yaw: last_ewma_vel_yaw,
-//         pitch: last_ewma_vel_pitch,
-//         roll: last_ewma_vel_roll,
-//     };
-
-//     let last_ewma_rad = YawPitchRoll{
-//         yaw: last_ewma_rad_yaw,
-//         pitch: last_ewma_rad_pitch,
-//         roll: last_ewma_rad_roll,
-//     };
-
-//     let last_ewma_acc = Accel{
-//         x: last_ewma_acc_x,
-//         y: last_ewma_acc_y,
-//         z: last_ewma_acc_z,
-//     };
-
-//     let lpf_sensordata = SensorData{
-//         height: last_ewma_height,
-//         v_z: last_ewma_v_z,
-//         pressure: last_ewma_pressure,
-//         velocity: last_ewma_vel,
-//         radius: last_ewma_rad,
-//         acceleration: last_ewma_acc,
-//         bat: last_ewma_bat,
-//         motor_speeds: last_ewma_motor_sp,
-//     };
-
-//     lpf_sensordata
-
-// }
-
-// /*
-// pub fn find_ewma0(data: &mut [f32]) -> f32{
-//     let mut ewma0: f32 = 0.0;
-//     for i in 0..10{
-//         ewma0 = ewma0 + data[i];
-//     }
-//     ewma0 = ewma0 / 10.0;
-//     ewma0
-// }
-
-// pub fn lpf(data: f32, last_ewma: f32, alpha: f32) -> f32 {
-//     // let mut a: [f32; 100] = [0.0; 100];
-//     let data_as_f32: f32 = data as f32;
-//     let mut new_last_ewma: f32 = last_ewma;
-
-//     new_last_ewma = alpha * data_as_f32 + (1.0 - alpha) * new_last_ewma;
-//     new_last_ewma
-// }
-//  */
+use architecture::{Frac, Pascal, SensorData};
+use fixed::types::I32F32;
+
+/// The poles of a low cutoff sit close to the unit circle, so the coefficients and the state get
+/// 32 fractional bits.
+pub type Coefficient = I32F32;
+
+/// Second order section `y = b0 x + b1 x[-1] + b2 x[-2] - a1 y[-1] - a2 y[-2]` in direct form I,
+/// with the coefficients from the audio EQ cookbook.
+#[derive(Debug, Clone, Copy)]
+pub struct Biquad {
+    b0: Coefficient,
+    b1: Coefficient,
+    b2: Coefficient,
+    a1: Coefficient,
+    a2: Coefficient,
+    x1: Coefficient,
+    x2: Coefficient,
+    y1: Coefficient,
+    y2: Coefficient,
+}
+
+impl Biquad {
+    /// A filter that passes everything.
+    pub fn identity() -> Self {
+        Biquad {
+            b0: Coefficient::ONE,
+            b1: Coefficient::ZERO,
+            b2: Coefficient::ZERO,
+            a1: Coefficient::ZERO,
+            a2: Coefficient::ZERO,
+            x1: Coefficient::ZERO,
+            x2: Coefficient::ZERO,
+            y1: Coefficient::ZERO,
+            y2: Coefficient::ZERO,
+        }
+    }
+
+    /// Butterworth low-pass with `cutoff` Hz at `sample_rate` Hz, -3 dB at the cutoff.
+    pub fn low_pass(cutoff: Frac, sample_rate: u64) -> Self {
+        let (sin, cos) = sin_cos(omega(cutoff, sample_rate));
+        let alpha = sin * Coefficient::FRAC_1_SQRT_2;
+        let a0 = Coefficient::ONE + alpha;
+        let b1 = (Coefficient::ONE - cos) / a0;
+        Biquad {
+            b0: b1 / 2,
+            b1,
+            b2: b1 / 2,
+            a1: cos * -2 / a0,
+            a2: (Coefficient::ONE - alpha) / a0,
+            ..Biquad::identity()
+        }
+    }
+
+    /// Notch removing `center` Hz at `sample_rate` Hz, `q` is the center over the bandwidth.
+    pub fn notch(center: Frac, q: Frac, sample_rate: u64) -> Self {
+        let (sin, cos) = sin_cos(omega(center, sample_rate));
+        let alpha = sin / (Coefficient::from_num(q) * 2);
+        let a0 = Coefficient::ONE + alpha;
+        Biquad {
+            b0: Coefficient::ONE / a0,
+            b1: cos * -2 / a0,
+            b2: Coefficient::ONE / a0,
+            a1: cos * -2 / a0,
+            a2: (Coefficient::ONE - alpha) / a0,
+            ..Biquad::identity()
+        }
+    }
+
+    /// Filters the next sample `x`, saturating like the sensor data.
+    pub fn apply(&mut self, x: Coefficient) -> Coefficient {
+        let y = self
+            .b0
+            .saturating_mul(x)
+            .saturating_add(self.b1.saturating_mul(self.x1))
+            .saturating_add(self.b2.saturating_mul(self.x2))
+            .saturating_sub(self.a1.saturating_mul(self.y1))
+            .saturating_sub(self.a2.saturating_mul(self.y2));
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+/// Angular frequency of `frequency` Hz in rad per sample.
+pub(crate) fn omega(frequency: Frac, sample_rate: u64) -> Coefficient {
+    Coefficient::TAU * Coefficient::from_num(frequency) / Coefficient::from_num(sample_rate)
+}
+
+/// Sine and cosine of `x` from their Taylor series, after moving `x` into `[-pi, pi]`.
+pub(crate) fn sin_cos(x: Coefficient) -> (Coefficient, Coefficient) {
+    let mut x = x % Coefficient::TAU;
+    if x > Coefficient::PI {
+        x -= Coefficient::TAU;
+    } else if x < -Coefficient::PI {
+        x += Coefficient::TAU;
+    }
+    let x2 = x * x;
+    let mut sin_term = x;
+    let mut cos_term = Coefficient::ONE;
+    let mut sin = sin_term;
+    let mut cos = cos_term;
+    for n in 1..12 {
+        cos_term = -cos_term * x2 / (2 * n * (2 * n - 1));
+        sin_term = -sin_term * x2 / (2 * n * (2 * n + 1));
+        cos += cos_term;
+        sin += sin_term;
+    }
+    (sin, cos)
+}
+
+/// Quality of a notch set by [`SignalFilter::set_notch`], wide enough for the motor speed to
+/// wander a bit.
+const NOTCH_Q: Frac = Frac::lit("2");
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Notch {
+    /// Hz
+    pub center: Frac,
+    pub q: Frac,
+}
+
+/// The filters applied to one signal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterConfig {
+    /// Cutoff of the low-pass in Hz, `None` to pass everything.
+    pub low_pass: Option<Frac>,
+    pub notch: Option<Notch>,
+}
+
+impl FilterConfig {
+    pub fn low_pass(cutoff: Frac) -> Self {
+        FilterConfig {
+            low_pass: Some(cutoff),
+            notch: None,
+        }
+    }
+}
+
+/// A low-pass followed by a notch, designed for the loop frequency.
+///
+/// The coefficients depend on the sample rate, so they are computed again whenever
+/// [`SignalFilter::apply`] is called with a different one than before.
+#[derive(Debug, Clone, Copy)]
+pub struct SignalFilter {
+    config: FilterConfig,
+    sample_rate: u64,
+    low_pass: Biquad,
+    notch: Biquad,
+}
+
+impl SignalFilter {
+    pub fn new(config: FilterConfig) -> Self {
+        SignalFilter {
+            config,
+            sample_rate: 0,
+            low_pass: Biquad::identity(),
+            notch: Biquad::identity(),
+        }
+    }
+
+    pub fn config(&self) -> FilterConfig {
+        self.config
+    }
+
+    pub fn configure(&mut self, config: FilterConfig) {
+        self.config = config;
+        // design again on the next sample
+        self.sample_rate = 0;
+    }
+
+    /// Sets the cutoff of the low-pass in Hz, 0 turns it off.
+    pub fn set_low_pass(&mut self, cutoff: Frac) {
+        let low_pass = if cutoff > 0 { Some(cutoff) } else { None };
+        self.configure(FilterConfig {
+            low_pass,
+            ..self.config
+        });
+    }
+
+    /// Sets the center of the notch in Hz, 0 turns it off.
+    pub fn set_notch(&mut self, center: Frac) {
+        let notch = if center > 0 {
+            Some(Notch { center, q: NOTCH_Q })
+        } else {
+            None
+        };
+        self.configure(FilterConfig {
+            notch,
+            ..self.config
+        });
+    }
+
+    fn design(&mut self, sample_rate: u64) {
+        // a cutoff at or above the Nyquist frequency is the same as no filter
+        let nyquist = Frac::from_num(sample_rate / 2);
+        self.low_pass = match self.config.low_pass {
+            Some(cutoff) if cutoff > 0 && cutoff < nyquist => Biquad::low_pass(cutoff, sample_rate),
+            _ => Biquad::identity(),
+        };
+        self.notch = match self.config.notch {
+            Some(notch) if notch.center > 0 && notch.center < nyquist && notch.q > 0 => {
+                Biquad::notch(notch.center, notch.q, sample_rate)
+            }
+            _ => Biquad::identity(),
+        };
+        self.sample_rate = sample_rate;
+    }
+
+    /// Filters the next sample `x` taken at `sample_rate` Hz.
+    pub fn apply(&mut self, x: Coefficient, sample_rate: u64) -> Coefficient {
+        if sample_rate != self.sample_rate {
+            self.design(sample_rate);
+        }
+        let x = self.low_pass.apply(x);
+        self.notch.apply(x)
+    }
+
+    pub fn apply_frac(&mut self, x: Frac, sample_rate: u64) -> Frac {
+        Frac::saturating_from_num(self.apply(Coefficient::from_num(x), sample_rate))
+    }
+}
+
+/// Filter settings of every signal, cutoffs in Hz.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterSettings {
+    pub gyro: FilterConfig,
+    pub accel: FilterConfig,
+    pub pressure: FilterConfig,
+    pub d_term: FilterConfig,
+}
+
+impl FilterSettings {
+    pub fn new() -> Self {
+        FilterSettings {
+            gyro: FilterConfig::low_pass(Frac::from_num(40)),
+            accel: FilterConfig::low_pass(Frac::from_num(15)),
+            pressure: FilterConfig::low_pass(Frac::from_num(2)),
+            d_term: FilterConfig::low_pass(Frac::from_num(25)),
+        }
+    }
+}
+
+/// The filters of the sensor readings, one per axis.
+#[derive(Debug, Clone, Copy)]
+pub struct SensorFilters {
+    /// yaw, pitch, roll
+    pub gyro: [SignalFilter; 3],
+    /// x, y, z
+    pub accel: [SignalFilter; 3],
+    pub pressure: SignalFilter,
+}
+
+impl SensorFilters {
+    pub fn new(settings: &FilterSettings) -> Self {
+        SensorFilters {
+            gyro: [SignalFilter::new(settings.gyro); 3],
+            accel: [SignalFilter::new(settings.accel); 3],
+            pressure: SignalFilter::new(settings.pressure),
+        }
+    }
+
+    /// Filters the rates, accelerations and pressure of `data` sampled at `sample_rate` Hz.
+    pub fn apply(&mut self, data: &mut SensorData, sample_rate: u64) {
+        let velocity = &mut data.velocity;
+        velocity.yaw.0 = self.gyro[0].apply_frac(velocity.yaw.0, sample_rate);
+        velocity.pitch.0 = self.gyro[1].apply_frac(velocity.pitch.0, sample_rate);
+        velocity.roll.0 = self.gyro[2].apply_frac(velocity.roll.0, sample_rate);
+
+        let acceleration = &mut data.acceleration;
+        for (filter, value) in self.accel.iter_mut().zip([
+            &mut acceleration.x,
+            &mut acceleration.y,
+            &mut acceleration.z,
+        ]) {
+            *value = filter
+                .apply(Coefficient::from_num(*value), sample_rate)
+                .saturating_round()
+                .saturating_to_num();
+        }
+
+        data.pressure = Pascal(
+            self.pressure
+                .apply(Coefficient::from_num(data.pressure.0), sample_rate)
+                .saturating_to_num(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::test_data::extreme;
+    use std::f64::consts::{FRAC_1_SQRT_2, TAU};
+
+    /// Amplitude of the output for a unit sine of `frequency` Hz, a whole number of periods in
+    /// two seconds, once the filter has settled.
+    fn gain(filter: &mut SignalFilter, frequency: f64, sample_rate: u64) -> f64 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for i in 0..(sample_rate * 4) {
+            let phase = TAU * frequency * i as f64 / sample_rate as f64;
+            let y: f64 = filter
+                .apply(Coefficient::from_num(phase.sin()), sample_rate)
+                .to_num();
+            if i >= sample_rate * 2 {
+                re += y * phase.cos();
+                im += y * phase.sin();
+            }
+        }
+        2.0 * (re * re + im * im).sqrt() / (sample_rate * 2) as f64
+    }
+
+    fn low_pass(cutoff: f32) -> SignalFilter {
+        SignalFilter::new(FilterConfig::low_pass(Frac::from_num(cutoff)))
+    }
+
+    #[test]
+    fn low_pass_has_unity_gain_at_dc() {
+        let mut filter = low_pass(2.0);
+        let mut y = Coefficient::ZERO;
+        for _ in 0..2000 {
+            y = filter.apply(Coefficient::from_num(3), 350);
+        }
+        assert!((y.to_num::<f64>() - 3.0).abs() < 1e-4, "{y}");
+    }
+
+    #[test]
+    fn low_pass_is_3_db_down_at_the_cutoff() {
+        for (cutoff, sample_rate) in [(2.0, 150), (20.0, 150), (40.0, 350), (100.0, 350)] {
+            let gain = gain(&mut low_pass(cutoff), cutoff as f64, sample_rate);
+            assert!(
+                (gain - FRAC_1_SQRT_2).abs() < 0.02,
+                "{cutoff} Hz at {sample_rate} Hz: {gain}"
+            );
+        }
+    }
+
+    #[test]
+    fn low_pass_rolls_off_at_40_db_per_decade() {
+        let stop = gain(&mut low_pass(5.0), 50.0, 350);
+        assert!(stop < 0.015, "{stop}");
+        let pass = gain(&mut low_pass(20.0), 2.0, 350);
+        assert!((pass - 1.0).abs() < 0.01, "{pass}");
+    }
+
+    #[test]
+    fn notch_removes_the_center_only() {
+        let config = FilterConfig {
+            low_pass: None,
+            notch: Some(Notch {
+                center: Frac::from_num(60),
+                q: Frac::from_num(2),
+            }),
+        };
+        let center = gain(&mut SignalFilter::new(config), 60.0, 350);
+        assert!(center < 0.01, "{center}");
+        let below = gain(&mut SignalFilter::new(config), 10.0, 350);
+        assert!(below > 0.97, "{below}");
+    }
+
+    #[test]
+    fn follows_a_change_of_the_sample_rate() {
+        let mut filter = low_pass(20.0);
+        gain(&mut filter, 20.0, 150);
+        let gain = gain(&mut filter, 20.0, 350);
+        assert!((gain - FRAC_1_SQRT_2).abs() < 0.02, "{gain}");
+    }
+
+    #[test]
+    fn cutoff_above_nyquist_passes_everything() {
+        let mut filter = low_pass(100.0);
+        let x = Coefficient::from_num(1.5);
+        assert_eq!(filter.apply(x, 150), x);
+    }
+
+    #[test]
+    fn extreme_readings_saturate() {
+        let mut filters = SensorFilters::new(&FilterSettings::new());
+        // square waves at full scale overshoot the most
+        for n in 0..300 {
+            filters.apply(&mut extreme(n / 7 % 2 == 0), 150);
+        }
+        for high in [true, false] {
+            for _ in 0..600 {
+                filters.apply(&mut extreme(high), 150);
+            }
+            let mut data = extreme(high);
+            filters.apply(&mut data, 150);
+            let sign = if high { 1 } else { -1 };
+            // these fit in the coefficients and come out as they went in
+            assert!((data.velocity.yaw - extreme(high).velocity.yaw).abs().0 <= 1);
+            assert!((data.pressure - extreme(high).pressure).0.abs() <= 100);
+            // the sums of the largest counts saturate, the sign still holds
+            assert!(data.acceleration.x.signum() == sign);
+            assert!(data.acceleration.x.unsigned_abs() > i32::MAX as u32 / 4);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use crate::sensor::Sensor;
+use architecture::{Attitude, Frac, Quat, RadiansPerSecond, Seconds, Vec3, Velocity};
+use fixed::types::I8F24;
+
+/// The quaternion changes by less than a single bit of a `Frac` per tick at low rates, so the
+/// filter state gets more fractional bits.
+pub type Precise = I8F24;
+
+pub use architecture::ACCEL_SCALE;
+
+/// Mahony filter: integrates the gyro in a quaternion and steers it towards the gravity
+/// direction measured by the accelerometer with a PI controller.
+///
+/// Unlike integrating Euler angles this keeps working at large angles. Vectors are in the frame
+/// of the MPU: x to the front, y to the left and z up.
+pub struct MahonyFilter {
+    q: Quat<Precise>,
+    /// Integral of the attitude error, the negative gyro bias in rad/s.
+    integral: Vec3<Precise>,
+    pub kp: Frac,
+    pub ki: Frac,
+    pub integration_constant: Frac,
+}
+
+impl MahonyFilter {
+    pub fn new(kp: Frac, ki: Frac) -> Self {
+        MahonyFilter {
+            q: Quat::default(),
+            integral: Vec3::ZERO,
+            kp,
+            ki,
+            integration_constant: Frac::from_num(0.),
+        }
+    }
+
+    pub fn quaternion(&self) -> Quat {
+        self.q.to_num()
+    }
+
+    /// Continues from `attitude`, uncalibrated like the state, instead of from where the filter
+    /// was when it last ran.
+    pub fn seed(&mut self, attitude: Attitude) {
+        self.q = Quat::from_yaw_pitch_roll(attitude).to_num();
+    }
+
+    /// Estimated gyro bias.
+    pub fn bias(&self) -> Velocity {
+        let Vec3 { x, y, z } = self.integral.to_num::<Frac>();
+        Velocity {
+            yaw: RadiansPerSecond(-z),
+            pitch: RadiansPerSecond(y),
+            roll: RadiansPerSecond(-x),
+        }
+    }
+
+    /// Attitude error between the measured up direction `a`, normalized, and the estimate.
+    fn error(&self, a: Vec3) -> Vec3<Precise> {
+        // up in the body frame according to the estimate
+        let one = Precise::from_num(1);
+        let up = self
+            .q
+            .conjugate()
+            .rotate(Vec3::new(Precise::ZERO, Precise::ZERO, one));
+        a.to_num().cross(up)
+    }
+
+    /// Advances the filter by `integration_constant` with the gyro rates `gyro` in rad/s and the
+    /// accelerometer counts `accel`, both in the MPU frame.
+    fn update(&mut self, gyro: Vec3, accel: [i32; 3]) {
+        let dt = Precise::from_num(self.integration_constant);
+        let mut omega = gyro.to_num();
+
+        // a corrected reading can exceed the range of the raw counts
+        let [x, y, z] = accel.map(|c| Frac::saturating_from_num(c) / ACCEL_SCALE);
+        let a = Vec3::new(x, y, z);
+        let norm = a.norm();
+        // far from 1 g the accelerometer measures mostly the motion, not gravity
+        if norm > Frac::from_num(0.5) && norm < Frac::from_num(1.5) {
+            let e = self.error(a / norm);
+            self.integral = self.integral + e * Precise::from_num(self.ki) * dt;
+            omega = omega + e * Precise::from_num(self.kp);
+        }
+        omega = omega + self.integral;
+
+        self.q = self.q.integrate(omega, Seconds(self.integration_constant));
+    }
+
+    pub fn fusion_algorithm(&mut self, sensor: &mut Sensor) {
+        let rate = sensor.data.velocity;
+        let accel = sensor.data.acceleration + sensor.calibrate_offset.acceleration;
+        self.update(
+            Vec3::new(rate.roll.0, -rate.pitch.0, rate.yaw.0),
+            [accel.x, accel.y, accel.z],
+        );
+
+        sensor.data.velocity = rate - self.bias();
+        sensor.data.radius = self.quaternion().to_yaw_pitch_roll() - sensor.calibrate_offset.radius;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Without the integral, which only settles over tens of seconds.
+    fn filter() -> MahonyFilter {
+        let mut filter = MahonyFilter::new(Frac::from_num(1), Frac::ZERO);
+        filter.integration_constant = Frac::from_num(1.0 / 150.0);
+        filter
+    }
+
+    #[test]
+    fn the_estimate_turns_towards_gravity() {
+        // lying on its side
+        let mut filter = filter();
+        for _ in 0..150 * 10 {
+            filter.update(Vec3::ZERO, [0, ACCEL_SCALE, 0]);
+        }
+        let Vec3 { x, y, z } = filter.error(Vec3::new(Frac::ZERO, Frac::ONE, Frac::ZERO));
+        for e in [x, y, z] {
+            assert!(e.abs() < Precise::from_num(0.01), "{e}");
+        }
+    }
+
+    #[test]
+    fn extreme_counts_keep_the_quaternion_normalized() {
+        let mut filter = filter();
+        for accel in [
+            [i32::MAX, i32::MIN, 70_000],
+            [0, 0, -100_000],
+            [40_000, 0, 0],
+        ] {
+            let rate = Frac::from_num(30);
+            filter.update(Vec3::new(rate, rate, rate), accel);
+        }
+        let norm = filter.quaternion().norm_squared();
+        assert!((norm - Frac::ONE).abs() < Frac::from_num(0.01), "{norm}");
+    }
+}
@@ -2,37 +2,26 @@
 #![no_main]
 #![feature(alloc_error_handler)]
 
-#[macro_use]
-extern crate enum_map;
-
 extern crate alloc;
 extern crate architecture;
-extern crate log;
 use alloc::format;
 use architecture::BASE_STATION;
-use base_station::base_station_loop;
 use core::alloc::Layout;
 use core::mem::MaybeUninit;
 use core::panic::PanicInfo;
+use dronecode::base_station::base_station_loop;
+use dronecode::funcdisk::FuncDisk;
+use dronecode::hal::quadrupel::Quadrupel;
+use dronecode::{control_loop, profiling};
+use tudelft_quadrupel::flash::{
+    flash_chip_erase, flash_read_byte, flash_read_bytes, flash_write_byte, flash_write_bytes,
+};
 use tudelft_quadrupel::initialize::initialize;
 use tudelft_quadrupel::led::Led::{Green, Red};
 use tudelft_quadrupel::time::assembly_delay;
 use tudelft_quadrupel::uart::send_bytes;
 use tudelft_quadrupel::{entry, uart};
 
-mod base_station;
-mod control;
-mod control_loop;
-mod funcdisk;
-mod kalman_filter;
-mod liveness;
-mod lowpassfilter;
-mod message;
-mod profiling;
-mod sensor;
-mod state_machine;
-mod yaw_pitch_roll_quaternion;
-
 /// The heap size of your drone code in bytes.
 /// Note: there are 8192 bytes of RAM available.
 const HEAP_SIZE: usize = 4096;
@@ -55,10 +44,17 @@ fn main() -> ! {
     }
 
     if BASE_STATION {
-        base_station_loop();
+        base_station_loop(Quadrupel);
     } else {
+        let disk = FuncDisk::func(
+            flash_write_bytes,
+            flash_read_bytes,
+            flash_chip_erase,
+            flash_write_byte,
+            flash_read_byte,
+        );
         profiling::init();
-        control_loop::control_loop()
+        control_loop::control_loop(Quadrupel, disk)
     }
 }
 
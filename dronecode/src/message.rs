@@ -1,47 +1,62 @@
-use crate::funcdisk::FuncDisk;
+use crate::config::{self, ConfigStore, EraseSector, LogArea};
+use crate::fir_filter::{windowed_sinc, SensorFir};
+use crate::flight_log::FlightLog;
+use crate::hal::{Hal, Led::Green};
 use crate::sensor::Sensor;
 use crate::state_machine::check_state;
 use crate::{control::Controller, liveness::Liveliness};
 use architecture::Mode::{Panic, Raw};
-use architecture::{ControlRequest, Frac, Message, Mode};
-use log::Logger;
+use architecture::{
+    BatteryLevel, ConfigCommand, ConfigError, ControlRequest, Message, Mode, Pascal,
+};
 use protocol::{DataLink, MessageLink};
-use tudelft_quadrupel::led::Led::Green;
-use tudelft_quadrupel::time::{delay_ms_assembly, set_tick_frequency};
 
-pub fn handle_message<T: protocol::Link>(
+pub fn handle_message<T: protocol::Link, H: Hal, D: EraseSector>(
+    hal: &mut H,
     liveliness: &mut Liveliness,
     link: &mut MessageLink<T>,
-    logger: &mut Logger<FuncDisk>,
+    logger: &mut FlightLog<LogArea<D>>,
+    config: &mut ConfigStore<D>,
     controller: &mut Controller,
     control_request: &mut ControlRequest,
     sensor: &mut Sensor,
+    battery: BatteryLevel,
+    tick: u32,
 ) {
     let msg = link.check_for_message();
-    Green.on();
+    hal.led_on(Green);
     match msg {
         Ok(msg) => match msg {
             Some(msg) => match msg {
-                Message::ChangeMode { mode } => match link.send(&Message::ChangeMode { mode }) {
-                    Ok(_) => {
-                        if mode == Raw {
-                            controller.raw_option = true;
-                            controller.frequency = 350;
-                            controller.set_parameters(
-                                Frac::from_num(100),
-                                Frac::from_num(10),
-                                Frac::from_num(2300),
-                            );
-                            set_tick_frequency(controller.frequency);
-                        } else {
-                            controller.mode = mode;
-                            if check_state(controller, mode) {
-                                controller.mode = mode;
-                            }
+                Message::ChangeMode { mode } => {
+                    let arming = controller.mode == Mode::Safe
+                        && !matches!(mode, Mode::Safe | Mode::Calibrate | Mode::Panic | Raw);
+                    // a battery that has to land already is too low to take off
+                    let too_low = matches!(battery, BatteryLevel::Land | BatteryLevel::Critical);
+                    let allowed = check_state(controller, mode) && !(arming && too_low);
+                    if allowed && mode == Raw {
+                        controller.use_raw_sensors();
+                        hal.set_tick_frequency(controller.frequency);
+                    } else if allowed {
+                        controller.mode = mode;
+                        if mode == Mode::Calibrate {
+                            sensor.start_calibration();
+                        }
+                        // the height is measured from where the drone was armed
+                        if arming && sensor.calibrated {
+                            sensor.capture_ground();
                         }
                     }
-                    Err(_) => controller.mode = Panic,
-                },
+                    // the mode the drone is in, so a refused change shows
+                    let reply = if allowed && mode == Raw {
+                        Raw
+                    } else {
+                        controller.mode
+                    };
+                    if link.send(&Message::ChangeMode { mode: reply }).is_err() {
+                        controller.mode = Panic;
+                    }
+                }
                 Message::ControlInput {
                     request,
                     base_pressure,
@@ -49,7 +64,10 @@ pub fn handle_message<T: protocol::Link>(
                     control_request.radius = request.radius;
                     control_request.throttle = request.throttle;
                     liveliness.notify_alive();
-                    sensor.base_pressure = base_pressure;
+                    // a base station without a barometer sends nothing sensible
+                    if base_pressure > Pascal::ZERO {
+                        sensor.base_pressure = base_pressure;
+                    }
                 }
                 Message::TuneParameter { parameter, value } => match parameter {
                     'p' => {
@@ -61,6 +79,42 @@ pub fn handle_message<T: protocol::Link>(
                     'd' => {
                         controller.d = value;
                     }
+                    // filter cutoffs in Hz, 0 turns the filter off
+                    'G' => {
+                        for filter in &mut sensor.filters.gyro {
+                            filter.set_low_pass(value);
+                        }
+                    }
+                    'N' => {
+                        for filter in &mut sensor.filters.gyro {
+                            filter.set_notch(value);
+                        }
+                    }
+                    'A' => {
+                        for filter in &mut sensor.filters.accel {
+                            filter.set_low_pass(value);
+                        }
+                    }
+                    'B' => {
+                        sensor.filters.pressure.set_low_pass(value);
+                    }
+                    'D' => {
+                        for filter in &mut controller.d_term_filter {
+                            filter.set_low_pass(value);
+                        }
+                    }
+                    // windowed sinc for the current loop frequency, starting from the last
+                    // reading so it can be switched on in flight
+                    'F' => {
+                        sensor.fir = if value > 0 {
+                            let mut fir =
+                                SensorFir::new(windowed_sinc(value, controller.frequency));
+                            fir.prime(&sensor.data);
+                            Some(fir)
+                        } else {
+                            None
+                        };
+                    }
                     _ => {
                         match link.send(&Message::LogMessage {
                             message: <[u8; 16]>::try_from("no para".as_bytes()).unwrap(),
@@ -71,38 +125,97 @@ pub fn handle_message<T: protocol::Link>(
                     }
                 },
                 Message::SensorData { .. } => (),
+                Message::EstimatorState { .. } => (),
+                Message::GyroBias { .. } => (),
+                Message::SensorHealth { .. } => (),
+                Message::BatteryStatus { .. } => (),
+                // only the selected filter runs, the other one would take over from a stale
+                // attitude in flight
+                Message::SelectEstimator { estimator } => {
+                    if controller.mode == Mode::Safe {
+                        controller.estimator = estimator;
+                    }
+                }
+                Message::SelectYawMode { mode } => {
+                    controller.yaw_mode = mode;
+                    controller.heading_setpoint = None;
+                }
+                Message::CalibrationResult { .. } => (),
+                Message::MeasureAccelFace { face } => {
+                    if controller.mode == Mode::Safe || controller.mode == Mode::Calibrate {
+                        sensor.accel_calibration.measure(face);
+                    }
+                }
+                Message::AccelFaceMeasured { .. } => (),
+                Message::AccelCalibrationResult { .. } => (),
+                Message::Config { command } => {
+                    let result = match command {
+                        ConfigCommand::Export => {
+                            let config = config.current(controller, sensor);
+                            let _ = link.send(&Message::ConfigData { config });
+                            Ok(())
+                        }
+                        _ if controller.mode != Mode::Safe => Err(ConfigError::NotSafe),
+                        ConfigCommand::Save => config.save(&config.current(controller, sensor)),
+                        ConfigCommand::Load => config
+                            .load()
+                            .and_then(|stored| {
+                                config::validate(&stored, controller.frequency)?;
+                                Ok(stored)
+                            })
+                            .map(|stored| config.apply(&stored, controller, sensor, hal)),
+                        ConfigCommand::Reset => {
+                            config.apply(&config::defaults(), controller, sensor, hal);
+                            config.save(&config::defaults())
+                        }
+                    };
+                    let _ = link.send(&Message::ConfigStatus {
+                        error: result.err(),
+                    });
+                }
+                Message::ConfigData { config: imported } => {
+                    let error = if controller.mode != Mode::Safe {
+                        Some(ConfigError::NotSafe)
+                    } else if let Err(error) = config::validate(&imported, controller.frequency) {
+                        Some(error)
+                    } else {
+                        config.apply(&imported, controller, sensor, hal);
+                        None
+                    };
+                    let _ = link.send(&Message::ConfigStatus { error });
+                }
+                Message::ConfigStatus { .. } => (),
                 Message::LogMessage { .. } => (),
                 Message::LoggerMode { mode } => match mode {
                     architecture::LoggerMode::Enabled => {
-                        logger.set_enabled(true);
-                        logger.clear().unwrap();
+                        let config = config.current(controller, sensor);
+                        if logger.start(config, controller.frequency, tick).is_err() {
+                            report_log_failure(link);
+                        }
                     }
                     architecture::LoggerMode::Disabled => logger.set_enabled(false),
-                    architecture::LoggerMode::Download => {
+                    architecture::LoggerMode::List => {
                         if controller.mode == Mode::Safe {
-                            let _n = logger.length().unwrap() as u32;
-                            let mut address = 0;
-                            for _ in 0..logger.nof_entries {
-                                Green.off();
-                                let data = logger.get_entry(address).unwrap();
-                                match data {
-                                    Some((data, size)) => {
-                                        address += size as u32;
-                                        link.send(&Message::LogDownload { entry: Some(data) })
-                                            .unwrap();
-                                        delay_ms_assembly(20);
-                                    }
-                                    None => unreachable!("corrupted data"),
-                                }
-                                Green.on();
-                            }
-                            link.send(&Message::LogDownload { entry: None }).unwrap();
+                            logger.list();
+                        }
+                    }
+                    architecture::LoggerMode::Delete { before } => {
+                        if controller.mode == Mode::Safe && logger.delete_before(before).is_err() {
+                            report_log_failure(link);
                         }
                     }
                 },
-                Message::LogDownload { .. } => {
-                    unreachable!("pc should not send log download events")
-                }
+                Message::LogChannel {
+                    channel,
+                    decimation,
+                } => logger.set_decimation(channel, decimation),
+                Message::LogRead {
+                    session,
+                    offset,
+                    length,
+                } => logger.request(session, offset, length),
+                Message::LogChunk { .. } => (),
+                Message::LogSession { .. } => (),
                 Message::ProfilerEvent(_) => unreachable!("pc should not send profiler events"),
                 Message::ProfilerTimed { .. } => unreachable!("pc should not send profiler events"),
             },
@@ -110,5 +223,12 @@ pub fn handle_message<T: protocol::Link>(
         },
         Err(_) => (),
     }
-    Green.off();
+    hal.led_off(Green);
+}
+
+/// Tells the host that the flight log could not be written.
+fn report_log_failure<T: protocol::Link>(link: &mut MessageLink<T>) {
+    let mut message = [0; 16];
+    message[..10].copy_from_slice(b"Log failed");
+    let _ = link.send(&Message::LogMessage { message });
 }
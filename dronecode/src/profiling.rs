@@ -1,7 +1,6 @@
-use tudelft_quadrupel::time::Instant;
-
 use enum_map::EnumMap;
 
+use crate::hal::Clock;
 use architecture::ProfilerEvent;
 use protocol::{DataLink, FuncLink, MessageLink};
 
@@ -39,17 +38,17 @@ pub mod macros {
     // This is a macro so that when PROFILING_ENABLED is false
     // then the compiler will remove the code
     macro_rules! profiler_event {
-        ($link:ident, $event:expr) => {
+        ($link:expr, $clock:expr, $event:expr) => {
             if crate::profiling::PROFILING_ENABLED {
-                crate::profiling::trigger_profiling_event(&mut $link, $event);
+                crate::profiling::trigger_profiling_event(&mut $link, &$clock, $event);
             }
         };
     }
 
     macro_rules! profiler_event_if {
-        ($condition:expr, $link:ident, $event:expr) => {
+        ($condition:expr, $link:expr, $clock:expr, $event:expr) => {
             if $condition && crate::profiling::PROFILING_ENABLED {
-                crate::profiling::trigger_profiling_event(&mut $link, $event);
+                crate::profiling::trigger_profiling_event(&mut $link, &$clock, $event);
             }
         };
     }
@@ -58,18 +57,25 @@ pub mod macros {
     pub(crate) use profiler_event_if;
 }
 
-pub fn trigger_profiling_event(link: &mut MessageLink<FuncLink>, event: ProfilerEvent) {
+pub fn trigger_profiling_event<C: Clock>(
+    link: &mut MessageLink<FuncLink>,
+    clock: &C,
+    event: ProfilerEvent,
+) {
     if SEND_ALL {
         send_profiling_event(link, event);
     } else {
-        count_profiling_event(link, event);
+        count_profiling_event(link, clock, event);
     }
 }
 
-pub fn count_profiling_event(link: &mut MessageLink<FuncLink>, event: ProfilerEvent) {
-    let now = Instant::now();
+pub fn count_profiling_event<C: Clock>(
+    link: &mut MessageLink<FuncLink>,
+    clock: &C,
+    event: ProfilerEvent,
+) {
+    let ns = clock.now_ns();
     unsafe {
-        let ns = now.ns_since_start();
         for (i, (start, stop)) in SCOPE_PAIRS.iter().enumerate() {
             if *start == event {
                 SCOPE_LAST[i] = Some(ns);
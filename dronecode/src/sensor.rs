@@ -1,30 +1,55 @@
-use alloc::vec;
-use alloc::vec::Vec;
-use architecture::{Frac, Message, SensorData, SensorDriver};
-
-use tudelft_quadrupel::barometer::read_pressure;
-use tudelft_quadrupel::time::assembly_delay;
-use tudelft_quadrupel::{
-    battery::read_battery,
-    block,
-    motor::get_motors,
-    mpu::{read_dmp_bytes, read_raw},
+use architecture::{
+    Accel, AccelCorrection, Attitude, CalibrationResult, Frac, Message, Millivolts, Pascal,
+    Seconds, SensorData, SensorDriver, Velocity, ACCEL_SCALE, STANDARD_GRAVITY,
 };
-//use tudelft_quadrupel::barometer::read_pressure;
+
+use crate::accel_calibration::AccelCalibration;
+use crate::altitude::Altimeter;
+use crate::calibration::{Calibration, CalibrationSettings};
+use crate::fir_filter::SensorFir;
+use crate::gyro_bias::GyroBias;
+use crate::hal::{Barometer, Battery, Clock, Imu, Motors, RawVec};
+use crate::height_filter::HeightFilter;
+use crate::lowpassfilter::{FilterSettings, SensorFilters};
+use crate::sensor_health::SensorHealth;
 use crate::yaw_pitch_roll_quaternion::yaw_pitch_roll_from_quaternion;
 use protocol::{DataLink, MessageLink};
 
+/// How long to wait for the DMP to produce a quaternion, a bit longer than its period.
+const DMP_TIMEOUT_NS: u64 = 12_000_000;
+
+/// Length of the optional FIR filter of the readings.
+pub const FIR_TAPS: usize = 9;
+
 #[derive(Clone)]
 pub struct Sensor {
     pub data: SensorData,
     pub calibrate_offset: SensorData,
     pub calibrated: bool,
-    pub cache: SensorData,
-    pub fir_cache: Vec<SensorData>,
+    /// The calibration in progress.
+    pub calibration: Option<Calibration>,
+    pub calibration_settings: CalibrationSettings,
+    /// The accelerometer reading before the correction.
+    pub raw_acceleration: Accel,
+    pub accel_correction: AccelCorrection,
+    pub accel_calibration: AccelCalibration,
+    /// Extra FIR low-pass of the readings, off by default.
+    pub fir: Option<SensorFir<FIR_TAPS>>,
     pub gravity_scale: Frac,
-    pub pressure_zero_point: f32,
+    pub gyro_bias: GyroBias,
     pub filter_times: i32,
-    pub base_pressure: f32,
+    /// The pressure at the base station.
+    pub base_pressure: Pascal,
+    /// The pressure of the last reading before any filtering, Pa.
+    pub raw_pressure: u32,
+    pub altimeter: Altimeter,
+    pub height_filter: HeightFilter,
+    pub filters: SensorFilters,
+    pub health: SensorHealth,
+    /// The last accelerometer and gyroscope reading that could be read.
+    pub raw_imu: (RawVec, RawVec),
+    /// The attitude of the last quaternion of the DMP.
+    pub dmp_radius: Attitude,
 }
 
 impl Sensor {
@@ -33,55 +58,166 @@ impl Sensor {
             data: SensorData::new(),
             calibrate_offset: SensorData::new(),
             calibrated: false,
-            cache: SensorData::new(),
-            fir_cache: vec![],
+            calibration: None,
+            calibration_settings: CalibrationSettings::new(),
+            raw_acceleration: Accel::new(),
+            accel_correction: AccelCorrection::new(),
+            accel_calibration: AccelCalibration::new(),
+            fir: None,
             gravity_scale: Frac::from_num(0),
-            pressure_zero_point: 0.0,
+            gyro_bias: GyroBias::new(),
             filter_times: 0,
-            base_pressure: 100000.0,
+            base_pressure: Pascal::from_barometer(100_000),
+            raw_pressure: 0,
+            altimeter: Altimeter::new(),
+            height_filter: HeightFilter::new(Frac::from_num(1)),
+            filters: SensorFilters::new(&FilterSettings::new()),
+            health: SensorHealth::new(),
+            raw_imu: (RawVec::default(), RawVec::default()),
+            dmp_radius: Attitude::new(),
         }
     }
     pub fn send_data<T: protocol::Link>(&self, link: &mut MessageLink<T>) {
-        match link.send(&Message::SensorData {
+        let _ = link.send(&Message::SensorData {
             data: self.data.clone(),
-        }) {
-            Ok(_) => (),
-            Err(_) => todo!(),
-        };
+        });
     }
-    pub fn calculate_height(&mut self, _integration_constant: Frac) {
-        if self.calibrated {
-            let height_from_pressure = (Frac::from_num(self.pressure_zero_point)
-                - Frac::from_num(self.data.pressure))
-                / Frac::from_num(11);
-            self.data.height = height_from_pressure;
-            //self.calibrate_offset.acceleration.z -= i32::from_fixed(err / c2 * Frac::from_num(11));
+    pub fn apply_fir(&mut self) {
+        if let Some(fir) = &mut self.fir {
+            fir.apply(&mut self.data);
+        }
+    }
+    /// Starts collecting readings for a new calibration, see [`Sensor::calibration_step`].
+    pub fn start_calibration(&mut self) {
+        self.calibration = Some(Calibration::new(self.calibration_settings));
+    }
+    /// Takes one reading for the running calibration. Returns the result once enough were
+    /// taken; the offsets are only changed if the drone stood still.
+    pub fn calibration_step<H: Imu + Barometer + Battery + Motors + Clock>(
+        &mut self,
+        hal: &mut H,
+    ) -> Option<CalibrationResult> {
+        if self.calibration.is_none() {
+            return None;
+        }
+        self.get_values(hal, false, false);
+        let calibration = self.calibration.as_mut()?;
+        calibration.add(&self.data, self.raw_pressure);
+        if !calibration.is_done() {
+            return None;
+        }
+        let (result, ground_pressure) = calibration.result();
+        self.calibration = None;
+        if result.failure.is_none() {
+            self.calibrate_offset = SensorData {
+                radius: result.attitude_offset,
+                velocity: result.gyro_offset,
+                acceleration: result.accel_offset,
+                ..SensorData::new()
+            };
+            // the correction scales the readings to the nominal counts per g
+            self.gravity_scale = Frac::from_num(ACCEL_SCALE) / STANDARD_GRAVITY;
+            self.altimeter
+                .set_ground(Pascal::from_barometer(ground_pressure), self.base_pressure);
+            self.height_filter = HeightFilter::new(Frac::from_num(1));
+            self.gyro_bias = GyroBias::new();
+            self.calibrated = true;
+        }
+        Some(result)
+    }
+    /// Adds the last reading to the face of the six position calibration being measured, and
+    /// reports the face and the correction when they are done.
+    ///
+    /// The offsets of [`Sensor::calibrate`] depend on the correction, so they are dropped with
+    /// a new one and the sensor counts as uncalibrated until it is calibrated again.
+    pub fn accel_calibration_step<T: protocol::Link>(&mut self, link: &mut MessageLink<T>) {
+        if let Some((face, failure)) = self.accel_calibration.add(self.raw_acceleration) {
+            let _ = link.send(&Message::AccelFaceMeasured { face, failure });
+            if let Some(correction) = self.accel_calibration.correction() {
+                self.accel_correction = correction;
+                self.accel_calibration = AccelCalibration::new();
+                self.calibrate_offset = SensorData::new();
+                self.calibrated = false;
+                let _ = link.send(&Message::AccelCalibrationResult { correction });
+            }
+        }
+    }
+    /// Takes the last reading as the ground level of the height, and the height as zero.
+    pub fn capture_ground(&mut self) {
+        self.altimeter.set_ground(
+            Pascal::from_barometer(self.raw_pressure),
+            self.base_pressure,
+        );
+        self.height_filter = HeightFilter::new(Frac::from_num(1));
+    }
+    /// Removes the gyro bias that built up since the calibration from the rates of a loop
+    /// running at `frequency` Hz, see [`GyroBias`].
+    pub fn compensate_gyro_bias(&mut self, disarmed: bool, frequency: u64) {
+        let accel = self.data.acceleration + self.calibrate_offset.acceleration;
+        self.gyro_bias
+            .update(&mut self.data.velocity, accel, disarmed, frequency);
+    }
+    /// Low-pass filters the readings for a loop running at `frequency` Hz.
+    pub fn apply_filters(&mut self, frequency: u64) {
+        self.filters.apply(&mut self.data, frequency);
+    }
+    /// Fuses the unfiltered barometer with the accelerometer into `data.height` and `data.v_z`,
+    /// `dt` after the last time. The height holds while the barometer has failed.
+    pub fn calculate_height(&mut self, dt: Seconds) {
+        if self.calibrated && !self.health.barometer.is_failed() {
+            let height_from_pressure = self.altimeter.height(
+                Pascal::from_barometer(self.raw_pressure),
+                self.base_pressure,
+            );
+            let accel_z = HeightFilter::vertical_acceleration(
+                self.data.acceleration + self.calibrate_offset.acceleration,
+                self.gravity_scale,
+                self.data.radius,
+            );
+            self.height_filter.update(accel_z, height_from_pressure, dt);
+            self.data.height = self.height_filter.height;
+            self.data.v_z = self.height_filter.v_z;
         }
     }
 }
-impl SensorDriver for Sensor {
-    fn get_values(&mut self, cal_option: bool, raw: bool) {
+impl<H: Imu + Barometer + Battery + Motors + Clock> SensorDriver<H> for Sensor {
+    /// Takes a reading of every sensor. A sensor that cannot be read repeats its last reading,
+    /// the faults are recorded in [`Sensor::health`].
+    fn get_values(&mut self, hal: &mut H, cal_option: bool, raw: bool) {
         if !raw {
-            self.data.radius = yaw_pitch_roll_from_quaternion(block!(read_dmp_bytes()).unwrap());
+            let deadline = hal.now_ns() + DMP_TIMEOUT_NS;
+            let quaternion = loop {
+                match hal.read_dmp() {
+                    Ok(None) if hal.now_ns() < deadline => hal.assembly_delay(100),
+                    result => break result,
+                }
+            };
+            if let Ok(Some(q)) = quaternion {
+                self.dmp_radius = yaw_pitch_roll_from_quaternion(q);
+            }
+            self.health.check_dmp(quaternion);
+            self.data.radius = self.dmp_radius;
         }
-        let raw = read_raw().unwrap();
-        let angle_scale: Frac = Frac::PI / 180;
-        self.data.velocity.yaw = Frac::from_num(raw.1.z) * angle_scale;
-        self.data.velocity.roll = Frac::from_num(raw.1.x) * angle_scale;
-        self.data.velocity.pitch = Frac::from_num(-raw.1.y) * angle_scale;
-        self.data.motor_speeds = get_motors();
-        self.data.acceleration.x = raw.0.x as i32;
-        self.data.acceleration.y = raw.0.y as i32;
-        self.data.acceleration.z = raw.0.z as i32;
-        self.data.bat = read_battery();
-        self.data.pressure = read_pressure() as f32; //get a larger result for filters;
-        self.data.pressure = (self.data.pressure - self.base_pressure) * 10.0;
-
-        // self.data.pressure = (self.data.pressure - 100000.0) * 10.0;
-
-        if !self.calibrated {
-            self.cache.pressure = self.data.pressure;
+        let reading = hal.read_raw();
+        self.health.check_imu(reading);
+        if let Ok(reading) = reading {
+            self.raw_imu = reading;
         }
+        let raw = self.raw_imu;
+        self.data.velocity = Velocity::from_gyro_counts(raw.1.z, raw.1.y, raw.1.x);
+        // positive pitch turns against the y axis of the MPU
+        self.data.velocity.pitch = -self.data.velocity.pitch;
+        self.data.motor_speeds = hal.get_motors();
+        self.raw_acceleration = Accel {
+            x: raw.0.x as i32,
+            y: raw.0.y as i32,
+            z: raw.0.z as i32,
+        };
+        self.data.acceleration = self.accel_correction.apply(self.raw_acceleration);
+        self.data.bat = Millivolts::from_battery(hal.read_battery());
+        self.raw_pressure = hal.read_pressure();
+        self.health.check_pressure(self.raw_pressure);
+        self.data.pressure = Pascal::from_barometer(self.raw_pressure) - self.base_pressure;
         if cal_option == true {
             self.data.radius = self.data.radius - self.calibrate_offset.radius;
             self.data.acceleration = self.data.acceleration - self.calibrate_offset.acceleration;
@@ -90,43 +226,11 @@ impl SensorDriver for Sensor {
             // self.calibrate_offset.pressure += self.calibrate_offset.pressure;
         }
     }
-    fn calibrate(&mut self) {
-        let mut temp = SensorData::new();
-        let mut compensate_pressure = SensorData::new();
-        for _i in 0..20 {
-            self.get_values(false, false);
-            compensate_pressure.pressure += self.data.pressure - temp.pressure;
-            //wait for some time for sensor to get a better sample
-            assembly_delay(1_000);
-            temp.radius = temp.radius + self.data.radius;
-            temp.velocity = temp.velocity + self.data.velocity;
-            temp.acceleration = temp.acceleration + self.data.acceleration / 20;
-            temp.pressure = self.data.pressure;
+    /// Runs a whole calibration at once, for tools outside the control loop.
+    fn calibrate(&mut self, hal: &mut H) {
+        self.start_calibration();
+        while self.calibration_step(hal).is_none() {
+            hal.assembly_delay(1_000);
         }
-        let scale: Frac = Frac::from_num(20);
-        temp.radius.yaw /= scale;
-        temp.radius.roll /= scale;
-        temp.radius.pitch /= scale;
-        temp.pressure = compensate_pressure.pressure / scale.to_num::<f32>();
-        temp.velocity.yaw /= scale;
-        temp.velocity.roll /= scale;
-        temp.velocity.pitch /= scale;
-
-        self.pressure_zero_point = self.cache.pressure;
-        self.gravity_scale = Frac::from_num(temp.acceleration.z) / Frac::from_num(9.80665);
-        self.calibrate_offset = temp;
-        self.calibrated = true;
-    }
-    fn filter_ewma(&mut self, alpha: Frac, pressure_alpha: f32) {
-        let pressure = self.data.pressure as f32;
-        let cache_pressure = self.cache.pressure as f32;
-
-        self.data = self.data * alpha + self.cache * (Frac::from_num(1) - alpha);
-        self.data.pressure = pressure * pressure_alpha + cache_pressure * (1.0 - pressure_alpha);
-        self.cache = self.data;
-    }
-
-    fn filter_fir(&mut self, alpha: Frac, beta: Frac, gama: Frac) {
-        self.data = self.fir_cache[0] * alpha + self.fir_cache[1] * beta + self.fir_cache[2] * gama;
     }
 }
@@ -0,0 +1,368 @@
+use crate::hal::{DmpQuaternion, ImuError, RawVec};
+use architecture::{Frac, HealthState, SensorFault, SensorKind};
+
+/// Bad readings in a row after which a sensor has failed.
+const FAIL_AFTER: u16 = 10;
+/// Good readings in a row after which a sensor is healthy again.
+const RECOVER_AFTER: u16 = 100;
+
+/// Identical raw readings in a row that count as stuck; the noise changes them every tick.
+const STUCK_IMU: u16 = 50;
+/// The barometer converts slower than the loop runs, so its readings repeat a while.
+const STUCK_BAROMETER: u16 = 500;
+/// Saturated IMU readings in a row after which they count towards failing. Hard manoeuvres
+/// and bumps saturate an axis for a moment, which only degrades the IMU.
+const SATURATED_IMU: u16 = 500;
+/// Largest change of a raw gyroscope axis between two ticks, about 600 deg/s.
+const MAX_GYRO_JUMP: i32 = 10_000;
+/// Largest change of a raw accelerometer axis between two ticks, about 1.5 g.
+const MAX_ACCEL_JUMP: i32 = 24_000;
+/// Pressures outside this range in Pa are not from a barometer near the ground.
+const PRESSURE_RANGE: (u32, u32) = (30_000, 120_000);
+/// Largest change of the pressure between two ticks in Pa, about 80 m.
+const MAX_PRESSURE_JUMP: u32 = 1_000;
+/// How far the squared norm of a DMP quaternion may be from one.
+const MAX_QUATERNION_ERROR: Frac = Frac::lit("0.1");
+
+/// Health of one sensor, from the faults of its last readings.
+///
+/// A single fault degrades the sensor, [`FAIL_AFTER`] in a row fail it. It only becomes healthy
+/// again after [`RECOVER_AFTER`] good readings in a row, so a flaky sensor does not flip back
+/// and forth.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Health {
+    pub state: HealthState,
+    /// The last fault seen.
+    pub fault: Option<SensorFault>,
+    bad: u16,
+    good: u16,
+    /// Whether the state changed since it was last reported.
+    changed: bool,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health {
+            state: HealthState::Healthy,
+            fault: None,
+            bad: 0,
+            good: 0,
+            changed: false,
+        }
+    }
+
+    /// Records the outcome of one reading.
+    pub fn record(&mut self, fault: Option<SensorFault>) {
+        let old = self.state;
+        match fault {
+            Some(fault) => {
+                self.fault = Some(fault);
+                self.good = 0;
+                self.bad = self.bad.saturating_add(1);
+                if self.bad >= FAIL_AFTER {
+                    self.state = HealthState::Failed;
+                } else if self.state == HealthState::Healthy {
+                    self.state = HealthState::Degraded;
+                }
+            }
+            None => {
+                self.bad = 0;
+                self.good = self.good.saturating_add(1);
+                if self.good >= RECOVER_AFTER {
+                    self.state = HealthState::Healthy;
+                }
+            }
+        }
+        self.changed |= self.state != old;
+    }
+
+    /// Records a fault that degrades the sensor but does not count towards failing it.
+    pub fn degrade(&mut self, fault: SensorFault) {
+        let old = self.state;
+        self.fault = Some(fault);
+        self.good = 0;
+        if self.state == HealthState::Healthy {
+            self.state = HealthState::Degraded;
+        }
+        self.changed |= self.state != old;
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.state == HealthState::Failed
+    }
+}
+
+/// Checks every reading of the IMU, the DMP and the barometer for faults.
+#[derive(Debug, Clone)]
+pub struct SensorHealth {
+    pub imu: Health,
+    pub dmp: Health,
+    pub barometer: Health,
+    /// The last accelerometer and gyroscope reading and how often it came back in a row.
+    last_imu: Option<(RawVec, RawVec)>,
+    same_imu: u16,
+    /// Saturated IMU readings in a row.
+    saturated_imu: u16,
+    last_pressure: Option<u32>,
+    same_pressure: u16,
+}
+
+impl SensorHealth {
+    pub fn new() -> Self {
+        SensorHealth {
+            imu: Health::new(),
+            dmp: Health::new(),
+            barometer: Health::new(),
+            last_imu: None,
+            same_imu: 0,
+            saturated_imu: 0,
+            last_pressure: None,
+            same_pressure: 0,
+        }
+    }
+
+    /// Checks a raw accelerometer and gyroscope reading.
+    pub fn check_imu(&mut self, reading: Result<(RawVec, RawVec), ImuError>) {
+        let fault = match reading {
+            Err(_) => Some(SensorFault::ReadError),
+            Ok((accel, gyro)) => {
+                let last = self.last_imu.replace((accel, gyro));
+                self.same_imu = match last {
+                    Some(last) if last == (accel, gyro) => self.same_imu.saturating_add(1),
+                    _ => 0,
+                };
+                let axes = |v: RawVec| [v.x, v.y, v.z];
+                let jumped = |new: RawVec, old: RawVec, max: i32| {
+                    axes(new)
+                        .iter()
+                        .zip(axes(old))
+                        .any(|(new, old)| (*new as i32 - old as i32).abs() > max)
+                };
+                let saturated = axes(accel)
+                    .iter()
+                    .chain(&axes(gyro))
+                    .any(|v| *v == i16::MAX || *v == i16::MIN);
+                self.saturated_imu = if saturated {
+                    self.saturated_imu.saturating_add(1)
+                } else {
+                    0
+                };
+                if saturated {
+                    if self.saturated_imu < SATURATED_IMU {
+                        self.imu.degrade(SensorFault::OutOfRange);
+                        return;
+                    }
+                    Some(SensorFault::OutOfRange)
+                } else if self.same_imu >= STUCK_IMU {
+                    Some(SensorFault::Stuck)
+                } else if last.map_or(false, |(last_accel, last_gyro)| {
+                    jumped(gyro, last_gyro, MAX_GYRO_JUMP)
+                        || jumped(accel, last_accel, MAX_ACCEL_JUMP)
+                }) {
+                    Some(SensorFault::Jump)
+                } else {
+                    None
+                }
+            }
+        };
+        self.imu.record(fault);
+    }
+
+    /// Checks the result of waiting for a DMP quaternion, `None` if it did not come in time.
+    pub fn check_dmp(&mut self, reading: Result<Option<DmpQuaternion>, ImuError>) {
+        let fault = match reading {
+            Err(_) => Some(SensorFault::ReadError),
+            Ok(None) => Some(SensorFault::DmpStall),
+            Ok(Some(q)) => {
+                let norm = q.w * q.w + q.x * q.x + q.y * q.y + q.z * q.z;
+                if (norm - Frac::from_num(1)).abs() > MAX_QUATERNION_ERROR {
+                    Some(SensorFault::OutOfRange)
+                } else {
+                    None
+                }
+            }
+        };
+        self.dmp.record(fault);
+    }
+
+    /// Checks a pressure reading in Pa.
+    pub fn check_pressure(&mut self, pressure: u32) {
+        let last = self.last_pressure.replace(pressure);
+        self.same_pressure = match last {
+            Some(last) if last == pressure => self.same_pressure.saturating_add(1),
+            _ => 0,
+        };
+        let fault = if pressure < PRESSURE_RANGE.0 || pressure > PRESSURE_RANGE.1 {
+            Some(SensorFault::OutOfRange)
+        } else if self.same_pressure >= STUCK_BAROMETER {
+            Some(SensorFault::Stuck)
+        } else if last.map_or(false, |last| last.abs_diff(pressure) > MAX_PRESSURE_JUMP) {
+            Some(SensorFault::Jump)
+        } else {
+            None
+        };
+        self.barometer.record(fault);
+    }
+
+    /// A sensor whose state changed since the last call, to report it once.
+    pub fn take_change(&mut self) -> Option<(SensorKind, Health)> {
+        [
+            (SensorKind::Imu, &mut self.imu),
+            (SensorKind::Dmp, &mut self.dmp),
+            (SensorKind::Barometer, &mut self.barometer),
+        ]
+        .into_iter()
+        .find(|(_, health)| health.changed)
+        .map(|(kind, health)| {
+            health.changed = false;
+            (kind, *health)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(i: i16) -> (RawVec, RawVec) {
+        // a bit of noise on the gyro
+        let gyro = RawVec {
+            x: i % 3,
+            y: -(i % 5),
+            z: 0,
+        };
+        let accel = RawVec {
+            x: 0,
+            y: 0,
+            z: 16384,
+        };
+        (accel, gyro)
+    }
+
+    #[test]
+    fn noisy_readings_stay_healthy() {
+        let mut health = SensorHealth::new();
+        for i in 0..1000 {
+            health.check_imu(Ok(reading(i)));
+            health.check_pressure(101_325 + (i % 4) as u32);
+        }
+        assert_eq!(health.imu.state, HealthState::Healthy);
+        assert_eq!(health.barometer.state, HealthState::Healthy);
+        assert_eq!(health.take_change(), None);
+    }
+
+    #[test]
+    fn read_errors_degrade_then_fail_then_recover() {
+        let mut health = SensorHealth::new();
+        health.check_imu(Err(ImuError::Bus));
+        assert_eq!(health.imu.state, HealthState::Degraded);
+        let (kind, imu) = health.take_change().unwrap();
+        assert_eq!(kind, SensorKind::Imu);
+        assert_eq!(imu.fault, Some(SensorFault::ReadError));
+        assert_eq!(health.take_change(), None);
+
+        for _ in 1..FAIL_AFTER {
+            health.check_imu(Err(ImuError::Bus));
+        }
+        assert!(health.imu.is_failed());
+        assert_eq!(health.take_change().unwrap().1.state, HealthState::Failed);
+
+        for i in 0..RECOVER_AFTER - 1 {
+            health.check_imu(Ok(reading(i as i16)));
+        }
+        assert!(health.imu.is_failed());
+        health.check_imu(Ok(reading(0)));
+        assert_eq!(health.take_change().unwrap().1.state, HealthState::Healthy);
+    }
+
+    #[test]
+    fn a_single_fault_only_degrades() {
+        let mut health = SensorHealth::new();
+        health.check_imu(Ok(reading(0)));
+        let (accel, mut gyro) = reading(1);
+        gyro.z = 20_000;
+        health.check_imu(Ok((accel, gyro)));
+        assert_eq!(health.imu.state, HealthState::Degraded);
+        assert_eq!(health.imu.fault, Some(SensorFault::Jump));
+    }
+
+    #[test]
+    fn stuck_readings_fail() {
+        let mut health = SensorHealth::new();
+        for _ in 0..STUCK_IMU + FAIL_AFTER {
+            health.check_imu(Ok(reading(0)));
+        }
+        assert!(health.imu.is_failed());
+        assert_eq!(health.imu.fault, Some(SensorFault::Stuck));
+    }
+
+    #[test]
+    fn saturated_readings_degrade_and_only_fail_when_they_last() {
+        let mut health = SensorHealth::new();
+        let saturated = |i: i16| {
+            let (mut accel, gyro) = reading(i);
+            accel.z = i16::MAX;
+            (accel, gyro)
+        };
+        // a hard landing
+        for i in 0..100 {
+            health.check_imu(Ok(saturated(i)));
+        }
+        assert_eq!(health.imu.state, HealthState::Degraded);
+        assert_eq!(health.imu.fault, Some(SensorFault::OutOfRange));
+        for i in 0..RECOVER_AFTER as i16 {
+            health.check_imu(Ok(reading(i)));
+        }
+        assert_eq!(health.imu.state, HealthState::Healthy);
+
+        for i in 0..(SATURATED_IMU + FAIL_AFTER - 1) as i16 {
+            assert!(!health.imu.is_failed());
+            health.check_imu(Ok(saturated(i)));
+        }
+        assert!(health.imu.is_failed());
+        assert_eq!(health.imu.fault, Some(SensorFault::OutOfRange));
+    }
+
+    #[test]
+    fn dmp_stalls_and_bad_quaternions_fail() {
+        let mut health = SensorHealth::new();
+        for _ in 0..FAIL_AFTER {
+            health.check_dmp(Ok(None));
+        }
+        assert!(health.dmp.is_failed());
+        assert_eq!(health.dmp.fault, Some(SensorFault::DmpStall));
+
+        let mut health = SensorHealth::new();
+        let one = Frac::from_num(1);
+        let zero = Frac::from_num(0);
+        health.check_dmp(Ok(Some(DmpQuaternion {
+            w: one,
+            x: zero,
+            y: zero,
+            z: zero,
+        })));
+        assert_eq!(health.dmp.state, HealthState::Healthy);
+        health.check_dmp(Ok(Some(DmpQuaternion {
+            w: one,
+            x: one,
+            y: zero,
+            z: zero,
+        })));
+        assert_eq!(health.dmp.fault, Some(SensorFault::OutOfRange));
+    }
+
+    #[test]
+    fn barometer_faults() {
+        let mut health = SensorHealth::new();
+        health.check_pressure(101_325);
+        health.check_pressure(99_000);
+        assert_eq!(health.barometer.fault, Some(SensorFault::Jump));
+        health.check_pressure(0);
+        assert_eq!(health.barometer.fault, Some(SensorFault::OutOfRange));
+        for _ in 0..STUCK_BAROMETER + FAIL_AFTER {
+            health.check_pressure(101_325);
+        }
+        assert!(health.barometer.is_failed());
+        assert_eq!(health.barometer.fault, Some(SensorFault::Stuck));
+    }
+}
@@ -0,0 +1,36 @@
+//! Readings shared by the tests of the filters and the controller.
+
+use architecture::{
+    Accel, Attitude, Frac, Metres, MetresPerSecond, Pascal, Pressure, Radians, RadiansPerSecond,
+    SensorData, Velocity,
+};
+
+/// Every reading at the largest or the smallest value of its type.
+pub fn extreme(high: bool) -> SensorData {
+    let (frac, count, pressure) = if high {
+        (Frac::MAX, i32::MAX, Pressure::MAX)
+    } else {
+        (Frac::MIN, i32::MIN, Pressure::MIN)
+    };
+    SensorData {
+        height: Metres(frac),
+        v_z: MetresPerSecond(frac),
+        pressure: Pascal(pressure),
+        velocity: Velocity {
+            yaw: RadiansPerSecond(frac),
+            pitch: RadiansPerSecond(frac),
+            roll: RadiansPerSecond(frac),
+        },
+        radius: Attitude {
+            yaw: Radians(frac),
+            pitch: Radians(frac),
+            roll: Radians(frac),
+        },
+        acceleration: Accel {
+            x: count,
+            y: count,
+            z: count,
+        },
+        ..SensorData::new()
+    }
+}
@@ -1,73 +1,152 @@
-use architecture::{Accel, Frac, YawPitchRoll};
+use architecture::{Accel, Attitude, Frac, Radians};
 
+use crate::hal::DmpQuaternion;
 use fixed_trigonometry::*;
-use tudelft_quadrupel::mpu::structs::Quaternion;
 
-//impl From<Quaternion> for YawPitchRoll {
-/// Creates a YawPitchRoll from a Quaternion
-pub fn yaw_pitch_roll_from_quaternion(q: Quaternion) -> YawPitchRoll {
-    let Quaternion { w, x, y, z } = q;
+//impl From<Quaternion> for Attitude {
+/// Creates an Attitude from a Quaternion
+pub fn yaw_pitch_roll_from_quaternion(q: DmpQuaternion) -> Attitude {
+    let DmpQuaternion { w, x, y, z } = q;
 
     let one = Frac::from_num(1);
     let two = Frac::from_num(2);
-    let w = Frac::from_num(w);
-    let x = Frac::from_num(x);
-    let y = Frac::from_num(y);
-    let z = Frac::from_num(z);
 
     let gx = two * (x * z - w * y);
     let gy = two * (w * x + y * z);
     let gz = w * w - x * x - y * y + z * z;
 
+    // yaw: (about Z axis)
     let yaw = atan::atan2(two * x * y - two * w * z, two * w * w + two * x * x - one);
+    // pitch: (nose up/down, about Y axis)
     let pitch = atan::atan2(gx, sqrt::niirf(gy * gy + gz * gz, 2));
+    // roll: (tilt left/right, about X axis)
     let roll = atan::atan2(gy, gz);
 
-    //---------------------------------------
-    /*let Quaternion { w, x, y, z } = q;
+    Attitude {
+        yaw: Radians(yaw),
+        pitch: Radians(pitch),
+        roll: Radians(roll),
+    }
+}
+//}
 
-    let w: f32 = w.to_num();
-    let x: f32 = x.to_num();
-    let y: f32 = y.to_num();
-    let z: f32 = z.to_num();
+/// Pitch and roll from the direction of gravity in the acceleration `acc`, yaw stays 0.
+/// Level without any acceleration.
+pub fn yaw_pitch_roll_from_acc(acc: Accel) -> Attitude {
+    let mut out = Attitude::new();
+    // only the direction counts, scaled to the largest component the squares fit
+    let largest = [acc.x, acc.y, acc.z]
+        .map(|a| a.unsigned_abs() as i64)
+        .into_iter()
+        .max()
+        .unwrap_or(0);
+    if largest == 0 {
+        return out;
+    }
+    let [x, y, z] = [acc.x, acc.y, acc.z]
+        .map(|a| Frac::from_bits((((a as i64) << Frac::FRAC_NBITS) / largest) as i32));
 
-    let gx = 2.0 * (x * z - w * y);
-    let gy = 2.0 * (w * x + y * z);
-    let gz = w * w - x * x - y * y + z * z;
+    out.roll = Radians(atan::atan2(y, root(x * x + z * z)));
+    out.pitch = Radians(atan::atan2(x, root(y * y + z * z)));
+    out
+}
+
+/// Square root of a non-negative `x`.
+fn root(x: Frac) -> Frac {
+    if x > 0 {
+        sqrt::niirf(x, 2)
+    } else {
+        Frac::ZERO
+    }
+}
 
-    // todo: trigonometry functions in fixed point
+#[cfg(test)]
+mod tests {
+    extern crate std;
 
-    // yaw: (about Z axis)
-    let yaw = Frac::from_num(
-        micromath::F32Ext::atan2(2.0 * x * y - 2.0 * w * z, 2.0 * w * w + 2.0 * x * x - 1.0)
-    );
+    use super::*;
+    use std::f64::consts::TAU;
+    use std::println;
 
-    // pitch: (nose up/down, about Y axis)
-    let pitch = Frac::from_num(
-        micromath::F32Ext::atan2(gx, micromath::F32Ext::sqrt(gy * gy + gz * gz))
-    );
+    /// Largest error in rad against the same math in f64.
+    const MAX_ERROR: f64 = 0.01;
 
-    // roll: (tilt left/right, about X axis)
-    let roll = Frac::from_num(
-        micromath::F32Ext::atan2(gy, gz)
-    );*/
+    /// Difference of two angles in rad, around the circle.
+    fn error(actual: Radians, expected: f64) -> f64 {
+        let error = (actual.0.to_num::<f64>() - expected).rem_euclid(TAU);
+        error.min(TAU - error)
+    }
 
-    YawPitchRoll { yaw, pitch, roll }
-}
-//}
-pub fn yaw_pitch_roll_from_acc(acc: Accel) -> YawPitchRoll {
-    let mut out = YawPitchRoll::new();
-    let x = acc.x as f32;
-    let y = acc.y as f32;
-    let z = acc.z as f32;
-
-    // todo: trigonometry functions in fixed point
-
-    out.roll = Frac::from_num(micromath::F32Ext::atan(
-        y / micromath::F32Ext::sqrt(x * x + z * z),
-    ));
-    out.pitch = Frac::from_num(-micromath::F32Ext::atan(
-        -x / micromath::F32Ext::sqrt(y * y + z * z),
-    ));
-    out
+    #[test]
+    fn acc_angles_match_f64() {
+        let mut max: [f64; 2] = [0.0; 2];
+        // the range of the sensor, the axes, and corrected readings beyond it
+        let counts: std::vec::Vec<i32> = (i16::MIN as i32..=i16::MAX as i32)
+            .step_by(2_048)
+            .chain([-1, 0, 1, i16::MAX as i32, i32::MIN, i32::MAX])
+            .collect();
+        for &x in &counts {
+            for &y in &counts {
+                for &z in &counts {
+                    let angles = yaw_pitch_roll_from_acc(Accel { x, y, z });
+                    let [x, y, z] = [x, y, z].map(|a| a as f64);
+                    let roll = y.atan2((x * x + z * z).sqrt());
+                    let pitch = x.atan2((y * y + z * z).sqrt());
+                    max[0] = max[0].max(error(angles.pitch, pitch));
+                    max[1] = max[1].max(error(angles.roll, roll));
+                    assert_eq!(angles.yaw, Radians::ZERO);
+                }
+            }
+        }
+        println!("max error pitch {} rad, roll {} rad", max[0], max[1]);
+        assert!(max.iter().all(|max| *max < MAX_ERROR), "{max:?}");
+    }
+
+    #[test]
+    fn quaternion_angles_match_f64() {
+        let mut max: [f64; 3] = [0.0; 3];
+        let degrees = |range: i32, step: usize| {
+            (-range..=range)
+                .step_by(step)
+                .map(|d| (d as f64).to_radians() / 2.0)
+        };
+        for yaw in degrees(170, 10) {
+            for pitch in degrees(80, 10) {
+                for roll in degrees(170, 10) {
+                    let (sy, cy) = yaw.sin_cos();
+                    let (sp, cp) = pitch.sin_cos();
+                    let (sr, cr) = roll.sin_cos();
+                    let q = DmpQuaternion {
+                        w: Frac::from_num(cr * cp * cy + sr * sp * sy),
+                        x: Frac::from_num(sr * cp * cy - cr * sp * sy),
+                        y: Frac::from_num(cr * sp * cy + sr * cp * sy),
+                        z: Frac::from_num(cr * cp * sy - sr * sp * cy),
+                    };
+                    let angles = yaw_pitch_roll_from_quaternion(q);
+
+                    // the reference from the rounded quaternion, to only see the math
+                    let [w, x, y, z] = [q.w, q.x, q.y, q.z].map(|c| c.to_num::<f64>());
+                    let gx = 2.0 * (x * z - w * y);
+                    let gy = 2.0 * (w * x + y * z);
+                    let gz = w * w - x * x - y * y + z * z;
+                    let expected = [
+                        (2.0 * x * y - 2.0 * w * z).atan2(2.0 * w * w + 2.0 * x * x - 1.0),
+                        gx.atan2((gy * gy + gz * gz).sqrt()),
+                        gy.atan2(gz),
+                    ];
+                    for (max, (actual, expected)) in max
+                        .iter_mut()
+                        .zip([angles.yaw, angles.pitch, angles.roll].iter().zip(expected))
+                    {
+                        *max = max.max(error(*actual, expected));
+                    }
+                }
+            }
+        }
+        println!(
+            "max error yaw {} rad, pitch {} rad, roll {} rad",
+            max[0], max[1], max[2]
+        );
+        assert!(max.iter().all(|max| *max < MAX_ERROR), "{max:?}");
+    }
 }
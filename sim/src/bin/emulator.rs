@@ -0,0 +1,71 @@
+//! Runs the flight code on the simulated quadcopter in real time, behind a pseudo terminal
+//! that speaks the same protocol as the uart of the real drone.
+//!
+//! Usage: `emulator [--seed N] [--replay FILE] [--link PATH]`
+//!
+//! `--replay` plays back recorded sensor data instead of simulating the sensors, `--link`
+//! creates a symlink to the pseudo terminal so the base station can use a fixed path.
+
+use dronecode::control_loop::control_loop;
+use dronecode::hal::mock::RamDisk;
+use sim::hal::{SimHal, FLASH_SIZE};
+use sim::model::{QuadModel, QuadParams};
+use sim::pty::{Pty, PtyUart};
+use sim::replay::Replay;
+use sim::sensors::{SensorModel, SensorParams};
+use std::process::exit;
+
+fn usage() -> ! {
+    eprintln!("usage: emulator [--seed N] [--replay FILE] [--link PATH]");
+    exit(2)
+}
+
+fn main() {
+    let mut seed = 1;
+    let mut replay = None;
+    let mut link = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--seed" => seed = value.parse().unwrap_or_else(|_| usage()),
+            "--replay" => {
+                let text = std::fs::read_to_string(&value).unwrap_or_else(|e| {
+                    eprintln!("cannot read {value}: {e}");
+                    exit(1)
+                });
+                replay = Some(Replay::parse(&text).unwrap_or_else(|e| {
+                    eprintln!("{value}: {e}");
+                    exit(1)
+                }));
+            }
+            "--link" => link = Some(value),
+            _ => usage(),
+        }
+    }
+
+    let pty = Pty::open().unwrap_or_else(|e| {
+        eprintln!("cannot open a pseudo terminal: {e}");
+        exit(1)
+    });
+    if let Some(link) = &link {
+        let _ = std::fs::remove_file(link);
+        std::os::unix::fs::symlink(&pty.slave_name, link).unwrap_or_else(|e| {
+            eprintln!("cannot create {link}: {e}");
+            exit(1)
+        });
+    }
+    println!(
+        "drone emulator on {}",
+        link.as_deref().unwrap_or(&pty.slave_name)
+    );
+    PtyUart::install(pty);
+
+    let mut hal: SimHal<PtyUart> = SimHal::new(
+        QuadModel::new(QuadParams::quadrupel()),
+        SensorModel::new(SensorParams::quadrupel(), seed),
+    );
+    hal.replay = replay;
+    hal.run_in_real_time();
+    control_loop(hal, RamDisk::new(FLASH_SIZE))
+}
@@ -0,0 +1,372 @@
+//! Compares the attitude estimators of the raw mode with each other and with the complementary
+//! filter they replaced, on the same sensor data.
+//!
+//! Usage: `estimator [--seed N] [--replay FILE | --record FILE]`
+//!
+//! Without `--replay` the quadcopter is swung around by a fixed motion profile, after standing
+//! still for a few seconds, and the estimates are compared with the true attitude. A replay
+//! is compared with the DMP attitude of the recording, or only the filters with the Kalman
+//! filter when the recording has no quaternions. `--record` writes the simulated readings of
+//! the motion profile, including the DMP, to a file that `--replay` can read. The start of
+//! such a recording is checked in as `sim/recordings/estimator.txt`.
+
+use architecture::SensorDriver;
+use architecture::{Attitude, Frac, Radians, Seconds, Velocity};
+use dronecode::hal::{Barometer, Battery, Imu};
+use dronecode::kalman_filter::{KalmanFilter, NoiseParameters};
+use dronecode::mahony_filter::MahonyFilter;
+use dronecode::sensor::Sensor;
+use dronecode::yaw_pitch_roll_quaternion::{
+    yaw_pitch_roll_from_acc, yaw_pitch_roll_from_quaternion,
+};
+use sim::hal::SimHal;
+use sim::model::{QuadModel, QuadParams, V3};
+use sim::replay::{Reading, Replay};
+use sim::sensors::{SensorModel, SensorParams};
+use std::f64::consts::TAU;
+use std::process::exit;
+
+/// Frequency of the control loop, Hz.
+const FREQUENCY: f64 = 150.0;
+/// Physics step, s.
+const PHYSICS_DT: f64 = 0.001;
+const DURATION: f64 = 60.0;
+/// Time standing still before the motion starts, s.
+const STILL: f64 = 5.0;
+
+trait Estimator {
+    fn fuse(&mut self, sensor: &mut Sensor, dt: Frac);
+
+    /// Estimated gyro bias on top of the calibration, rad/s.
+    fn bias(&self) -> Option<Velocity> {
+        None
+    }
+}
+
+impl Estimator for KalmanFilter {
+    fn fuse(&mut self, sensor: &mut Sensor, dt: Frac) {
+        self.integration_constant = dt;
+        self.fusion_algorithm(sensor);
+    }
+
+    fn bias(&self) -> Option<Velocity> {
+        Some(KalmanFilter::bias(self))
+    }
+}
+
+impl Estimator for MahonyFilter {
+    fn fuse(&mut self, sensor: &mut Sensor, dt: Frac) {
+        self.integration_constant = dt;
+        self.fusion_algorithm(sensor);
+    }
+
+    fn bias(&self) -> Option<Velocity> {
+        Some(MahonyFilter::bias(self))
+    }
+}
+
+/// The complementary filter as it was before the Kalman filter, kept as a reference.
+struct Complementary {
+    phi: Attitude,
+    c1: Frac,
+    c2: Frac,
+}
+
+impl Estimator for Complementary {
+    fn fuse(&mut self, sensor: &mut Sensor, dt: Frac) {
+        let acc_phi = yaw_pitch_roll_from_acc(
+            sensor.data.acceleration + sensor.calibrate_offset.acceleration,
+        );
+        let dt = Seconds(dt);
+        self.phi = self.phi + sensor.data.velocity * dt;
+        let e = self.phi - acc_phi;
+        self.phi = self.phi - e / self.c1;
+        sensor.calibrate_offset.velocity = sensor.calibrate_offset.velocity + (e / dt) / self.c2;
+        sensor.data.radius = self.phi - sensor.calibrate_offset.radius;
+        sensor.data.radius.yaw = Radians::ZERO;
+    }
+}
+
+/// Body rates of the test motion at `time`, rad/s.
+fn motion(time: f64) -> V3 {
+    if time < STILL {
+        return V3::ZERO;
+    }
+    let t = time - STILL;
+    V3::new(
+        0.8 * (TAU * 0.5 * t).sin(),
+        0.6 * (TAU * 0.3 * t + 1.0).sin(),
+        0.3 * (TAU * 0.1 * t).sin(),
+    )
+}
+
+#[derive(Default)]
+struct Errors {
+    sum_squared: f64,
+    max: f64,
+    count: usize,
+}
+
+impl Errors {
+    fn add(&mut self, estimate: Attitude, reference: (f64, f64)) {
+        for error in [
+            estimate.pitch.0.to_num::<f64>() - reference.0,
+            estimate.roll.0.to_num::<f64>() - reference.1,
+        ] {
+            self.sum_squared += error * error;
+            self.max = self.max.max(error.abs());
+            self.count += 1;
+        }
+    }
+
+    fn report(&self, name: &str) {
+        let rms = (self.sum_squared / self.count.max(1) as f64).sqrt();
+        println!(
+            "{name:<24} rms {:>6.2} deg  max {:>6.2} deg",
+            rms.to_degrees(),
+            self.max.to_degrees()
+        );
+    }
+}
+
+fn world(seed: u64) -> SimHal {
+    SimHal::new(
+        QuadModel::new(QuadParams::quadrupel()),
+        SensorModel::new(SensorParams::quadrupel(), seed),
+    )
+}
+
+/// Moves the drone along the motion profile until `time`, without any dynamics.
+fn move_to(hal: &mut SimHal, time: f64) {
+    while hal.time + PHYSICS_DT <= time {
+        hal.model.state.rates = motion(hal.time);
+        hal.model
+            .state
+            .attitude
+            .integrate(hal.model.state.rates, PHYSICS_DT);
+        hal.sensors.step(&hal.model, PHYSICS_DT);
+        hal.time += PHYSICS_DT;
+    }
+}
+
+/// One estimator with its own copy of the simulated world, so all of them see the same readings.
+struct Run {
+    name: &'static str,
+    hal: SimHal,
+    sensor: Sensor,
+    estimator: Box<dyn Estimator>,
+    errors: Errors,
+}
+
+impl Run {
+    fn new(
+        name: &'static str,
+        seed: u64,
+        replay: Option<Replay>,
+        estimator: Box<dyn Estimator>,
+    ) -> Self {
+        let mut hal = world(seed);
+        hal.replay = replay;
+        let mut sensor = Sensor::new();
+        sensor.calibrate(&mut hal);
+        Run {
+            name,
+            hal,
+            sensor,
+            estimator,
+            errors: Errors::default(),
+        }
+    }
+
+    fn tick(&mut self) -> Attitude {
+        let dt = Frac::from_num(1.0 / FREQUENCY);
+        self.sensor.get_values(&mut self.hal, true, true);
+        // armed, so the bias is only what the estimators measure
+        self.sensor.compensate_gyro_bias(false, FREQUENCY as u64);
+        self.sensor.apply_filters(FREQUENCY as u64);
+        self.estimator.fuse(&mut self.sensor, dt);
+        self.sensor.data.radius
+    }
+
+    /// `(pitch, roll)` the recording or the model says the drone has.
+    fn reference(&self) -> Option<(f64, f64)> {
+        match &self.hal.replay {
+            Some(replay) => replay.at(self.hal.time).dmp.map(|q| {
+                let ypr = yaw_pitch_roll_from_quaternion(q) - self.sensor.calibrate_offset.radius;
+                (ypr.pitch.0.to_num(), ypr.roll.0.to_num())
+            }),
+            None => {
+                let (_, pitch, roll) = self.hal.model.state.attitude.euler();
+                Some((pitch, roll))
+            }
+        }
+    }
+}
+
+/// Writes the readings of the motion profile in the format of [`Replay`].
+fn record(seed: u64, path: &str) -> std::io::Result<()> {
+    let mut hal = world(seed);
+    let mut text = String::from("# time ax ay az gx gy gz pressure battery qw qx qy qz\n");
+    for tick in 0..(DURATION * FREQUENCY) as usize {
+        let time = tick as f64 / FREQUENCY;
+        move_to(&mut hal, time);
+        let (accel, gyro) = hal.read_raw().unwrap();
+        let reading = Reading {
+            time,
+            accel,
+            gyro,
+            pressure: hal.read_pressure(),
+            battery: hal.read_battery(),
+            dmp: Some(hal.sensors.read_dmp(&hal.model)),
+        };
+        text += &format!("{reading}\n");
+    }
+    std::fs::write(path, text)
+}
+
+/// Runs all estimators up to `end` s and adds up their errors.
+fn compare(runs: &mut [Run], end: f64) {
+    let start = runs[0].hal.time;
+    let mut tick = 0;
+    loop {
+        tick += 1;
+        let time = start + tick as f64 / FREQUENCY;
+        if time > end {
+            break;
+        }
+        let mut first = None;
+        for run in runs.iter_mut() {
+            move_to(&mut run.hal, time);
+            let estimate = run.tick();
+            // give the filters a second to converge from the calibration
+            if time - start < 1.0 {
+                continue;
+            }
+            // without a reference, compare with the kalman filter
+            let reference = run.reference().or(first);
+            if let Some(reference) = reference {
+                run.errors.add(estimate, reference);
+            }
+            first = first.or(Some((estimate.pitch.0.to_num(), estimate.roll.0.to_num())));
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: estimator [--seed N] [--replay FILE | --record FILE]");
+    exit(2)
+}
+
+fn main() {
+    let mut seed = 1;
+    let mut replay = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--seed" => seed = value.parse().unwrap_or_else(|_| usage()),
+            "--replay" => {
+                let text = std::fs::read_to_string(&value).unwrap_or_else(|e| {
+                    eprintln!("cannot read {value}: {e}");
+                    exit(1)
+                });
+                replay = Some(Replay::parse(&text).unwrap_or_else(|e| {
+                    eprintln!("{value}: {e}");
+                    exit(1)
+                }));
+            }
+            "--record" => {
+                record(seed, &value).unwrap_or_else(|e| {
+                    eprintln!("cannot write {value}: {e}");
+                    exit(1)
+                });
+                return;
+            }
+            _ => usage(),
+        }
+    }
+
+    let noise = NoiseParameters::quadrupel();
+    let complementary = Complementary {
+        phi: Attitude::new(),
+        c1: Frac::from_num(4),
+        c2: Frac::from_num(5000),
+    };
+    let mahony = MahonyFilter::new(Frac::from_num(1), Frac::from_num(0.05));
+    let mut runs = [
+        Run::new(
+            "kalman filter",
+            seed,
+            replay.clone(),
+            Box::new(KalmanFilter::new(noise)),
+        ),
+        Run::new("mahony filter", seed, replay.clone(), Box::new(mahony)),
+        Run::new(
+            "complementary filter",
+            seed,
+            replay.clone(),
+            Box::new(complementary),
+        ),
+    ];
+
+    // the calibration already used the start of a recording, stop before it loops
+    let end = replay
+        .as_ref()
+        .map_or(runs[0].hal.time + DURATION, |r| r.duration());
+    compare(&mut runs, end);
+
+    if replay.as_ref().is_some_and(|r| r.readings[0].dmp.is_none()) {
+        println!("no dmp in the recording, errors are relative to the kalman filter");
+    }
+    for run in &runs {
+        run.errors.report(run.name);
+    }
+    for run in &runs {
+        let Some(bias) = run.estimator.bias() else {
+            continue;
+        };
+        // the kalman filter hands its estimate over to the gyro bias
+        let bias = bias + run.sensor.gyro_bias.bias() + run.sensor.calibrate_offset.velocity;
+        println!(
+            "{:<24} yaw {:>7.4}  pitch {:>7.4}  roll {:>7.4} rad/s",
+            format!("{} bias", run.name),
+            bias.yaw.0.to_num::<f64>(),
+            bias.pitch.0.to_num::<f64>(),
+            bias.roll.0.to_num::<f64>(),
+        );
+    }
+    if replay.is_none() {
+        let truth = runs[0].hal.sensors.gyro_bias;
+        println!(
+            "{:<24} yaw {:>7.4}  pitch {:>7.4}  roll {:>7.4} rad/s",
+            "true bias", truth.z, -truth.y, truth.x
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The first 10 s of `estimator --record`: standing still for 5 s, then swinging around.
+    const RECORDING: &str = include_str!("../../recordings/estimator.txt");
+
+    #[test]
+    fn the_filters_follow_the_dmp_of_a_recording() {
+        let replay = Replay::parse(RECORDING).unwrap();
+        let mahony = MahonyFilter::new(Frac::from_num(1), Frac::from_num(0.05));
+        let kalman = KalmanFilter::new(NoiseParameters::quadrupel());
+        let mut runs = [
+            Run::new("kalman filter", 1, Some(replay.clone()), Box::new(kalman)),
+            Run::new("mahony filter", 1, Some(replay.clone()), Box::new(mahony)),
+        ];
+        compare(&mut runs, replay.duration());
+        for run in &runs {
+            let errors = &run.errors;
+            assert!(errors.count > 1000, "{}: {}", run.name, errors.count);
+            let rms = (errors.sum_squared / errors.count as f64).sqrt();
+            assert!(rms < 0.05, "{}: rms {rms} rad", run.name);
+            assert!(errors.max < 0.15, "{}: max {} rad", run.name, errors.max);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::model::QuadModel;
+use crate::replay::Replay;
+use crate::sensors::SensorModel;
+use core::marker::PhantomData;
+use dronecode::hal::mock::MockHal;
+use dronecode::hal::{
+    Barometer, Battery, Clock, DmpQuaternion, Imu, ImuError, Led, Leds, Motors, RawVec, Uart,
+};
+use std::time::{Duration, Instant};
+
+/// Size of the simulated flash chip, the same as on the quadrupel.
+pub const FLASH_SIZE: usize = 128 * 1024;
+
+/// Physics step, s.
+const PHYSICS_DT: f64 = 0.001;
+/// Time spent by one poll of the DMP that found no new quaternion, s.
+const DMP_POLL_TIME: f64 = 0.0001;
+/// Clock of the nrf51, Hz.
+const CPU_FREQUENCY: f64 = 16e6;
+
+/// The hardware of the flight code backed by the simulated quadcopter.
+///
+/// Simulated time only advances when the flight code waits: on `wait_for_next_tick`, on the
+/// delays and while it polls the DMP. The uart is provided by `U`.
+pub struct SimHal<U: Uart = MockHal> {
+    pub model: QuadModel,
+    pub sensors: SensorModel,
+    /// When set, the imu, barometer and battery readings come from this recording instead.
+    pub replay: Option<Replay>,
+    /// On/off state of the red, yellow, green and blue led.
+    pub leds: [bool; 4],
+    pub motor_max: u16,
+    pub tick_frequency: u64,
+    /// Makes every read of the DMP fail, as if it stopped answering on the bus.
+    pub dmp_failed: bool,
+    /// Simulated time, s.
+    pub time: f64,
+    next_tick: f64,
+    physics_time: f64,
+    last_dmp: f64,
+    /// Wall clock time at which the simulation started, if it runs in real time.
+    realtime: Option<Instant>,
+    _uart: PhantomData<U>,
+}
+
+impl<U: Uart> SimHal<U> {
+    pub fn new(model: QuadModel, sensors: SensorModel) -> Self {
+        SimHal {
+            model,
+            sensors,
+            replay: None,
+            leds: [false; 4],
+            motor_max: 1000,
+            tick_frequency: 100,
+            dmp_failed: false,
+            time: 0.0,
+            next_tick: 0.0,
+            physics_time: 0.0,
+            last_dmp: f64::NEG_INFINITY,
+            realtime: None,
+            _uart: PhantomData,
+        }
+    }
+
+    /// Slows the simulation down to the wall clock, for talking to a real base station.
+    pub fn run_in_real_time(&mut self) {
+        self.realtime = Some(Instant::now() - Duration::from_secs_f64(self.time));
+    }
+
+    /// Lets `dt` seconds pass in the simulated world.
+    pub fn advance(&mut self, dt: f64) {
+        self.time += dt;
+        while self.physics_time + PHYSICS_DT <= self.time {
+            self.model.step(PHYSICS_DT);
+            self.sensors.step(&self.model, PHYSICS_DT);
+            self.physics_time += PHYSICS_DT;
+        }
+        if let Some(start) = self.realtime {
+            let target = start + Duration::from_secs_f64(self.time);
+            let now = Instant::now();
+            if target > now {
+                std::thread::sleep(target - now);
+            }
+        }
+    }
+
+    pub fn led(&self, led: Led) -> bool {
+        self.leds[led as usize]
+    }
+}
+
+impl<U: Uart> Motors for SimHal<U> {
+    fn set_motors(&mut self, speeds: [u16; 4]) {
+        self.model.motor_commands = speeds.map(|s| s.min(self.motor_max));
+    }
+
+    fn get_motors(&self) -> [u16; 4] {
+        self.model.motor_commands
+    }
+
+    fn set_motor_max(&mut self, max: u16) {
+        self.motor_max = max;
+    }
+}
+
+impl<U: Uart> Imu for SimHal<U> {
+    fn read_raw(&mut self) -> Result<(RawVec, RawVec), ImuError> {
+        match &self.replay {
+            Some(replay) => {
+                let reading = replay.at(self.time);
+                Ok((reading.accel, reading.gyro))
+            }
+            None => Ok(self.sensors.read_raw(&self.model)),
+        }
+    }
+
+    fn read_dmp(&mut self) -> Result<Option<DmpQuaternion>, ImuError> {
+        if self.dmp_failed {
+            return Err(ImuError::Bus);
+        }
+        if self.time - self.last_dmp >= 1.0 / self.sensors.params.dmp_rate {
+            self.last_dmp = self.time;
+            let recorded = self.replay.as_ref().and_then(|r| r.at(self.time).dmp);
+            Ok(Some(
+                recorded.unwrap_or_else(|| self.sensors.read_dmp(&self.model)),
+            ))
+        } else {
+            self.advance(DMP_POLL_TIME);
+            Ok(None)
+        }
+    }
+}
+
+impl<U: Uart> Barometer for SimHal<U> {
+    fn read_pressure(&mut self) -> u32 {
+        match &self.replay {
+            Some(replay) => replay.at(self.time).pressure,
+            None => self.sensors.read_pressure(&self.model),
+        }
+    }
+}
+
+impl<U: Uart> Battery for SimHal<U> {
+    fn read_battery(&mut self) -> u16 {
+        match &self.replay {
+            Some(replay) => replay.at(self.time).battery,
+            None => self.sensors.read_battery(&self.model),
+        }
+    }
+}
+
+impl<U: Uart> Leds for SimHal<U> {
+    fn led_on(&mut self, led: Led) {
+        self.leds[led as usize] = true;
+    }
+
+    fn led_off(&mut self, led: Led) {
+        self.leds[led as usize] = false;
+    }
+
+    fn led_toggle(&mut self, led: Led) {
+        self.leds[led as usize] = !self.leds[led as usize];
+    }
+}
+
+impl<U: Uart> Clock for SimHal<U> {
+    fn set_tick_frequency(&mut self, frequency: u64) {
+        self.tick_frequency = frequency;
+    }
+
+    fn wait_for_next_tick(&mut self) {
+        // like the timer on the board, a tick that was already missed returns immediately
+        self.next_tick = (self.next_tick + 1.0 / self.tick_frequency as f64).max(self.time);
+        self.advance(self.next_tick - self.time);
+    }
+
+    fn assembly_delay(&mut self, cycles: usize) {
+        self.advance(cycles as f64 / CPU_FREQUENCY);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.advance(ms as f64 / 1000.0);
+    }
+
+    fn now_ns(&self) -> u64 {
+        (self.time * 1e9) as u64
+    }
+}
+
+impl<U: Uart> Uart for SimHal<U> {
+    fn send_bytes(bytes: &[u8]) -> bool {
+        U::send_bytes(bytes)
+    }
+
+    fn receive_bytes(bytes: &mut [u8]) -> usize {
+        U::receive_bytes(bytes)
+    }
+}
@@ -0,0 +1,15 @@
+//! Software in the loop simulation of the quadcopter.
+//!
+//! [`model`] is a rigid body model of the drone, [`sensors`] turns its state into noisy sensor
+//! readings and [`hal::SimHal`] hands those to the flight code through the `dronecode` hardware
+//! traits. [`scenario`] flies the real control loop through a script of pilot inputs, much
+//! faster than real time. The `emulator` binary instead runs the control loop in real time
+//! behind a pseudo terminal ([`pty`]), optionally on recorded sensor data ([`replay`]).
+
+pub mod hal;
+pub mod model;
+pub mod noise;
+pub mod pty;
+pub mod replay;
+pub mod scenario;
+pub mod sensors;
@@ -0,0 +1,65 @@
+use architecture::{AttitudeEstimator, Mode};
+use sim::scenario::{request, Command, Script, Simulation, Trace};
+
+fn report(name: &str, trace: &Trace, settle: f64) {
+    let last = trace.last().unwrap();
+    println!(
+        "{name:<24} mode {:<12} height {:>6.2} m  max tilt {:>5.1} deg  max estimate error {:>5.1} deg  height error {:>5.2} m",
+        format!("{:?}", last.mode),
+        last.height,
+        trace.max_tilt(settle).to_degrees(),
+        trace.max_attitude_error(settle).to_degrees(),
+        trace.max_height_error(settle),
+    );
+}
+
+fn main() {
+    let seed = std::env::args()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1);
+
+    // a throttle of 3000 is roughly hover
+    let hover = Script::takeoff(Mode::FullControl)
+        .at(1.5, Command::Throttle(3300))
+        .at(3.0, Command::Throttle(3000));
+    let trace = Simulation::quadrupel(seed).run(&hover, 10.0);
+    report("full control hover", &trace, 3.0);
+
+    let roll_step = Script::takeoff(Mode::FullControl)
+        .at(1.5, Command::Throttle(3300))
+        .at(3.0, Command::Throttle(3000))
+        .at(4.0, Command::Request(request(0.0, 0.0, 0.1, 3000)))
+        .at(5.0, Command::Request(request(0.0, 0.0, 0.0, 3000)));
+    let trace = Simulation::quadrupel(seed).run(&roll_step, 8.0);
+    report("full control roll step", &trace, 6.0);
+
+    let raw = Script::takeoff(Mode::FullControl)
+        .at(0.9, Command::Mode(Mode::Raw))
+        .at(1.5, Command::Throttle(3300))
+        .at(3.0, Command::Throttle(3000));
+    let trace = Simulation::quadrupel(seed).run(&raw, 10.0);
+    report("raw mode hover", &trace, 3.0);
+
+    let mahony = Script::takeoff(Mode::FullControl)
+        .at(0.9, Command::Mode(Mode::Raw))
+        .at(0.9, Command::Estimator(AttitudeEstimator::Mahony))
+        .at(1.5, Command::Throttle(3300))
+        .at(3.0, Command::Throttle(3000));
+    let trace = Simulation::quadrupel(seed).run(&mahony, 10.0);
+    report("raw mode hover, mahony", &trace, 3.0);
+
+    let dmp_failure = Script::takeoff(Mode::FullControl)
+        .at(1.5, Command::Throttle(3300))
+        .at(3.0, Command::Throttle(3000))
+        .at(4.0, Command::FailDmp);
+    let trace = Simulation::quadrupel(seed).run(&dmp_failure, 10.0);
+    report("dmp fails in flight", &trace, 3.0);
+
+    let disconnect = Script::takeoff(Mode::FullControl)
+        .at(1.5, Command::Throttle(3300))
+        .at(3.0, Command::Throttle(3000))
+        .at(4.0, Command::Disconnect);
+    let trace = Simulation::quadrupel(seed).run(&disconnect, 10.0);
+    report("link lost", &trace, 0.0);
+}
@@ -0,0 +1,299 @@
+//! Rigid body model of the quadcopter.
+//!
+//! The world frame has z pointing up. The body frame matches the MPU: x to the front motor,
+//! y to the left motor and z up. Motors are numbered like in the flight code: 0 front,
+//! 1 right, 2 back, 3 left. Front and back spin clockwise seen from above.
+
+use std::ops;
+
+pub const GRAVITY: f64 = 9.80665;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct V3 {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl V3 {
+    pub const ZERO: V3 = V3::new(0.0, 0.0, 0.0);
+
+    pub const fn new(x: f64, y: f64, z: f64) -> Self {
+        V3 { x, y, z }
+    }
+
+    pub fn dot(self, rhs: V3) -> f64 {
+        self.x * rhs.x + self.y * rhs.y + self.z * rhs.z
+    }
+
+    pub fn cross(self, rhs: V3) -> V3 {
+        V3::new(
+            self.y * rhs.z - self.z * rhs.y,
+            self.z * rhs.x - self.x * rhs.z,
+            self.x * rhs.y - self.y * rhs.x,
+        )
+    }
+
+    pub fn norm(self) -> f64 {
+        self.dot(self).sqrt()
+    }
+
+    fn component_mul(self, rhs: V3) -> V3 {
+        V3::new(self.x * rhs.x, self.y * rhs.y, self.z * rhs.z)
+    }
+
+    fn component_div(self, rhs: V3) -> V3 {
+        V3::new(self.x / rhs.x, self.y / rhs.y, self.z / rhs.z)
+    }
+}
+
+impl ops::Add for V3 {
+    type Output = V3;
+    fn add(self, rhs: V3) -> V3 {
+        V3::new(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z)
+    }
+}
+
+impl ops::Sub for V3 {
+    type Output = V3;
+    fn sub(self, rhs: V3) -> V3 {
+        V3::new(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z)
+    }
+}
+
+impl ops::Mul<f64> for V3 {
+    type Output = V3;
+    fn mul(self, rhs: f64) -> V3 {
+        V3::new(self.x * rhs, self.y * rhs, self.z * rhs)
+    }
+}
+
+impl ops::Neg for V3 {
+    type Output = V3;
+    fn neg(self) -> V3 {
+        V3::new(-self.x, -self.y, -self.z)
+    }
+}
+
+/// Unit quaternion rotating body vectors into the world frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attitude {
+    pub w: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Attitude {
+    pub const LEVEL: Attitude = Attitude {
+        w: 1.0,
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+
+    /// Builds an attitude from angles in the flight code convention, see [`Attitude::euler`].
+    pub fn from_euler(yaw: f64, pitch: f64, roll: f64) -> Self {
+        // the body y axis points left, so a positive (nose up) pitch is a negative rotation
+        let (sy, cy) = (yaw / 2.0).sin_cos();
+        let (sp, cp) = (-pitch / 2.0).sin_cos();
+        let (sr, cr) = (roll / 2.0).sin_cos();
+        Attitude {
+            w: cr * cp * cy + sr * sp * sy,
+            x: sr * cp * cy - cr * sp * sy,
+            y: cr * sp * cy + sr * cp * sy,
+            z: cr * cp * sy - sr * sp * cy,
+        }
+    }
+
+    pub fn rotate(&self, v: V3) -> V3 {
+        let u = V3::new(self.x, self.y, self.z);
+        let t = u.cross(v) * 2.0;
+        v + t * self.w + u.cross(t)
+    }
+
+    pub fn rotate_inverse(&self, v: V3) -> V3 {
+        Attitude {
+            w: self.w,
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+        .rotate(v)
+    }
+
+    /// Integrates the body rates `rates` over `dt`.
+    pub fn integrate(&mut self, rates: V3, dt: f64) {
+        let Attitude { w, x, y, z } = *self;
+        let h = dt / 2.0;
+        self.w += h * (-x * rates.x - y * rates.y - z * rates.z);
+        self.x += h * (w * rates.x + y * rates.z - z * rates.y);
+        self.y += h * (w * rates.y - x * rates.z + z * rates.x);
+        self.z += h * (w * rates.z + x * rates.y - y * rates.x);
+        let n = (self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z).sqrt();
+        self.w /= n;
+        self.x /= n;
+        self.y /= n;
+        self.z /= n;
+    }
+
+    /// `(yaw, pitch, roll)` in radians, in the convention of the flight code:
+    /// yaw counter clockwise seen from above, pitch nose up and roll right side down.
+    pub fn euler(&self) -> (f64, f64, f64) {
+        let Attitude { w, x, y, z } = *self;
+        let roll = (2.0 * (w * x + y * z)).atan2(1.0 - 2.0 * (x * x + y * y));
+        let pitch = -(2.0 * (w * y - z * x)).clamp(-1.0, 1.0).asin();
+        let yaw = (2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (y * y + z * z));
+        (yaw, pitch, roll)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QuadParams {
+    /// kg
+    pub mass: f64,
+    /// Distance from the centre to each motor, m.
+    pub arm: f64,
+    /// Principal moments of inertia, kg m^2.
+    pub inertia: V3,
+    /// Thrust of one motor per squared motor command, N.
+    pub thrust_coefficient: f64,
+    /// Reaction torque of one motor per squared motor command, Nm.
+    pub torque_coefficient: f64,
+    /// Time constant of the motor speed following the command, s.
+    pub motor_time_constant: f64,
+    /// Linear drag, N per m/s.
+    pub drag: f64,
+    /// Rotational drag, Nm per rad/s.
+    pub angular_drag: f64,
+}
+
+impl QuadParams {
+    /// Roughly the quadrupel: about 0.6 kg, hovering at a motor command of 300.
+    pub fn quadrupel() -> Self {
+        let mass = 0.6;
+        let thrust_coefficient = mass * GRAVITY / (4.0 * 300.0 * 300.0);
+        QuadParams {
+            mass,
+            arm: 0.16,
+            inertia: V3::new(0.01, 0.01, 0.018),
+            thrust_coefficient,
+            torque_coefficient: thrust_coefficient * 0.016,
+            motor_time_constant: 0.03,
+            drag: 0.15,
+            angular_drag: 0.002,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QuadState {
+    /// World frame, m.
+    pub position: V3,
+    /// World frame, m/s.
+    pub velocity: V3,
+    /// World frame acceleration of the last step, m/s^2.
+    pub acceleration: V3,
+    pub attitude: Attitude,
+    /// Body frame, rad/s.
+    pub rates: V3,
+    /// Motor speeds in units of the motor command.
+    pub motor_speeds: [f64; 4],
+}
+
+impl QuadState {
+    pub fn on_ground() -> Self {
+        QuadState {
+            position: V3::ZERO,
+            velocity: V3::ZERO,
+            acceleration: V3::ZERO,
+            attitude: Attitude::LEVEL,
+            rates: V3::ZERO,
+            motor_speeds: [0.0; 4],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QuadModel {
+    pub params: QuadParams,
+    pub state: QuadState,
+    /// What the flight code last passed to `set_motors`.
+    pub motor_commands: [u16; 4],
+}
+
+/// Motor positions in the body frame, as multiples of the arm length.
+const MOTOR_POSITIONS: [V3; 4] = [
+    V3::new(1.0, 0.0, 0.0),
+    V3::new(0.0, -1.0, 0.0),
+    V3::new(-1.0, 0.0, 0.0),
+    V3::new(0.0, 1.0, 0.0),
+];
+/// Direction of the reaction torque of each motor around body z.
+const MOTOR_SPIN: [f64; 4] = [1.0, -1.0, 1.0, -1.0];
+
+impl QuadModel {
+    pub fn new(params: QuadParams) -> Self {
+        QuadModel {
+            params,
+            state: QuadState::on_ground(),
+            motor_commands: [0; 4],
+        }
+    }
+
+    pub fn motor_thrusts(&self) -> [f64; 4] {
+        self.state
+            .motor_speeds
+            .map(|s| self.params.thrust_coefficient * s * s)
+    }
+
+    /// The force per unit mass the accelerometer feels, in the body frame.
+    pub fn specific_force(&self) -> V3 {
+        self.state
+            .attitude
+            .rotate_inverse(self.state.acceleration + V3::new(0.0, 0.0, GRAVITY))
+    }
+
+    /// Advances the model by `dt` seconds. Keep `dt` around a millisecond.
+    pub fn step(&mut self, dt: f64) {
+        let p = &self.params;
+        let s = &mut self.state;
+
+        let alpha = (dt / p.motor_time_constant).min(1.0);
+        for (speed, command) in s.motor_speeds.iter_mut().zip(self.motor_commands) {
+            *speed += (command as f64 - *speed) * alpha;
+        }
+
+        let mut thrust = 0.0;
+        let mut torque = V3::ZERO;
+        for i in 0..4 {
+            let speed_squared = s.motor_speeds[i] * s.motor_speeds[i];
+            let force = p.thrust_coefficient * speed_squared;
+            thrust += force;
+            torque = torque + (MOTOR_POSITIONS[i] * p.arm).cross(V3::new(0.0, 0.0, force));
+            torque.z += MOTOR_SPIN[i] * p.torque_coefficient * speed_squared;
+        }
+
+        let force = s.attitude.rotate(V3::new(0.0, 0.0, thrust)) - s.velocity * p.drag;
+        s.acceleration = force * (1.0 / p.mass) - V3::new(0.0, 0.0, GRAVITY);
+
+        let gyroscopic = s.rates.cross(s.rates.component_mul(p.inertia));
+        let angular_acceleration =
+            (torque - gyroscopic - s.rates * p.angular_drag).component_div(p.inertia);
+
+        s.velocity = s.velocity + s.acceleration * dt;
+        s.position = s.position + s.velocity * dt;
+        s.rates = s.rates + angular_acceleration * dt;
+        s.attitude.integrate(s.rates, dt);
+
+        // the ground holds the drone up and keeps it level
+        if s.position.z <= 0.0 && s.velocity.z <= 0.0 {
+            let (yaw, _, _) = s.attitude.euler();
+            s.position.z = 0.0;
+            s.velocity = V3::ZERO;
+            s.acceleration = V3::ZERO;
+            s.rates = V3::ZERO;
+            s.attitude = Attitude::from_euler(yaw, 0.0, 0.0);
+        }
+    }
+}
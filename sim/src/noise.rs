@@ -0,0 +1,36 @@
+/// A small xorshift generator so simulations are reproducible from a seed.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng {
+            // xorshift gets stuck on zero
+            state: seed.max(1),
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Normally distributed with mean 0 (Box-Muller).
+    pub fn gaussian(&mut self, std_dev: f64) -> f64 {
+        if std_dev == 0.0 {
+            return 0.0;
+        }
+        let u1 = 1.0 - self.uniform();
+        let u2 = self.uniform();
+        std_dev * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
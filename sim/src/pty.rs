@@ -0,0 +1,113 @@
+//! A pseudo terminal standing in for the serial cable to the drone.
+
+use dronecode::hal::Uart;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::FromRawFd;
+use std::sync::{Mutex, OnceLock};
+
+static MASTER: OnceLock<File> = OnceLock::new();
+/// The end of a frame the pty did not take yet, written before anything else.
+static UNSENT: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+pub struct Pty {
+    pub master: File,
+    /// The path the base station should open, like `/dev/pts/3`.
+    pub slave_name: String,
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+impl Pty {
+    /// Opens a new pseudo terminal in raw, non blocking mode.
+    pub fn open() -> io::Result<Pty> {
+        // SAFETY: plain libc calls on a file descriptor we own, the name buffer outlives ptsname_r.
+        unsafe {
+            let fd = check(libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY))?;
+            let master = File::from_raw_fd(fd);
+            check(libc::grantpt(fd))?;
+            check(libc::unlockpt(fd))?;
+
+            let mut name = [0 as libc::c_char; 64];
+            let error = libc::ptsname_r(fd, name.as_mut_ptr(), name.len());
+            if error != 0 {
+                return Err(io::Error::from_raw_os_error(error));
+            }
+            let slave_name = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+
+            // no echo or line editing, the protocol is binary
+            let mut termios = core::mem::zeroed::<libc::termios>();
+            check(libc::tcgetattr(fd, &mut termios))?;
+            libc::cfmakeraw(&mut termios);
+            check(libc::tcsetattr(fd, libc::TCSANOW, &termios))?;
+
+            let flags = check(libc::fcntl(fd, libc::F_GETFL))?;
+            check(libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK))?;
+
+            Ok(Pty { master, slave_name })
+        }
+    }
+}
+
+/// The uart of the emulated drone, backed by the master side of a [`Pty`].
+pub struct PtyUart;
+
+impl PtyUart {
+    /// Connects the uart to `pty`. Can only be done once per process.
+    pub fn install(pty: Pty) {
+        if MASTER.set(pty.master).is_err() {
+            panic!("the emulator uart is already connected");
+        }
+    }
+}
+
+/// Writes as much of `unsent` as the non blocking `master` takes. `true` once it is all written.
+fn flush(mut master: &File, unsent: &mut Vec<u8>) -> bool {
+    while !unsent.is_empty() {
+        match master.write(unsent) {
+            Ok(0) => return false,
+            Ok(written) => {
+                unsent.drain(..written);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+impl Uart for PtyUart {
+    fn send_bytes(bytes: &[u8]) -> bool {
+        let Some(master) = MASTER.get() else {
+            return false;
+        };
+        let mut unsent = UNSENT.lock().unwrap();
+        // when nobody reads the other side the buffer fills up. The frame is dropped whole then,
+        // like on a cable nobody listens on, a frame the pty took a part of is finished later.
+        if !flush(master, &mut unsent) {
+            return true;
+        }
+        unsent.extend_from_slice(bytes);
+        flush(master, &mut unsent);
+        true
+    }
+
+    fn receive_bytes(bytes: &mut [u8]) -> usize {
+        match MASTER.get() {
+            // reading fails while no base station has the slave side open
+            Some(mut master) => {
+                // also finish a frame cut short while the drone has nothing to send
+                flush(master, &mut UNSENT.lock().unwrap());
+                master.read(bytes).unwrap_or(0)
+            }
+            None => 0,
+        }
+    }
+}
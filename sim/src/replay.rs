@@ -0,0 +1,129 @@
+//! Canned sensor data, played back instead of the sensor models.
+//!
+//! Recordings are text files with one reading per line:
+//!
+//! ```text
+//! # time ax ay az gx gy gz pressure battery [qw qx qy qz]
+//! 0.000 12 -40 16410 3 -1 0 101325 1195
+//! ```
+//!
+//! Time is in seconds, the sensor values in the units of the drivers. Columns may be separated
+//! by whitespace or commas, lines starting with `#` are ignored. The recording loops.
+
+use architecture::Frac;
+use dronecode::hal::{DmpQuaternion, RawVec};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Reading {
+    pub time: f64,
+    pub accel: RawVec,
+    pub gyro: RawVec,
+    pub pressure: u32,
+    pub battery: u16,
+    pub dmp: Option<DmpQuaternion>,
+}
+
+impl fmt::Display for Reading {
+    /// One line of a recording, without the newline.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let RawVec { x, y, z } = self.accel;
+        write!(f, "{:.4} {x} {y} {z}", self.time)?;
+        let RawVec { x, y, z } = self.gyro;
+        write!(f, " {x} {y} {z} {} {}", self.pressure, self.battery)?;
+        if let Some(DmpQuaternion { w, x, y, z }) = self.dmp {
+            write!(f, " {w} {x} {y} {z}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone)]
+pub struct Replay {
+    pub readings: Vec<Reading>,
+}
+
+fn parse_line(line: &str) -> Result<Reading, String> {
+    let fields: Vec<f64> = line
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|f| !f.is_empty())
+        .map(|f| f.parse().map_err(|_| format!("`{f}` is not a number")))
+        .collect::<Result<_, _>>()?;
+    if fields.len() != 9 && fields.len() != 13 {
+        return Err(format!("expected 9 or 13 columns, got {}", fields.len()));
+    }
+    let vec = |i: usize| RawVec {
+        x: fields[i] as i16,
+        y: fields[i + 1] as i16,
+        z: fields[i + 2] as i16,
+    };
+    Ok(Reading {
+        time: fields[0],
+        accel: vec(1),
+        gyro: vec(4),
+        pressure: fields[7] as u32,
+        battery: fields[8] as u16,
+        dmp: (fields.len() == 13).then(|| DmpQuaternion {
+            w: Frac::from_num(fields[9]),
+            x: Frac::from_num(fields[10]),
+            y: Frac::from_num(fields[11]),
+            z: Frac::from_num(fields[12]),
+        }),
+    })
+}
+
+impl Replay {
+    pub fn parse(text: &str) -> Result<Replay, ParseError> {
+        let mut readings = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let reading = parse_line(line).map_err(|message| ParseError {
+                line: i + 1,
+                message,
+            })?;
+            readings.push(reading);
+        }
+        if readings.is_empty() {
+            return Err(ParseError {
+                line: 0,
+                message: "the recording is empty".into(),
+            });
+        }
+        Ok(Replay { readings })
+    }
+
+    pub fn duration(&self) -> f64 {
+        let first = self.readings.first().unwrap().time;
+        let last = self.readings.last().unwrap().time;
+        last - first
+    }
+
+    /// The last reading at or before `time`, looping over the recording.
+    pub fn at(&self, time: f64) -> &Reading {
+        let first = self.readings[0].time;
+        let duration = self.duration();
+        let offset = if duration > 0.0 { time % duration } else { 0.0 };
+        let index = self
+            .readings
+            .partition_point(|r| r.time - first <= offset)
+            .saturating_sub(1);
+        &self.readings[index]
+    }
+}
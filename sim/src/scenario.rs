@@ -0,0 +1,344 @@
+//! Scripted flights of the real control loop against the simulated quadcopter.
+
+use crate::hal::{SimHal, FLASH_SIZE};
+use crate::model::{QuadModel, QuadParams};
+use crate::sensors::{SensorModel, SensorParams};
+use architecture::{
+    Attitude, AttitudeEstimator, ControlRequest, Frac, Message, Mode, Pascal, Pressure,
+    YawPitchRoll,
+};
+use dronecode::control_loop::ControlLoop;
+use dronecode::hal::mock::{host_link, MockHal, RamDisk};
+use dronecode::hal::Clock;
+use protocol::{DataLink, FuncLink, MessageLink};
+
+/// What the pilot does at some point in a [`Script`].
+#[derive(Debug, Clone)]
+pub enum Command {
+    Mode(Mode),
+    /// Sets the stick input that is sent with every following `ControlInput`.
+    Request(ControlRequest),
+    Throttle(i16),
+    /// Selects the attitude estimator of the raw mode.
+    Estimator(AttitudeEstimator),
+    /// Stops sending `ControlInput`, as if the cable was unplugged.
+    Disconnect,
+    /// Makes the DMP stop answering.
+    FailDmp,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    steps: Vec<(f64, Command)>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Script { steps: vec![] }
+    }
+
+    /// Adds `command` at `time` seconds after the start.
+    pub fn at(mut self, time: f64, command: Command) -> Self {
+        self.steps.push((time, command));
+        self.steps.sort_by(|a, b| a.0.total_cmp(&b.0));
+        self
+    }
+
+    /// Calibrates on the ground and switches to `mode` at one second.
+    pub fn takeoff(mode: Mode) -> Self {
+        Script::new()
+            .at(0.0, Command::Mode(Mode::Calibrate))
+            .at(0.8, Command::Mode(Mode::Safe))
+            .at(1.0, Command::Mode(mode))
+    }
+}
+
+/// One control loop tick of a simulation.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// s
+    pub time: f64,
+    pub mode: Mode,
+    /// The true `(yaw, pitch, roll)` of the model, rad.
+    pub attitude: (f64, f64, f64),
+    /// What the flight code thinks the attitude is, rad.
+    pub estimate: (f64, f64, f64),
+    /// The true height, m.
+    pub height: f64,
+    pub estimated_height: f64,
+    /// The true vertical speed, m/s.
+    pub v_z: f64,
+    pub estimated_v_z: f64,
+    pub motors: [u16; 4],
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    pub samples: Vec<Sample>,
+}
+
+impl Trace {
+    /// Largest difference between the true and estimated pitch and roll after `from` s.
+    pub fn max_attitude_error(&self, from: f64) -> f64 {
+        self.samples
+            .iter()
+            .filter(|s| s.time >= from)
+            .map(|s| {
+                (s.attitude.1 - s.estimate.1)
+                    .abs()
+                    .max((s.attitude.2 - s.estimate.2).abs())
+            })
+            .fold(0.0, f64::max)
+    }
+
+    /// Largest difference between the true and estimated height after `from` s.
+    pub fn max_height_error(&self, from: f64) -> f64 {
+        self.samples
+            .iter()
+            .filter(|s| s.time >= from)
+            .map(|s| (s.height - s.estimated_height).abs())
+            .fold(0.0, f64::max)
+    }
+
+    /// Largest true pitch or roll after `from` s.
+    pub fn max_tilt(&self, from: f64) -> f64 {
+        self.samples
+            .iter()
+            .filter(|s| s.time >= from)
+            .map(|s| s.attitude.1.abs().max(s.attitude.2.abs()))
+            .fold(0.0, f64::max)
+    }
+
+    pub fn last(&self) -> Option<&Sample> {
+        self.samples.last()
+    }
+}
+
+fn to_f64(attitude: Attitude) -> (f64, f64, f64) {
+    (
+        attitude.yaw.0.to_num(),
+        attitude.pitch.0.to_num(),
+        attitude.roll.0.to_num(),
+    )
+}
+
+/// The flight code running on a simulated quadcopter, with a pilot on the other end of the uart.
+///
+/// The uart buffers of [`MockHal`] are per thread, so run one simulation per thread.
+pub struct Simulation {
+    pub drone: ControlLoop<SimHal<MockHal>, RamDisk>,
+    pilot: MessageLink<FuncLink>,
+    request: ControlRequest,
+    connected: bool,
+    /// Every message the drone sent, in order.
+    pub received: Vec<Message>,
+}
+
+impl Simulation {
+    pub fn new(model: QuadModel, sensors: SensorModel) -> Self {
+        // drop whatever a previous simulation on this thread left on the wire
+        MockHal::take_tx();
+        let mut pilot = host_link();
+        while let Ok(Some(_)) = pilot.check_for_message() {}
+        Simulation {
+            drone: ControlLoop::new(SimHal::new(model, sensors), RamDisk::new(FLASH_SIZE)),
+            pilot,
+            request: ControlRequest::new(),
+            connected: true,
+            received: vec![],
+        }
+    }
+
+    /// The quadrupel with realistic sensor noise.
+    pub fn quadrupel(seed: u64) -> Self {
+        Simulation::new(
+            QuadModel::new(QuadParams::quadrupel()),
+            SensorModel::new(SensorParams::quadrupel(), seed),
+        )
+    }
+
+    pub fn time(&self) -> f64 {
+        self.drone.hal.time
+    }
+
+    pub fn send(&mut self, message: &Message) {
+        self.pilot.send(message).unwrap();
+    }
+
+    pub fn command(&mut self, command: &Command) {
+        match command {
+            Command::Mode(mode) => self.send(&Message::ChangeMode { mode: *mode }),
+            Command::Request(request) => self.request = request.clone(),
+            Command::Throttle(throttle) => self.request.throttle = *throttle,
+            Command::Estimator(estimator) => self.send(&Message::SelectEstimator {
+                estimator: *estimator,
+            }),
+            Command::Disconnect => self.connected = false,
+            Command::FailDmp => self.drone.hal.dmp_failed = true,
+        }
+    }
+
+    /// Runs one tick of the control loop and waits for the next one.
+    pub fn step(&mut self) -> Sample {
+        if self.connected {
+            let base_pressure = Pascal(Pressure::saturating_from_num(
+                self.drone.hal.sensors.params.ground_pressure,
+            ));
+            self.pilot
+                .send(&Message::ControlInput {
+                    request: self.request.clone(),
+                    base_pressure,
+                })
+                .unwrap();
+        }
+
+        self.drone.tick();
+        while let Ok(Some(message)) = self.pilot.check_for_message() {
+            self.received.push(message);
+        }
+
+        let hal = &self.drone.hal;
+        let sample = Sample {
+            time: hal.time,
+            mode: self.drone.controller.mode,
+            attitude: hal.model.state.attitude.euler(),
+            estimate: to_f64(self.drone.sensor.data.radius),
+            height: hal.model.state.position.z,
+            estimated_height: self.drone.sensor.data.height.0.to_num(),
+            v_z: hal.model.state.velocity.z,
+            estimated_v_z: self.drone.sensor.data.v_z.0.to_num(),
+            motors: hal.model.motor_commands,
+        };
+        self.drone.hal.wait_for_next_tick();
+        sample
+    }
+
+    /// Runs `script` for `duration` seconds of simulated time, as fast as possible.
+    pub fn run(&mut self, script: &Script, duration: f64) -> Trace {
+        let start = self.time();
+        let mut steps = script.steps.iter().peekable();
+        let mut trace = Trace::default();
+        while self.time() - start < duration {
+            while let Some((_, command)) = steps.next_if(|(t, _)| *t <= self.time() - start) {
+                self.command(command);
+            }
+            trace.samples.push(self.step());
+        }
+        trace
+    }
+}
+
+/// A stick input, angles in rad and yaw in rad/s.
+pub fn request(yaw: f64, pitch: f64, roll: f64, throttle: i16) -> ControlRequest {
+    ControlRequest {
+        radius: YawPitchRoll {
+            // the yaw stick counts 16.4 per rad/s
+            yaw: Frac::from_num(yaw * 16.4),
+            pitch: Frac::from_num(pitch),
+            roll: Frac::from_num(roll),
+        },
+        throttle,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use architecture::{BatteryLevel, Millivolts};
+
+    /// The modes of `trace` in the order they were entered.
+    fn modes(trace: &Trace) -> Vec<Mode> {
+        let mut modes: Vec<Mode> = trace.samples.iter().map(|s| s.mode).collect();
+        modes.dedup();
+        modes
+    }
+
+    fn hover() -> Script {
+        Script::takeoff(Mode::FullControl)
+            .at(1.5, Command::Throttle(3300))
+            .at(3.0, Command::Throttle(3000))
+    }
+
+    #[test]
+    fn full_control_hovers_level() {
+        let trace = Simulation::quadrupel(1).run(&hover(), 8.0);
+        assert_eq!(trace.last().unwrap().mode, Mode::FullControl);
+        assert!(trace.samples.iter().any(|s| s.height > 0.1));
+        assert!(trace.max_tilt(3.0) < 0.15, "{}", trace.max_tilt(3.0));
+        let error = trace.max_attitude_error(3.0);
+        assert!(error < 0.1, "{error}");
+    }
+
+    #[test]
+    fn the_pilot_arms_and_disarms() {
+        let script = hover().at(4.0, Command::Mode(Mode::Safe));
+        let trace = Simulation::quadrupel(2).run(&script, 5.0);
+        let modes = modes(&trace);
+        let start = modes.iter().position(|m| *m == Mode::Calibrate).unwrap();
+        assert_eq!(
+            modes[start..],
+            [Mode::Calibrate, Mode::Safe, Mode::FullControl, Mode::Safe]
+        );
+        assert_eq!(trace.last().unwrap().motors, [0; 4]);
+    }
+
+    #[test]
+    fn a_lost_link_panics_then_goes_safe() {
+        let script = hover().at(4.0, Command::Disconnect);
+        let mut simulation = Simulation::quadrupel(3);
+        let trace = simulation.run(&script, 6.0);
+        // panic mode takes the motors down and hands over to safe within the tick
+        let modes = modes(&trace);
+        assert!(
+            modes.ends_with(&[Mode::FullControl, Mode::Safe]),
+            "{modes:?}"
+        );
+        let landed = trace
+            .samples
+            .iter()
+            .rev()
+            .take_while(|s| s.mode == Mode::Safe)
+            .last()
+            .unwrap();
+        assert!(landed.time > 4.0);
+        assert_eq!(trace.last().unwrap().motors, [0; 4]);
+        // the pilot's own switch to safe after the calibration, and the one of panic mode
+        let safe = Message::ChangeMode { mode: Mode::Safe };
+        let reported = simulation.received.iter().filter(|m| **m == safe).count();
+        assert_eq!(reported, 2);
+    }
+
+    #[test]
+    fn a_failed_dmp_hands_over_to_the_estimator_in_flight() {
+        for estimator in [AttitudeEstimator::Kalman, AttitudeEstimator::Mahony] {
+            let script = hover()
+                .at(0.9, Command::Estimator(estimator))
+                .at(4.0, Command::FailDmp);
+            let mut simulation = Simulation::quadrupel(5);
+            let trace = simulation.run(&script, 10.0);
+            assert_eq!(trace.last().unwrap().mode, Mode::FullControl);
+            assert!(simulation.drone.controller.raw_option);
+            assert_eq!(simulation.drone.hal.tick_frequency, 350);
+            let tilt = trace.max_tilt(3.0);
+            assert!(tilt < 0.15, "{estimator:?} {tilt}");
+            let error = trace.max_attitude_error(4.0);
+            assert!(error < 0.1, "{estimator:?} {error}");
+        }
+    }
+
+    #[test]
+    fn arming_is_refused_on_a_battery_that_has_to_land() {
+        let mut simulation = Simulation::quadrupel(4);
+        // a full pack is below the landing level of this limit
+        simulation.drone.controller.limits.min_battery = Millivolts(12_100);
+        let trace = simulation.run(&hover(), 3.0);
+        assert_eq!(simulation.drone.battery.level, BatteryLevel::Land);
+        assert!(trace.samples.iter().all(|s| s.mode != Mode::FullControl));
+        assert_eq!(trace.last().unwrap().motors, [0; 4]);
+        // the drone answers with the mode it stayed in
+        let armed = Message::ChangeMode {
+            mode: Mode::FullControl,
+        };
+        assert!(!simulation.received.contains(&armed));
+    }
+}
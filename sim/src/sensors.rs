@@ -0,0 +1,185 @@
+//! Sensor models turning the true state of the [`QuadModel`] into readings as the
+//! quadrupel drivers would return them.
+
+use crate::model::{QuadModel, V3, GRAVITY};
+use crate::noise::Rng;
+use architecture::Frac;
+use dronecode::hal::{DmpQuaternion, RawVec};
+
+#[derive(Debug, Clone)]
+pub struct SensorParams {
+    pub accel_lsb_per_g: f64,
+    pub gyro_lsb_per_dps: f64,
+    /// Standard deviation of the accelerometer noise, m/s^2.
+    pub accel_noise: f64,
+    /// Standard deviation of the gyroscope noise, rad/s.
+    pub gyro_noise: f64,
+    /// Constant gyroscope offset, rad/s.
+    pub gyro_bias: V3,
+    /// How fast the gyroscope offset wanders, rad/s per sqrt(s).
+    pub gyro_bias_walk: f64,
+    /// Standard deviation of the DMP attitude noise, rad.
+    pub dmp_noise: f64,
+    /// Rate at which the DMP produces quaternions, Hz.
+    pub dmp_rate: f64,
+    /// Pressure at the ground, Pa.
+    pub ground_pressure: f64,
+    /// Standard deviation of the barometer noise, Pa.
+    pub pressure_noise: f64,
+    /// Battery capacity, mAh.
+    pub battery_capacity: f64,
+    /// Charge at the start of the simulation, between 0 and 1.
+    pub battery_charge: f64,
+    /// Internal resistance of the pack, Ohm.
+    pub battery_resistance: f64,
+    /// Current drawn by one motor per squared motor command, A.
+    pub motor_current_coefficient: f64,
+    /// Standard deviation of the battery reading noise, V.
+    pub battery_noise: f64,
+}
+
+impl SensorParams {
+    /// The MPU6050 at 2 g and 2000 deg/s, the BMP180 and a 3S 1300 mAh pack.
+    pub fn quadrupel() -> Self {
+        SensorParams {
+            accel_lsb_per_g: 16384.0,
+            gyro_lsb_per_dps: 16.4,
+            accel_noise: 0.05,
+            gyro_noise: 0.003,
+            gyro_bias: V3::new(0.01, -0.005, 0.008),
+            gyro_bias_walk: 0.0005,
+            dmp_noise: 0.002,
+            dmp_rate: 100.0,
+            ground_pressure: 101325.0,
+            pressure_noise: 3.0,
+            battery_capacity: 1300.0,
+            battery_charge: 1.0,
+            battery_resistance: 0.06,
+            motor_current_coefficient: 6.0 / (4.0 * 300.0 * 300.0),
+            battery_noise: 0.01,
+        }
+    }
+
+    /// The same sensors without any noise or offsets.
+    pub fn ideal() -> Self {
+        SensorParams {
+            accel_noise: 0.0,
+            gyro_noise: 0.0,
+            gyro_bias: V3::ZERO,
+            gyro_bias_walk: 0.0,
+            dmp_noise: 0.0,
+            pressure_noise: 0.0,
+            battery_noise: 0.0,
+            ..SensorParams::quadrupel()
+        }
+    }
+}
+
+pub struct SensorModel {
+    pub params: SensorParams,
+    pub rng: Rng,
+    pub gyro_bias: V3,
+    /// Charge drawn from the battery so far, mAh.
+    pub battery_used: f64,
+}
+
+fn saturate(value: f64) -> i16 {
+    value.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+impl SensorModel {
+    pub fn new(params: SensorParams, seed: u64) -> Self {
+        SensorModel {
+            gyro_bias: params.gyro_bias,
+            params,
+            rng: Rng::new(seed),
+            battery_used: 0.0,
+        }
+    }
+
+    fn noise(&mut self, std_dev: f64) -> V3 {
+        V3::new(
+            self.rng.gaussian(std_dev),
+            self.rng.gaussian(std_dev),
+            self.rng.gaussian(std_dev),
+        )
+    }
+
+    fn motor_current(&self, model: &QuadModel) -> f64 {
+        model
+            .state
+            .motor_speeds
+            .iter()
+            .map(|s| s * s * self.params.motor_current_coefficient)
+            .sum()
+    }
+
+    /// Advances the slowly changing parts of the sensors (bias drift, battery charge) by `dt`.
+    pub fn step(&mut self, model: &QuadModel, dt: f64) {
+        let walk = self.params.gyro_bias_walk * dt.sqrt();
+        self.gyro_bias = self.gyro_bias + self.noise(walk);
+        self.battery_used += self.motor_current(model) * dt / 3.6;
+    }
+
+    /// Raw accelerometer and gyroscope counts.
+    pub fn read_raw(&mut self, model: &QuadModel) -> (RawVec, RawVec) {
+        let accel = model.specific_force() + self.noise(self.params.accel_noise);
+        let accel = accel * (self.params.accel_lsb_per_g / GRAVITY);
+        let gyro = model.state.rates + self.gyro_bias + self.noise(self.params.gyro_noise);
+        let gyro = gyro * (self.params.gyro_lsb_per_dps * 180.0 / std::f64::consts::PI);
+        (
+            RawVec {
+                x: saturate(accel.x),
+                y: saturate(accel.y),
+                z: saturate(accel.z),
+            },
+            RawVec {
+                x: saturate(gyro.x),
+                y: saturate(gyro.y),
+                z: saturate(gyro.z),
+            },
+        )
+    }
+
+    pub fn read_dmp(&mut self, model: &QuadModel) -> DmpQuaternion {
+        let q = model.state.attitude;
+        let n = self.noise(self.params.dmp_noise * 0.5);
+        // small rotation error applied on top of the true attitude
+        let (w, x, y, z) = (
+            q.w - n.x * q.x - n.y * q.y - n.z * q.z,
+            q.x + n.x * q.w + n.z * q.y - n.y * q.z,
+            q.y + n.y * q.w - n.z * q.x + n.x * q.z,
+            q.z + n.z * q.w + n.y * q.x - n.x * q.y,
+        );
+        let norm = (w * w + x * x + y * y + z * z).sqrt();
+        DmpQuaternion {
+            w: Frac::from_num(w / norm),
+            x: Frac::from_num(x / norm),
+            y: Frac::from_num(y / norm),
+            z: Frac::from_num(z / norm),
+        }
+    }
+
+    /// Pressure in Pa, following the standard atmosphere above the ground.
+    pub fn read_pressure(&mut self, model: &QuadModel) -> u32 {
+        let height = model.state.position.z;
+        let pressure = self.params.ground_pressure * (1.0 - 2.25577e-5 * height).powf(5.25588);
+        (pressure + self.rng.gaussian(self.params.pressure_noise)).max(0.0) as u32
+    }
+
+    /// Open circuit voltage of the pack minus the sag of the motor current, in volts.
+    pub fn battery_voltage(&self, model: &QuadModel) -> f64 {
+        let charge = (self.params.battery_charge
+            - self.battery_used / self.params.battery_capacity)
+            .clamp(0.0, 1.0);
+        // a LiPo cell is around 4.2 V full, 3.7 V nominal and drops off quickly below 3.5 V
+        let cell = 3.3 + 0.9 * charge - 0.4 * (1.0 - charge).powi(8);
+        3.0 * cell - self.motor_current(model) * self.params.battery_resistance
+    }
+
+    /// Battery voltage in units of 10 mV.
+    pub fn read_battery(&mut self, model: &QuadModel) -> u16 {
+        let voltage = self.battery_voltage(model) + self.rng.gaussian(self.params.battery_noise);
+        (voltage * 100.0).max(0.0) as u16
+    }
+}